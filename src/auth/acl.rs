@@ -0,0 +1,305 @@
+use super::Principal;
+use crate::topic;
+use sage_mqtt::Topic;
+use std::{collections::HashMap, fs, io, path::Path, str::FromStr};
+
+/// What a rule allows, or forbids, on the topics it matches
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+    ReadWrite,
+    Deny,
+}
+
+impl Access {
+    fn reads(self) -> bool {
+        matches!(self, Access::Read | Access::ReadWrite)
+    }
+
+    fn writes(self) -> bool {
+        matches!(self, Access::Write | Access::ReadWrite)
+    }
+}
+
+impl FromStr for Access {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Access::Read),
+            "write" => Ok(Access::Write),
+            "readwrite" => Ok(Access::ReadWrite),
+            "deny" => Ok(Access::Deny),
+            _ => Err(format!("unknown access '{}'", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Rule {
+    access: Access,
+    filter: String,
+}
+
+impl Rule {
+    /// Returns the levels of the filter of the rule for the given client, or
+    /// None if the rule does not apply to it.
+    /// `%c` is replaced with the client identifier and `%u` with the user
+    /// name. Rules are ignored when the client has no user name or when these
+    /// contain wildcards or level separators.
+    fn levels(&self, client_id: &str, user_name: Option<&str>) -> Option<Vec<String>> {
+        let valid = |value: &str| !value.contains(['/', '+', '#']);
+        let mut filter = self.filter.clone();
+        if filter.contains("%c") {
+            if !valid(client_id) {
+                return None;
+            }
+            filter = filter.replace("%c", client_id);
+        }
+        if filter.contains("%u") {
+            let user_name = user_name.filter(|user_name| valid(user_name))?;
+            filter = filter.replace("%u", user_name);
+        }
+        Some(filter.split('/').map(String::from).collect())
+    }
+}
+
+/// Access control lists deciding on which topics each client can publish and
+/// subscribe.
+///
+/// The rules are usually loaded from a file. Each line is one of:
+/// - `user <user name>`: the following `topic` rules apply to this user only.
+///   `topic` rules before any `user` line apply to anonymous clients.
+/// - `topic [read|write|readwrite|deny] <filter>`: gives access to the topics
+///   matching the filter, `readwrite` being the default.
+/// - `pattern [read|write|readwrite|deny] <filter>`: same as `topic` for all
+///   clients, where `%c` stands for the client identifier and `%u` for the
+///   user name.
+///
+/// Empty lines and lines starting with `#` are ignored.
+///
+/// The user name is the one of the principal the client is authenticated as,
+/// clients without one being anonymous.
+/// A client can publish on a topic name if a rule gives write access to it
+/// and no `deny` rule matches it.
+/// A client can subscribe to a filter if a rule gives read access to all the
+/// topic names it matches, and no `deny` rule matches any of them.
+/// The default `Acl` has no rule, so that nothing is allowed.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    anonymous: Vec<Rule>,
+    users: HashMap<String, Vec<Rule>>,
+    patterns: Vec<Rule>,
+}
+
+impl Acl {
+    /// Loads the access control lists of the file at the given path
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Returns the rules which apply to the given client with their levels
+    fn rules(&self, client_id: &str, principal: Option<&Principal>) -> Vec<(Access, Vec<String>)> {
+        let user_name = principal.map(|principal| principal.name.as_str());
+        let rules = match user_name {
+            Some(user_name) => self.users.get(user_name).map(Vec::as_slice),
+            None => Some(self.anonymous.as_slice()),
+        };
+        rules
+            .unwrap_or_default()
+            .iter()
+            .chain(&self.patterns)
+            .filter_map(|rule| {
+                rule.levels(client_id, user_name)
+                    .map(|levels| (rule.access, levels))
+            })
+            .collect()
+    }
+
+    /// Checks whether the client can publish on the given topic name
+    pub fn can_publish(
+        &self,
+        client_id: &str,
+        principal: Option<&Principal>,
+        topic_name: &Topic,
+    ) -> bool {
+        let name = topic::levels(topic_name);
+        let rules = self.rules(client_id, principal);
+        rules
+            .iter()
+            .any(|(access, filter)| access.writes() && topic::matches_levels(filter, &name))
+            && !rules.iter().any(|(access, filter)| {
+                *access == Access::Deny && topic::matches_levels(filter, &name)
+            })
+    }
+
+    /// Checks whether the client can subscribe to the given topic filter. For
+    /// shared subscriptions, the filter following the share name is checked.
+    pub fn can_subscribe(
+        &self,
+        client_id: &str,
+        principal: Option<&Principal>,
+        filter: &Topic,
+    ) -> bool {
+        let filter = topic::levels(&topic::share(filter).unwrap_or_else(|| filter.clone()));
+        let rules = self.rules(client_id, principal);
+        rules
+            .iter()
+            .any(|(access, rule)| access.reads() && topic::covers_levels(rule, &filter))
+            && !rules.iter().any(|(access, rule)| {
+                *access == Access::Deny && topic::overlaps_levels(rule, &filter)
+            })
+    }
+}
+
+impl FromStr for Acl {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut acl = Acl::default();
+        let mut user_name: Option<String> = None;
+        for (index, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |e: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid ACL file line {}: {}", index + 1, e),
+                )
+            };
+
+            let (keyword, arguments) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let arguments = arguments.trim();
+            if keyword == "user" {
+                if arguments.is_empty() {
+                    return Err(error("expected user <user name>".into()));
+                }
+                user_name = Some(arguments.into());
+                continue;
+            }
+
+            let (access, filter) = match arguments.split_once(char::is_whitespace) {
+                Some((access, filter)) => (access.parse().map_err(error)?, filter.trim()),
+                None => (Access::ReadWrite, arguments),
+            };
+            if filter.is_empty() || !topic::is_valid_filter(&Topic::from(filter)) {
+                return Err(error(format!("invalid topic filter '{}'", filter)));
+            }
+            let rule = Rule {
+                access,
+                filter: filter.into(),
+            };
+
+            match (keyword, &user_name) {
+                ("topic", Some(user_name)) => {
+                    acl.users.entry(user_name.clone()).or_default().push(rule)
+                }
+                ("topic", None) => acl.anonymous.push(rule),
+                ("pattern", _) => acl.patterns.push(rule),
+                _ => return Err(error(format!("unknown keyword '{}'", keyword))),
+            }
+        }
+        Ok(acl)
+    }
+}
+
+#[cfg(test)]
+mod unit {
+
+    use super::*;
+
+    const ACL: &str = "
+        # Anybody can read the news
+        pattern read news/#
+        pattern readwrite tenants/%u/#
+        pattern deny tenants/%u/secret
+        pattern write devices/%c/status
+
+        topic read public/#
+
+        user jaden
+        topic write news/sport
+        topic readwrite $SYS/#
+    ";
+
+    fn jaden() -> Option<Principal> {
+        Some(Principal::new("jaden"))
+    }
+
+    #[test]
+    fn publish() {
+        let acl: Acl = ACL.parse().unwrap();
+        let jaden = jaden();
+        let can_publish =
+            |principal, topic_name| acl.can_publish("kettle", principal, &Topic::from(topic_name));
+
+        assert!(can_publish(jaden.as_ref(), "news/sport"));
+        assert!(!can_publish(jaden.as_ref(), "news/music"));
+        assert!(can_publish(jaden.as_ref(), "tenants/jaden/kitchen"));
+        assert!(!can_publish(jaden.as_ref(), "tenants/jarod/kitchen"));
+        assert!(!can_publish(jaden.as_ref(), "tenants/jaden/secret"));
+        assert!(can_publish(jaden.as_ref(), "devices/kettle/status"));
+        assert!(!can_publish(jaden.as_ref(), "devices/oven/status"));
+        assert!(can_publish(jaden.as_ref(), "$SYS/uptime"));
+
+        // Anonymous clients have no user name
+        assert!(!can_publish(None, "news/sport"));
+        assert!(!can_publish(None, "tenants/%u/kitchen"));
+        assert!(can_publish(None, "devices/kettle/status"));
+    }
+
+    #[test]
+    fn subscribe() {
+        let acl: Acl = ACL.parse().unwrap();
+        let jaden = jaden();
+        let can_subscribe =
+            |principal, filter| acl.can_subscribe("kettle", principal, &Topic::from(filter));
+
+        assert!(can_subscribe(jaden.as_ref(), "news/#"));
+        assert!(can_subscribe(jaden.as_ref(), "news/+/today"));
+        assert!(!can_subscribe(jaden.as_ref(), "#"));
+        assert!(can_subscribe(jaden.as_ref(), "tenants/jaden/kitchen"));
+        assert!(can_subscribe(
+            jaden.as_ref(),
+            "$share/group/tenants/jaden/kitchen"
+        ));
+        assert!(!can_subscribe(jaden.as_ref(), "tenants/+/kitchen"));
+        // The secret is denied even through wildcards
+        assert!(!can_subscribe(jaden.as_ref(), "tenants/jaden/#"));
+        assert!(!can_subscribe(jaden.as_ref(), "tenants/jaden/+"));
+        assert!(!can_subscribe(jaden.as_ref(), "devices/kettle/status"));
+        assert!(!can_subscribe(jaden.as_ref(), "public/#"));
+
+        assert!(can_subscribe(None, "public/#"));
+        assert!(!can_subscribe(None, "$SYS/#"));
+    }
+
+    #[test]
+    fn substitutions_must_not_contain_wildcards() {
+        let acl: Acl = "pattern readwrite devices/%c/#".parse().unwrap();
+        assert!(acl.can_subscribe("kettle", None, &Topic::from("devices/kettle/#")));
+        assert!(!acl.can_subscribe("+", None, &Topic::from("devices/+/#")));
+        assert!(!acl.can_subscribe("#", None, &Topic::from("devices/#")));
+        assert!(!acl.can_publish("a/b", None, &Topic::from("devices/a/b/c")));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for content in [
+            "user",
+            "topic",
+            "topic readonly sport/#",
+            "topic read sport/#/tennis",
+            "owner jaden",
+        ] {
+            let error = content.parse::<Acl>().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        let acl: Acl = "".parse().unwrap();
+        assert!(!acl.can_publish("kettle", None, &Topic::from("sport")));
+    }
+}
@@ -0,0 +1,47 @@
+/// The identity of the certificate a client presented when establishing a
+/// TLS connection. The certificate is verified against the CA bundle of the
+/// TLS layer before the client is served.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// The distinguished name of the subject, such as
+    /// `CN=kettle,O=Factory`
+    pub subject: String,
+    /// The common name (CN) of the subject, if any
+    pub common_name: Option<String>,
+    /// The DNS names, email addresses or URIs of the Subject Alternative Name
+    /// extension
+    pub subject_alt_names: Vec<String>,
+}
+
+/// What is known of the TLS connection of a client, established by the
+/// application or by a proxy in front of the broker
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// The TLS version, such as `TLSv1.3`
+    pub version: Option<String>,
+    /// The cipher suite, such as `TLS_AES_256_GCM_SHA384`
+    pub cipher: Option<String>,
+    /// The algorithm the certificate of the client is signed with
+    pub signature_algorithm: Option<String>,
+    /// The algorithm of the public key of the certificate of the client
+    pub key_algorithm: Option<String>,
+    /// The verified certificate the client presented, if any
+    pub certificate: Option<ClientCertificate>,
+}
+
+/// How the broker uses the common name of client certificates.
+/// A client without a certificate or whose certificate has no common name
+/// is refused with `NotAuthorized`, unless the identity is ignored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CertificateIdentity {
+    /// The certificate is only given to the authenticator (default)
+    #[default]
+    Ignore,
+    /// The client is authenticated as the common name, which replaces the
+    /// user name. The user name and password of the CONNECT packet are not
+    /// checked.
+    UserName,
+    /// The common name is the client identifier, which the broker assigns to
+    /// the client if it requested another one.
+    ClientId,
+}
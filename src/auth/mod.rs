@@ -0,0 +1,125 @@
+//! Authentication of the clients connecting to the broker.
+//!
+//! The broker authenticates the user name and password of each CONNECT packet
+//! with the `Authenticator` given in its settings, if any. An application can
+//! supply its own implementation or use the built-in `PasswordFile`.
+//!
+//! Clients can instead use enhanced authentication [MQTT 4.12], exchanging
+//! AUTH packets with the broker according to one of the `Mechanism`s given in
+//! its settings. The built-in `ScramSha256` mechanism authenticates the users
+//! of a `PasswordFile` without sending their passwords.
+//!
+//! Clients connecting over TLS can be identified by their `ClientCertificate`
+//! instead, according to the `CertificateIdentity` of the settings.
+//!
+//! Once connected, clients can be restricted to some topics with an `Acl`.
+use crate::RemoteAddr;
+use futures::future::BoxFuture;
+use sage_mqtt::{ConnAck, Connect, ReasonCode};
+use std::fmt::Debug;
+
+mod acl;
+mod certificate;
+mod password_file;
+mod scram;
+
+pub use acl::Acl;
+pub use certificate::{CertificateIdentity, ClientCertificate, TlsInfo};
+pub use password_file::PasswordFile;
+pub use scram::ScramSha256;
+
+/// The identity a client has been authenticated as. It is kept along with the
+/// session for as long as the client stays connected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Principal {
+    /// The name identifying the authenticated user
+    pub name: String,
+}
+
+impl Principal {
+    /// Creates a new principal with the given name
+    pub fn new(name: &str) -> Self {
+        Principal { name: name.into() }
+    }
+}
+
+/// The credentials a client provides in its CONNECT packet
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    /// The address of the client. The credentials of the process of clients
+    /// connected over a Unix domain socket can authenticate them.
+    pub addr: RemoteAddr,
+    /// The client identifier. It is the one assigned by the server if the
+    /// client did not provide any.
+    pub client_id: String,
+    /// The user name, if any
+    pub user_name: Option<String>,
+    /// The password, if any
+    pub password: Option<Vec<u8>>,
+    /// The TLS connection of the client, including the certificate it
+    /// presented if any
+    pub tls: Option<TlsInfo>,
+}
+
+/// Decides whether a client can connect to the broker.
+///
+/// The authenticator returns the principal the client is authenticated as, or
+/// the reason code of the CONNACK refusing the connection. This should be one
+/// of:
+/// - `BadUserNameOrPassword`: The user name or password is not valid.
+/// - `NotAuthorized`: The client is not allowed to connect, for instance
+///   because it did not provide any credentials.
+/// - `ServerUnavailable` or `ServerBusy`: The credentials cannot be checked
+///   at the moment.
+pub trait Authenticator: Debug + Send + Sync {
+    /// Authenticates the given credentials
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a Credentials,
+    ) -> BoxFuture<'a, Result<Principal, ReasonCode>>;
+}
+
+/// The outcome of a successful step of an enhanced authentication exchange
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// More data is needed from the client. The given data is sent in an AUTH
+    /// packet with the `ContinueAuthentication` reason code.
+    Continue(Vec<u8>),
+    /// The client is authenticated as the given principal. The given data is
+    /// sent in the CONNACK packet, or in the AUTH packet ending a
+    /// re-authentication.
+    Success(Principal, Vec<u8>),
+}
+
+/// An enhanced authentication method [MQTT 4.12].
+pub trait Mechanism: Debug + Send + Sync {
+    /// The name of the method, as given by the clients in the Authentication
+    /// Method property, such as `SCRAM-SHA-256`
+    fn method(&self) -> &str;
+
+    /// Starts an exchange with a client which connects or re-authenticates.
+    /// Only the address and client identifier are given on re-authentication.
+    fn start(&self, credentials: &Credentials) -> Box<dyn Exchange>;
+}
+
+/// An enhanced authentication exchange with a client.
+///
+/// The exchange is given the data of the CONNECT packet (or of the AUTH packet
+/// starting a re-authentication), then the data of each AUTH packet the client
+/// sends, until it returns `Step::Success` or fails. A failure refuses the
+/// connection with the returned reason code, which should be `NotAuthorized`.
+pub trait Exchange: Debug + Send {
+    /// Processes the authentication data received from the client
+    fn step<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<Step, ReasonCode>>;
+}
+
+/// An enhanced authentication exchange in progress
+#[derive(Debug)]
+pub(crate) struct Handshake {
+    /// The authentication method
+    pub method: String,
+    pub exchange: Box<dyn Exchange>,
+    /// The CONNECT packet and its prepared CONNACK, unless the client is
+    /// re-authenticating
+    pub connect: Option<(Connect, ConnAck)>,
+}
@@ -0,0 +1,291 @@
+use super::{Authenticator, Credentials, Principal};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::future::BoxFuture;
+use rand::RngCore;
+use sage_mqtt::ReasonCode;
+use sha2::Sha256;
+use std::{collections::HashMap, fmt, fs, io, path::Path, str::FromStr};
+use subtle::ConstantTimeEq;
+
+/// The number of PBKDF2 iterations used to hash new passwords
+pub const DEFAULT_ITERATIONS: u32 = 4096;
+
+const SCHEME: &str = "pbkdf2-sha256";
+/// The size in bytes of a SHA-256 digest
+pub(crate) const DIGEST_SIZE: usize = 32;
+/// The size in bytes of the salts of new passwords
+pub(crate) const SALT_SIZE: usize = 16;
+
+/// The entry the passwords of unknown users are checked against, only to take
+/// as long as for known users
+const DUMMY_ENTRY: Entry = Entry {
+    iterations: DEFAULT_ITERATIONS,
+    salt: Vec::new(),
+    salted_password: [0; DIGEST_SIZE],
+};
+
+/// Hashes the password with PBKDF2-HMAC-SHA-256 [RFC 8018]
+pub(crate) fn salt_password(password: &[u8], salt: &[u8], iterations: u32) -> [u8; DIGEST_SIZE] {
+    pbkdf2::pbkdf2_hmac_array::<Sha256, DIGEST_SIZE>(password, salt, iterations)
+}
+
+/// The salted hash of a password
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Entry {
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub salted_password: [u8; DIGEST_SIZE],
+}
+
+impl Entry {
+    /// Hashes the given password with a random salt
+    fn new(password: &[u8]) -> Self {
+        let mut salt = vec![0u8; SALT_SIZE];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let salted_password = salt_password(password, &salt, DEFAULT_ITERATIONS);
+        Entry {
+            iterations: DEFAULT_ITERATIONS,
+            salt,
+            salted_password,
+        }
+    }
+
+    /// Returns true if the given password is the one the entry was created
+    /// from
+    fn verify(&self, password: &[u8]) -> bool {
+        let salted_password = salt_password(password, &self.salt, self.iterations);
+        salted_password.ct_eq(&self.salted_password).into()
+    }
+}
+
+impl FromStr for Entry {
+    type Err = String;
+
+    /// Parses an entry of the form `$pbkdf2-sha256$<iterations>$<salt>$<hash>`
+    /// where salt and hash are Base64 encoded
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split('$').collect();
+        match fields[..] {
+            ["", SCHEME, iterations, salt, hash] => {
+                let iterations = iterations
+                    .parse()
+                    .ok()
+                    .filter(|&i| i > 0)
+                    .ok_or("invalid iteration count")?;
+                let salt = BASE64.decode(salt).map_err(|_| "invalid salt")?;
+                let salted_password = BASE64
+                    .decode(hash)
+                    .ok()
+                    .and_then(|hash| hash.try_into().ok())
+                    .ok_or("invalid hash")?;
+                Ok(Entry {
+                    iterations,
+                    salt,
+                    salted_password,
+                })
+            }
+            _ => Err(format!("expected ${}$<iterations>$<salt>$<hash>", SCHEME)),
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "${}${}${}${}",
+            SCHEME,
+            self.iterations,
+            BASE64.encode(&self.salt),
+            BASE64.encode(self.salted_password)
+        )
+    }
+}
+
+/// An `Authenticator` checking user names and passwords against a list of
+/// users and the salted hashes of their passwords.
+///
+/// The list is usually loaded from a file where each line is of the form
+/// `<user name>:$pbkdf2-sha256$<iterations>$<salt>$<hash>`. Salt and hash are
+/// Base64 encoded and the hash is computed with PBKDF2-HMAC-SHA-256. Empty
+/// lines and lines starting with `#` are ignored.
+/// The file content is obtained by formatting a `PasswordFile` to which users
+/// were added with `insert`.
+///
+/// Clients which do not provide a user name are refused with `NotAuthorized`
+/// and clients with an unknown user name or a wrong password with
+/// `BadUserNameOrPassword`.
+#[derive(Clone, Debug, Default)]
+pub struct PasswordFile {
+    users: HashMap<String, Entry>,
+}
+
+impl PasswordFile {
+    /// Loads the password file at the given path
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Adds a user with the given password, replacing any existing one.
+    /// The password is hashed with a random salt.
+    pub fn insert(&mut self, user_name: &str, password: &str) {
+        self.users
+            .insert(user_name.into(), Entry::new(password.as_bytes()));
+    }
+
+    /// Removes the given user. Returns true if the user existed.
+    pub fn remove(&mut self, user_name: &str) -> bool {
+        self.users.remove(user_name).is_some()
+    }
+
+    /// Returns the number of users
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    /// Returns true if there is no user
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Returns the salted hash of the password of the given user
+    pub(crate) fn entry(&self, user_name: &str) -> Option<&Entry> {
+        self.users.get(user_name)
+    }
+
+    /// Returns true if the given user exists and the password is the right one.
+    /// The password of an unknown user is checked against a dummy entry
+    /// hashed with the same iteration count, so that users cannot be guessed
+    /// from the time it takes.
+    pub fn verify(&self, user_name: &str, password: &[u8]) -> bool {
+        match self.users.get(user_name) {
+            Some(entry) => entry.verify(password),
+            None => {
+                DUMMY_ENTRY.verify(password);
+                false
+            }
+        }
+    }
+}
+
+impl FromStr for PasswordFile {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut users = HashMap::new();
+        for (index, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let entry = line
+                .split_once(':')
+                .ok_or_else(|| String::from("expected <user name>:<hash>"))
+                .and_then(|(user_name, entry)| Ok((user_name.into(), entry.parse()?)));
+            match entry {
+                Ok((user_name, entry)) => {
+                    users.insert(user_name, entry);
+                }
+                Err(e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid password file line {}: {}", index + 1, e),
+                    ))
+                }
+            }
+        }
+        Ok(PasswordFile { users })
+    }
+}
+
+impl fmt::Display for PasswordFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut users: Vec<_> = self.users.iter().collect();
+        users.sort_by_key(|(user_name, _)| *user_name);
+        for (user_name, entry) in users {
+            writeln!(f, "{}:{}", user_name, entry)?;
+        }
+        Ok(())
+    }
+}
+
+impl Authenticator for PasswordFile {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a Credentials,
+    ) -> BoxFuture<'a, Result<Principal, ReasonCode>> {
+        Box::pin(async move {
+            let user_name = credentials
+                .user_name
+                .as_deref()
+                .ok_or(ReasonCode::NotAuthorized)?;
+            let password = credentials.password.as_deref().unwrap_or_default();
+            if self.verify(user_name, password) {
+                Ok(Principal::new(user_name))
+            } else {
+                Err(ReasonCode::BadUserNameOrPassword)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod unit {
+
+    use super::*;
+
+    #[test]
+    fn verify_passwords() {
+        let mut passwords = PasswordFile::default();
+        passwords.insert("jaden", "secret");
+        passwords.insert("jarod", "secret");
+
+        assert!(passwords.verify("jaden", b"secret"));
+        assert!(!passwords.verify("jaden", b"Secret"));
+        assert!(!passwords.verify("jason", b"secret"));
+
+        // The same password is salted differently for each user
+        assert_ne!(passwords.users["jaden"], passwords.users["jarod"]);
+
+        assert!(passwords.remove("jaden"));
+        assert!(!passwords.verify("jaden", b"secret"));
+        assert_eq!(passwords.len(), 1);
+    }
+
+    #[test]
+    fn format_and_parse() {
+        let mut passwords = PasswordFile::default();
+        passwords.insert("jaden", "secret");
+        passwords.insert("jarod", "hunter2");
+
+        let content = format!("# Lab users\n\n{}", passwords);
+        let parsed: PasswordFile = content.parse().unwrap();
+        assert_eq!(parsed.users, passwords.users);
+        assert!(parsed.verify("jarod", b"hunter2"));
+    }
+
+    #[test]
+    fn parse_known_hash() {
+        let passwords: PasswordFile =
+            "jaden:$pbkdf2-sha256$1$c2FsdA==$VawEblbjCJ/sFpHCJUS2BflBhSFt3gRl5oudV8INrLw="
+                .parse()
+                .unwrap();
+        assert!(passwords.verify("jaden", b"passwd"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for content in [
+            "jaden",
+            "jaden:secret",
+            "jaden:$sha1$1$c2FsdA==$c2FsdA==",
+            "jaden:$pbkdf2-sha256$0$c2FsdA==$VawEblbjCJ/sFpHCJUS2BflBhSFt3gRl5oudV8INrLw=",
+            "jaden:$pbkdf2-sha256$1$c2FsdA$VawEblbjCJ/sFpHCJUS2BflBhSFt3gRl5oudV8INrLw=",
+            "jaden:$pbkdf2-sha256$1$c2FsdA==$c2FsdA==",
+        ] {
+            let error = content.parse::<PasswordFile>().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
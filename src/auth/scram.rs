@@ -0,0 +1,341 @@
+use super::{
+    password_file::{Entry, DEFAULT_ITERATIONS, DIGEST_SIZE, SALT_SIZE},
+    Credentials, Exchange, Mechanism, PasswordFile, Principal, Step,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use nanoid::nanoid;
+use rand::RngCore;
+use sage_mqtt::ReasonCode;
+use sha2::{Digest, Sha256};
+use std::{fmt, sync::Arc};
+use subtle::ConstantTimeEq;
+
+const METHOD: &str = "SCRAM-SHA-256";
+
+/// The key the salts of unknown users are derived from. It is drawn once
+/// for the broker so that a user keeps the same salt from one exchange to
+/// another.
+#[derive(Clone)]
+struct Secret([u8; DIGEST_SIZE]);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+/// The SCRAM-SHA-256 enhanced authentication method [RFC 5802] [RFC 7677].
+///
+/// Clients prove they know the password of a user of the given
+/// `PasswordFile` without sending it, and the broker proves it knows the
+/// salted password in return, in the final CONNACK or AUTH packet.
+/// Channel binding is not supported.
+#[derive(Debug, Clone)]
+pub struct ScramSha256 {
+    passwords: Arc<PasswordFile>,
+    secret: Secret,
+}
+
+impl ScramSha256 {
+    /// Creates the mechanism for the users of the given password file
+    pub fn new(passwords: Arc<PasswordFile>) -> Self {
+        let mut secret = [0u8; DIGEST_SIZE];
+        rand::thread_rng().fill_bytes(&mut secret);
+        ScramSha256 {
+            passwords,
+            secret: Secret(secret),
+        }
+    }
+}
+
+impl Mechanism for ScramSha256 {
+    fn method(&self) -> &str {
+        METHOD
+    }
+
+    fn start(&self, _: &Credentials) -> Box<dyn Exchange> {
+        Box::new(Scram {
+            passwords: self.passwords.clone(),
+            secret: self.secret.clone(),
+            server_nonce: nanoid!(24),
+            state: State::ClientFirst,
+        })
+    }
+}
+
+/// The messages of the exchange which the server expects next
+#[derive(Debug)]
+enum State {
+    ClientFirst,
+    ClientFinal {
+        user_name: String,
+        /// None if the user is unknown, in which case the exchange goes on
+        /// with a made up salt so that users cannot be guessed. The salt is
+        /// derived from the user name so that it does not change from one
+        /// exchange to another either.
+        entry: Option<Entry>,
+        gs2_header: String,
+        nonce: String,
+        auth_message: String,
+    },
+    Done,
+}
+
+#[derive(Debug)]
+struct Scram {
+    passwords: Arc<PasswordFile>,
+    secret: Secret,
+    server_nonce: String,
+    state: State,
+}
+
+/// Decodes a user name, where `=2C` and `=3D` stand for `,` and `=`
+fn decode_name(name: &str) -> Option<String> {
+    let mut result = String::new();
+    let mut parts = name.split('=');
+    result.push_str(parts.next()?);
+    for part in parts {
+        let (escape, rest) = (part.get(..2)?, &part[2..]);
+        match escape {
+            "2C" => result.push(','),
+            "3D" => result.push('='),
+            _ => return None,
+        }
+        result.push_str(rest);
+    }
+    Some(result)
+}
+
+/// Computes the HMAC-SHA-256 of the given data [RFC 2104]
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Returns the value of an attribute of the form `<name>=<value>`
+fn attribute<'a>(field: Option<&'a str>, name: &str) -> Option<&'a str> {
+    field?.strip_prefix(name)?.strip_prefix('=')
+}
+
+impl Scram {
+    /// Processes the client-first-message, returning the server-first-message
+    fn client_first(&mut self, message: &str) -> Option<String> {
+        // Only clients which do not support channel binding and do not give
+        // an authorization identity are supported
+        let bare = message
+            .strip_prefix("n,,")
+            .or_else(|| message.strip_prefix("y,,"))?;
+        let gs2_header = &message[..3];
+
+        let mut fields = bare.split(',');
+        let user_name = decode_name(attribute(fields.next(), "n")?)?;
+        let client_nonce = attribute(fields.next(), "r")?;
+        if user_name.is_empty() || client_nonce.is_empty() {
+            return None;
+        }
+
+        let entry = self.passwords.entry(&user_name).cloned();
+        let (salt, iterations) = match &entry {
+            Some(entry) => (entry.salt.clone(), entry.iterations),
+            None => {
+                let salt = hmac_sha256(&self.secret.0, user_name.as_bytes());
+                (salt[..SALT_SIZE].to_vec(), DEFAULT_ITERATIONS)
+            }
+        };
+
+        let nonce = format!("{}{}", client_nonce, self.server_nonce);
+        let server_first = format!("r={},s={},i={}", nonce, BASE64.encode(&salt), iterations);
+        self.state = State::ClientFinal {
+            user_name,
+            entry,
+            gs2_header: gs2_header.into(),
+            nonce,
+            auth_message: format!("{},{}", bare, server_first),
+        };
+        Some(server_first)
+    }
+
+    /// Processes the client-final-message, returning the principal and the
+    /// server-final-message if the client proof is valid
+    fn client_final(&mut self, message: &str) -> Option<(Principal, String)> {
+        if let State::ClientFinal {
+            user_name,
+            entry,
+            gs2_header,
+            nonce,
+            auth_message,
+        } = std::mem::replace(&mut self.state, State::Done)
+        {
+            let (without_proof, proof) = message.rsplit_once(",p=")?;
+            let proof = BASE64
+                .decode(proof)
+                .ok()
+                .filter(|p| p.len() == DIGEST_SIZE)?;
+            let mut fields = without_proof.split(',');
+            let channel_binding = BASE64.decode(attribute(fields.next(), "c")?).ok()?;
+            if channel_binding != gs2_header.as_bytes() || attribute(fields.next(), "r")? != nonce {
+                return None;
+            }
+
+            let entry = entry?;
+            let auth_message = format!("{},{}", auth_message, without_proof);
+            let client_key = hmac_sha256(&entry.salted_password, b"Client Key");
+            let stored_key = Sha256::digest(client_key);
+            let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+            let received_key: Vec<u8> = proof
+                .iter()
+                .zip(client_signature)
+                .map(|(p, s)| p ^ s)
+                .collect();
+            if !bool::from(Sha256::digest(received_key).ct_eq(&stored_key)) {
+                return None;
+            }
+
+            let server_key = hmac_sha256(&entry.salted_password, b"Server Key");
+            let server_signature = hmac_sha256(&server_key, auth_message.as_bytes());
+            Some((
+                Principal::new(&user_name),
+                format!("v={}", BASE64.encode(server_signature)),
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+impl Exchange for Scram {
+    fn step<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<Step, ReasonCode>> {
+        Box::pin(async move {
+            let message = std::str::from_utf8(data).map_err(|_| ReasonCode::NotAuthorized)?;
+            let step = match self.state {
+                State::ClientFirst => self
+                    .client_first(message)
+                    .map(|server_first| Step::Continue(server_first.into_bytes())),
+                State::ClientFinal { .. } => {
+                    self.client_final(message).map(|(principal, server_final)| {
+                        Step::Success(principal, server_final.into_bytes())
+                    })
+                }
+                State::Done => None,
+            };
+            step.ok_or(ReasonCode::NotAuthorized)
+        })
+    }
+}
+
+#[cfg(test)]
+mod unit {
+
+    use super::*;
+    use futures::executor::block_on;
+
+    /// The example exchange of RFC 7677
+    fn exchange() -> Scram {
+        let passwords =
+            "user:$pbkdf2-sha256$4096$W22ZaJ0SNY7soEsUEjb6gQ==$xKSVEDI6tPlSysH6mUQZOeeOp01r6B3fcJbodRPcYV0="
+                .parse()
+                .unwrap();
+        Scram {
+            passwords: Arc::new(passwords),
+            secret: Secret([7; DIGEST_SIZE]),
+            server_nonce: "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".into(),
+            state: State::ClientFirst,
+        }
+    }
+
+    const CLIENT_FIRST: &[u8] = b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+
+    #[test]
+    fn rfc7677_exchange() {
+        let mut scram = exchange();
+        assert_eq!(
+            block_on(scram.step(CLIENT_FIRST)),
+            Ok(Step::Continue(
+                b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096".to_vec()
+            ))
+        );
+        assert_eq!(
+            block_on(scram.step(
+                b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+            )),
+            Ok(Step::Success(
+                Principal::new("user"),
+                b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=".to_vec()
+            ))
+        );
+        assert_eq!(
+            block_on(scram.step(CLIENT_FIRST)),
+            Err(ReasonCode::NotAuthorized)
+        );
+    }
+
+    #[test]
+    fn wrong_proof() {
+        let mut scram = exchange();
+        block_on(scram.step(CLIENT_FIRST)).unwrap();
+        assert_eq!(
+            block_on(scram.step(
+                b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=AHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+            )),
+            Err(ReasonCode::NotAuthorized)
+        );
+    }
+
+    #[test]
+    fn unknown_user() {
+        let mut scram = exchange();
+        let step = block_on(scram.step(b"n,,n=jaden,r=rOprNGfwEbeRWgbNEkqO")).unwrap();
+        assert!(matches!(step, Step::Continue(_)));
+        assert_eq!(
+            block_on(scram.step(
+                b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+            )),
+            Err(ReasonCode::NotAuthorized)
+        );
+
+        // The salt of an unknown user does not change from one exchange to
+        // another
+        let server_first = |user_name: &str| {
+            let client_first = format!("n,,n={},r=rOprNGfwEbeRWgbNEkqO", user_name);
+            block_on(exchange().step(client_first.as_bytes())).unwrap()
+        };
+        assert_eq!(server_first("jaden"), server_first("jaden"));
+        assert_ne!(server_first("jaden"), server_first("jarod"));
+    }
+
+    #[test]
+    fn invalid_messages() {
+        for message in [
+            &b"p=tls-unique,,n=user,r=rOprNGfwEbeRWgbNEkqO"[..],
+            b"n,a=admin,n=user,r=rOprNGfwEbeRWgbNEkqO",
+            b"n,,r=rOprNGfwEbeRWgbNEkqO,n=user",
+            b"n,,n=us=er,r=rOprNGfwEbeRWgbNEkqO",
+            b"n,,n=user,r=",
+        ] {
+            assert_eq!(
+                block_on(exchange().step(message)),
+                Err(ReasonCode::NotAuthorized)
+            );
+        }
+
+        // The channel binding and nonce must be the ones of the exchange
+        for message in [
+            &b"c=eSws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="[..],
+            b"c=biws,r=rOprNGfwEbeRWgbNEkqO,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+        ] {
+            let mut scram = exchange();
+            block_on(scram.step(CLIENT_FIRST)).unwrap();
+            assert_eq!(block_on(scram.step(message)), Err(ReasonCode::NotAuthorized));
+        }
+    }
+
+    #[test]
+    fn escaped_names() {
+        assert_eq!(decode_name("jaden=2Cjarod=3D"), Some("jaden,jarod=".into()));
+        assert_eq!(decode_name("jaden=2"), None);
+        assert_eq!(decode_name("jaden=41"), None);
+    }
+}
@@ -1,84 +1,220 @@
 use log::{error, info};
-use sage_broker::{service, BrokerSettings, Sessions, Trigger};
+use sage_broker::{service, BrokerSettings, CommandSender, Sessions, Trigger};
 use std::{
     net::ToSocketAddrs,
     sync::{Arc, RwLock},
 };
-use tokio::{net::TcpListener, sync::mpsc, task};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    net::TcpListener,
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
 
+/// Listens to each listener given on the command line as
+/// `<scheme>://<address>[?<setting>=<value>[&...]]`, where the scheme is `tcp`,
+/// `tls` (MQTT over TLS), `ws` (MQTT over WebSocket) or `unix` (Unix domain
+/// socket path), and the settings override the default ones for the clients
+/// of this listener only.
+/// See `BrokerSettings::set_option` for the available settings. For instance,
+/// a permissive internal port and a restricted public one:
+///
+/// `server tcp://127.0.0.1:1883 "tcp://0.0.0.0:8883?password_file=passwd&maximum_qos=1&max_connections=100"`
+///
+/// TLS listeners need a certificate chain and a private key:
+///
+/// `server "tls://0.0.0.0:8883?tls_certificate_chain=chain.pem&tls_private_key=key.pem"`
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init();
-    if let Some(listener) = bind("localhost:1883").await {
-        let settings = Arc::new(BrokerSettings::valid_default());
-
-        let shutdown = Trigger::default();
-        let sessions = Arc::new(RwLock::new(Sessions::default()));
-
-        // Create the command packet channel and spawn a new
-        // task for command packet treatment.
-        let (command_sender, command_receiver) = mpsc::unbounded_channel();
-
-        info!("Creating the command loop...");
-        let command_loop = task::spawn(service::command_loop(
-            settings.clone(),
-            sessions,
-            command_receiver, // The command receiver to use
-            shutdown.clone(), // Shutdown trigger
-        ));
-
-        // Launch the listen server.
-        // This is the main task, responsible for listening the Tcp connexions
-        // And creating new peers from it.
-        info!("Creating the listen loop...");
-        let server = task::spawn(service::listen_tcp(
-            listener,
-            command_sender,
-            settings,
-            shutdown.clone(),
-        ));
-
-        // Use the ctrlc crate to handle manual termination
-        ctrlc::set_handler(move || {
-            if shutdown.is_fired() {
-                std::process::exit(0);
-            } else {
-                shutdown.fire()
+
+    let defaults = BrokerSettings::valid_default();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() {
+        args = default_listeners();
+    }
+    let mut listeners = Vec::new();
+    for arg in &args {
+        match Listener::parse(arg, &defaults) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                error!("Invalid listener '{}': {}", arg, e);
+                return;
             }
-        })
-        .expect("Error setting Ctrl-C handler");
+        }
+    }
+
+    let settings = Arc::new(defaults);
+
+    let shutdown = Trigger::default();
+    let sessions = Arc::new(RwLock::new(Sessions::default()));
+
+    // Create the command packet channel and spawn a new
+    // task for command packet treatment.
+    let (command_sender, command_receiver) = mpsc::unbounded_channel();
 
+    info!("Creating the command loop...");
+    let command_loop = task::spawn(service::command_loop(
+        settings,
+        sessions,
+        command_receiver, // The command receiver to use
+        shutdown.clone(), // Shutdown trigger
+    ));
+
+    // Launch the listen servers, sharing the command loop.
+    // These are the main tasks, responsible for listening the connexions
+    // And creating new peers from it.
+    info!("Creating the listen loops...");
+    let mut servers = Vec::new();
+    for listener in listeners {
+        if let Some(server) = listener
+            .spawn(command_sender.clone(), shutdown.clone())
+            .await
+        {
+            servers.push(server);
+        }
+    }
+    // Only the listen loops hold a command sender from now on
+    drop(command_sender);
+
+    // Use the ctrlc crate to handle manual termination
+    ctrlc::set_handler(move || {
+        if shutdown.is_fired() {
+            std::process::exit(0);
+        } else {
+            shutdown.fire()
+        }
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    for server in servers {
         server.await.unwrap();
-        info!("Listen loop ended");
-
-        // When `broker.is_shutting_down().await` returns true, `listen_tcp` will
-        // complete by itself.
-
-        // -----------------------------------------------------------------------
-        // Graceful close
-        // A lot of tasks have been spawned during the server lifetime.
-        // To gracefully stop all of them, follow these instructions:
-        //
-        // First, graceful close is made by waiting `listen_tcp` to complete.
-        // In our case we awaited it. So we're sure about it.
-        // If `listen_tcp` still runs, it may create new connexions, hence the
-        // importance of waiting for it.
-
-        // At this point peers won't process any incoming data and thus no
-        // new command will be generated.
-        // All peers being flagged as closed, their respective `listen_peer` tasks
-        // will all end.
-        // `listen_peer` hold a `CommandSender` instance, which means that once
-        // they are all complete, `command_loop` will end.
-        // Thus, by awaiting for `command_loop`, we ensure all `listen_peer` and
-        // `command_loop` are done.
-        //
-        // Command loop will itself wait for the end of all pending tasks
-        // registered by any other part of the server
-        info!("Waiting for command loop to complete...");
-        command_loop.await.unwrap();
-
-        info!("Done.");
+    }
+    info!("Listen loops ended");
+
+    // When `broker.is_shutting_down().await` returns true, `listen_tcp` will
+    // complete by itself.
+
+    // -----------------------------------------------------------------------
+    // Graceful close
+    // A lot of tasks have been spawned during the server lifetime.
+    // To gracefully stop all of them, follow these instructions:
+    //
+    // First, graceful close is made by waiting the listen loops to complete.
+    // In our case we awaited it. So we're sure about it.
+    // If `listen_tcp` still runs, it may create new connexions, hence the
+    // importance of waiting for it.
+
+    // At this point peers won't process any incoming data and thus no
+    // new command will be generated.
+    // All peers being flagged as closed, their respective `listen_peer` tasks
+    // will all end.
+    // `listen_peer` hold a `CommandSender` instance, which means that once
+    // they are all complete, `command_loop` will end.
+    // Thus, by awaiting for `command_loop`, we ensure all `listen_peer` and
+    // `command_loop` are done.
+    //
+    // Command loop will itself wait for the end of all pending tasks
+    // registered by any other part of the server
+    info!("Waiting for command loop to complete...");
+    command_loop.await.unwrap();
+
+    info!("Done.");
+}
+
+/// The listeners used when none is given on the command line
+fn default_listeners() -> Vec<String> {
+    vec![String::from("tcp://localhost:1883")]
+}
+
+/// A listener given on the command line, with the settings of its clients
+struct Listener {
+    scheme: String,
+    address: String,
+    settings: BrokerSettings,
+}
+
+impl Listener {
+    fn parse(arg: &str, defaults: &BrokerSettings) -> Result<Self, String> {
+        let (scheme, rest) = arg
+            .split_once("://")
+            .ok_or("expected <scheme>://<address>")?;
+        let supported = scheme == "tcp"
+            || (scheme == "tls" && cfg!(feature = "tls"))
+            || (scheme == "ws" && cfg!(feature = "websocket"))
+            || (scheme == "unix" && cfg!(unix));
+        if !supported {
+            return Err(format!("unsupported scheme '{}'", scheme));
+        }
+
+        let (address, options) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut settings = defaults.clone();
+        for option in options.split('&').filter(|option| !option.is_empty()) {
+            let (name, value) = option
+                .split_once('=')
+                .ok_or_else(|| format!("expected <setting>=<value>, got '{}'", option))?;
+            settings.set_option(name, value)?;
+        }
+
+        Ok(Listener {
+            scheme: scheme.into(),
+            address: address.into(),
+            settings,
+        })
+    }
+
+    /// Binds the listener and spawns its listen loop
+    async fn spawn(
+        self,
+        command_sender: CommandSender,
+        shutdown: Trigger,
+    ) -> Option<JoinHandle<()>> {
+        let settings = Arc::new(self.settings);
+        match self.scheme.as_str() {
+            #[cfg(feature = "tls")]
+            "tls" => bind(&self.address).await.map(|listener| {
+                task::spawn(service::listen_tls(
+                    listener,
+                    command_sender,
+                    settings,
+                    shutdown,
+                ))
+            }),
+            #[cfg(feature = "websocket")]
+            "ws" => bind(&self.address).await.map(|listener| {
+                task::spawn(service::listen_websocket(
+                    listener,
+                    command_sender,
+                    settings,
+                    shutdown,
+                ))
+            }),
+            #[cfg(unix)]
+            "unix" => match UnixListener::bind(&self.address) {
+                Ok(listener) => {
+                    info!("Unix socket bound to {}", self.address);
+                    Some(task::spawn(service::listen_unix(
+                        listener,
+                        command_sender,
+                        settings,
+                        shutdown,
+                    )))
+                }
+                Err(e) => {
+                    error!("Cannot listen from {}: {}", self.address, e);
+                    None
+                }
+            },
+            _ => bind(&self.address).await.map(|listener| {
+                task::spawn(service::listen_tcp(
+                    listener,
+                    command_sender,
+                    settings,
+                    shutdown,
+                ))
+            }),
+        }
     }
 }
 
@@ -1,15 +1,25 @@
+use crate::{
+    auth::{Acl, Authenticator, CertificateIdentity, Mechanism, PasswordFile},
+    SharingStrategy,
+};
 use log::warn;
 use sage_mqtt::{defaults, QoS, ReasonCode};
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+use std::{net::IpAddr, sync::Arc};
 
 /// Configuration structure for a broker.
 /// This structure is used to customize the behaviour of your broker. It is used
 /// as a parameter for the `start` function.
+/// Each listener can be given its own settings, which apply to the clients it
+/// accepts, while sharing the same command loop.
 #[derive(Clone, Debug)]
 pub struct BrokerSettings {
     /// Once the connection is closed, the client and server still keep the
     /// session active during a certain amount of time expressed in seconds.
     /// - If the value is `0` (default) the session ends when the connection is closed.
     /// - If the value is `0xFFFFFFFF` the session never expires.
+    ///
     /// The client can override the session expiry interval within the
     /// DISCONNECT packet.
     pub session_expiry_interval: Option<u32>,
@@ -23,34 +33,41 @@ pub struct BrokerSettings {
     /// packets that should be processed concurrently.
     /// There is no such limit for QoS `AtMostOnce` packets.
     /// The default value is `65_535`
-    /// Upon receiving a connect packet, the assigned received maximum will be
-    /// the lowest value between the server configuration and the client request
+    /// A client exceeding this value is disconnected with a
+    /// `ReceiveMaximumExceeded` error. The packets sent by the server are
+    /// limited by the receive maximum of each client instead.
     pub receive_maximum: u16,
 
+    /// The maximum number of QoS 1 and QoS 2 messages each session queues
+    /// while its client is offline or has Receive Maximum messages to
+    /// acknowledge. Once reached, the oldest queued message is dropped to make
+    /// room for the new one. Messages already sent are not counted.
+    /// If `None`, there is no limit. The default value is `1_000`.
+    pub max_queued_messages: Option<usize>,
+
     /// The maximum quality of service the server is willing to operate on.
     pub maximum_qos: QoS,
 
-    /// If `true` the server will allow retain messages. Default is `false`
+    /// If `true` the server will allow retain messages. Default is `true`
     pub retain_enabled: bool,
 
-    /// Defines the maximum size per packet the client is willing to receive
-    /// from the server. It is a procotol error to send a packet which size
-    /// exceeds this value and the client is expected to disconnect from the
-    /// server with a `PacketTooLarge` error.
-    /// This value cannot be `0`. Sending or receiving a CONNECT packet with a
-    /// `maximum_packet_size` of value `0` is a procotol error.
+    /// Defines the maximum size per packet the server is willing to receive
+    /// from clients. It is a procotol error to send a packet which size
+    /// exceeds this value and the server disconnects the client with a
+    /// `PacketTooLarge` error.
+    /// This value cannot be `0`.
     /// `maximum_packet_size` is `None` (default), there is no size limit.
-    /// The maximum packet size is either the minimal value of client and
-    /// server requirements or the only value defined by one of them, or None.
+    /// Packets sent by the server are limited by the maximum packet size of
+    /// each client instead.
     pub maximum_packet_size: Option<u32>,
 
     /// Topic aliases are a way to reduce the size of packets by substituting
     /// aliases (which are strings) to integer values.
     /// The number of aliases allowed by the server is defined
     /// with the `topic_alias_maximum`. It can be `0`, meaning aliases are
-    /// entirely disallowed.
-    /// The assigned topic alias maximum if the minimum value between server
-    /// and client requirements.
+    /// entirely disallowed (default).
+    /// This only limits the aliases sent by clients: the server uses as many
+    /// aliases as each client accepts.
     pub topic_alias_maximum: u16,
 
     /// Specifies the maximum amount of time the client and the server may not
@@ -65,6 +82,101 @@ pub struct BrokerSettings {
     /// If `true` the connections will use the keep alive value from the server.
     /// if `false` the value requested by the client will be use instead.
     pub force_keep_alive: bool,
+
+    /// Defines how the messages matching a shared subscription are
+    /// distributed among the clients of its group. Each message is sent to
+    /// one of them only. The default strategy is `RoundRobin`.
+    /// The QoS 1 messages a client did not acknowledge when it disconnects
+    /// are sent to another connected client of the group, if any.
+    pub shared_subscription_strategy: SharingStrategy,
+
+    /// Checks the user name and password of the clients when they connect.
+    /// A client which is not authenticated is refused with the reason code
+    /// given by the authenticator, usually `BadUserNameOrPassword` or
+    /// `NotAuthorized`.
+    /// If `None` (default), any client can connect.
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+
+    /// The enhanced authentication methods clients can use instead of a user
+    /// name and password, such as `ScramSha256`. A client requesting any other
+    /// method is refused with `BadAuthenticationMethod`.
+    /// The default is an empty list.
+    pub authentication_mechanisms: Vec<Arc<dyn Mechanism>>,
+
+    /// How the common name of the certificates of clients connected over
+    /// mutual TLS identifies them: as their user name, or as their client
+    /// identifier. Clients without a certificate are then refused with
+    /// `NotAuthorized`.
+    /// The default is `Ignore`.
+    pub certificate_identity: CertificateIdentity,
+
+    /// The maximum number of clients a listener serves at once. Further
+    /// connections are closed as soon as they are accepted.
+    /// If `None` (default), there is no limit.
+    pub max_connections: Option<usize>,
+
+    /// If `true`, each connection of the listener starts with a PROXY
+    /// protocol header (version 1 or 2), sent by a load balancer in front of
+    /// the broker. The address of the client it carries replaces the one of
+    /// the balancer, along with the TLS connection it terminated, if any.
+    /// Connections without a valid header are closed, and so are the ones
+    /// which do not come from one of the `trusted_proxies`, as clients could
+    /// otherwise forge their address.
+    /// The default is `false`.
+    pub proxy_protocol: bool,
+
+    /// The addresses of the proxies allowed to send a PROXY protocol header
+    /// when `proxy_protocol` is enabled. Connections from any other address
+    /// are closed before their header is read.
+    /// The default is an empty list, trusting no proxy at all.
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// If `true`, the certificate a trusted proxy verified and tells the
+    /// common name of in its PROXY header is taken as the certificate of the
+    /// client, which can then identify it according to
+    /// `certificate_identity`. Otherwise, it is ignored.
+    /// The default is `false`.
+    pub trust_proxy_certificates: bool,
+
+    /// Restricts the topics each client can publish on and subscribe to.
+    /// Unauthorized subscriptions are refused with `NotAuthorized` and so
+    /// are unauthorized messages, QoS 0 ones being dropped. A client with a
+    /// will message it would not be allowed to publish cannot connect.
+    /// If `None` (default), all clients can use any topic.
+    pub acl: Option<Arc<Acl>>,
+
+    /// The PEM file holding the certificate chain a TLS listener presents to
+    /// its clients, starting with the certificate of the broker.
+    /// TLS listeners cannot listen without it. The default is `None`.
+    #[cfg(feature = "tls")]
+    pub tls_certificate_chain: Option<PathBuf>,
+
+    /// The PEM file holding the private key of the certificate of the
+    /// broker, in PKCS#1, PKCS#8 or SEC1 format.
+    /// TLS listeners cannot listen without it. The default is `None`.
+    #[cfg(feature = "tls")]
+    pub tls_private_key: Option<PathBuf>,
+
+    /// The cipher suites a TLS listener negotiates, by order of preference,
+    /// such as `TLS13_AES_256_GCM_SHA384`. If empty (default), all the
+    /// cipher suites considered safe are allowed.
+    #[cfg(feature = "tls")]
+    pub tls_cipher_suites: Vec<String>,
+
+    /// The PEM file holding the certificates of the authorities which issue
+    /// the certificates of the clients. A TLS listener verifies the
+    /// certificate a client presents against them during the handshake,
+    /// refusing the connection if it is not valid. Only verified certificates
+    /// are given to the authenticator and identify clients.
+    /// If `None` (default), clients are not asked for a certificate.
+    #[cfg(feature = "tls")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// If `true`, clients which do not present a certificate are refused
+    /// during the TLS handshake. It requires a client CA bundle.
+    /// The default is `false`.
+    #[cfg(feature = "tls")]
+    pub tls_require_client_certificate: bool,
 }
 
 impl Default for BrokerSettings {
@@ -75,11 +187,31 @@ impl Default for BrokerSettings {
             session_expiry_interval: defaults::DEFAULT_SESSION_EXPIRY_INTERVAL,
             force_session_expiry_interval: false,
             receive_maximum: defaults::DEFAULT_RECEIVE_MAXIMUM,
+            max_queued_messages: Some(1_000),
             maximum_qos: defaults::DEFAULT_MAXIMUM_QOS,
             retain_enabled: true,
             maximum_packet_size: None,
             topic_alias_maximum: defaults::DEFAULT_TOPIC_ALIAS_MAXIMUM,
             force_keep_alive: false,
+            shared_subscription_strategy: Default::default(),
+            authenticator: None,
+            authentication_mechanisms: Default::default(),
+            certificate_identity: Default::default(),
+            max_connections: None,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            trust_proxy_certificates: false,
+            acl: None,
+            #[cfg(feature = "tls")]
+            tls_certificate_chain: None,
+            #[cfg(feature = "tls")]
+            tls_private_key: None,
+            #[cfg(feature = "tls")]
+            tls_cipher_suites: Vec::new(),
+            #[cfg(feature = "tls")]
+            tls_client_ca: None,
+            #[cfg(feature = "tls")]
+            tls_require_client_certificate: false,
         }
     }
 }
@@ -89,8 +221,6 @@ impl BrokerSettings {
     /// according to dev current limitations
     pub fn valid_default() -> Self {
         BrokerSettings {
-            maximum_qos: QoS::AtMostOnce,
-            retain_enabled: false,
             ..Default::default()
         }
     }
@@ -103,48 +233,210 @@ impl BrokerSettings {
     pub fn is_valid(&self) -> bool {
         let mut valid = true;
 
-        if !matches!(self.session_expiry_interval, None | Some(0xFFFFFFFF)) {
-            warn!("Invalid Setting value: 'session_expiry_interval': Sessions don't expire yet");
+        if self.receive_maximum == 0 {
+            warn!("Invalid Setting value: 'receive_maximum': Cannot be 0");
             valid = false;
         }
 
-        if self.maximum_qos != QoS::AtMostOnce {
-            warn!("Invalid Setting value: 'maximum_qos': Only QoS Level 0 is supported");
+        if self.maximum_packet_size == Some(0) {
+            warn!("Invalid Setting value: 'maximum_packet_size': Cannot be 0");
             valid = false;
         }
 
-        if self.retain_enabled {
-            warn!("Invalid Setting value: 'retain_enabled': retain is not available");
-            valid = false;
-        }
+        valid
+    }
 
-        if self.maximum_packet_size.is_some() {
-            warn!(
-                "Invalid Setting value: 'maximum_packet_size': Cannot enforce maximum packet size"
-            );
-            valid = false;
-        }
+    /// Returns the enhanced authentication mechanism of the given method, if
+    /// supported
+    pub fn authentication_mechanism(&self, method: &str) -> Option<Arc<dyn Mechanism>> {
+        self.authentication_mechanisms
+            .iter()
+            .find(|mechanism| mechanism.method() == method)
+            .cloned()
+    }
 
-        if self.topic_alias_maximum > 0 {
-            warn!("Invalid Setting value: 'topic_alias_maximum': Topic alias is disabled");
-            valid = false;
+    /// Overrides the setting of the given name with the given value, such as
+    /// `maximum_qos` and `1`, for instance to give a listener its own settings.
+    /// The supported settings are `keep_alive`, `force_keep_alive`,
+    /// `session_expiry_interval`, `receive_maximum`, `max_queued_messages`,
+    /// `maximum_qos`, `retain_enabled`, `maximum_packet_size`, `topic_alias_maximum`,
+    /// `max_connections`, `proxy_protocol` and `trust_proxy_certificates`,
+    /// where `none` unsets optional values, `trusted_proxies`, a
+    /// comma-separated list of IP addresses, and `certificate_identity`, as
+    /// `ignore`, `user_name` or `client_id`.
+    /// `password_file` and `acl_file` load the authenticator and the ACL from
+    /// the file at the given path, or remove them if `none`.
+    /// With the `tls` feature, `tls_certificate_chain`, `tls_private_key` and
+    /// `tls_client_ca` are file paths, `tls_cipher_suites` a comma-separated
+    /// list and `tls_require_client_certificate` a boolean.
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<(), String> {
+        fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+            value
+                .parse()
+                .map_err(|_| format!("Invalid value '{}' for '{}'", value, name))
+        }
+        fn optional<T: std::str::FromStr>(name: &str, value: &str) -> Result<Option<T>, String> {
+            match value {
+                "none" => Ok(None),
+                value => parse(name, value).map(Some),
+            }
         }
 
-        valid
+        match name {
+            "keep_alive" => self.keep_alive = parse(name, value)?,
+            "force_keep_alive" => self.force_keep_alive = parse(name, value)?,
+            "session_expiry_interval" => self.session_expiry_interval = optional(name, value)?,
+            "receive_maximum" => self.receive_maximum = parse(name, value)?,
+            "max_queued_messages" => self.max_queued_messages = optional(name, value)?,
+            "maximum_qos" => {
+                self.maximum_qos = match value {
+                    "0" => QoS::AtMostOnce,
+                    "1" => QoS::AtLeastOnce,
+                    "2" => QoS::ExactlyOnce,
+                    _ => return Err(format!("Invalid value '{}' for '{}'", value, name)),
+                }
+            }
+            "retain_enabled" => self.retain_enabled = parse(name, value)?,
+            "maximum_packet_size" => self.maximum_packet_size = optional(name, value)?,
+            "topic_alias_maximum" => self.topic_alias_maximum = parse(name, value)?,
+            "max_connections" => self.max_connections = optional(name, value)?,
+            "proxy_protocol" => self.proxy_protocol = parse(name, value)?,
+            "trusted_proxies" => {
+                self.trusted_proxies = value
+                    .split(',')
+                    .filter(|address| !address.is_empty())
+                    .map(|address| parse(name, address))
+                    .collect::<Result<_, _>>()?
+            }
+            "trust_proxy_certificates" => self.trust_proxy_certificates = parse(name, value)?,
+            "certificate_identity" => {
+                self.certificate_identity = match value {
+                    "ignore" => CertificateIdentity::Ignore,
+                    "user_name" => CertificateIdentity::UserName,
+                    "client_id" => CertificateIdentity::ClientId,
+                    _ => return Err(format!("Invalid value '{}' for '{}'", value, name)),
+                }
+            }
+            "password_file" => {
+                self.authenticator = match value {
+                    "none" => None,
+                    path => Some(Arc::new(
+                        PasswordFile::load(path).map_err(|e| format!("{}: {}", path, e))?,
+                    )),
+                }
+            }
+            "acl_file" => {
+                self.acl = match value {
+                    "none" => None,
+                    path => Some(Arc::new(
+                        Acl::load(path).map_err(|e| format!("{}: {}", path, e))?,
+                    )),
+                }
+            }
+            #[cfg(feature = "tls")]
+            "tls_certificate_chain" => self.tls_certificate_chain = optional(name, value)?,
+            #[cfg(feature = "tls")]
+            "tls_private_key" => self.tls_private_key = optional(name, value)?,
+            #[cfg(feature = "tls")]
+            "tls_cipher_suites" => {
+                self.tls_cipher_suites = value
+                    .split(',')
+                    .filter(|suite| !suite.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            #[cfg(feature = "tls")]
+            "tls_client_ca" => self.tls_client_ca = optional(name, value)?,
+            #[cfg(feature = "tls")]
+            "tls_require_client_certificate" => {
+                self.tls_require_client_certificate = parse(name, value)?
+            }
+            _ => return Err(format!("Unknown setting '{}'", name)),
+        }
+        Ok(())
     }
 
     /// Gets the reason code a broker with these settings would reponds to a
     /// given requested QoS:
     /// - QoS 0 is always granted (Success)
     /// - QoS 1 is granted (GrantedQoS1) only if maximum supported is not 0, otherwise Success
-    /// - QoS 2 is granted (GrantedQoS2) only if maximum supported is 2, otherwise UnspecifiedError
+    /// - QoS 2 is granted (GrantedQoS2) only if maximum supported is 2,
+    ///   otherwise the maximum supported is granted
     pub fn check_qos(&self, request: QoS) -> ReasonCode {
         match (request, self.maximum_qos) {
             (QoS::AtMostOnce, _) => ReasonCode::Success,
-            (QoS::AtLeastOnce, QoS::AtMostOnce) => ReasonCode::Success,
+            (_, QoS::AtMostOnce) => ReasonCode::Success,
             (QoS::AtLeastOnce, _) => ReasonCode::GrantedQoS1,
+            (QoS::ExactlyOnce, QoS::AtLeastOnce) => ReasonCode::GrantedQoS1,
             (QoS::ExactlyOnce, QoS::ExactlyOnce) => ReasonCode::GrantedQoS2,
-            (QoS::ExactlyOnce, _) => ReasonCode::UnspecifiedError,
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit {
+
+    use super::*;
+
+    #[test]
+    fn set_option() {
+        let mut settings = BrokerSettings::default();
+        for (name, value) in [
+            ("keep_alive", "30"),
+            ("maximum_qos", "1"),
+            ("maximum_packet_size", "1024"),
+            ("max_connections", "10"),
+            ("max_queued_messages", "none"),
+            ("retain_enabled", "false"),
+            ("proxy_protocol", "true"),
+            ("trusted_proxies", "10.0.0.1,::1"),
+            ("certificate_identity", "client_id"),
+            ("session_expiry_interval", "none"),
+        ] {
+            settings.set_option(name, value).unwrap();
+        }
+        assert_eq!(settings.keep_alive, 30);
+        assert_eq!(settings.maximum_qos, QoS::AtLeastOnce);
+        assert_eq!(settings.maximum_packet_size, Some(1024));
+        assert_eq!(settings.max_connections, Some(10));
+        assert_eq!(settings.max_queued_messages, None);
+        assert!(!settings.retain_enabled);
+        assert!(settings.proxy_protocol);
+        assert_eq!(
+            settings.trusted_proxies,
+            vec![
+                IpAddr::from([10, 0, 0, 1]),
+                IpAddr::from(std::net::Ipv6Addr::LOCALHOST)
+            ]
+        );
+        assert!(!settings.trust_proxy_certificates);
+        assert_eq!(settings.certificate_identity, CertificateIdentity::ClientId);
+        assert_eq!(settings.session_expiry_interval, None);
+
+        settings.set_option("maximum_packet_size", "none").unwrap();
+        assert_eq!(settings.maximum_packet_size, None);
+
+        #[cfg(feature = "tls")]
+        {
+            settings
+                .set_option(
+                    "tls_cipher_suites",
+                    "TLS13_AES_256_GCM_SHA384,TLS13_CHACHA20_POLY1305_SHA256",
+                )
+                .unwrap();
+            assert_eq!(settings.tls_cipher_suites.len(), 2);
+        }
+
+        for (name, value) in [
+            ("keep_alive", "-1"),
+            ("maximum_qos", "3"),
+            ("retain_enabled", "1"),
+            ("certificate_identity", "common_name"),
+            ("trusted_proxies", "10.0.0.1,proxy"),
+            ("password_file", "/nonexistent/passwords"),
+            ("port", "1883"),
+        ] {
+            assert!(settings.set_option(name, value).is_err());
         }
     }
 }
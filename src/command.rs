@@ -0,0 +1,36 @@
+use crate::{
+    auth::{Handshake, Principal, Step},
+    Peer,
+};
+use sage_mqtt::{ConnAck, Connect, Packet, ReasonCode};
+use std::sync::Arc;
+
+/// A command processed by the command loop
+#[derive(Debug)]
+pub enum Command {
+    /// A control packet was received from the peer
+    Control(Arc<Peer>, Packet),
+
+    /// The network connection with the peer is closed. This is sent once,
+    /// when the peer stops listening.
+    Close(Arc<Peer>),
+
+    /// The authentication of the peer, which runs apart from the command loop
+    /// so as not to hold it, is done.
+    Authenticated(Arc<Peer>, AuthOutcome),
+}
+
+/// The outcome of an authentication, with which the command loop resumes the
+/// connection or the enhanced authentication exchange of the peer
+#[derive(Debug)]
+pub struct AuthOutcome(pub(crate) Authenticated);
+
+#[derive(Debug)]
+pub(crate) enum Authenticated {
+    /// The authenticator checked the credentials of the CONNECT packet, given
+    /// along with its prepared CONNACK
+    Connect(Connect, ConnAck, Result<Principal, ReasonCode>),
+
+    /// The exchange processed the authentication data of the client
+    Exchange(Handshake, Result<Step, ReasonCode>),
+}
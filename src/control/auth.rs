@@ -0,0 +1,172 @@
+use super::connect;
+use crate::{
+    auth::{Credentials, Handshake, Step},
+    Authenticated, BrokerSettings, Cache, Peer, Sessions,
+};
+use log::warn;
+use sage_mqtt::{Auth, Authentication, ConnAck, Disconnect, ReasonCode};
+use std::sync::{Arc, RwLock};
+
+/// Continues the enhanced authentication of a connecting client, or starts a
+/// re-authentication [MQTT 4.12].
+/// An AUTH packet is a protocol error if the client did not connect with an
+/// authentication method [MQTT-4.12.0-7], if it is not the method of the
+/// exchange [MQTT-4.12.0-5] or if its reason code is unexpected:
+/// `ContinueAuthentication` during an exchange [MQTT-4.12.0-3] and
+/// `ReAuthenticate` otherwise [MQTT-4.12.1-1].
+pub async fn run(settings: Arc<BrokerSettings>, packet: Auth, peer: Arc<Peer>) {
+    let method = packet.authentication.method;
+    let handshake = match peer.take_handshake() {
+        Some(handshake)
+            if packet.reason_code == ReasonCode::ContinueAuthentication
+                && handshake.method == method =>
+        {
+            handshake
+        }
+        Some(handshake) => {
+            refuse(&peer, handshake, ReasonCode::ProtocolError);
+            return;
+        }
+        None => {
+            let mechanism = match (peer.session(), peer.authentication_method()) {
+                (Some(session), Some(connected_method))
+                    if packet.reason_code == ReasonCode::ReAuthenticate
+                        && connected_method == method =>
+                {
+                    settings
+                        .authentication_mechanism(&method)
+                        .map(|mechanism| (session, mechanism))
+                }
+                _ => None,
+            };
+            if let Some((session, mechanism)) = mechanism {
+                let credentials = Credentials {
+                    addr: peer.addr().clone(),
+                    client_id: session.client_id().into(),
+                    user_name: None,
+                    password: None,
+                    tls: peer.tls(),
+                };
+                Handshake {
+                    exchange: mechanism.start(&credentials),
+                    method,
+                    connect: None,
+                }
+            } else {
+                peer.send_close(
+                    Disconnect {
+                        reason_code: ReasonCode::ProtocolError,
+                        ..Default::default()
+                    }
+                    .into(),
+                );
+                return;
+            }
+        }
+    };
+
+    step(peer, handshake, packet.authentication.data);
+}
+
+/// Gives the authentication data received from the client to the exchange,
+/// apart from the command loop. The exchange is resumed by `stepped`.
+pub fn step(peer: Arc<Peer>, mut handshake: Handshake, data: Vec<u8>) {
+    super::authenticate(peer, async move {
+        let result = handshake.exchange.step(&data).await;
+        Authenticated::Exchange(handshake, result)
+    });
+}
+
+/// Goes on with the exchange once it processed the authentication data of
+/// the client.
+/// The exchange goes on with an AUTH packet [MQTT-4.12.0-2] and ends with the
+/// CONNACK packet, or an AUTH packet with the `Success` reason code for
+/// re-authentications. These packets carry the authentication method
+/// [MQTT-4.12.0-5].
+pub fn stepped(
+    settings: Arc<BrokerSettings>,
+    sessions: Arc<RwLock<Sessions>>,
+    handshake: Handshake,
+    result: Result<Step, ReasonCode>,
+    peer: Arc<Peer>,
+    cache: Arc<Cache>,
+) {
+    match result {
+        Ok(Step::Continue(data)) => {
+            peer.send(
+                Auth {
+                    reason_code: ReasonCode::ContinueAuthentication,
+                    authentication: Authentication {
+                        method: handshake.method.clone(),
+                        data,
+                    },
+                    ..Default::default()
+                }
+                .into(),
+            );
+            peer.set_handshake(handshake);
+        }
+        Ok(Step::Success(principal, data)) => {
+            let authentication = Authentication {
+                method: handshake.method,
+                data,
+            };
+            if let Some((connect, connack)) = handshake.connect {
+                let connack = ConnAck {
+                    authentication: Some(authentication),
+                    ..connack
+                };
+                connect::accept(
+                    settings,
+                    sessions,
+                    connect,
+                    connack,
+                    Some(principal),
+                    peer,
+                    cache,
+                );
+            } else {
+                if let Some(session) = peer.session() {
+                    session.set_principal(Some(principal));
+                }
+                peer.send(
+                    Auth {
+                        reason_code: ReasonCode::Success,
+                        authentication,
+                        ..Default::default()
+                    }
+                    .into(),
+                );
+            }
+        }
+        Err(reason_code) => refuse(&peer, handshake, reason_code),
+    }
+}
+
+/// Ends a failed exchange, closing the connection with a CONNACK packet while
+/// connecting [MQTT-4.12.0-4] or a DISCONNECT packet while re-authenticating
+/// [MQTT-4.12.1-2].
+fn refuse(peer: &Peer, handshake: Handshake, reason_code: ReasonCode) {
+    warn!(
+        "Authentication of '{}' failed: {:?}",
+        peer.addr(),
+        reason_code
+    );
+    if handshake.connect.is_some() {
+        peer.send_close(
+            ConnAck {
+                reason_code,
+                ..Default::default()
+            }
+            .into(),
+        );
+    } else {
+        peer.send_close(
+            Disconnect {
+                reason_code,
+                ..Default::default()
+            }
+            .into(),
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use super::{publish, will};
+use crate::{Cache, Peer, Session, Sessions};
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{task, time};
+
+/// Handles the end of the network connection with a peer.
+/// - The will message of its session, if any, is published once the delay
+///   interval has elapsed [MQTT-3.1.2-8]
+/// - The session is kept until its expiry interval has elapsed
+///   [MQTT-3.1.2-23] then discarded [MQTT-4.1.0-2]
+/// - The messages of its shared subscriptions which can be delivered by
+///   another connected session of their group are handed over to it
+///
+/// Both are cancelled if a new network connection is made to the session in
+/// the meantime [MQTT-3.1.3-9].
+pub async fn run(sessions: Arc<RwLock<Sessions>>, peer: Arc<Peer>, cache: Arc<Cache>) {
+    if let Some(session) = peer.session() {
+        // The session was taken over by another connection
+        let peer = Arc::downgrade(&peer);
+        if !session.is_bound(&peer) {
+            return;
+        }
+
+        publish::redistribute(&sessions, &session);
+
+        match session.will_delay() {
+            None => {}
+            Some(0) => {
+                if let Some(will) = session.take_will() {
+                    will::publish(&sessions, &cache, session.client_id(), will);
+                }
+            }
+            Some(delay) => {
+                let sessions = sessions.clone();
+                let cache = cache.clone();
+                let session = session.clone();
+                let peer = peer.clone();
+                task::spawn(async move {
+                    time::sleep(Duration::from_secs(delay.into())).await;
+                    if session.is_bound(&peer) {
+                        if let Some(will) = session.take_will() {
+                            will::publish(&sessions, &cache, session.client_id(), will);
+                        }
+                    }
+                });
+            }
+        }
+
+        match session.expiry_interval() {
+            0 => end(&sessions, &cache, &session),
+            0xFFFFFFFF => {}
+            interval => {
+                task::spawn(async move {
+                    time::sleep(Duration::from_secs(interval.into())).await;
+                    if session.is_bound(&peer) {
+                        end(&sessions, &cache, &session);
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Discards the session along with its subscriptions and pending messages.
+/// Pending messages of shared subscriptions are handed over to another
+/// session of their group beforehand, if one is connected.
+/// A will message still waiting for its delay is published right away
+/// [MQTT-3.1.2-8].
+fn end(sessions: &RwLock<Sessions>, cache: &Cache, session: &Session) {
+    publish::redistribute(sessions, session);
+    if sessions.write().unwrap().remove(session) {
+        log::info!("Session expired: {}", session.client_id());
+    }
+    if let Some(will) = session.take_will() {
+        will::publish(sessions, cache, session.client_id(), will);
+    }
+}
@@ -1,10 +1,12 @@
-use crate::{BrokerSettings, Cache, Peer, Session, Sessions};
+use super::{auth, will};
+use crate::{
+    auth::{CertificateIdentity, Credentials, Handshake, Principal},
+    Authenticated, BrokerSettings, Cache, Peer, Session, Sessions,
+};
+use log::warn;
 use nanoid::nanoid;
 use sage_mqtt::{ConnAck, Connect, Disconnect, ReasonCode};
-use std::{
-    cmp::min,
-    sync::{Arc, RwLock},
-};
+use std::sync::{Arc, RwLock};
 
 pub async fn run(
     settings: Arc<BrokerSettings>,
@@ -13,52 +15,220 @@ pub async fn run(
     peer: Arc<Peer>,
     cache: Arc<Cache>,
 ) {
+    // The CONNACK packet already complies with the maximum packet size of the
+    // client [MQTT-3.1.2-24]
+    peer.set_maximum_packet_size(connect.maximum_packet_size);
+
     // First, we prepare an first connack using broker policy
     // and infer the actual client_id requested for this client
-    let mut connack = acknowledge_connect(settings, &connect);
-
-    if connack.reason_code == ReasonCode::Success {
-        let client_id = connack
-            .assigned_client_id
-            .clone()
-            .or(connect.client_id)
-            .unwrap();
-
-        let clean_start = connect.clean_start;
-        // Session creation/overtaking
-        // First, we get the may be existing session from the db:
-        // TODO: This can be simplified
-        let session = {
-            if let Some(session) = sessions.write().unwrap().take(&client_id) {
-                // If the existing session has a peer, it'll be disconnected with takeover
-                if let Some(peer) = session.peer() {
-                    peer.send_close(
-                        Disconnect {
-                            reason_code: ReasonCode::SessionTakenOver,
-                            ..Default::default()
-                        }
-                        .into(),
-                    );
-                }
+    let mut connack = acknowledge_connect(settings.clone(), &connect);
+    if connack.reason_code != ReasonCode::Success {
+        peer.send_close(connack.into());
+        return;
+    }
+
+    // The common name of the client certificate may identify the client
+    let common_name = if settings.certificate_identity == CertificateIdentity::Ignore {
+        None
+    } else if let Some(common_name) = certificate_name(&peer) {
+        Some(common_name)
+    } else {
+        connack.reason_code = ReasonCode::NotAuthorized;
+        peer.send_close(connack.into());
+        return;
+    };
+    if settings.certificate_identity == CertificateIdentity::ClientId
+        && connect.client_id != common_name
+    {
+        connack.assigned_client_id = common_name.clone();
+    }
+
+    // A valid CONNECT packet is then authenticated [MQTT-3.1.4-2]
+    let user_name = match settings.certificate_identity {
+        CertificateIdentity::UserName => common_name.clone(),
+        _ => connect.user_name.clone(),
+    };
+    let credentials = Credentials {
+        addr: peer.addr().clone(),
+        client_id: client_id(&connect, &connack),
+        user_name,
+        password: connect.password.clone(),
+        tls: peer.tls(),
+    };
+    if let Some(authentication) = connect.authentication.clone() {
+        // The method is known to be supported by now
+        if let Some(mechanism) = settings.authentication_mechanism(&authentication.method) {
+            let handshake = Handshake {
+                method: authentication.method,
+                exchange: mechanism.start(&credentials),
+                connect: Some((connect, connack)),
+            };
+            auth::step(peer, handshake, authentication.data);
+        }
+        return;
+    }
+
+    let mut principal = None;
+    if settings.certificate_identity == CertificateIdentity::UserName {
+        // The certificate authenticates the client
+        principal = common_name.as_deref().map(Principal::new);
+    } else if let Some(authenticator) = settings.authenticator.clone() {
+        // The connection is resumed by `authenticated`
+        super::authenticate(peer, async move {
+            let result = authenticator.authenticate(&credentials).await;
+            Authenticated::Connect(connect, connack, result)
+        });
+        return;
+    }
+
+    accept(settings, sessions, connect, connack, principal, peer, cache);
+}
+
+/// Accepts the client once its authenticator authenticated it, or refuses
+/// the connection with the reason code it returned
+pub fn authenticated(
+    settings: Arc<BrokerSettings>,
+    sessions: Arc<RwLock<Sessions>>,
+    connect: Connect,
+    mut connack: ConnAck,
+    result: Result<Principal, ReasonCode>,
+    peer: Arc<Peer>,
+    cache: Arc<Cache>,
+) {
+    match result {
+        Ok(principal) => accept(
+            settings,
+            sessions,
+            connect,
+            connack,
+            Some(principal),
+            peer,
+            cache,
+        ),
+        Err(reason_code) => {
+            connack.reason_code = reason_code;
+            peer.send_close(connack.into());
+        }
+    }
+}
+
+/// Returns the common name of the certificate of the client, logging why the
+/// certificate is rejected if there is none
+fn certificate_name(peer: &Peer) -> Option<String> {
+    match peer.certificate() {
+        Some(certificate) => {
+            if certificate.common_name.is_none() {
+                warn!(
+                    "Certificate of '{}' rejected: no common name in '{}'",
+                    peer.addr(),
+                    certificate.subject
+                );
+            }
+            certificate.common_name
+        }
+        None => {
+            warn!("Certificate of '{}' rejected: none presented", peer.addr());
+            None
+        }
+    }
+}
+
+/// Returns the client identifier of the CONNECT packet, or the one assigned
+/// by the server
+fn client_id(connect: &Connect, connack: &ConnAck) -> String {
+    connack
+        .assigned_client_id
+        .clone()
+        .or_else(|| connect.client_id.clone())
+        .unwrap()
+}
+
+/// Binds the peer to a new or existing session once the client is
+/// authenticated, and sends the CONNACK packet.
+/// The connection is refused with `NotAuthorized` if the ACL of the broker
+/// does not allow the client to publish its will message.
+pub fn accept(
+    settings: Arc<BrokerSettings>,
+    sessions: Arc<RwLock<Sessions>>,
+    connect: Connect,
+    mut connack: ConnAck,
+    principal: Option<Principal>,
+    peer: Arc<Peer>,
+    cache: Arc<Cache>,
+) {
+    let client_id = client_id(&connect, &connack);
+    if let (Some(acl), Some(will)) = (&settings.acl, &connect.will) {
+        if !acl.can_publish(&client_id, principal.as_ref(), &will.topic) {
+            connack.reason_code = ReasonCode::NotAuthorized;
+            peer.send_close(connack.into());
+            return;
+        }
+    }
 
-                if clean_start {
-                    connack.session_present = false;
-                    Arc::new(Session::new(&client_id, peer.clone(), cache))
-                } else {
-                    connack.session_present = true;
-                    session.bind(peer.clone());
-                    session
+    let clean_start = connect.clean_start;
+    // Session creation/overtaking
+    // First, we get the may be existing session from the db:
+    // TODO: This can be simplified
+    let session = {
+        let existing = sessions.write().unwrap().take(&client_id);
+        if let Some(session) = existing {
+            // If the existing session has a peer, it'll be disconnected with takeover
+            if let Some(peer) = session.peer() {
+                peer.send_close(
+                    Disconnect {
+                        reason_code: ReasonCode::SessionTakenOver,
+                        ..Default::default()
+                    }
+                    .into(),
+                );
+            }
+
+            if clean_start {
+                // The existing session ends, so does its will delay
+                // [MQTT-3.1.2-8] and the sessions its messages stick to
+                sessions.read().unwrap().unstick(&client_id);
+                if let Some(will) = session.take_will() {
+                    will::publish(&sessions, &cache, session.client_id(), will);
                 }
-            } else {
                 connack.session_present = false;
-                Arc::new(Session::new(&client_id, peer.clone(), cache))
+                Arc::new(Session::new(&client_id, peer.clone()))
+            } else {
+                // The will of an existing connection without delay is
+                // published as the connection closes. Any other will is
+                // not sent because of the new connection [MQTT-3.1.3-9]
+                if session.peer().is_some() && session.will_delay() == Some(0) {
+                    if let Some(will) = session.take_will() {
+                        will::publish(&sessions, &cache, session.client_id(), will);
+                    }
+                }
+                connack.session_present = true;
+                session.bind(peer.clone());
+                session
             }
-        };
-        sessions.write().unwrap().add(session.clone());
-        peer.bind(session);
-        peer.send(connack.into());
-    } else {
-        peer.send_close(connack.into());
+        } else {
+            connack.session_present = false;
+            Arc::new(Session::new(&client_id, peer.clone()))
+        }
+    };
+    // The will message is stored in the session [MQTT-3.1.2-7]. It is
+    // published at the latest when the session ends [MQTT-3.1.2-8]
+    session.set_will(connect.will);
+    session.set_expiry_interval(connack.session_expiry_interval.unwrap_or(0));
+    session.set_receive_maximum(connect.receive_maximum);
+    session.set_max_queued(settings.max_queued_messages);
+    session.set_principal(principal);
+
+    sessions.write().unwrap().add(session.clone());
+    peer.bind(session.clone());
+    peer.set_topic_alias_maximum(connect.topic_alias_maximum);
+    peer.set_authentication_method(connect.authentication.map(|a| a.method));
+    let session_present = connack.session_present;
+    peer.send(connack.into());
+
+    // Any unacknowledged message is sent again once the session is
+    // resumed [MQTT-4.4.0-1]
+    if session_present {
+        session.resume();
     }
 }
 
@@ -76,17 +246,14 @@ fn acknowledge_connect(settings: Arc<BrokerSettings>, connect: &Connect) -> Conn
         }
     };
 
-    // The maximum receive_maximum if the minimum value between server and
-    // client requirements
-    let receive_maximum = min(connect.receive_maximum, settings.receive_maximum);
+    // The number of QoS 1 and QoS 2 messages the client may send
+    // concurrently. The messages the server sends are limited by the value of
+    // the CONNECT packet.
+    let receive_maximum = settings.receive_maximum;
 
-    // The maximum packet size is either the minimal value of client and
-    // server requirements or the only value defined by one of them, or None
-    let maximum_packet_size = match (connect.maximum_packet_size, settings.maximum_packet_size) {
-        (Some(a), Some(b)) => Some(min(a, b)),
-        (None, Some(a)) | (Some(a), None) => Some(a),
-        _ => None,
-    };
+    // The maximum size of the packets the client may send. The maximum size
+    // of the packets the server may send is the one of the CONNECT packet.
+    let maximum_packet_size = settings.maximum_packet_size;
 
     // If the client did not specify a client ID, the server must generate
     // and assign one.
@@ -96,9 +263,9 @@ fn acknowledge_connect(settings: Arc<BrokerSettings>, connect: &Connect) -> Conn
         Some(format!("sage_mqtt-{}", nanoid!()))
     };
 
-    // The topic alias maximum if the minimum value between server and client
-    // requirements.
-    let topic_alias_maximum = min(settings.topic_alias_maximum, connect.topic_alias_maximum);
+    // The number of topic aliases the client may send. The number of aliases
+    // the server may send is the one of the CONNECT packet.
+    let topic_alias_maximum = settings.topic_alias_maximum;
 
     // Values directly defined by the configuration
     let maximum_qos = settings.maximum_qos;
@@ -112,21 +279,36 @@ fn acknowledge_connect(settings: Arc<BrokerSettings>, connect: &Connect) -> Conn
         None
     };
 
-    // Enhanced authentication is not supported for now
+    // Enhanced authentication requires a supported method [MQTT-4.12.0-1].
+    // User names and passwords are checked by the authenticator, if any.
+    // The will message is rejected if its QoS [MQTT-3.2.2-12] or RETAIN flag
+    // [MQTT-3.2.2-13] are not supported
     let (reason_code, reason_string) = {
-        if connect.authentication.is_some() || connect.user_name.is_some() {
+        let unsupported_method = connect
+            .authentication
+            .as_ref()
+            .is_some_and(|a| settings.authentication_mechanism(&a.method).is_none());
+        if unsupported_method {
             (
                 ReasonCode::BadAuthenticationMethod,
-                Some("Enhanced anthentication non supported".into()),
+                Some("Unsupported authentication method".into()),
             )
         } else {
-            (ReasonCode::Success, None)
+            match &connect.will {
+                Some(will) if will.qos as u8 > maximum_qos as u8 => {
+                    (ReasonCode::QoSNotSupported, None)
+                }
+                Some(will) if will.retain && !retain_available => {
+                    (ReasonCode::RetainNotSupported, None)
+                }
+                _ => (ReasonCode::Success, None),
+            }
         }
     };
 
-    let wildcard_subscription_available = false;
-    let subscription_identifiers_available = false;
-    let shared_subscription_available = false;
+    let wildcard_subscription_available = true;
+    let subscription_identifiers_available = true;
+    let shared_subscription_available = true;
     let response_information = None;
     let reference = None;
 
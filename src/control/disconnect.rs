@@ -0,0 +1,53 @@
+use crate::{BrokerSettings, Peer};
+use log::{info, warn};
+use sage_mqtt::{Disconnect, ReasonCode};
+use std::sync::Arc;
+
+/// Closes the connection on the client request.
+/// The will message is discarded unless the client requested it to be sent
+/// with `DisconnectWithWillMessage` [MQTT-3.14.4-3] [MQTT-3.1.2-10].
+/// The client can change the session expiry interval, unless it was zero or
+/// the server forces its own value.
+/// Reason codes from 0x80 tell the client closes the connection on an error.
+pub async fn run(settings: Arc<BrokerSettings>, disconnect: Disconnect, peer: Arc<Peer>) {
+    if let Some(session) = peer.session() {
+        if (disconnect.reason_code as u8) < 0x80 {
+            info!(
+                "Client '{}' disconnected: {:?}",
+                session.client_id(),
+                disconnect.reason_code
+            );
+        } else {
+            warn!(
+                "Client '{}' disconnected on error: {:?} {}",
+                session.client_id(),
+                disconnect.reason_code,
+                disconnect.reason_string.as_deref().unwrap_or_default()
+            );
+        }
+
+        match disconnect.session_expiry_interval {
+            // Setting a non-zero interval on a session which ends with its
+            // connection is a protocol error
+            Some(interval) if interval != 0 && session.expiry_interval() == 0 => {
+                peer.send_close(
+                    Disconnect {
+                        reason_code: ReasonCode::ProtocolError,
+                        ..Default::default()
+                    }
+                    .into(),
+                );
+                return;
+            }
+            Some(interval) if !settings.force_session_expiry_interval => {
+                session.set_expiry_interval(interval)
+            }
+            _ => {}
+        }
+
+        if disconnect.reason_code != ReasonCode::DisconnectWithWillMessage {
+            session.take_will();
+        }
+    }
+    peer.close();
+}
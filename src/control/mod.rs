@@ -1,11 +1,22 @@
-use crate::{BrokerSettings, Peer, Publisher, Sessions};
+use crate::{AuthOutcome, Authenticated, BrokerSettings, Command, Peer, Publisher, Sessions};
+use futures::Future;
 use log::error;
 use sage_mqtt::{ConnAck, Packet, PingResp, ReasonCode};
 use std::sync::{Arc, RwLock};
+use tokio::task;
 
+mod auth;
+mod close;
 mod connect;
+mod disconnect;
+mod puback;
+mod pubcomp;
 mod publish;
+mod pubrec;
+mod pubrel;
 mod subscribe;
+mod unsubscribe;
+mod will;
 
 pub async fn run(
     settings: Arc<BrokerSettings>,
@@ -14,13 +25,44 @@ pub async fn run(
     peer: Arc<Peer>,
     publisher: Arc<Publisher>,
 ) {
+    // The packets received while the client is being authenticated are
+    // processed once it is done
+    let packet = match peer.defer(packet) {
+        Some(packet) => packet,
+        None => return,
+    };
+
+    // A client authenticating with an authentication method can only send
+    // AUTH or DISCONNECT packets until it receives the CONNACK [MQTT-3.1.2-30]
+    if peer.authenticating() && !matches!(packet, Packet::Auth(_) | Packet::Disconnect(_)) {
+        peer.send_close(
+            ConnAck {
+                reason_code: ReasonCode::ProtocolError,
+                ..Default::default()
+            }
+            .into(),
+        );
+        return;
+    }
+
     match packet {
-        Packet::Subscribe(packet) => subscribe::run(settings, packet, peer).await,
+        Packet::Subscribe(packet) => {
+            subscribe::run(settings, sessions, packet, peer, publisher.cache().clone()).await
+        }
         Packet::PingReq => peer.send(PingResp.into()),
         Packet::Connect(packet) => {
             connect::run(settings, sessions, packet, peer, publisher.cache().clone()).await
         }
-        Packet::Publish(packet) => publish::run(packet, sessions).await,
+        Packet::Disconnect(packet) => disconnect::run(settings, packet, peer).await,
+        Packet::Publish(packet) => {
+            publish::run(settings, sessions, packet, peer, publisher.cache().clone()).await
+        }
+        Packet::PubAck(packet) => puback::run(packet, peer).await,
+        Packet::PubRec(packet) => pubrec::run(packet, peer).await,
+        Packet::PubRel(packet) => pubrel::run(packet, peer).await,
+        Packet::PubComp(packet) => pubcomp::run(packet, peer).await,
+        Packet::UnSubscribe(packet) => unsubscribe::run(sessions, packet, peer).await,
+        Packet::Auth(packet) => auth::run(settings, packet, peer).await,
         _ => {
             error!("Unsupported packet: {:#?}", packet);
             peer.send_close(
@@ -33,3 +75,74 @@ pub async fn run(
         }
     }
 }
+
+/// Runs the given authentication apart from the command loop, so that the
+/// loop goes on processing other packets in the meantime. The outcome is sent
+/// back to the loop, which resumes the peer with it. The packets received
+/// from the client until then are deferred.
+fn authenticate<F>(peer: Arc<Peer>, authentication: F)
+where
+    F: Future<Output = Authenticated> + Send + 'static,
+{
+    peer.suspend();
+    task::spawn(async move {
+        let authenticated = authentication.await;
+        let command = Command::Authenticated(peer.clone(), AuthOutcome(authenticated));
+        if let Err(e) = peer.command_sender().send(command) {
+            error!("Cannot send command: {:?}", e);
+        }
+    });
+}
+
+/// Resumes the connection or the enhanced authentication exchange of the
+/// given peer with the outcome of its authentication, then processes the
+/// packets it received in the meantime.
+/// Nothing is done if the connection was closed in the meantime.
+pub async fn resume(
+    settings: Arc<BrokerSettings>,
+    sessions: Arc<RwLock<Sessions>>,
+    outcome: AuthOutcome,
+    peer: Arc<Peer>,
+    publisher: Arc<Publisher>,
+) {
+    if peer.closing() {
+        return;
+    }
+
+    let cache = publisher.cache().clone();
+    match outcome.0 {
+        Authenticated::Connect(connect, connack, result) => connect::authenticated(
+            settings.clone(),
+            sessions.clone(),
+            connect,
+            connack,
+            result,
+            peer.clone(),
+            cache,
+        ),
+        Authenticated::Exchange(handshake, result) => auth::stepped(
+            settings.clone(),
+            sessions.clone(),
+            handshake,
+            result,
+            peer.clone(),
+            cache,
+        ),
+    }
+
+    for packet in peer.resume() {
+        run(
+            settings.clone(),
+            sessions.clone(),
+            packet,
+            peer.clone(),
+            publisher.clone(),
+        )
+        .await;
+    }
+}
+
+/// Handles the end of the network connection with the given peer
+pub async fn close(sessions: Arc<RwLock<Sessions>>, peer: Arc<Peer>, publisher: Arc<Publisher>) {
+    close::run(sessions, peer, publisher.cache().clone()).await
+}
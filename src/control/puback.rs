@@ -0,0 +1,11 @@
+use crate::{Ack, Peer};
+use sage_mqtt::PubAck;
+use std::sync::Arc;
+
+/// Acknowledges an outbound QoS 1 message. The message is considered
+/// acknowledged whatever the reason code is [MQTT-4.4.0-2].
+pub async fn run(puback: PubAck, peer: Arc<Peer>) {
+    if let Some(session) = peer.session() {
+        session.acknowledge(puback.packet_identifier, Ack::PubAck);
+    }
+}
@@ -0,0 +1,11 @@
+use crate::{Ack, Peer};
+use sage_mqtt::PubComp;
+use std::sync::Arc;
+
+/// Ends the delivery of an outbound QoS 2 message, after which its packet
+/// identifier can be reused [MQTT-4.3.3-6].
+pub async fn run(pubcomp: PubComp, peer: Arc<Peer>) {
+    if let Some(session) = peer.session() {
+        session.acknowledge(pubcomp.packet_identifier, Ack::PubComp);
+    }
+}
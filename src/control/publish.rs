@@ -1,19 +1,275 @@
-use crate::Sessions;
-use sage_mqtt::Publish;
+use crate::{topic, BrokerSettings, Cache, Peer, Session, Sessions};
+use sage_mqtt::{Disconnect, PubAck, PubRec, Publish, QoS, ReasonCode, Topic};
 use std::sync::{Arc, RwLock};
 
-pub async fn run(publish: Publish, sessions: Arc<RwLock<Sessions>>) {
-    // For now we'll apply the naive way.
-    // Loop through sessions and if any subscription apply, send it
-    // a publish message
-    for session in sessions
+/// Dispatches the publish message to all subscribed sessions and acknowledges
+/// it according to its QoS:
+/// - QoS 0: Nothing is sent back
+/// - QoS 1: A PUBACK packet is sent back with the same packet identifier
+///   [MQTT-4.3.2-4]
+/// - QoS 2: A PUBREC packet is sent back with the same packet identifier
+///   [MQTT-4.3.3-8]. Until the matching PUBREL is received, any PUBLISH
+///   packet with the same identifier is acknowledged again but not
+///   dispatched [MQTT-4.3.3-10]
+///
+/// A message with the RETAIN flag replaces the retained message of its topic
+/// name.
+///
+/// A client sending more unacknowledged QoS 1 and QoS 2 messages than the
+/// Receive Maximum of the server is disconnected with
+/// `ReceiveMaximumExceeded`.
+///
+/// The topic alias of the message is replaced by the topic name it stands for.
+/// An invalid alias causes a disconnection with `TopicAliasInvalid`.
+///
+/// A message containing a subscription identifier causes a disconnection with
+/// `ProtocolError` [MQTT-3.3.4-6].
+///
+/// A message on a topic the ACL of the broker does not allow the client to
+/// publish on is not dispatched. It is acknowledged with `NotAuthorized`.
+///
+/// A message with a QoS greater than the maximum supported by the broker
+/// causes a disconnection with `QoSNotSupported` [MQTT-3.2.2-11]. Likewise,
+/// a message with the RETAIN flag causes a disconnection with
+/// `RetainNotSupported` if retain is not available [MQTT-3.2.2-14].
+pub async fn run(
+    settings: Arc<BrokerSettings>,
+    sessions: Arc<RwLock<Sessions>>,
+    mut publish: Publish,
+    peer: Arc<Peer>,
+    cache: Arc<Cache>,
+) {
+    let reason_code = if let Err(reason_code) =
+        peer.resolve_topic_alias(&mut publish, settings.topic_alias_maximum)
+    {
+        Some(reason_code)
+    } else if !publish.subscription_identifiers.is_empty() {
+        Some(ReasonCode::ProtocolError)
+    } else if publish.qos as u8 > settings.maximum_qos as u8 {
+        Some(ReasonCode::QoSNotSupported)
+    } else if publish.retain && !settings.retain_enabled {
+        Some(ReasonCode::RetainNotSupported)
+    } else {
+        None
+    };
+
+    if let Some(reason_code) = reason_code {
+        peer.send_close(
+            Disconnect {
+                reason_code,
+                ..Default::default()
+            }
+            .into(),
+        );
+        return;
+    }
+
+    if let (Some(acl), Some(session)) = (&settings.acl, peer.session()) {
+        let principal = session.principal();
+        if !acl.can_publish(session.client_id(), principal.as_ref(), &publish.topic_name) {
+            acknowledge(&peer, &publish, ReasonCode::NotAuthorized);
+            return;
+        }
+    }
+
+    if let (Some(session), Some(packet_identifier)) = (peer.session(), publish.packet_identifier) {
+        // QoS 1 messages are acknowledged right away, only QoS 2 messages
+        // stay unacknowledged until released
+        let receiving = match publish.qos {
+            QoS::ExactlyOnce => {
+                // A QoS 2 message already received is acknowledged but not
+                // dispatched
+                if !session.receive(packet_identifier) {
+                    peer.send(
+                        PubRec {
+                            packet_identifier,
+                            ..Default::default()
+                        }
+                        .into(),
+                    );
+                    return;
+                }
+                session.receiving()
+            }
+            _ => session.receiving() + 1,
+        };
+
+        if receiving > settings.receive_maximum as usize {
+            if publish.qos == QoS::ExactlyOnce {
+                session.complete(packet_identifier);
+            }
+            peer.send_close(
+                Disconnect {
+                    reason_code: ReasonCode::ReceiveMaximumExceeded,
+                    ..Default::default()
+                }
+                .into(),
+            );
+            return;
+        }
+    }
+
+    let publisher = peer.session();
+    let publisher = publisher.as_ref().map(|session| session.client_id());
+    let reason_code = dispatch(&sessions, &cache, &publish, publisher);
+    acknowledge(&peer, &publish, reason_code);
+}
+
+/// Acknowledges a QoS 1 or QoS 2 message with the given reason code
+fn acknowledge(peer: &Peer, publish: &Publish, reason_code: ReasonCode) {
+    match (publish.qos, publish.packet_identifier) {
+        (QoS::AtLeastOnce, Some(packet_identifier)) => peer.send(
+            PubAck {
+                packet_identifier,
+                reason_code,
+                ..Default::default()
+            }
+            .into(),
+        ),
+        (QoS::ExactlyOnce, Some(packet_identifier)) => peer.send(
+            PubRec {
+                packet_identifier,
+                reason_code,
+                ..Default::default()
+            }
+            .into(),
+        ),
+        _ => {}
+    }
+}
+
+/// Returns the QoS a message is sent with to a subscription with the given
+/// granted QoS [MQTT-3.8.4-8] [MQTT-4.8.2-3].
+pub fn minimum_qos(granted_qos: QoS, qos: QoS) -> QoS {
+    if (granted_qos as u8) < (qos as u8) {
+        granted_qos
+    } else {
+        qos
+    }
+}
+
+/// Sends the message to the given session on behalf of one of its shared
+/// subscriptions, using the options and identifier of that subscription.
+fn publish_shared(session: &Session, share: Topic, publish: &Publish) {
+    let subscription = session.subs().read().unwrap().get(&share);
+    if let Some((options, identifier)) = subscription {
+        session.publish_shared(
+            Publish {
+                qos: minimum_qos(options.qos, publish.qos),
+                retain: publish.retain && options.retain_as_published,
+                subscription_identifiers: identifier.into_iter().collect(),
+                ..publish.clone()
+            },
+            Some(share),
+        );
+    }
+}
+
+/// Stores the message if retained and sends it to all subscribed sessions.
+/// `publisher` is the client id of the session publishing the message, if
+/// known.
+/// Returns the reason code to acknowledge the message with.
+pub fn dispatch(
+    sessions: &RwLock<Sessions>,
+    cache: &Cache,
+    publish: &Publish,
+    publisher: Option<&str>,
+) -> ReasonCode {
+    // Only messages with the RETAIN flag change the retained messages
+    // [MQTT-3.3.1-8]
+    if publish.retain {
+        cache.retain(publish);
+    }
+
+    // The subscription tree gives all sessions with at least one matching
+    // subscription, along with the options and identifiers of their matching
+    // subscriptions. Each of them is sent a publish message with the minimum
+    // QoS between the message and the granted subscription [MQTT-3.8.4-8].
+    // The RETAIN flag is kept only for subscriptions with the Retain As
+    // Published option [MQTT-3.3.1-12] [MQTT-3.3.1-13]. The message carries
+    // the identifiers of all matching subscriptions [MQTT-3.3.4-3].
+    // Subscriptions with the No Local option do not receive the messages of
+    // their own client [MQTT-3.8.3-3]
+    // Each shared subscription sends the message to one session of its group
+    // only [MQTT 4.8.2]
+    let (subscribers, shared_subscribers) = {
+        let sessions = sessions.read().unwrap();
+        (
+            sessions.subscribers(&publish.topic_name),
+            sessions.shared_subscribers(&publish.topic_name, publisher),
+        )
+    };
+
+    let reason_code = if subscribers.is_empty() && shared_subscribers.is_empty() {
+        ReasonCode::NoMatchingSubscribers
+    } else {
+        ReasonCode::Success
+    };
+
+    for (session, subscriptions) in subscribers {
+        let local = publisher == Some(session.client_id());
+        let subscriptions: Vec<_> = subscriptions
+            .into_iter()
+            .filter(|(options, _)| !(local && options.no_local))
+            .collect();
+        let granted_qos = subscriptions
+            .iter()
+            .map(|(options, _)| options.qos)
+            .max_by_key(|&qos| qos as u8);
+        if let Some(granted_qos) = granted_qos {
+            session.publish(Publish {
+                qos: minimum_qos(granted_qos, publish.qos),
+                retain: publish.retain
+                    && subscriptions
+                        .iter()
+                        .any(|(options, _)| options.retain_as_published),
+                subscription_identifiers: subscriptions
+                    .iter()
+                    .filter_map(|(_, identifier)| *identifier)
+                    .collect(),
+                ..publish.clone()
+            });
+        }
+    }
+
+    for (share, session) in shared_subscribers {
+        publish_shared(&session, share, publish);
+    }
+
+    reason_code
+}
+
+/// Sends the messages the given session has yet to deliver on behalf of its
+/// shared subscriptions to other connected sessions of their groups, if any.
+/// This is used once the session lost its connection.
+pub fn redistribute(sessions: &RwLock<Sessions>, session: &Session) {
+    let shares: Vec<Topic> = session
+        .subs()
         .read()
         .unwrap()
-        .iter()
-        .filter(|&session| session.subs().read().unwrap().matches(&publish.topic_name))
-    {
-        if let Some(peer) = session.peer() {
-            peer.send(Publish { ..publish.clone() }.into());
+        .filters()
+        .filter(|filter| topic::share(filter).is_some())
+        .cloned()
+        .collect();
+
+    for share in shares {
+        if sessions
+            .read()
+            .unwrap()
+            .other_shared_subscriber(&share, session)
+            .is_none()
+        {
+            continue;
+        }
+        for publish in session.take_shared(&share) {
+            let other = sessions
+                .read()
+                .unwrap()
+                .other_shared_subscriber(&share, session);
+            match other {
+                Some(other) => publish_shared(&other, share.clone(), &publish),
+                None => session.publish_shared(publish, Some(share.clone())),
+            }
         }
     }
 }
@@ -0,0 +1,38 @@
+use crate::{Ack, Peer};
+use sage_mqtt::{PubRec, PubRel, ReasonCode};
+use std::sync::Arc;
+
+/// Receives the acknowledgement of an outbound QoS 2 message.
+/// - If the reason code is a success, the message is released and a PUBREL
+///   packet is sent back [MQTT-4.3.3-4]
+/// - Otherwise, the delivery ends and the packet identifier can be reused
+///   [MQTT-4.3.3-4]
+///
+/// A PUBREC for an unknown packet identifier is answered with a PUBREL with
+/// `PacketIdentifierNotFound`.
+pub async fn run(pubrec: PubRec, peer: Arc<Peer>) {
+    if let Some(session) = peer.session() {
+        if !matches!(
+            pubrec.reason_code,
+            ReasonCode::Success | ReasonCode::NoMatchingSubscribers
+        ) {
+            session.acknowledge(pubrec.packet_identifier, Ack::PubRec);
+            return;
+        }
+
+        let reason_code = if session.release(pubrec.packet_identifier) {
+            ReasonCode::Success
+        } else {
+            ReasonCode::PacketIdentifierNotFound
+        };
+
+        peer.send(
+            PubRel {
+                packet_identifier: pubrec.packet_identifier,
+                reason_code,
+                ..Default::default()
+            }
+            .into(),
+        );
+    }
+}
@@ -0,0 +1,26 @@
+use crate::Peer;
+use sage_mqtt::{PubComp, PubRel, ReasonCode};
+use std::sync::Arc;
+
+/// Releases an inbound QoS 2 message. Its packet identifier is discarded and
+/// a PUBCOMP packet is sent back [MQTT-4.3.3-11].
+/// A PUBREL for an unknown packet identifier is answered with a PUBCOMP with
+/// `PacketIdentifierNotFound`.
+pub async fn run(pubrel: PubRel, peer: Arc<Peer>) {
+    if let Some(session) = peer.session() {
+        let reason_code = if session.complete(pubrel.packet_identifier) {
+            ReasonCode::Success
+        } else {
+            ReasonCode::PacketIdentifierNotFound
+        };
+
+        peer.send(
+            PubComp {
+                packet_identifier: pubrel.packet_identifier,
+                reason_code,
+                ..Default::default()
+            }
+            .into(),
+        );
+    }
+}
@@ -1,6 +1,7 @@
-use crate::{BrokerSettings, Peer};
-use sage_mqtt::{ReasonCode, SubAck, Subscribe};
-use std::sync::Arc;
+use super::publish;
+use crate::{topic, BrokerSettings, Cache, Peer, Sessions};
+use sage_mqtt::{Disconnect, Publish, QoS, ReasonCode, RetainHandling, SubAck, Subscribe};
+use std::sync::{Arc, RwLock};
 
 /// Simply returns a ConnAck package
 /// With the correct packet identifier
@@ -10,46 +11,108 @@ use std::sync::Arc;
 /// - GrantedQoS2: The subscription is accepted and any received QoS will be sent to this subscription.
 /// - UnspecifiedError: The subscription is not accepted and the Server either does not wish to reveal the reason or none of the other Reason Codes apply.
 /// - ImplementationSpecificError: The SUBSCRIBE is valid but the Server does not accept it.
-/// - NotAuthorized: The Client is not authorized to make this subscription.
+/// - NotAuthorized: The Client is not authorized to make this subscription,
+///   according to the ACL of the broker.
 /// - TopicFilterInvalid: The Topic Filter is correctly formed but is not allowed for this Client.
 /// - PacketIdentifierInUse: The specified Packet Identifier is already in use.
 /// - QuotaExceeded: An implementation or administrative imposed limit has been exceeded.
-/// - SharedSubscriptionsNotSupported: The Server does not support Shared Subscriptions for this Client.
-/// + SubscriptionIdentifiersNotSupported: The Server does not support Subscription Identifiers; the subscription is not accepted.
-/// - WildcardSubscriptionsNotSupported: The Server does not support Wildcard Subscriptions; the subscription is not accepted.
-pub async fn run(settings: Arc<BrokerSettings>, packet: Subscribe, peer: Arc<Peer>) {
+///
+/// A shared subscription with the No Local option causes a disconnection with
+/// `ProtocolError` [MQTT-3.8.3-4].
+///
+/// Once the SUBACK is sent, the retained messages matching each accepted
+/// filter are sent according to its Retain Handling option, along with the
+/// subscription identifier if any. Shared subscriptions are not sent
+/// retained messages [MQTT 4.8.2].
+pub async fn run(
+    settings: Arc<BrokerSettings>,
+    sessions: Arc<RwLock<Sessions>>,
+    packet: Subscribe,
+    peer: Arc<Peer>,
+    cache: Arc<Cache>,
+) {
+    if packet
+        .subscriptions
+        .iter()
+        .any(|(filter, options)| options.no_local && topic::share(filter).is_some())
+    {
+        peer.send_close(
+            Disconnect {
+                reason_code: ReasonCode::ProtocolError,
+                ..Default::default()
+            }
+            .into(),
+        );
+        return;
+    }
+
     // Take the client if exist, from the peer, and at it a new sub
     if let Some(session) = peer.session() {
         let mut suback = SubAck {
             packet_identifier: packet.packet_identifier,
             ..Default::default()
         };
+        let mut retained = Vec::new();
+        let principal = session.principal();
 
-        for (topic, options) in packet.subscriptions {
+        for (filter, mut options) in packet.subscriptions {
             // QoS Checking
             let mut reason_code = settings.check_qos(options.qos);
 
-            if topic.share().is_some() {
-                reason_code = ReasonCode::SharedSubscriptionsNotSupported;
-            }
-
-            if topic.has_wildcards() {
-                reason_code = ReasonCode::WildcardSubscriptionsNotSupported;
+            if !topic::is_valid_filter(&filter) {
+                reason_code = ReasonCode::TopicFilterInvalid;
+            } else if let Some(acl) = &settings.acl {
+                if !acl.can_subscribe(session.client_id(), principal.as_ref(), &filter) {
+                    reason_code = ReasonCode::NotAuthorized;
+                }
             }
 
             suback.reason_codes.push(reason_code);
-            if matches!(
-                reason_code,
-                ReasonCode::Success | ReasonCode::GrantedQoS1 | ReasonCode::GrantedQoS2
-            ) {
-                session
-                    .subs()
-                    .write()
-                    .unwrap()
-                    .add(topic, options, packet.subscription_identifier);
+
+            // The subscription is stored with the granted QoS, which is the
+            // maximum QoS messages will be delivered with
+            let granted_qos = match reason_code {
+                ReasonCode::Success => Some(QoS::AtMostOnce),
+                ReasonCode::GrantedQoS1 => Some(QoS::AtLeastOnce),
+                ReasonCode::GrantedQoS2 => Some(QoS::ExactlyOnce),
+                _ => None,
+            };
+
+            if let Some(qos) = granted_qos {
+                options.qos = qos;
+                let exists = sessions.write().unwrap().subscribe(
+                    &session,
+                    filter.clone(),
+                    options,
+                    packet.subscription_identifier,
+                );
+
+                // Retained messages are sent [MQTT-3.3.1-9] unless the
+                // subscription already existed with Retain Handling 1
+                // [MQTT-3.3.1-10], Retain Handling is 2 [MQTT-3.3.1-11] or
+                // the subscription is shared
+                let send_retained = match options.retain_handling {
+                    _ if topic::share(&filter).is_some() => false,
+                    RetainHandling::OnSubscribe => true,
+                    RetainHandling::OnFirstSubscribe => !exists,
+                    RetainHandling::DontSend => false,
+                };
+                if send_retained {
+                    retained.extend(cache.retained(&filter).into_iter().map(|publish| Publish {
+                        qos: publish::minimum_qos(qos, publish.qos),
+                        retain: true,
+                        subscription_identifiers:
+                            packet.subscription_identifier.into_iter().collect(),
+                        ..publish
+                    }));
+                }
             }
         }
-        peer.send(suback.into())
+        peer.send(suback.into());
+
+        for publish in retained {
+            session.publish(publish);
+        }
     } else {
         // If not session present, close the peer.
         // Send an UnspecifiedError error for each topic
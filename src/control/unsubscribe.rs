@@ -0,0 +1,51 @@
+use crate::{Peer, Sessions};
+use sage_mqtt::{ReasonCode, Topic, UnSubAck, UnSubscribe};
+use std::sync::{Arc, RwLock};
+
+/// Removes the subscriptions matching exactly the given filters
+/// [MQTT-3.10.4-1] and answers with a UNSUBACK packet [MQTT-3.10.4-4] with
+/// the same packet identifier [MQTT-3.10.4-5].
+/// Each filter is processed in turn [MQTT-3.10.4-6] and given one of the
+/// following reason codes, in the order of the UNSUBSCRIBE packet
+/// [MQTT-3.11.3-1]:
+/// - Success: The subscription is deleted.
+/// - NoSubscriptionExisted: No matching Topic Filter is being used by the Client.
+///
+/// Messages already being delivered for a removed subscription are still
+/// completed [MQTT-3.10.4-3].
+pub async fn run(sessions: Arc<RwLock<Sessions>>, packet: UnSubscribe, peer: Arc<Peer>) {
+    if let Some(session) = peer.session() {
+        let mut sessions = sessions.write().unwrap();
+        let reason_codes = packet
+            .subscriptions
+            .into_iter()
+            .map(|filter| {
+                if sessions.unsubscribe(&session, &Topic::from(filter.as_str())) {
+                    ReasonCode::Success
+                } else {
+                    ReasonCode::NoSubscriptionExisted
+                }
+            })
+            .collect();
+
+        peer.send(
+            UnSubAck {
+                packet_identifier: packet.packet_identifier,
+                reason_codes,
+                ..Default::default()
+            }
+            .into(),
+        );
+    } else {
+        // If not session present, close the peer.
+        // Send an UnspecifiedError error for each topic
+        peer.send_close(
+            UnSubAck {
+                packet_identifier: packet.packet_identifier,
+                reason_codes: vec![ReasonCode::UnspecifiedError; packet.subscriptions.len()],
+                ..Default::default()
+            }
+            .into(),
+        )
+    }
+}
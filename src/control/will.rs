@@ -0,0 +1,24 @@
+use super::publish;
+use crate::{Cache, Sessions};
+use sage_mqtt::{Publish, Will};
+use std::sync::RwLock;
+
+/// Publishes the given will message as any application message, keeping its
+/// QoS and RETAIN flag [MQTT-3.1.2-14] [MQTT-3.1.2-15].
+/// `client_id` is the client id of the session the will message belongs to.
+pub fn publish(sessions: &RwLock<Sessions>, cache: &Cache, client_id: &str, will: Will) {
+    let publish = Publish {
+        qos: will.qos,
+        retain: will.retain,
+        topic_name: will.topic,
+        payload_format_indicator: will.payload_format_indicator,
+        message_expiry_interval: will.message_expiry_interval,
+        response_topic: will.response_topic,
+        correlation_data: will.correlation_data,
+        user_properties: will.user_properties,
+        content_type: will.content_type,
+        message: will.message,
+        ..Default::default()
+    };
+    publish::dispatch(sessions, cache, &publish, Some(client_id));
+}
@@ -0,0 +1,638 @@
+use crate::publisher;
+use log::warn;
+use sage_mqtt::{defaults::DEFAULT_RECEIVE_MAXIMUM, Packet, PubRel, Publish, QoS, Topic};
+use std::{
+    collections::{HashSet, VecDeque},
+    time::Instant,
+};
+
+/// The progress of an outbound message in its delivery protocol.
+#[derive(Debug, PartialEq)]
+enum Stage {
+    /// The PUBLISH packet was not sent yet
+    Pending,
+    /// The PUBLISH packet was sent and waits for a PUBACK or a PUBREC
+    Sent,
+    /// The PUBREC packet was received, a PUBREL packet was sent and waits for
+    /// a PUBCOMP (QoS 2 only)
+    Released,
+}
+
+/// The packets ending the delivery of an outbound message
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Ack {
+    /// A PUBACK, ending the delivery of a QoS 1 message
+    PubAck,
+    /// A PUBREC with an error reason code, ending the delivery of a QoS 2
+    /// message which was not released
+    PubRec,
+    /// A PUBCOMP, ending the delivery of a released QoS 2 message
+    PubComp,
+}
+
+/// An outbound `Publish` message of QoS > 0 waiting for its acknowledgement,
+/// along with the time it was queued and the shared subscription it was sent
+/// for, if any. Its packet identifier is assigned once it is sent.
+#[derive(Debug)]
+struct Outgoing {
+    publish: Publish,
+    stage: Stage,
+    received: Instant,
+    share: Option<Topic>,
+}
+
+/// Holds the state of QoS > 0 messages for a session:
+/// - The packet identifiers in use by the outbound messages which were sent,
+///   queued messages holding none
+/// - The outbound messages waiting for an acknowledgement, in the order they
+///   were first sent [MQTT-4.6.0-1]. No more than the Receive Maximum of the
+///   client are sent at a time [MQTT-3.3.4-9], the others are queued until
+///   acknowledgements arrive. The oldest queued messages are dropped beyond
+///   the maximum number of queued messages, if any.
+/// - The packet identifiers of inbound QoS 2 messages which were received but
+///   not released yet
+#[derive(Debug)]
+pub struct InFlight {
+    last_packet_identifier: u16,
+    identifiers: HashSet<u16>,
+    outgoing: VecDeque<Outgoing>,
+    incoming: HashSet<u16>,
+    receive_maximum: u16,
+    max_queued: Option<usize>,
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        InFlight {
+            last_packet_identifier: 0,
+            identifiers: Default::default(),
+            outgoing: Default::default(),
+            incoming: Default::default(),
+            receive_maximum: DEFAULT_RECEIVE_MAXIMUM,
+            max_queued: None,
+        }
+    }
+}
+
+/// Returns the message as it must be sent, with the time it spent in the
+/// queue deducted from its Message Expiry Interval. A message which already
+/// started its delivery is sent even if it expired since.
+fn forward(outgoing: &Outgoing) -> Publish {
+    publisher::age(&outgoing.publish, outgoing.received).unwrap_or_else(|| Publish {
+        message_expiry_interval: Some(0),
+        ..outgoing.publish.clone()
+    })
+}
+
+impl InFlight {
+    /// Sets the number of QoS 1 and QoS 2 messages the client is willing to
+    /// process concurrently.
+    pub fn set_receive_maximum(&mut self, receive_maximum: u16) {
+        self.receive_maximum = receive_maximum;
+    }
+
+    /// Sets the number of outbound messages which can be queued, or None if
+    /// there is no limit. The oldest ones are dropped beyond it.
+    pub fn set_max_queued(&mut self, max_queued: Option<usize>) {
+        self.max_queued = max_queued;
+        self.drop_oldest();
+    }
+
+    /// Returns the number of outbound messages not acknowledged yet, may they
+    /// be sent or queued.
+    pub fn outgoing(&self) -> usize {
+        self.outgoing.len()
+    }
+
+    /// Returns the number of outbound messages sent and not acknowledged yet,
+    /// each of which holds a packet identifier
+    fn sent(&self) -> usize {
+        self.identifiers.len()
+    }
+
+    /// Returns the oldest queued messages which can be sent without exceeding
+    /// the Receive Maximum, giving them a packet identifier and marking them
+    /// as sent.
+    /// Queued messages which expired are discarded [MQTT-3.3.2-5].
+    pub fn send_pending(&mut self) -> Vec<Publish> {
+        self.outgoing.retain(|o| {
+            o.stage != Stage::Pending || publisher::age(&o.publish, o.received).is_some()
+        });
+
+        let mut pending = Vec::new();
+        for index in 0..self.outgoing.len() {
+            if self.sent() >= self.receive_maximum as usize {
+                break;
+            }
+            if self.outgoing[index].stage == Stage::Pending {
+                if let Some(publish) = self.send(index) {
+                    pending.push(publish);
+                }
+            }
+        }
+        pending
+    }
+
+    /// Gives a packet identifier to the queued message at the given index and
+    /// marks it as sent. Returns the message as it must be sent, or None if
+    /// no packet identifier is available, in which case it stays queued.
+    fn send(&mut self, index: usize) -> Option<Publish> {
+        let Some(packet_identifier) = self.next_packet_identifier() else {
+            warn!("No packet identifier available, message kept queued");
+            return None;
+        };
+        let outgoing = &mut self.outgoing[index];
+        outgoing.publish.packet_identifier = Some(packet_identifier);
+        outgoing.stage = Stage::Sent;
+        Some(forward(outgoing))
+    }
+
+    /// Finds an unused packet identifier [MQTT-4.3.2-1] and marks it as in
+    /// use. Returns None if all identifiers are in use.
+    fn next_packet_identifier(&mut self) -> Option<u16> {
+        if self.identifiers.len() >= u16::MAX as usize {
+            return None;
+        }
+        loop {
+            self.last_packet_identifier = self.last_packet_identifier.checked_add(1).unwrap_or(1);
+            if self.identifiers.insert(self.last_packet_identifier) {
+                return Some(self.last_packet_identifier);
+            }
+        }
+    }
+
+    /// Stores the message as unacknowledged, along with the shared
+    /// subscription it is sent for if any. `online` tells if the message can
+    /// be sent now, in which case it is given a packet identifier.
+    /// Returns the message as it must be sent, or None if it is queued.
+    pub fn push(
+        &mut self,
+        mut publish: Publish,
+        share: Option<Topic>,
+        online: bool,
+    ) -> Option<Publish> {
+        publish.packet_identifier = None;
+        publish.duplicate = false;
+        self.outgoing.push_back(Outgoing {
+            publish,
+            stage: Stage::Pending,
+            received: Instant::now(),
+            share,
+        });
+        if online && self.sent() < self.receive_maximum as usize {
+            self.send(self.outgoing.len() - 1)
+        } else {
+            self.drop_oldest();
+            None
+        }
+    }
+
+    /// Drops the oldest queued messages while there are more than the
+    /// maximum number of queued messages
+    fn drop_oldest(&mut self) {
+        let Some(max_queued) = self.max_queued else {
+            return;
+        };
+        let mut queued = self.outgoing.len() - self.sent();
+        while queued > max_queued {
+            match self.outgoing.iter().position(|o| o.stage == Stage::Pending) {
+                Some(index) => {
+                    warn!("Too many queued messages, oldest one dropped");
+                    self.remove(index);
+                    queued -= 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Removes the message at the given index, releasing its packet
+    /// identifier if it has one
+    fn remove(&mut self, index: usize) -> Option<Outgoing> {
+        let outgoing = self.outgoing.remove(index)?;
+        if let Some(packet_identifier) = outgoing.publish.packet_identifier {
+            self.identifiers.remove(&packet_identifier);
+        }
+        Some(outgoing)
+    }
+
+    fn position(&self, packet_identifier: u16) -> Option<usize> {
+        self.outgoing
+            .iter()
+            .position(|o| o.publish.packet_identifier == Some(packet_identifier))
+    }
+
+    /// Removes the message with the given packet identifier if the given
+    /// packet ends its delivery: a PUBACK for a QoS 1 message, a PUBREC for a
+    /// QoS 2 message which was not released, and a PUBCOMP for a released one.
+    /// Returns false if no such message exists, in which case nothing is
+    /// removed.
+    pub fn acknowledge(&mut self, packet_identifier: u16, ack: Ack) -> bool {
+        let index = self.position(packet_identifier).filter(|index| {
+            let outgoing = &self.outgoing[*index];
+            match ack {
+                Ack::PubAck => outgoing.publish.qos == QoS::AtLeastOnce,
+                Ack::PubRec => {
+                    outgoing.publish.qos == QoS::ExactlyOnce && outgoing.stage == Stage::Sent
+                }
+                Ack::PubComp => outgoing.stage == Stage::Released,
+            }
+        });
+        index.and_then(|index| self.remove(index)).is_some()
+    }
+
+    /// Removes the message with the given packet identifier, whatever the
+    /// stage of its delivery, for instance if it cannot be sent.
+    /// Returns false if no such message exists.
+    pub fn discard(&mut self, packet_identifier: u16) -> bool {
+        self.position(packet_identifier)
+            .and_then(|index| self.remove(index))
+            .is_some()
+    }
+
+    /// Marks the QoS 2 message with the given packet identifier as released:
+    /// the message is not sent again, a PUBREL packet is sent instead
+    /// [MQTT-4.3.3-4].
+    /// Returns false if no such QoS 2 message exists.
+    pub fn release(&mut self, packet_identifier: u16) -> bool {
+        match self.position(packet_identifier) {
+            Some(index) if self.outgoing[index].publish.qos == QoS::ExactlyOnce => {
+                self.outgoing[index].stage = Stage::Released;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes the messages of the given shared subscription whose delivery
+    /// can be handed over to another session, and returns them as they must
+    /// be published again. These are QoS 1 messages not acknowledged yet and
+    /// QoS 2 messages not sent yet: the delivery of a QoS 2 message which was
+    /// sent is completed by this session only [MQTT-4.8.2-4] [MQTT-4.8.2-5].
+    /// Expired messages are discarded.
+    pub fn take_shared(&mut self, share: &Topic) -> Vec<Publish> {
+        let mut taken = Vec::new();
+        let mut index = 0;
+        while index < self.outgoing.len() {
+            let o = &self.outgoing[index];
+            let movable = o.share.as_ref() == Some(share)
+                && match o.publish.qos {
+                    QoS::ExactlyOnce => o.stage == Stage::Pending,
+                    _ => o.stage != Stage::Released,
+                };
+            if !movable {
+                index += 1;
+            } else if let Some(o) = self.remove(index) {
+                if let Some(publish) = publisher::age(&o.publish, o.received) {
+                    taken.push(Publish {
+                        duplicate: false,
+                        packet_identifier: None,
+                        ..publish
+                    });
+                }
+            }
+        }
+        taken
+    }
+
+    /// Returns all the unacknowledged messages in order, ready to be sent
+    /// again, followed by the queued messages the Receive Maximum allows:
+    /// - Messages which were already sent have their DUP flag set
+    ///   [MQTT-3.3.1-1]
+    /// - Released messages are replaced by a PUBREL packet [MQTT-4.4.0-1]
+    pub fn resend(&mut self) -> Vec<Packet> {
+        let mut packets: Vec<Packet> = self
+            .outgoing
+            .iter_mut()
+            .filter_map(|outgoing| match outgoing.stage {
+                Stage::Pending => None,
+                Stage::Released => Some(
+                    PubRel {
+                        packet_identifier: outgoing.publish.packet_identifier.unwrap_or_default(),
+                        ..Default::default()
+                    }
+                    .into(),
+                ),
+                Stage::Sent => {
+                    outgoing.publish.duplicate = true;
+                    Some(forward(outgoing).into())
+                }
+            })
+            .collect();
+        packets.extend(self.send_pending().into_iter().map(Packet::from));
+        packets
+    }
+
+    /// Stores the packet identifier of an inbound QoS 2 message.
+    /// Returns false if the identifier is already stored, meaning the
+    /// message was already received and must not be delivered again
+    /// [MQTT-4.3.3-10].
+    pub fn receive(&mut self, packet_identifier: u16) -> bool {
+        self.incoming.insert(packet_identifier)
+    }
+
+    /// Returns the number of inbound QoS 2 messages not released yet
+    pub fn receiving(&self) -> usize {
+        self.incoming.len()
+    }
+
+    /// Discards the packet identifier of an inbound QoS 2 message, after which
+    /// it can be reused for a new message [MQTT-4.3.3-11].
+    /// Returns false if the identifier was not stored.
+    pub fn complete(&mut self, packet_identifier: u16) -> bool {
+        self.incoming.remove(&packet_identifier)
+    }
+}
+
+#[cfg(test)]
+mod unit {
+
+    use super::*;
+
+    fn publish() -> Publish {
+        Publish {
+            qos: QoS::AtLeastOnce,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn packet_identifiers_are_unique() {
+        let mut inflight = InFlight::default();
+        let first = inflight.push(publish(), None, true).unwrap();
+        let second = inflight.push(publish(), None, true).unwrap();
+        assert_ne!(first.packet_identifier, second.packet_identifier);
+        assert_eq!(inflight.outgoing.len(), 2);
+    }
+
+    #[test]
+    fn packet_identifier_is_never_zero() {
+        let mut inflight = InFlight {
+            last_packet_identifier: u16::MAX,
+            ..Default::default()
+        };
+        assert_eq!(
+            inflight
+                .push(publish(), None, true)
+                .unwrap()
+                .packet_identifier,
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn packet_identifiers_in_use_are_skipped() {
+        let mut inflight = InFlight::default();
+        inflight.push(publish(), None, true);
+        inflight.last_packet_identifier = 0;
+        assert_eq!(
+            inflight
+                .push(publish(), None, true)
+                .unwrap()
+                .packet_identifier,
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn queued_messages_hold_no_packet_identifier() {
+        let mut inflight = InFlight::default();
+        inflight.set_receive_maximum(1);
+        let first = inflight.push(publish(), None, true).unwrap();
+        assert!(inflight.push(publish(), None, true).is_none());
+        assert!(inflight.push(publish(), None, false).is_none());
+        assert_eq!(inflight.identifiers.len(), 1);
+        assert!(inflight
+            .outgoing
+            .iter()
+            .skip(1)
+            .all(|o| o.publish.packet_identifier.is_none()));
+
+        // A queued message cannot be acknowledged before it is sent
+        assert!(!inflight.acknowledge(2, Ack::PubAck));
+        assert!(inflight.acknowledge(first.packet_identifier.unwrap(), Ack::PubAck));
+        assert!(inflight.identifiers.is_empty());
+        let pending = inflight.send_pending();
+        assert_eq!(pending[0].packet_identifier, Some(2));
+        assert_eq!(inflight.identifiers.len(), 1);
+    }
+
+    #[test]
+    fn acknowledge_removes_message() {
+        let mut inflight = InFlight::default();
+        let packet_identifier = inflight
+            .push(publish(), None, true)
+            .unwrap()
+            .packet_identifier;
+        assert!(inflight.acknowledge(packet_identifier.unwrap(), Ack::PubAck));
+        assert!(!inflight.acknowledge(packet_identifier.unwrap(), Ack::PubAck));
+        assert!(inflight.outgoing.is_empty());
+    }
+
+    #[test]
+    fn acknowledge_must_match_delivery() {
+        let mut inflight = InFlight::default();
+        let qos1 = inflight
+            .push(publish(), None, true)
+            .unwrap()
+            .packet_identifier
+            .unwrap();
+        let qos2 = inflight
+            .push(
+                Publish {
+                    qos: QoS::ExactlyOnce,
+                    ..Default::default()
+                },
+                None,
+                true,
+            )
+            .unwrap()
+            .packet_identifier
+            .unwrap();
+
+        // A QoS 2 message waiting for PUBREC is not completed by a stray
+        // PUBACK or PUBCOMP, and a QoS 1 message is not completed by PUBCOMP
+        assert!(!inflight.acknowledge(qos2, Ack::PubAck));
+        assert!(!inflight.acknowledge(qos2, Ack::PubComp));
+        assert!(!inflight.acknowledge(qos1, Ack::PubComp));
+        assert!(!inflight.acknowledge(qos1, Ack::PubRec));
+        assert!(!inflight.release(qos1));
+        assert_eq!(inflight.outgoing(), 2);
+
+        assert!(inflight.release(qos2));
+        assert!(!inflight.acknowledge(qos2, Ack::PubRec));
+        assert!(inflight.acknowledge(qos2, Ack::PubComp));
+        assert!(inflight.acknowledge(qos1, Ack::PubAck));
+        assert_eq!(inflight.outgoing(), 0);
+    }
+
+    #[test]
+    fn resend_sets_duplicate_on_sent_messages() {
+        let mut inflight = InFlight::default();
+        inflight.push(publish(), None, true);
+        inflight.push(publish(), None, false);
+        let messages = inflight.resend();
+        assert!(matches!(&messages[0], Packet::Publish(p) if p.duplicate));
+        assert!(matches!(&messages[1], Packet::Publish(p) if !p.duplicate));
+        assert!(inflight
+            .resend()
+            .iter()
+            .all(|p| matches!(p, Packet::Publish(p) if p.duplicate)));
+    }
+
+    #[test]
+    fn resend_replaces_released_messages() {
+        let mut inflight = InFlight::default();
+        let packet_identifier = inflight
+            .push(
+                Publish {
+                    qos: QoS::ExactlyOnce,
+                    ..Default::default()
+                },
+                None,
+                true,
+            )
+            .unwrap()
+            .packet_identifier
+            .unwrap();
+        inflight.push(publish(), None, true);
+
+        assert!(inflight.release(packet_identifier));
+        let messages = inflight.resend();
+        assert!(
+            matches!(&messages[0], Packet::PubRel(p) if p.packet_identifier == packet_identifier)
+        );
+        assert!(matches!(&messages[1], Packet::Publish(_)));
+
+        // The packet identifier stays in use until PUBCOMP
+        assert!(!inflight.acknowledge(packet_identifier, Ack::PubAck));
+        assert!(inflight.acknowledge(packet_identifier, Ack::PubComp));
+        assert_eq!(inflight.outgoing.len(), 1);
+    }
+
+    #[test]
+    fn receive_maximum_queues_messages() {
+        let mut inflight = InFlight::default();
+        inflight.set_receive_maximum(2);
+        assert!(inflight.push(publish(), None, true).is_some());
+        let second = inflight.push(publish(), None, true).unwrap();
+        assert!(inflight.push(publish(), None, true).is_none());
+        assert!(inflight.push(publish(), None, true).is_none());
+        assert!(inflight.send_pending().is_empty());
+
+        // Each acknowledgement makes room for a queued message, in order
+        assert!(inflight.acknowledge(second.packet_identifier.unwrap(), Ack::PubAck));
+        let pending = inflight.send_pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].packet_identifier, Some(3));
+        assert!(inflight.send_pending().is_empty());
+    }
+
+    #[test]
+    fn max_queued_drops_oldest_messages() {
+        let mut inflight = InFlight::default();
+        inflight.set_receive_maximum(1);
+        inflight.set_max_queued(Some(2));
+        assert!(inflight.push(publish(), None, true).is_some());
+        for message in 1..=4 {
+            let publish = Publish {
+                message: vec![message],
+                ..publish()
+            };
+            assert!(inflight.push(publish, None, true).is_none());
+        }
+
+        // The message in flight is kept, along with the newest queued ones
+        assert_eq!(inflight.outgoing(), 3);
+        let messages = inflight.resend();
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(&messages[0], Packet::Publish(p) if p.duplicate));
+        assert_eq!(inflight.identifiers.len(), 1);
+        let queued: Vec<_> = inflight
+            .outgoing
+            .iter()
+            .skip(1)
+            .map(|o| o.publish.message.clone())
+            .collect();
+        assert_eq!(queued, vec![vec![3], vec![4]]);
+
+        // Lowering the limit drops the queued messages at once
+        inflight.set_max_queued(Some(0));
+        assert_eq!(inflight.outgoing(), 1);
+    }
+
+    #[test]
+    fn resend_respects_receive_maximum() {
+        let mut inflight = InFlight::default();
+        inflight.push(publish(), None, true);
+        inflight.push(publish(), None, false);
+        inflight.push(publish(), None, false);
+        inflight.set_receive_maximum(2);
+
+        let messages = inflight.resend();
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(&messages[0], Packet::Publish(p) if p.duplicate));
+        assert!(matches!(&messages[1], Packet::Publish(p) if !p.duplicate));
+    }
+
+    #[test]
+    fn expired_messages_are_not_sent() {
+        let mut inflight = InFlight::default();
+        inflight.push(
+            Publish {
+                message_expiry_interval: Some(0),
+                ..publish()
+            },
+            None,
+            false,
+        );
+        inflight.push(
+            Publish {
+                message_expiry_interval: Some(60),
+                ..publish()
+            },
+            None,
+            false,
+        );
+
+        let messages = inflight.resend();
+        assert_eq!(messages.len(), 1);
+        assert!(
+            matches!(&messages[0], Packet::Publish(p) if p.message_expiry_interval == Some(60))
+        );
+        assert_eq!(inflight.outgoing.len(), 1);
+    }
+
+    #[test]
+    fn take_shared_keeps_started_qos2_messages() {
+        let share = Topic::from("$share/group/sport/tennis");
+        let mut inflight = InFlight::default();
+        let qos2 = Publish {
+            qos: QoS::ExactlyOnce,
+            ..Default::default()
+        };
+        inflight.push(publish(), Some(share.clone()), true);
+        inflight.push(qos2.clone(), Some(share.clone()), true);
+        inflight.push(qos2, Some(share.clone()), false);
+        inflight.push(publish(), None, true);
+
+        let taken = inflight.take_shared(&share);
+        assert_eq!(taken.len(), 2);
+        assert_eq!(taken[0].qos, QoS::AtLeastOnce);
+        assert_eq!(taken[1].qos, QoS::ExactlyOnce);
+        assert!(taken.iter().all(|p| p.packet_identifier.is_none()));
+        assert_eq!(inflight.outgoing(), 2);
+        assert!(inflight.take_shared(&share).is_empty());
+    }
+
+    #[test]
+    fn incoming_identifiers_are_stored_until_complete() {
+        let mut inflight = InFlight::default();
+        assert!(inflight.receive(42));
+        assert!(!inflight.receive(42));
+        assert!(inflight.complete(42));
+        assert!(!inflight.complete(42));
+        assert!(inflight.receive(42));
+    }
+}
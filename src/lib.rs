@@ -3,36 +3,51 @@
 // #![warn(missing_doc_code_examples)]
 #![allow(clippy::large_enum_variant)]
 
-use std::sync::Arc;
 use tokio::sync::mpsc;
 
 mod broker_settings;
-//mod command;
+mod command;
 mod control;
+mod inflight;
 mod peer;
 mod publisher;
+mod remote_addr;
 mod session;
 mod sessions;
+mod shares;
 mod subs;
+mod subs_tree;
+mod topic;
+mod topic_aliases;
 mod trigger;
 
+/// Authentication of the clients.
+pub mod auth;
+
 /// All functions related to service control.
 pub mod service;
 
 pub use broker_settings::BrokerSettings;
-//use command::Command;
+use command::Authenticated;
+pub use command::{AuthOutcome, Command};
+use inflight::{Ack, InFlight};
 use peer::Peer;
 use publisher::Cache;
 pub use publisher::Publisher;
+pub use remote_addr::{RemoteAddr, UnixAddr};
 use sage_mqtt::Packet;
 pub use session::Session;
 pub use sessions::Sessions;
+use shares::Group;
+pub use shares::SharingStrategy;
 pub use subs::Subs;
+use subs_tree::SubsTree;
+use topic_aliases::{Assignment, TopicAliases};
 pub use trigger::Trigger;
 /// The MPSC sender for controlling a running server
-pub type CommandSender = mpsc::UnboundedSender<(Arc<Peer>, Packet)>;
+pub type CommandSender = mpsc::UnboundedSender<Command>;
 /// The MPSC sender for controlling a running server
-pub type CommandReceiver = mpsc::UnboundedReceiver<(Arc<Peer>, Packet)>;
+pub type CommandReceiver = mpsc::UnboundedReceiver<Command>;
 
 type PacketReceiver = mpsc::UnboundedReceiver<Packet>;
 type PacketSender = mpsc::UnboundedSender<Packet>;
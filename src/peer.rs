@@ -1,33 +1,60 @@
-use crate::{PacketSender, Session, Trigger};
-use log::error;
-use sage_mqtt::Packet;
-use std::{
-    net::SocketAddr,
-    sync::{Arc, RwLock, Weak},
+use crate::{
+    auth::{ClientCertificate, Handshake, TlsInfo},
+    Assignment, BrokerSettings, CommandSender, PacketSender, RemoteAddr, Session, TopicAliases,
+    Trigger,
 };
+use log::error;
+use sage_mqtt::{Packet, Publish, ReasonCode};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 
 #[derive(Debug)]
 pub struct Peer {
-    addr: SocketAddr,
+    addr: RemoteAddr,
+    settings: Arc<BrokerSettings>,
     session: RwLock<Weak<Session>>,
     packet_sender: PacketSender,
+    command_sender: CommandSender,
     closing: Trigger,
+    topic_aliases: Mutex<TopicAliases>,
+    maximum_packet_size: RwLock<Option<u32>>,
+    authentication_method: RwLock<Option<String>>,
+    handshake: Mutex<Option<Handshake>>,
+    tls: RwLock<Option<TlsInfo>>,
+    deferred: Mutex<Option<Vec<Packet>>>,
 }
 
 impl Peer {
-    pub fn new(addr: SocketAddr, packet_sender: PacketSender) -> Self {
+    pub fn new(
+        addr: RemoteAddr,
+        settings: Arc<BrokerSettings>,
+        packet_sender: PacketSender,
+        command_sender: CommandSender,
+    ) -> Self {
         Peer {
             addr,
+            settings,
             packet_sender,
+            command_sender,
             session: Default::default(),
             closing: Default::default(),
+            topic_aliases: Default::default(),
+            maximum_packet_size: Default::default(),
+            authentication_method: Default::default(),
+            handshake: Default::default(),
+            tls: Default::default(),
+            deferred: Default::default(),
         }
     }
 
-    pub fn addr(&self) -> &SocketAddr {
+    pub fn addr(&self) -> &RemoteAddr {
         &self.addr
     }
 
+    /// Returns the settings of the listener which accepted the client
+    pub fn settings(&self) -> Arc<BrokerSettings> {
+        self.settings.clone()
+    }
+
     pub fn bind(&self, new_session: Arc<Session>) {
         if let Ok(mut session) = self.session.write() {
             *session = Arc::downgrade(&new_session);
@@ -45,6 +72,118 @@ impl Peer {
         }
     }
 
+    /// Returns the TLS connection of the client, if any
+    pub fn tls(&self) -> Option<TlsInfo> {
+        self.tls.read().unwrap().clone()
+    }
+
+    /// Sets the TLS connection of the client
+    pub fn set_tls(&self, tls: Option<TlsInfo>) {
+        *self.tls.write().unwrap() = tls;
+    }
+
+    /// Returns the certificate the client presented, if any
+    pub fn certificate(&self) -> Option<ClientCertificate> {
+        self.tls().and_then(|tls| tls.certificate)
+    }
+
+    /// Returns the maximum size of the packets the client accepts, if any
+    pub fn maximum_packet_size(&self) -> Option<u32> {
+        *self.maximum_packet_size.read().unwrap()
+    }
+
+    /// Sets the maximum size of the packets the client accepts
+    pub fn set_maximum_packet_size(&self, maximum_packet_size: Option<u32>) {
+        *self.maximum_packet_size.write().unwrap() = maximum_packet_size;
+    }
+
+    /// Returns the enhanced authentication method the client connected with,
+    /// if any
+    pub fn authentication_method(&self) -> Option<String> {
+        self.authentication_method.read().unwrap().clone()
+    }
+
+    /// Sets the enhanced authentication method the client connected with
+    pub fn set_authentication_method(&self, method: Option<String>) {
+        *self.authentication_method.write().unwrap() = method;
+    }
+
+    /// Returns true if the client is connecting and has not completed its
+    /// enhanced authentication yet
+    pub fn authenticating(&self) -> bool {
+        matches!(&*self.handshake.lock().unwrap(), Some(handshake) if handshake.connect.is_some())
+    }
+
+    /// Removes the enhanced authentication exchange in progress, if any
+    pub(crate) fn take_handshake(&self) -> Option<Handshake> {
+        self.handshake.lock().unwrap().take()
+    }
+
+    /// Keeps the enhanced authentication exchange in progress until the
+    /// client sends its next AUTH packet
+    pub(crate) fn set_handshake(&self, handshake: Handshake) {
+        *self.handshake.lock().unwrap() = Some(handshake);
+    }
+
+    /// Returns the channel of the command loop, to which the outcome of the
+    /// tasks run apart from it on behalf of the client is sent
+    pub fn command_sender(&self) -> &CommandSender {
+        &self.command_sender
+    }
+
+    /// Defers the packets received from the client until `resume` is called,
+    /// while its authentication runs apart from the command loop
+    pub fn suspend(&self) {
+        *self.deferred.lock().unwrap() = Some(Vec::new());
+    }
+
+    /// Keeps the given packet until `resume` is called if the peer is
+    /// suspended, or returns it to be processed right away
+    pub fn defer(&self, packet: Packet) -> Option<Packet> {
+        match &mut *self.deferred.lock().unwrap() {
+            Some(deferred) => {
+                deferred.push(packet);
+                None
+            }
+            None => Some(packet),
+        }
+    }
+
+    /// Ends the suspension of the peer, returning the packets received in the
+    /// meantime
+    pub fn resume(&self) -> Vec<Packet> {
+        self.deferred.lock().unwrap().take().unwrap_or_default()
+    }
+
+    /// Sets the number of topic aliases the client accepts
+    pub fn set_topic_alias_maximum(&self, maximum: u16) {
+        self.topic_aliases
+            .lock()
+            .unwrap()
+            .set_outbound_maximum(maximum);
+    }
+
+    /// Assigns a topic alias to a PUBLISH packet about to be sent to the
+    /// client, when possible. The assignment must be recorded with
+    /// `record_topic_alias` once the packet is sent.
+    pub fn assign_topic_alias(&self, publish: &mut Publish) -> Option<Assignment> {
+        self.topic_aliases.lock().unwrap().assign(publish)
+    }
+
+    /// Records the topic alias of a PUBLISH packet sent to the client
+    pub fn record_topic_alias(&self, assignment: Assignment) {
+        self.topic_aliases.lock().unwrap().record(assignment);
+    }
+
+    /// Resolves the topic alias of a PUBLISH packet received from the client
+    pub fn resolve_topic_alias(
+        &self,
+        publish: &mut Publish,
+        maximum: u16,
+    ) -> Result<(), ReasonCode> {
+        self.topic_aliases.lock().unwrap().resolve(publish, maximum)
+    }
+
     pub fn closing(&self) -> bool {
         self.closing.is_fired()
     }
@@ -58,6 +197,8 @@ impl Peer {
         self.close();
     }
 
+    /// Queues the packet to be sent to the client.
+    /// PUBLISH packets are given a topic alias when they are sent.
     pub fn send(&self, packet: Packet) {
         if let Err(e) = self.packet_sender.send(packet) {
             error!("Cannot send packet to channel: {:?}", e);
@@ -1,12 +1,73 @@
 // use async_std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
-use std::sync::Arc;
+use crate::topic;
+use sage_mqtt::{Publish, Topic};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
 
+/// Returns the message as it must be forwarded, its Message Expiry Interval
+/// reduced by the time elapsed since it was received [MQTT-3.3.2-6].
+/// Returns None if the message expired.
+pub fn age(publish: &Publish, received: Instant) -> Option<Publish> {
+    match publish.message_expiry_interval {
+        None => Some(publish.clone()),
+        Some(interval) => {
+            let elapsed = received.elapsed().as_secs();
+            if elapsed >= interval as u64 {
+                None
+            } else {
+                Some(Publish {
+                    message_expiry_interval: Some(interval - elapsed as u32),
+                    ..publish.clone()
+                })
+            }
+        }
+    }
+}
+
+/// Holds the retained messages of the broker, at most one per topic name,
+/// along with the time they were received.
 #[derive(Default, Debug)]
-pub struct Cache;
+pub struct Cache {
+    retained: RwLock<HashMap<Topic, (Publish, Instant)>>,
+}
 
 impl Cache {
-    pub fn clear(&self) {
-        log::info!("Cache cleared");
+    /// Stores the given message as the retained message of its topic name,
+    /// replacing any existing one [MQTT-3.3.1-5].
+    /// A message with an empty payload removes the retained message instead
+    /// and is not stored itself [MQTT-3.3.1-6] [MQTT-3.3.1-7].
+    pub fn retain(&self, publish: &Publish) {
+        let mut retained = self.retained.write().unwrap();
+        if publish.message.is_empty() {
+            retained.remove(&publish.topic_name);
+        } else {
+            retained.insert(
+                publish.topic_name.clone(),
+                (
+                    Publish {
+                        duplicate: false,
+                        packet_identifier: None,
+                        ..publish.clone()
+                    },
+                    Instant::now(),
+                ),
+            );
+        }
+    }
+
+    /// Returns all the retained messages whose topic name matches the given
+    /// filter. Expired messages are discarded instead [MQTT-3.3.2-5].
+    pub fn retained(&self, filter: &Topic) -> Vec<Publish> {
+        let mut retained = self.retained.write().unwrap();
+        retained.retain(|_, (publish, received)| age(publish, *received).is_some());
+        retained
+            .iter()
+            .filter(|(name, _)| topic::matches(filter, name))
+            .filter_map(|(_, (publish, received))| age(publish, *received))
+            .collect()
     }
 }
 
@@ -22,3 +83,80 @@ impl Publisher {
         &self.cache
     }
 }
+
+#[cfg(test)]
+mod unit {
+
+    use super::*;
+
+    fn publish(name: &str, message: &str) -> Publish {
+        Publish {
+            retain: true,
+            topic_name: Topic::from(name),
+            message: message.as_bytes().to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn retained(cache: &Cache, filter: &str) -> Vec<String> {
+        let mut messages: Vec<String> = cache
+            .retained(&Topic::from(filter))
+            .into_iter()
+            .map(|p| String::from_utf8(p.message).unwrap())
+            .collect();
+        messages.sort();
+        messages
+    }
+
+    #[test]
+    fn retain_replaces_existing_message() {
+        let cache = Cache::default();
+        cache.retain(&publish("sport/tennis", "first"));
+        cache.retain(&publish("sport/tennis", "second"));
+        cache.retain(&publish("sport/golf", "third"));
+
+        assert_eq!(retained(&cache, "sport/tennis"), vec!["second"]);
+        assert_eq!(retained(&cache, "sport/#"), vec!["second", "third"]);
+        assert!(retained(&cache, "music/#").is_empty());
+    }
+
+    #[test]
+    fn expired_messages_are_discarded() {
+        let cache = Cache::default();
+        cache.retain(&Publish {
+            message_expiry_interval: Some(0),
+            ..publish("sport/tennis", "expired")
+        });
+        cache.retain(&Publish {
+            message_expiry_interval: Some(60),
+            ..publish("sport/golf", "fresh")
+        });
+
+        assert_eq!(retained(&cache, "#"), vec!["fresh"]);
+        assert!(cache.retained.read().unwrap().len() == 1);
+    }
+
+    #[test]
+    fn age_reduces_message_expiry_interval() {
+        let publish = Publish {
+            message_expiry_interval: Some(60),
+            ..Default::default()
+        };
+        let received = Instant::now() - std::time::Duration::from_secs(20);
+        let aged = age(&publish, received).unwrap();
+        assert_eq!(aged.message_expiry_interval, Some(40));
+
+        let received = Instant::now() - std::time::Duration::from_secs(60);
+        assert!(age(&publish, received).is_none());
+        assert!(age(&Publish::default(), received).is_some());
+    }
+
+    #[test]
+    fn empty_message_removes_retained_message() {
+        let cache = Cache::default();
+        cache.retain(&publish("sport/tennis", "first"));
+        cache.retain(&publish("sport/tennis", ""));
+
+        assert!(retained(&cache, "#").is_empty());
+    }
+}
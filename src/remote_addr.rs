@@ -0,0 +1,55 @@
+use std::{fmt, net::SocketAddr, path::PathBuf};
+
+/// The address of a connected client, whatever the transport it uses
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RemoteAddr {
+    /// A client connected over TCP, including the streams established over
+    /// TCP such as TLS and WebSocket ones
+    Socket(SocketAddr),
+    /// A client connected over a Unix domain socket
+    Unix(UnixAddr),
+}
+
+/// The address of a client connected over a Unix domain socket, along with
+/// the credentials of its process when the platform provides them
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UnixAddr {
+    /// The path of the socket the client connected to, if it is bound to one
+    pub path: Option<PathBuf>,
+    /// The user identifier of the process of the client
+    pub uid: Option<u32>,
+    /// The group identifier of the process of the client
+    pub gid: Option<u32>,
+    /// The identifier of the process of the client
+    pub pid: Option<i32>,
+}
+
+impl From<SocketAddr> for RemoteAddr {
+    fn from(addr: SocketAddr) -> Self {
+        RemoteAddr::Socket(addr)
+    }
+}
+
+impl From<UnixAddr> for RemoteAddr {
+    fn from(addr: UnixAddr) -> Self {
+        RemoteAddr::Unix(addr)
+    }
+}
+
+impl fmt::Display for RemoteAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteAddr::Socket(addr) => addr.fmt(f),
+            RemoteAddr::Unix(addr) => {
+                match &addr.path {
+                    Some(path) => write!(f, "unix:{}", path.display())?,
+                    None => write!(f, "unix")?,
+                }
+                if let Some(pid) = addr.pid {
+                    write!(f, " (pid {})", pid)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
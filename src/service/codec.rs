@@ -0,0 +1,272 @@
+//! Wraps `sage_mqtt` packet encoding and decoding.
+//!
+//! `sage_mqtt` 0.5 has a few flaws this module works around:
+//! - It writes the QoS of PUBLISH packets one bit too far in the fixed header
+//!   and reads the DUP flag from the wrong bits [MQTT 3.3.1].
+//! - It omits the Reason Code of PUBACK, PUBREC, PUBREL and PUBCOMP packets
+//!   precisely when it cannot be omitted: when it is not Success and there
+//!   are no properties [MQTT 3.4.2.1].
+//! - It loses the share name of shared subscription filters, which is checked
+//!   on the raw SUBSCRIBE packet instead [MQTT-4.8.2-1]. See
+//!   `topic::split_share`.
+//!
+//! It also enforces the Maximum Packet Size in both directions.
+use crate::topic;
+use sage_mqtt::{codec, Packet, ReasonCode, Result as SageResult, Subscribe, Topic};
+use std::io::Cursor;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const DUP_FLAG: u8 = 0b0000_1000;
+const SUBSCRIBE_TYPE: u8 = 0b1000_0000;
+const PACKET_TYPE: u8 = 0b1111_0000;
+
+/// Replaces shared filters whose share name is invalid, which cannot be told
+/// once decoded: a shared filter without topic filter is refused the same way
+const INVALID_SHARE: &str = "$share/";
+
+/// Encodes the given packet into a buffer ready to be sent.
+pub async fn encode(packet: Packet) -> SageResult<Vec<u8>> {
+    let header = match &packet {
+        Packet::Publish(publish) => Some(
+            0b0011_0000
+                | (publish.duplicate as u8) << 3
+                | (publish.qos as u8) << 1
+                | publish.retain as u8,
+        ),
+        _ => None,
+    };
+    let reason_code = match &packet {
+        Packet::PubAck(packet) => Some(packet.reason_code),
+        Packet::PubRec(packet) => Some(packet.reason_code),
+        Packet::PubRel(packet) => Some(packet.reason_code),
+        Packet::PubComp(packet) => Some(packet.reason_code),
+        _ => None,
+    };
+
+    let mut buffer = Vec::new();
+    packet.encode(&mut buffer).await?;
+
+    if let Some(header) = header {
+        buffer[0] = header;
+    }
+
+    // A packet made of the fixed header and the packet identifier only
+    // lost its reason code. It is written back along with an empty property
+    // length.
+    if let Some(reason_code) = reason_code {
+        if reason_code != ReasonCode::Success && buffer.len() == 4 {
+            buffer[1] = 4;
+            codec::write_reason_code(reason_code, &mut buffer).await?;
+            buffer.push(0);
+        }
+    }
+
+    Ok(buffer)
+}
+
+/// Encodes the given packet so that it does not exceed the maximum packet
+/// size of the client, if any [MQTT-3.1.2-24].
+/// The reason string and user properties of acknowledgement packets are left
+/// out if they would exceed it [MQTT-3.2.2-19] [MQTT-3.2.2-20]
+/// [MQTT-3.4.2-2] [MQTT-3.4.2-3].
+/// Returns None if the packet is too large anyway.
+pub async fn encode_within(
+    packet: Packet,
+    maximum_packet_size: Option<u32>,
+) -> SageResult<Option<Vec<u8>>> {
+    let maximum_packet_size = match maximum_packet_size {
+        Some(maximum_packet_size) => maximum_packet_size as usize,
+        None => return encode(packet).await.map(Some),
+    };
+
+    let buffer = encode(packet.clone()).await?;
+    if buffer.len() <= maximum_packet_size {
+        return Ok(Some(buffer));
+    }
+
+    if let Some(packet) = trim(packet) {
+        let buffer = encode(packet).await?;
+        if buffer.len() <= maximum_packet_size {
+            return Ok(Some(buffer));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Removes the optional reason string and user properties of the packet.
+/// Returns None if the packet has none of them.
+fn trim(packet: Packet) -> Option<Packet> {
+    macro_rules! trim {
+        ($packet:expr, $variant:ident) => {
+            Packet::$variant(sage_mqtt::$variant {
+                reason_string: None,
+                user_properties: Default::default(),
+                ..$packet
+            })
+        };
+    }
+
+    match packet {
+        Packet::ConnAck(packet) => Some(trim!(packet, ConnAck)),
+        Packet::PubAck(packet) => Some(trim!(packet, PubAck)),
+        Packet::PubRec(packet) => Some(trim!(packet, PubRec)),
+        Packet::PubRel(packet) => Some(trim!(packet, PubRel)),
+        Packet::PubComp(packet) => Some(trim!(packet, PubComp)),
+        Packet::UnSubAck(packet) => Some(trim!(packet, UnSubAck)),
+        Packet::Disconnect(packet) => Some(trim!(packet, Disconnect)),
+        Packet::Auth(packet) => Some(trim!(packet, Auth)),
+        Packet::SubAck(packet) => Some(Packet::SubAck(sage_mqtt::SubAck {
+            user_properties: Default::default(),
+            ..packet
+        })),
+        _ => None,
+    }
+}
+
+/// Decodes the next packet from the given reader.
+/// A packet larger than `maximum_packet_size` is refused with
+/// `PacketTooLarge` as soon as its fixed header is read, before its content
+/// is read [MQTT-3.2.2-15].
+pub async fn decode<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    maximum_packet_size: Option<u32>,
+) -> SageResult<Packet> {
+    // The fixed header is made of the packet type and flags, followed by the
+    // remaining length encoded on 1 to 4 bytes [MQTT 2.1.4]
+    let mut header = vec![codec::read_byte(reader).await?];
+    let mut remaining_length = 0;
+    for index in 0..4 {
+        let byte = codec::read_byte(reader).await?;
+        header.push(byte);
+        remaining_length += ((byte & 0x7F) as usize) << (7 * index);
+        if byte & 0x80 == 0 {
+            break;
+        } else if index == 3 {
+            return Err(ReasonCode::MalformedPacket.into());
+        }
+    }
+
+    if let Some(maximum_packet_size) = maximum_packet_size {
+        if header.len() + remaining_length > maximum_packet_size as usize {
+            return Err(ReasonCode::PacketTooLarge.into());
+        }
+    }
+
+    let flags = header[0];
+    if flags & PACKET_TYPE == SUBSCRIBE_TYPE {
+        let mut body = Vec::new();
+        reader
+            .take(remaining_length as u64)
+            .read_to_end(&mut body)
+            .await?;
+        let mut packet = Packet::decode(&mut Cursor::new(header).chain(&body[..])).await?;
+        if let Packet::Subscribe(subscribe) = &mut packet {
+            check_share_names(subscribe, &body).await?;
+        }
+        return Ok(packet);
+    }
+
+    let mut packet = Packet::decode(&mut Cursor::new(header).chain(reader)).await?;
+    if let Packet::Publish(publish) = &mut packet {
+        publish.duplicate = flags & DUP_FLAG > 0;
+    }
+    Ok(packet)
+}
+
+/// Reads the raw topic filters of the given SUBSCRIBE packet body, replacing
+/// those with an invalid share name by `INVALID_SHARE`.
+async fn check_share_names(subscribe: &mut Subscribe, body: &[u8]) -> SageResult<()> {
+    let mut reader = Cursor::new(body);
+    codec::read_two_byte_integer(&mut reader).await?;
+    let properties = codec::read_variable_byte_integer(&mut reader).await?;
+    reader.set_position(reader.position() + properties as u64);
+
+    for (filter, _) in subscribe.subscriptions.iter_mut() {
+        let raw = codec::read_utf8_string(&mut reader).await?;
+        codec::read_byte(&mut reader).await?;
+        if !topic::is_valid_share_name(&raw) {
+            *filter = Topic::from(INVALID_SHARE);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod unit {
+
+    use super::*;
+    use sage_mqtt::{PubAck, Publish};
+
+    #[tokio::test]
+    async fn encode_within_trims_optional_properties() {
+        let puback = PubAck {
+            packet_identifier: 1,
+            reason_code: ReasonCode::NoMatchingSubscribers,
+            reason_string: Some("Nobody listens to this topic".into()),
+            ..Default::default()
+        };
+        let buffer = encode_within(puback.into(), Some(16)).await.unwrap();
+        assert_eq!(buffer, Some(vec![0x40, 4, 0, 1, 0x10, 0]));
+    }
+
+    #[tokio::test]
+    async fn encode_within_discards_large_packets() {
+        let publish = Publish {
+            topic_name: Topic::from("sport/tennis"),
+            message: vec![0; 64],
+            ..Default::default()
+        };
+        assert!(encode_within(publish.clone().into(), Some(64))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(encode_within(publish.into(), None).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn decode_checks_share_names() {
+        let mut buffer = vec![0x82, 0, 0, 1, 0];
+        for filter in ["$share/workers/sport", "$share/work+ers/sport"] {
+            buffer.extend_from_slice(&(filter.len() as u16).to_be_bytes());
+            buffer.extend_from_slice(filter.as_bytes());
+            buffer.push(0);
+        }
+        buffer[1] = buffer.len() as u8 - 2;
+
+        if let Ok(Packet::Subscribe(subscribe)) = decode(&mut Cursor::new(buffer), None).await {
+            let filters: Vec<_> = subscribe
+                .subscriptions
+                .into_iter()
+                .map(|(f, _)| f)
+                .collect();
+            assert_eq!(
+                filters,
+                vec![
+                    Topic::from("$share/workers/sport"),
+                    Topic::from(INVALID_SHARE)
+                ]
+            );
+        } else {
+            panic!("Expected SUBSCRIBE packet");
+        }
+    }
+
+    #[tokio::test]
+    async fn decode_refuses_large_packets() {
+        let publish = Publish {
+            topic_name: Topic::from("sport/tennis"),
+            message: vec![0; 64],
+            ..Default::default()
+        };
+        let buffer = encode(publish.into()).await.unwrap();
+
+        let result = decode(&mut Cursor::new(buffer.clone()), Some(64)).await;
+        assert!(matches!(
+            result,
+            Err(sage_mqtt::Error::Reason(ReasonCode::PacketTooLarge))
+        ));
+        let result = decode(&mut Cursor::new(buffer), Some(128)).await;
+        assert!(matches!(result, Ok(Packet::Publish(_))));
+    }
+}
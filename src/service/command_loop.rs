@@ -1,4 +1,6 @@
-use crate::{control, BrokerSettings, CommandReceiver, Publisher, Sessions, Trigger};
+use crate::{
+    control, BrokerSettings, Command, CommandReceiver, Peer, Publisher, Sessions, Trigger,
+};
 use log::{debug, error, info};
 use sage_mqtt::{Disconnect, ReasonCode};
 use std::sync::{Arc, RwLock};
@@ -11,6 +13,9 @@ use std::sync::{Arc, RwLock};
 /// These are held by `listen_loop` (one per peer) and the `listen_tcp`
 /// tasks. Meaning when all peers are dropped and port listenning is stopped
 /// The command loop ends.
+/// Each packet is processed with the settings of the listener which accepted
+/// the client. `settings` are the ones of the broker as a whole, such as the
+/// sharing strategy.
 /// Eventually, this task may become a spawner for other tasks
 pub async fn command_loop(
     settings: Arc<BrokerSettings>,
@@ -24,39 +29,66 @@ pub async fn command_loop(
         error!("Shutting down server due to current limitations");
         shutdown.fire();
     }
+    sessions
+        .write()
+        .unwrap()
+        .set_sharing_strategy(settings.shared_subscription_strategy);
 
     info!("Start command loop");
-    while let Some((peer, packet)) = from_command_channel.recv().await {
-        debug!(
-            "[{:?}] <<< {:#?}",
-            if let Some(s) = peer.session() {
-                s.client_id().into()
-            } else {
-                String::from("N/A")
-            },
-            packet
-        );
-        // If the broker is stopping, let's notify here the client with a
-        // DISCONNECT and close the peer
-        if shutdown.is_fired() {
-            peer.send_close(
-                Disconnect {
-                    reason_code: ReasonCode::ServerShuttingDown,
-                    ..Default::default()
+    while let Some(command) = from_command_channel.recv().await {
+        match command {
+            Command::Control(peer, packet) => {
+                debug!("[{:?}] <<< {:#?}", client_id(&peer), packet);
+                // If the broker is stopping, let's notify here the client with a
+                // DISCONNECT and close the peer
+                if shutdown.is_fired() {
+                    peer.send_close(
+                        Disconnect {
+                            reason_code: ReasonCode::ServerShuttingDown,
+                            ..Default::default()
+                        }
+                        .into(),
+                    );
+                } else {
+                    control::run(
+                        peer.settings(),
+                        sessions.clone(),
+                        packet,
+                        peer,
+                        publisher.clone(),
+                    )
+                    .await;
+                };
+            }
+            Command::Authenticated(peer, outcome) => {
+                debug!("[{:?}] Authenticated", client_id(&peer));
+                if !shutdown.is_fired() {
+                    control::resume(
+                        peer.settings(),
+                        sessions.clone(),
+                        outcome,
+                        peer,
+                        publisher.clone(),
+                    )
+                    .await;
                 }
-                .into(),
-            );
-        } else {
-            control::run(
-                settings.clone(),
-                sessions.clone(),
-                packet,
-                peer,
-                publisher.clone(),
-            )
-            .await;
-        };
+            }
+            Command::Close(peer) => {
+                debug!("[{:?}] Connection closed", client_id(&peer));
+                if !shutdown.is_fired() {
+                    control::close(sessions.clone(), peer, publisher.clone()).await;
+                }
+            }
+        }
     }
     info!("Stop command loop");
     from_command_channel
 }
+
+fn client_id(peer: &Peer) -> String {
+    if let Some(s) = peer.session() {
+        s.client_id().into()
+    } else {
+        String::from("N/A")
+    }
+}
@@ -1,28 +1,35 @@
-use crate::{CommandSender, Peer, Trigger};
+use super::codec;
+use crate::{Command, CommandSender, Peer, Trigger};
 use log::{debug, error, info};
-use sage_mqtt::{Disconnect, Packet, ReasonCode};
+use sage_mqtt::{Disconnect, ReasonCode};
 use std::{
     sync::Arc,
     time::{Duration, Instant},
 };
-use tokio::{io::BufReader, net::tcp::OwnedReadHalf, time};
+use tokio::{
+    io::{AsyncRead, BufReader},
+    time,
+};
 
 /// The listen peer task is responsible for listening any incoming packet from a specific peer
 /// and convert it to a MQTT packet. Once converted, it sends it into the commands channel.
 /// All listen_peer tasks hold an instance of the same command_sender.
 /// This loop may end for several reasons:
-/// - Error while decoding a packet from a client
+/// - Error while decoding a packet from a client, such as a packet exceeding
+///   the maximum packet size
 /// - The server is marked as shutting down
 /// - The peer is marked as closing
-/// At that moment, it'll release its instance of CommandSender.
-pub async fn listen_peer(
-    peer: Peer,
+///
+/// At that moment, it sends a `Close` command and releases its instance of
+/// CommandSender.
+pub async fn listen_peer<R: AsyncRead + Unpin>(
+    peer: Arc<Peer>,
     to_command_channel: CommandSender,
     keep_alive: u16,
-    stream: OwnedReadHalf,
+    maximum_packet_size: Option<u32>,
+    stream: R,
     shutdown: Trigger,
 ) {
-    let peer = Arc::new(peer);
     info!("Start listening from '{}'", peer.addr(),);
     // The keep_alive value is initially given by `settings`.
     // If 0: no keep_alive (no timeout, listener waits forever)
@@ -52,7 +59,12 @@ pub async fn listen_peer(
         }
 
         // T is a Result<Packet, Error>
-        if let Ok(decoded) = time::timeout(timeout_delay, Packet::decode(&mut stream)).await {
+        if let Ok(decoded) = time::timeout(
+            timeout_delay,
+            codec::decode(&mut stream, maximum_packet_size),
+        )
+        .await
+        {
             // At this point, decoded may be an `Err(Io(Kind(UnexpectedEof)))`
             // But it's only considered an error if the peer was not is close state.
 
@@ -65,7 +77,8 @@ pub async fn listen_peer(
             match decoded {
                 // If the result is a packet, we create a packet command
                 Ok(packet) => {
-                    if let Err(e) = to_command_channel.send((peer.clone(), packet)) {
+                    if let Err(e) = to_command_channel.send(Command::Control(peer.clone(), packet))
+                    {
                         error!("Cannot send command: {:?}", e);
                     }
                 }
@@ -108,4 +121,7 @@ pub async fn listen_peer(
     }
 
     info!("Stop listening from '{}'", peer.addr(),);
+    if let Err(e) = to_command_channel.send(Command::Close(peer)) {
+        error!("Cannot send command: {:?}", e);
+    }
 }
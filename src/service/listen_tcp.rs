@@ -1,10 +1,10 @@
-use crate::{service, BrokerSettings, CommandSender, Peer, Trigger};
+use super::proxy;
+use crate::{service, BrokerSettings, CommandSender, Trigger};
 use futures::future::join_all;
-use log::{error, info};
-use std::{sync::Arc, time::Duration};
+use log::{error, info, warn};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::mpsc,
     task::{self, JoinHandle},
     time,
 };
@@ -13,6 +13,11 @@ use tokio::{
 /// listen Tcp loop.
 /// `listener` can be any instance of `async_std::net::TcpListener` but you can
 /// use `bind` to obtain one.
+/// The clients are served with the given settings. Several listeners can be
+/// given different settings, sharing the same command loop. A listener whose
+/// settings are not valid does not listen at all.
+/// If the settings enable the PROXY protocol, the header of each connection
+/// is read before its peer is spawned.
 /// Task ends when the shutdown object is triggered.
 /// It owns all peer listen/send in order to wait for them once the server is stopping.
 pub async fn listen_tcp(
@@ -21,6 +26,14 @@ pub async fn listen_tcp(
     settings: Arc<BrokerSettings>,
     shutdown: Trigger,
 ) {
+    if !settings.is_valid() {
+        error!(
+            "Cannot listen from '{:?}' due to invalid settings",
+            listener.local_addr().unwrap(),
+        );
+        return;
+    }
+
     // Listen to any connection
     info!(
         "Start listening from '{:?}'",
@@ -29,26 +42,47 @@ pub async fn listen_tcp(
 
     let listen_timeout = Duration::from_secs(1);
 
-    let mut tcp_listeners = Vec::new();
-    let mut tcp_senders = Vec::new();
+    let mut tcp_listeners: Vec<JoinHandle<()>> = Vec::new();
+    let mut tcp_senders: Vec<JoinHandle<()>> = Vec::new();
 
     while !shutdown.is_fired() {
         // Listen for 1 second for an incoming connexion
         if let Ok(result) = time::timeout(listen_timeout, listener.accept()).await {
             match result {
-                Err(e) => error!("Cannot accept Tcp stream: {}", e.to_string()),
-                Ok((stream, _)) => {
-                    if let Some((listener, sender)) = create_peer(
+                Err(e) => error!("Cannot accept Tcp stream: {}", e),
+                Ok((stream, peer_addr)) => {
+                    // Ended peers are forgotten
+                    tcp_listeners.retain(|task| !task.is_finished());
+                    tcp_senders.retain(|task| !task.is_finished());
+                    if settings
+                        .max_connections
+                        .is_some_and(|max| tcp_listeners.len() >= max)
+                    {
+                        warn!("Connection from '{}' refused: too many clients", peer_addr);
+                        continue;
+                    }
+
+                    if settings.proxy_protocol {
+                        tcp_listeners.push(task::spawn(serve_proxied(
+                            stream,
+                            peer_addr,
+                            to_command_channel.clone(),
+                            settings.clone(),
+                            shutdown.clone(),
+                        )));
+                        continue;
+                    }
+
+                    let (listener, sender) = service::spawn_peer(
                         stream,
+                        peer_addr.into(),
+                        None,
                         to_command_channel.clone(),
                         settings.clone(),
                         shutdown.clone(),
-                    )
-                    .await
-                    {
-                        tcp_listeners.push(listener);
-                        tcp_senders.push(sender);
-                    }
+                    );
+                    tcp_listeners.push(listener);
+                    tcp_senders.push(sender);
                 }
             }
         }
@@ -61,48 +95,24 @@ pub async fn listen_tcp(
     join_all(tcp_senders).await;
 }
 
-async fn create_peer(
-    stream: TcpStream,
+/// Serves the client once the PROXY header is read
+async fn serve_proxied(
+    mut stream: TcpStream,
+    peer_addr: SocketAddr,
     command_sender: CommandSender,
     settings: Arc<BrokerSettings>,
     shutdown: Trigger,
-) -> Option<(JoinHandle<()>, JoinHandle<()>)> {
-    match stream.peer_addr() {
-        Err(e) => {
-            error!("Cannot get peer addr: {:?}", e);
-            None
-        }
-        Ok(peer_addr) => {
-            info!("Incoming connection from '{}'", peer_addr);
-
-            // New peer
-            // Create the packet send/receive channel
-            // Launch the packet sender loop
-            // Create peer
-            // Launch the listen peer loop
-
-            let (packet_sender, packet_receiver) = mpsc::unbounded_channel();
-
-            // The send_peer task will end as long as no packet_sender is
-            // open anymore.
-            // The packet sender is held in the Peer instance, meaning that
-            // it is alive as long as the listen_peer is, and any pending
-            // task temporary keeping the Peer alive (Command Packets)
-            let (rd, wr) = stream.into_split();
-            let sender_task = task::spawn(service::send_peer(packet_receiver, wr));
-
-            // No need to handle this one, a safe close
-            // Will always terminate it before the command_loop
-            // See "service::run" for example
-            let listen_task = task::spawn(service::listen_peer(
-                Peer::new(peer_addr, packet_sender),
-                command_sender,
-                settings.keep_alive,
-                rd,
-                shutdown,
-            ));
-
-            Some((listen_task, sender_task))
-        }
+) {
+    if let Some((peer_addr, tls)) = proxy::accept(&mut stream, peer_addr, &settings).await {
+        let (listen_task, send_task) = service::spawn_peer(
+            stream,
+            peer_addr.into(),
+            tls,
+            command_sender,
+            settings,
+            shutdown,
+        );
+        let _ = listen_task.await;
+        let _ = send_task.await;
     }
 }
@@ -0,0 +1,133 @@
+use super::{proxy, tls};
+use crate::{service, BrokerSettings, CommandSender, Trigger};
+use futures::future::join_all;
+use log::{error, info, warn};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::{self, JoinHandle},
+    time,
+};
+use tokio_rustls::{rustls, TlsAcceptor};
+
+/// The time a client has to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listens to MQTT over TLS connections, with the certificate chain, private
+/// key and cipher suites of the given settings. A listener whose settings do
+/// not give a valid certificate and key does not listen at all.
+/// With a client CA bundle, the certificates of the clients are verified
+/// during the handshake, and clients presenting an invalid one, or none if
+/// it is required, are refused.
+/// Once the TLS handshake is complete, each client is served like the ones
+/// of `listen_tcp`, along with the TLS connection it established. Both can
+/// run alongside, sending commands to the same command loop.
+/// Task ends when the shutdown object is triggered, once all its peers ended.
+pub async fn listen_tls(
+    listener: TcpListener,
+    to_command_channel: CommandSender,
+    settings: Arc<BrokerSettings>,
+    shutdown: Trigger,
+) {
+    if !settings.is_valid() {
+        error!(
+            "Cannot listen TLS from '{:?}' due to invalid settings",
+            listener.local_addr().unwrap(),
+        );
+        return;
+    }
+    let acceptor = match tls::server_config(&settings) {
+        Ok(config) => TlsAcceptor::from(Arc::new(config)),
+        Err(e) => {
+            error!(
+                "Cannot listen TLS from '{:?}': {}",
+                listener.local_addr().unwrap(),
+                e
+            );
+            return;
+        }
+    };
+
+    info!(
+        "Start listening TLS from '{:?}'",
+        listener.local_addr().unwrap(),
+    );
+
+    let listen_timeout = Duration::from_secs(1);
+    let mut peers: Vec<JoinHandle<()>> = Vec::new();
+
+    while !shutdown.is_fired() {
+        // Listen for 1 second for an incoming connexion
+        if let Ok(result) = time::timeout(listen_timeout, listener.accept()).await {
+            match result {
+                Err(e) => error!("Cannot accept Tcp stream: {}", e),
+                Ok((stream, peer_addr)) => {
+                    // Ended peers are forgotten
+                    peers.retain(|task| !task.is_finished());
+                    if settings
+                        .max_connections
+                        .is_some_and(|max| peers.len() >= max)
+                    {
+                        warn!("Connection from '{}' refused: too many clients", peer_addr);
+                        continue;
+                    }
+
+                    peers.push(task::spawn(serve(
+                        stream,
+                        peer_addr,
+                        acceptor.clone(),
+                        to_command_channel.clone(),
+                        settings.clone(),
+                        shutdown.clone(),
+                    )));
+                }
+            }
+        }
+    }
+    info!(
+        "Stop listening TLS from '{:?}'",
+        listener.local_addr().unwrap(),
+    );
+
+    info!("Waiting for TLS peers end...");
+    join_all(peers).await;
+}
+
+/// Serves the client once the PROXY header, if expected, is read and the
+/// TLS handshake is complete
+async fn serve(
+    mut stream: TcpStream,
+    peer_addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    command_sender: CommandSender,
+    settings: Arc<BrokerSettings>,
+    shutdown: Trigger,
+) {
+    // The TLS connection is established with the broker, not the balancer
+    let Some((peer_addr, _)) = proxy::accept(&mut stream, peer_addr, &settings).await else {
+        return;
+    };
+    match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => {
+            let tls = tls::info(stream.get_ref().1);
+            let (listen_task, send_task) = service::spawn_peer(
+                stream,
+                peer_addr.into(),
+                Some(tls),
+                command_sender,
+                settings,
+                shutdown,
+            );
+            let _ = listen_task.await;
+            let _ = send_task.await;
+        }
+        Ok(Err(e)) => match e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+            // The certificate is not valid, or the client presented none
+            Some(rustls::Error::InvalidCertificate(_) | rustls::Error::NoCertificatesPresented) => {
+                warn!("Certificate of '{}' rejected: {}", peer_addr, e)
+            }
+            _ => warn!("TLS handshake with '{}' failed: {}", peer_addr, e),
+        },
+        Err(_) => warn!("TLS handshake with '{}' timed out", peer_addr),
+    }
+}
@@ -0,0 +1,100 @@
+use crate::{service, BrokerSettings, CommandSender, RemoteAddr, Trigger, UnixAddr};
+use futures::future::join_all;
+use log::{error, info, warn};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    task::JoinHandle,
+    time,
+};
+
+/// Listens to connections on a Unix domain socket, for clients running on the
+/// same host, and serves them like the ones of `listen_tcp`. Both can run
+/// alongside, sending commands to the same command loop.
+/// The credentials of the process of each client are given to the
+/// authenticator along with its address, so that it can authenticate it.
+/// Task ends when the shutdown object is triggered.
+pub async fn listen_unix(
+    listener: UnixListener,
+    to_command_channel: CommandSender,
+    settings: Arc<BrokerSettings>,
+    shutdown: Trigger,
+) {
+    let path = listener
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(Into::into));
+    if !settings.is_valid() {
+        error!("Cannot listen from '{:?}' due to invalid settings", path);
+        return;
+    }
+    info!("Start listening from '{:?}'", path);
+
+    let listen_timeout = Duration::from_secs(1);
+
+    let mut unix_listeners: Vec<JoinHandle<()>> = Vec::new();
+    let mut unix_senders: Vec<JoinHandle<()>> = Vec::new();
+
+    while !shutdown.is_fired() {
+        // Listen for 1 second for an incoming connexion
+        if let Ok(result) = time::timeout(listen_timeout, listener.accept()).await {
+            match result {
+                Err(e) => error!("Cannot accept Unix stream: {}", e),
+                Ok((stream, _)) => {
+                    let peer_addr = UnixAddr {
+                        path: path.clone(),
+                        ..credentials(&stream)
+                    };
+
+                    // Ended peers are forgotten
+                    unix_listeners.retain(|task| !task.is_finished());
+                    unix_senders.retain(|task| !task.is_finished());
+                    if settings
+                        .max_connections
+                        .is_some_and(|max| unix_listeners.len() >= max)
+                    {
+                        warn!(
+                            "Connection from '{}' refused: too many clients",
+                            RemoteAddr::from(peer_addr)
+                        );
+                        continue;
+                    }
+
+                    let (listener, sender) = service::spawn_peer(
+                        stream,
+                        peer_addr.into(),
+                        None,
+                        to_command_channel.clone(),
+                        settings.clone(),
+                        shutdown.clone(),
+                    );
+                    unix_listeners.push(listener);
+                    unix_senders.push(sender);
+                }
+            }
+        }
+    }
+    info!("Stop listening from '{:?}'", path);
+
+    info!("Waiting for listeners end...");
+    join_all(unix_listeners).await;
+    info!("Waiting for senders end...");
+    join_all(unix_senders).await;
+}
+
+/// Returns the credentials of the process of the client, when the platform
+/// provides them
+fn credentials(stream: &UnixStream) -> UnixAddr {
+    match stream.peer_cred() {
+        Ok(credentials) => UnixAddr {
+            path: None,
+            uid: Some(credentials.uid()),
+            gid: Some(credentials.gid()),
+            pid: credentials.pid(),
+        },
+        Err(e) => {
+            error!("Cannot get peer credentials: {}", e);
+            Default::default()
+        }
+    }
+}
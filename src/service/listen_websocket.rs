@@ -0,0 +1,112 @@
+use super::{proxy, websocket};
+use crate::{service, BrokerSettings, CommandSender, Trigger};
+use futures::future::join_all;
+use log::{error, info, warn};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::{self, JoinHandle},
+    time,
+};
+
+/// The time a client has to complete the opening handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listens to MQTT over WebSocket connections [MQTT 6.0], for clients such as
+/// browsers which cannot open raw TCP connections.
+/// Once the opening handshake is complete, each client is served like the
+/// ones of `listen_tcp`. Both can run alongside, sending commands to the same
+/// command loop.
+/// Task ends when the shutdown object is triggered, once all its peers ended.
+pub async fn listen_websocket(
+    listener: TcpListener,
+    to_command_channel: CommandSender,
+    settings: Arc<BrokerSettings>,
+    shutdown: Trigger,
+) {
+    if !settings.is_valid() {
+        error!(
+            "Cannot listen WebSocket from '{:?}' due to invalid settings",
+            listener.local_addr().unwrap(),
+        );
+        return;
+    }
+
+    info!(
+        "Start listening WebSocket from '{:?}'",
+        listener.local_addr().unwrap(),
+    );
+
+    let listen_timeout = Duration::from_secs(1);
+    let mut peers: Vec<JoinHandle<()>> = Vec::new();
+
+    while !shutdown.is_fired() {
+        // Listen for 1 second for an incoming connexion
+        if let Ok(result) = time::timeout(listen_timeout, listener.accept()).await {
+            match result {
+                Err(e) => error!("Cannot accept Tcp stream: {}", e),
+                Ok((stream, peer_addr)) => {
+                    // Ended peers are forgotten
+                    peers.retain(|task| !task.is_finished());
+                    if settings
+                        .max_connections
+                        .is_some_and(|max| peers.len() >= max)
+                    {
+                        warn!("Connection from '{}' refused: too many clients", peer_addr);
+                        continue;
+                    }
+
+                    peers.push(task::spawn(serve(
+                        stream,
+                        peer_addr,
+                        to_command_channel.clone(),
+                        settings.clone(),
+                        shutdown.clone(),
+                    )));
+                }
+            }
+        }
+    }
+    info!(
+        "Stop listening WebSocket from '{:?}'",
+        listener.local_addr().unwrap(),
+    );
+
+    info!("Waiting for WebSocket peers end...");
+    join_all(peers).await;
+}
+
+/// Serves the client once the PROXY header, if expected, is read and the
+/// opening handshake is complete
+async fn serve(
+    mut stream: TcpStream,
+    peer_addr: SocketAddr,
+    command_sender: CommandSender,
+    settings: Arc<BrokerSettings>,
+    shutdown: Trigger,
+) {
+    let Some((peer_addr, tls)) = proxy::accept(&mut stream, peer_addr, &settings).await else {
+        return;
+    };
+    match time::timeout(
+        HANDSHAKE_TIMEOUT,
+        websocket::accept(stream, settings.maximum_packet_size),
+    )
+    .await
+    {
+        Ok(Ok(stream)) => {
+            let (listen_task, send_task) = service::spawn_peer(
+                stream,
+                peer_addr.into(),
+                tls,
+                command_sender,
+                settings,
+                shutdown,
+            );
+            let _ = listen_task.await;
+            let _ = send_task.await;
+        }
+        Ok(Err(e)) => warn!("WebSocket handshake with '{}' failed: {}", peer_addr, e),
+        Err(_) => warn!("WebSocket handshake with '{}' timed out", peer_addr),
+    }
+}
@@ -17,6 +17,11 @@
 //!
 //! > When all CommandSender instances are closed, the command loop ends.
 //!
+//! Authenticating a client may take a while, so it runs in a task of its own
+//! which sends its outcome back to the loop with an `Authenticated` command.
+//! The task reaches the loop through the command sender held by the Peer,
+//! which is dropped along with it.
+//!
 //! ## Listen TCP
 //!
 //! The Listen TCP loop runs as long as the Broker is not set to a closing state.
@@ -28,10 +33,49 @@
 //! - A listen_peer task which receives from the stream
 //! - A Peer object which is held by the listen_peer task
 //!
+//! The peer tasks are spawned by `spawn_peer`, which serves a client over any
+//! async stream. Applications can call it with streams they accept
+//! themselves, such as TLS streams.
+//!
 //! > When the broker is marked as shut down, the listen tcp loop ends.
 //! > This operation will drop:
 //! > - A command channel sender
 //!
+//! ## Listen TLS
+//!
+//! With the `tls` feature, the Listen TLS loop accepts MQTT over TLS
+//! connections, with the certificate chain, private key and cipher suites of
+//! its settings. It works like the Listen TCP loop, each connection
+//! completing the TLS handshake before its peer is spawned along with the TLS
+//! connection it established. With a client CA bundle, the handshake also
+//! verifies the certificate of the client, the only one its identity is read
+//! from.
+//!
+//! ## Listen Unix
+//!
+//! On Unix platforms, the Listen Unix loop accepts connections on a Unix
+//! domain socket, for clients running on the same host. It works like the
+//! Listen TCP loop, the address of its peers carrying the credentials of the
+//! process of the client instead of a socket address.
+//!
+//! ## Listen WebSocket
+//!
+//! With the `websocket` feature, the Listen WebSocket loop accepts
+//! MQTT over WebSocket connections. It works like the Listen TCP loop, except
+//! that each connection completes the WebSocket opening handshake before its
+//! peer is spawned. It can run alongside the Listen TCP loop, with another
+//! instance of the command channel sender. The frames and messages of its
+//! clients cannot exceed the maximum packet size of its settings.
+//!
+//! ## PROXY protocol
+//!
+//! Listeners whose settings enable `proxy_protocol` read the PROXY protocol
+//! header a load balancer sends ahead of each connection, before spawning its
+//! peer. The peer is then given the address of the client instead of the one
+//! of the balancer, along with the TLS connection the balancer terminated.
+//! Connections which do not come from one of the trusted proxies of the
+//! settings are closed without reading any header.
+//!
 //! ## Listen peer
 //!
 //! One listen peer exist per active connexion. This loop is created by the
@@ -42,7 +86,8 @@
 //! the command channel from time to time.
 //!
 //! > When the peer is marked as closed, the listen peer loop ends.
-//! > This action will drop:
+//! > A `Close` command is sent to the command loop, then this action will
+//! > drop:
 //! > - A command channel sender
 //! > - The associated Peer
 //! The loop has a timeout that will ask for closing the peer if it does not
@@ -58,9 +103,10 @@
 //! The Send peer task is the writing half of a peer stream. It waits for any
 //!
 //! incoming packets from the packet channel and serializes it before sending it
-//! through TCP.
+//! through the stream.
 //! There is one instance of the send peer loop per active peer.
-//! > When all PacketSender instances have been closed, the sender peer loop ends.
+//! > When all PacketSender instances have been closed, the sender peer loop ends,
+//! > shutting down the stream.
 //!
 //! # Safe Close
 //!
@@ -69,11 +115,31 @@
 //! - The Listen TCP loop ends, releasing a Command Sender
 //! - The Command Loop ends.
 //!
+mod codec;
 mod command_loop;
 mod listen_peer;
 mod listen_tcp;
+#[cfg(feature = "tls")]
+mod listen_tls;
+#[cfg(unix)]
+mod listen_unix;
+#[cfg(feature = "websocket")]
+mod listen_websocket;
+pub mod proxy;
 mod send_peer;
+mod spawn_peer;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "websocket")]
+mod websocket;
 pub use command_loop::command_loop;
 use listen_peer::listen_peer;
 pub use listen_tcp::listen_tcp;
+#[cfg(feature = "tls")]
+pub use listen_tls::listen_tls;
+#[cfg(unix)]
+pub use listen_unix::listen_unix;
+#[cfg(feature = "websocket")]
+pub use listen_websocket::listen_websocket;
 use send_peer::send_peer;
+pub use spawn_peer::{spawn_peer, Stream};
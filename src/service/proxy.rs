@@ -0,0 +1,373 @@
+//! Reads the header of the HAProxy PROXY protocol, versions 1 and 2, which
+//! load balancers send ahead of the connections they relay.
+use crate::{
+    auth::{ClientCertificate, TlsInfo},
+    BrokerSettings,
+};
+use log::{info, warn};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time,
+};
+
+/// The time a proxy has to send the header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The signature starting version 2 headers
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The maximum length of version 1 headers, including the final CRLF
+const MAX_V1_LENGTH: usize = 107;
+
+// Type-Length-Values of version 2 headers
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_VERSION: u8 = 0x21;
+const PP2_SUBTYPE_SSL_CN: u8 = 0x22;
+const PP2_SUBTYPE_SSL_CIPHER: u8 = 0x23;
+const PP2_SUBTYPE_SSL_SIG_ALG: u8 = 0x24;
+const PP2_SUBTYPE_SSL_KEY_ALG: u8 = 0x25;
+
+// Flags of the PP2_TYPE_SSL value
+const PP2_CLIENT_SSL: u8 = 0x01;
+const PP2_CLIENT_CERT_CONN: u8 = 0x02;
+const PP2_CLIENT_CERT_SESS: u8 = 0x04;
+
+/// What a proxy tells of the connection it relays
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// The address of the client. It is `None` for the connections of the
+    /// proxy itself, such as health checks, and for unsupported address
+    /// families.
+    pub source: Option<SocketAddr>,
+    /// The TLS connection the proxy terminated, if any. Only version 2
+    /// headers carry it.
+    pub tls: Option<TlsInfo>,
+}
+
+/// Returns the address and the TLS connection of the client of a stream
+/// accepted from the given address. If the settings enable the PROXY
+/// protocol, they are read from its header, the address of the proxy being
+/// kept for its own connections. The certificate of the client is only kept
+/// if the settings trust the ones of proxies.
+/// None is returned if the stream does not come from a trusted proxy, or if
+/// the header is not valid or not received in time, in which case the stream
+/// should be closed.
+pub async fn accept<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    peer_addr: SocketAddr,
+    settings: &BrokerSettings,
+) -> Option<(SocketAddr, Option<TlsInfo>)> {
+    if !settings.proxy_protocol {
+        return Some((peer_addr, None));
+    }
+    if !settings
+        .trusted_proxies
+        .contains(&peer_addr.ip().to_canonical())
+    {
+        warn!(
+            "PROXY header from '{}' refused: untrusted source",
+            peer_addr
+        );
+        return None;
+    }
+    match time::timeout(HEADER_TIMEOUT, read_header(stream)).await {
+        Ok(Ok(ProxyHeader { source, mut tls })) => {
+            if let Some(source) = source {
+                info!("Connection from '{}' through '{}'", source, peer_addr);
+            }
+            if !settings.trust_proxy_certificates {
+                if let Some(tls) = &mut tls {
+                    tls.certificate = None;
+                }
+            }
+            Some((source.unwrap_or(peer_addr), tls))
+        }
+        Ok(Err(e)) => {
+            warn!("PROXY header from '{}' rejected: {}", peer_addr, e);
+            None
+        }
+        Err(_) => {
+            warn!("PROXY header from '{}' timed out", peer_addr);
+            None
+        }
+    }
+}
+
+/// Reads the PROXY protocol header, of version 1 or 2, starting the given
+/// stream. Nothing is read past the header, so that the stream can then be
+/// served as usual.
+/// A stream which does not start with a valid header is an `InvalidData`
+/// error.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<ProxyHeader> {
+    // The shortest valid header is `PROXY UNKNOWN\r\n`, so that the prefix
+    // can be read at once whatever the version
+    let mut prefix = [0u8; 12];
+    stream.read_exact(&mut prefix).await?;
+    if &prefix == SIGNATURE {
+        read_v2(stream).await
+    } else if prefix.starts_with(b"PROXY ") {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == MAX_V1_LENGTH {
+                return Err(invalid("header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        let line = std::str::from_utf8(&line[..line.len() - 2])
+            .map_err(|_| invalid("header is not ASCII"))?;
+        parse_v1(line)
+    } else {
+        Err(invalid("missing header"))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid PROXY header: {}", message),
+    )
+}
+
+/// Parses a version 1 header line, such as
+/// `PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883`
+fn parse_v1(line: &str) -> io::Result<ProxyHeader> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::default()),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("invalid address"))?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(invalid("address does not match the family"));
+            }
+            let port: u16 = port.parse().map_err(|_| invalid("invalid port"))?;
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(ip, port)),
+                tls: None,
+            })
+        }
+        _ => Err(invalid("unexpected fields")),
+    }
+}
+
+/// Reads the rest of a version 2 header, following its signature
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<ProxyHeader> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let length = stream.read_u16().await?;
+    let mut data = vec![0u8; length as usize];
+    stream.read_exact(&mut data).await?;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    match version_command & 0x0F {
+        // LOCAL: the connection is established by the proxy itself
+        0x00 => return Ok(ProxyHeader::default()),
+        0x01 => {}
+        _ => return Err(invalid("unsupported command")),
+    }
+
+    let (source, address_length) = match family >> 4 {
+        0x01 if data.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&data[0..4]).unwrap());
+            let port = u16::from_be_bytes([data[8], data[9]]);
+            (Some(SocketAddr::new(ip.into(), port)), 12)
+        }
+        0x02 if data.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&data[0..16]).unwrap());
+            let port = u16::from_be_bytes([data[32], data[33]]);
+            (Some(SocketAddr::new(ip.into(), port)), 36)
+        }
+        0x03 if data.len() >= 216 => (None, 216),
+        // Unspecified family: the addresses and TLVs are ignored
+        0x00 => return Ok(ProxyHeader::default()),
+        _ => return Err(invalid("unsupported address family")),
+    };
+
+    let mut tls = None;
+    for (kind, value) in tlvs(&data[address_length..])? {
+        if kind == PP2_TYPE_SSL {
+            tls = parse_ssl(value)?;
+        }
+    }
+    Ok(ProxyHeader { source, tls })
+}
+
+/// Splits the given data into its Type-Length-Values
+fn tlvs(mut data: &[u8]) -> io::Result<Vec<(u8, &[u8])>> {
+    let mut tlvs = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 3 {
+            return Err(invalid("truncated TLV"));
+        }
+        let length = u16::from_be_bytes([data[1], data[2]]) as usize;
+        let value = data
+            .get(3..3 + length)
+            .ok_or_else(|| invalid("truncated TLV"))?;
+        tlvs.push((data[0], value));
+        data = &data[3 + length..];
+    }
+    Ok(tlvs)
+}
+
+/// Parses the value of a PP2_TYPE_SSL TLV. The common name is only trusted
+/// if the client presented a certificate and the proxy verified it.
+fn parse_ssl(value: &[u8]) -> io::Result<Option<TlsInfo>> {
+    if value.len() < 5 {
+        return Err(invalid("truncated SSL TLV"));
+    }
+    let client = value[0];
+    let verified = value[1..5] == [0, 0, 0, 0];
+    if client & PP2_CLIENT_SSL == 0 {
+        return Ok(None);
+    }
+
+    let mut tls = TlsInfo::default();
+    let mut common_name = None;
+    for (kind, value) in tlvs(&value[5..])? {
+        let value = Some(String::from_utf8_lossy(value).into_owned());
+        match kind {
+            PP2_SUBTYPE_SSL_VERSION => tls.version = value,
+            PP2_SUBTYPE_SSL_CN => common_name = value,
+            PP2_SUBTYPE_SSL_CIPHER => tls.cipher = value,
+            PP2_SUBTYPE_SSL_SIG_ALG => tls.signature_algorithm = value,
+            PP2_SUBTYPE_SSL_KEY_ALG => tls.key_algorithm = value,
+            _ => {}
+        }
+    }
+    if client & (PP2_CLIENT_CERT_CONN | PP2_CLIENT_CERT_SESS) != 0 && verified {
+        tls.certificate = Some(ClientCertificate {
+            subject: common_name
+                .as_ref()
+                .map(|name| format!("CN={}", name))
+                .unwrap_or_default(),
+            common_name,
+            subject_alt_names: Vec::new(),
+        });
+    }
+    Ok(Some(tls))
+}
+
+#[cfg(test)]
+mod unit {
+
+    use super::*;
+
+    async fn read(header: &[u8]) -> io::Result<ProxyHeader> {
+        let mut stream = header.to_vec();
+        stream.extend_from_slice(b"payload");
+        let mut stream = &stream[..];
+        let header = read_header(&mut stream).await?;
+        // The data following the header is left untouched
+        assert_eq!(stream, b"payload");
+        Ok(header)
+    }
+
+    /// Builds a version 2 header of the given command, family and data
+    fn v2_header(command: u8, family: u8, data: &[u8]) -> Vec<u8> {
+        let mut header = SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family]);
+        header.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        header.extend_from_slice(data);
+        header
+    }
+
+    fn tlv(kind: u8, value: &[u8]) -> Vec<u8> {
+        let mut tlv = vec![kind];
+        tlv.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        tlv.extend_from_slice(value);
+        tlv
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        let header = read(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n")
+            .await
+            .unwrap();
+        assert_eq!(header.source, Some("192.168.0.1:56324".parse().unwrap()));
+
+        let header = read(b"PROXY TCP6 ::1 ::2 4000 1883\r\n").await.unwrap();
+        assert_eq!(header.source, Some("[::1]:4000".parse().unwrap()));
+
+        let header = read(b"PROXY UNKNOWN\r\n").await.unwrap();
+        assert_eq!(header, ProxyHeader::default());
+    }
+
+    #[tokio::test]
+    async fn v1_errors() {
+        for header in [
+            &b"GET / HTTP/1.1\r\n"[..],
+            b"PROXY TCP4 ::1 ::2 4000 1883\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 port 1883\r\n",
+        ] {
+            let error = read(header).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        let mut header = b"PROXY UNKNOWN ".to_vec();
+        header.resize(200, b'x');
+        let error = read_header(&mut &header[..]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v2() {
+        let addresses = [192, 168, 0, 1, 192, 168, 0, 11, 0xDC, 0x04, 0x07, 0x5B];
+        let header = read(&v2_header(0x01, 0x11, &addresses)).await.unwrap();
+        assert_eq!(header.source, Some("192.168.0.1:56324".parse().unwrap()));
+
+        let mut addresses = [0u8; 36];
+        addresses[15] = 1;
+        addresses[31] = 2;
+        addresses[32..34].copy_from_slice(&4000u16.to_be_bytes());
+        let header = read(&v2_header(0x01, 0x21, &addresses)).await.unwrap();
+        assert_eq!(header.source, Some("[::1]:4000".parse().unwrap()));
+
+        // LOCAL connections of the proxy itself carry no client address
+        let header = read(&v2_header(0x00, 0x11, &[0; 12])).await.unwrap();
+        assert_eq!(header, ProxyHeader::default());
+
+        for header in [
+            v2_header(0x01, 0x11, &[0; 4]),
+            v2_header(0x02, 0x11, &[0; 12]),
+        ] {
+            let error = read(&header).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_ssl() {
+        let mut ssl = vec![PP2_CLIENT_SSL | PP2_CLIENT_CERT_CONN, 0, 0, 0, 0];
+        ssl.extend(tlv(PP2_SUBTYPE_SSL_VERSION, b"TLSv1.3"));
+        ssl.extend(tlv(PP2_SUBTYPE_SSL_CN, b"kettle"));
+        ssl.extend(tlv(PP2_SUBTYPE_SSL_CIPHER, b"TLS_AES_256_GCM_SHA384"));
+        let mut data = vec![127, 0, 0, 1, 127, 0, 0, 1, 0x0F, 0xA0, 0x07, 0x5B];
+        data.extend(tlv(0x04, b"ignored"));
+        data.extend(tlv(PP2_TYPE_SSL, &ssl));
+
+        let header = read(&v2_header(0x01, 0x11, &data)).await.unwrap();
+        let tls = header.tls.unwrap();
+        assert_eq!(tls.version.as_deref(), Some("TLSv1.3"));
+        assert_eq!(tls.cipher.as_deref(), Some("TLS_AES_256_GCM_SHA384"));
+        assert_eq!(
+            tls.certificate,
+            Some(ClientCertificate {
+                subject: "CN=kettle".into(),
+                common_name: Some("kettle".into()),
+                subject_alt_names: Vec::new(),
+            })
+        );
+
+        // A certificate the proxy could not verify is not trusted
+        ssl[4] = 1;
+        let mut data = vec![127, 0, 0, 1, 127, 0, 0, 1, 0x0F, 0xA0, 0x07, 0x5B];
+        data.extend(tlv(PP2_TYPE_SSL, &ssl));
+        let header = read(&v2_header(0x01, 0x11, &data)).await.unwrap();
+        assert_eq!(header.tls.unwrap().certificate, None);
+    }
+}
@@ -1,22 +1,73 @@
-use crate::PacketReceiver;
-use tokio::{io::AsyncWriteExt, net::tcp::OwnedWriteHalf};
+use super::codec;
+use crate::{PacketReceiver, Peer, RemoteAddr};
+use sage_mqtt::Packet;
+use std::sync::Weak;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 /// This function loop-reads from the given `PacketReceiver` for any incoming
 /// `Packet`. Each of them is then encoded and sent to `stream`.
 /// Once all senders are dropped, the receiver is dropped as well and the loop
 /// is broken, ending the function.
 /// The sender is held in a `Peer` instance.
-pub async fn send_peer(mut from_packet_channel: PacketReceiver, mut stream: OwnedWriteHalf) {
-    log::info!("Start send loop for '{}'", stream.peer_addr().unwrap());
-    while let Some(packet) = from_packet_channel.recv().await {
+///
+/// PUBLISH packets are given a topic alias when possible, which is only
+/// recorded once the packet is sent.
+/// Packets exceeding the maximum packet size of the client are discarded.
+/// A discarded QoS 1 or QoS 2 message is considered delivered
+/// [MQTT-3.1.2-25].
+/// The stream is shut down once the loop ends.
+pub async fn send_peer<W: AsyncWrite + Unpin>(
+    mut from_packet_channel: PacketReceiver,
+    mut stream: W,
+    peer_addr: RemoteAddr,
+    peer: Weak<Peer>,
+) {
+    log::info!("Start send loop for '{}'", peer_addr);
+    let mut maximum_packet_size = None;
+    while let Some(mut packet) = from_packet_channel.recv().await {
         log::info!(">>> {:#?}", packet);
-        let mut buffer = Vec::new();
-        if let Err(e) = packet.encode(&mut buffer).await {
-            log::error!("Cannot encode packet: {:#?}", e);
+
+        // The last known value is kept once the peer is dropped
+        let peer = peer.upgrade();
+        if let Some(peer) = &peer {
+            maximum_packet_size = peer.maximum_packet_size();
         }
-        if let Err(e) = stream.write_all(&buffer).await {
-            log::error!("Cannot send packet: {:#?}", e);
+        let (packet_identifier, assignment) = match (&mut packet, &peer) {
+            (Packet::Publish(publish), Some(peer)) => {
+                (publish.packet_identifier, peer.assign_topic_alias(publish))
+            }
+            (Packet::Publish(publish), None) => (publish.packet_identifier, None),
+            _ => (None, None),
+        };
+
+        match codec::encode_within(packet, maximum_packet_size).await {
+            Err(e) => log::error!("Cannot encode packet: {:#?}", e),
+            Ok(None) => {
+                log::warn!("Packet exceeds the client maximum packet size, discarded");
+                if let (Some(session), Some(packet_identifier)) =
+                    (peer.and_then(|peer| peer.session()), packet_identifier)
+                {
+                    session.discard(packet_identifier);
+                }
+            }
+            Ok(Some(buffer)) => {
+                if let (Some(peer), Some(assignment)) = (&peer, assignment) {
+                    peer.record_topic_alias(assignment);
+                }
+                // Streams such as TLS ones buffer what is written
+                let sent = async {
+                    stream.write_all(&buffer).await?;
+                    stream.flush().await
+                };
+                if let Err(e) = sent.await {
+                    log::error!("Cannot send packet: {:#?}", e);
+                }
+            }
         }
     }
-    log::info!("Stop send loop for '{}'", stream.peer_addr().unwrap());
+    // Streams such as WebSocket ones send their closing frame
+    if let Err(e) = stream.shutdown().await {
+        log::debug!("Cannot shut down stream: {:#?}", e);
+    }
+    log::info!("Stop send loop for '{}'", peer_addr);
 }
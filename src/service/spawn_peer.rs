@@ -0,0 +1,77 @@
+use crate::{auth::TlsInfo, service, BrokerSettings, CommandSender, Peer, RemoteAddr, Trigger};
+use log::info;
+use std::sync::Arc;
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
+
+/// A stream a client is served over, such as a TCP stream, or a TLS stream
+/// established by the application.
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Stream for T {}
+
+/// Serves a client over the given stream with the given settings, spawning its
+/// listen peer and send peer tasks, whose handles are returned so that they
+/// can be waited for once the server is stopping.
+/// `listen_tcp` calls it for each accepted TCP connection. Applications can
+/// call it with their own streams, for instance to serve clients over TLS,
+/// along with the TLS connection and the verified client certificate if any.
+pub fn spawn_peer<S: Stream>(
+    stream: S,
+    peer_addr: RemoteAddr,
+    tls: Option<TlsInfo>,
+    command_sender: CommandSender,
+    settings: Arc<BrokerSettings>,
+    shutdown: Trigger,
+) -> (JoinHandle<()>, JoinHandle<()>) {
+    info!("Incoming connection from '{}'", peer_addr);
+
+    // New peer
+    // Create the packet send/receive channel
+    // Launch the packet sender loop
+    // Create peer
+    // Launch the listen peer loop
+
+    let (packet_sender, packet_receiver) = mpsc::unbounded_channel();
+
+    // The send_peer task will end as long as no packet_sender is
+    // open anymore.
+    // The packet sender is held in the Peer instance, meaning that
+    // it is alive as long as the listen_peer is, and any pending
+    // task temporary keeping the Peer alive (Command Packets)
+    // The send_peer task only holds a weak reference to the peer, to
+    // read the maximum packet size of the client.
+    let keep_alive = settings.keep_alive;
+    let maximum_packet_size = settings.maximum_packet_size;
+    let peer = Arc::new(Peer::new(
+        peer_addr.clone(),
+        settings,
+        packet_sender,
+        command_sender.clone(),
+    ));
+    peer.set_tls(tls);
+    let (rd, wr) = io::split(stream);
+    let sender_task = task::spawn(service::send_peer(
+        packet_receiver,
+        wr,
+        peer_addr,
+        Arc::downgrade(&peer),
+    ));
+
+    // No need to handle this one, a safe close
+    // Will always terminate it before the command_loop
+    // See "service::run" for example
+    let listen_task = task::spawn(service::listen_peer(
+        peer,
+        command_sender,
+        keep_alive,
+        maximum_packet_size,
+        rd,
+        shutdown,
+    ));
+
+    (listen_task, sender_task)
+}
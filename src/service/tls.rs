@@ -0,0 +1,150 @@
+use crate::{
+    auth::{ClientCertificate, TlsInfo},
+    BrokerSettings,
+};
+use std::{path::Path, sync::Arc};
+use tokio_rustls::rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    ProtocolVersion, RootCertStore, ServerConfig, ServerConnection, ALL_VERSIONS,
+};
+use x509_parser::{
+    certificate::X509Certificate, extensions::GeneralName, objects, prelude::FromDer,
+};
+
+/// Builds the TLS configuration of a listener from the certificate chain,
+/// private key, cipher suites and client CA bundle of its settings
+pub fn server_config(settings: &BrokerSettings) -> Result<ServerConfig, String> {
+    let chain_path = settings
+        .tls_certificate_chain
+        .as_ref()
+        .ok_or("No certificate chain")?;
+    let key_path = settings.tls_private_key.as_ref().ok_or("No private key")?;
+
+    let chain = load_certificates(chain_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("{}: {}", key_path.display(), e))?;
+
+    let mut provider = ring::default_provider();
+    if !settings.tls_cipher_suites.is_empty() {
+        let mut suites = Vec::new();
+        for name in &settings.tls_cipher_suites {
+            let suite = provider
+                .cipher_suites
+                .iter()
+                .find(|suite| suite.suite().as_str() == Some(name.as_str()))
+                .ok_or_else(|| format!("Unsupported cipher suite '{}'", name))?;
+            suites.push(*suite);
+        }
+        provider.cipher_suites = suites;
+    }
+    let provider = Arc::new(provider);
+    let verifier = client_verifier(settings, provider.clone())?;
+
+    ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(ALL_VERSIONS)
+        .and_then(|builder| {
+            builder
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(chain, key)
+        })
+        .map_err(|e| e.to_string())
+}
+
+/// Builds the verifier of the certificates of the clients, which must be
+/// issued by one of the authorities of the client CA bundle, if any
+fn client_verifier(
+    settings: &BrokerSettings,
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn ClientCertVerifier>, String> {
+    let Some(ca_path) = &settings.tls_client_ca else {
+        if settings.tls_require_client_certificate {
+            return Err("No client CA bundle to verify the required certificates".into());
+        }
+        return Ok(WebPkiClientVerifier::no_client_auth());
+    };
+
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(ca_path)? {
+        roots
+            .add(certificate)
+            .map_err(|e| format!("{}: {}", ca_path.display(), e))?;
+    }
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+    if settings.tls_require_client_certificate {
+        builder.build()
+    } else {
+        builder.allow_unauthenticated().build()
+    }
+    .map_err(|e| format!("{}: {}", ca_path.display(), e))
+}
+
+/// Loads the certificates of a PEM file, which holds at least one
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if certificates.is_empty() {
+        return Err(format!("{}: No certificate", path.display()));
+    }
+    Ok(certificates)
+}
+
+/// Describes an established TLS connection, along with the certificate of
+/// the client. Rustls only gives the certificates it verified.
+pub fn info(connection: &ServerConnection) -> TlsInfo {
+    let mut info = TlsInfo {
+        version: connection.protocol_version().map(|version| match version {
+            ProtocolVersion::TLSv1_2 => "TLSv1.2".into(),
+            ProtocolVersion::TLSv1_3 => "TLSv1.3".into(),
+            version => format!("{:?}", version),
+        }),
+        cipher: connection
+            .negotiated_cipher_suite()
+            .and_then(|suite| suite.suite().as_str())
+            .map(String::from),
+        ..Default::default()
+    };
+
+    let certificate = connection
+        .peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .and_then(|certificate| X509Certificate::from_der(certificate).ok());
+    if let Some((_, certificate)) = certificate {
+        let registry = objects::oid_registry();
+        let name = |oid| objects::oid2sn(oid, registry).ok().map(String::from);
+        info.signature_algorithm = name(&certificate.signature_algorithm.algorithm);
+        info.key_algorithm = name(&certificate.public_key().algorithm.algorithm);
+        info.certificate = Some(client_certificate(&certificate));
+    }
+    info
+}
+
+/// Reads the subject of a certificate
+fn client_certificate(certificate: &X509Certificate) -> ClientCertificate {
+    let subject = certificate.subject();
+    let subject_alt_names = match certificate.subject_alternative_name() {
+        Ok(Some(extension)) => extension
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name)
+                | GeneralName::RFC822Name(name)
+                | GeneralName::URI(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    ClientCertificate {
+        subject: subject.to_string(),
+        common_name: subject
+            .iter_common_name()
+            .next()
+            .and_then(|common_name| common_name.as_str().ok())
+            .map(String::from),
+        subject_alt_names,
+    }
+}
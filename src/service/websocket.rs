@@ -0,0 +1,357 @@
+//! MQTT over WebSocket [MQTT 6.0]: the opening handshake, selecting the
+//! `mqtt` subprotocol, and the byte stream carried by WebSocket binary
+//! messages [RFC 6455].
+use futures::{Sink, Stream};
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::{
+        header::{CONNECTION, SEC_WEBSOCKET_PROTOCOL},
+        HeaderValue, StatusCode,
+    },
+    protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
+    Bytes, Error, Message,
+};
+
+const SUBPROTOCOL: &str = "mqtt";
+/// The largest MQTT packet along with its fixed header
+const MAX_PACKET_SIZE: usize = 268_435_460;
+/// The room left for the fixed header in addition to the maximum packet
+/// size, for frames and messages carrying a whole packet
+const HEADER_OVERHEAD: usize = 5;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn io_error(error: Error) -> io::Error {
+    match error {
+        Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+/// Selects the `mqtt` subprotocol, which the client must offer
+/// [MQTT-6.0.0-3] [MQTT-6.0.0-4].
+/// The error response is returned as is, as tungstenite expects.
+#[allow(clippy::result_large_err)]
+fn select_subprotocol(
+    request: &Request,
+    mut response: Response,
+) -> Result<Response, ErrorResponse> {
+    let offered = request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == SUBPROTOCOL);
+    if offered {
+        response.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(SUBPROTOCOL),
+        );
+        Ok(response)
+    } else {
+        let mut response = ErrorResponse::new(None);
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+        response
+            .headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+        Err(response)
+    }
+}
+
+/// The size of the largest frames and messages accepted from clients, which
+/// is the size of the largest packet along with its fixed header
+fn max_message_size(maximum_packet_size: Option<u32>) -> usize {
+    maximum_packet_size
+        .map(|size| size as usize + HEADER_OVERHEAD)
+        .unwrap_or(MAX_PACKET_SIZE)
+}
+
+/// Performs the opening handshake of a client connecting over the given
+/// stream. A request which is not a valid WebSocket upgrade offering the
+/// `mqtt` subprotocol is refused and returned as an error.
+/// Frames and messages cannot exceed the maximum packet size of the
+/// listener, so that their payload is never buffered beyond it.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    maximum_packet_size: Option<u32>,
+) -> io::Result<WebSocketStream<S>> {
+    let max_size = max_message_size(maximum_packet_size);
+    let config = WebSocketConfig::default()
+        .max_frame_size(Some(max_size))
+        .max_message_size(Some(max_size));
+    tokio_tungstenite::accept_hdr_async_with_config(stream, select_subprotocol, Some(config))
+        .await
+        .map(WebSocketStream::new)
+        .map_err(io_error)
+}
+
+/// A stream whose data is carried by WebSocket binary messages once the
+/// opening handshake is complete.
+/// MQTT packets are read regardless of the message boundaries [MQTT-6.0.0-2]
+/// and each write is sent in a binary message [MQTT-6.0.0-1].
+/// Receiving a text message, or a frame or message exceeding the maximum
+/// size, is an error. The connection is then closed with the status code
+/// `1003` or `1009` respectively once the stream is shut down.
+/// Pings are answered, and so is the closing handshake which ends the
+/// stream.
+#[derive(Debug)]
+pub struct WebSocketStream<S> {
+    inner: tokio_tungstenite::WebSocketStream<S>,
+    /// The payload of the binary messages, which has not been read yet
+    payload: Bytes,
+    /// Whether the client started the closing handshake
+    closed: bool,
+    /// The frame which closes the connection after an error, once the stream
+    /// is shut down
+    close_frame: Option<CloseFrame>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketStream<S> {
+    fn new(inner: tokio_tungstenite::WebSocketStream<S>) -> Self {
+        WebSocketStream {
+            inner,
+            payload: Default::default(),
+            closed: false,
+            close_frame: None,
+        }
+    }
+
+    /// Fails the connection with the given status code
+    fn fail(&mut self, code: CloseCode, reason: &'static str) -> io::Error {
+        self.close_frame = Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        });
+        invalid(reason)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if !this.payload.is_empty() {
+                let size = this.payload.len().min(buf.remaining());
+                buf.put_slice(&this.payload.split_to(size));
+                return Poll::Ready(Ok(()));
+            }
+            if this.closed || this.close_frame.is_some() {
+                return Poll::Ready(Ok(()));
+            }
+
+            // Pings and the closing handshake are answered by the inner stream
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(payload))) => this.payload = payload,
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Close(_))) | None => this.closed = true,
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(this.fail(
+                        CloseCode::Unsupported,
+                        "MQTT packets must be sent in binary frames",
+                    )))
+                }
+                Some(Err(Error::Capacity(_))) => {
+                    return Poll::Ready(Err(
+                        this.fail(CloseCode::Size, "WebSocket message too large")
+                    ))
+                }
+                Some(Err(Error::ConnectionClosed | Error::AlreadyClosed)) => this.closed = true,
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut inner = Pin::new(&mut self.inner);
+        ready!(inner.as_mut().poll_ready(cx)).map_err(io_error)?;
+        inner
+            .start_send(Message::Binary(Bytes::copy_from_slice(buf)))
+            .map_err(io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.close_frame.is_some() {
+            let mut inner = Pin::new(&mut this.inner);
+            ready!(inner.as_mut().poll_ready(cx)).map_err(io_error)?;
+            inner
+                .start_send(Message::Close(this.close_frame.take()))
+                .map_err(io_error)?;
+        }
+        match ready!(Pin::new(&mut this.inner).poll_close(cx)) {
+            Ok(()) | Err(Error::ConnectionClosed | Error::AlreadyClosed) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(io_error(e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit {
+
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    const CONTINUATION: u8 = 0x0;
+    const TEXT: u8 = 0x1;
+    const BINARY: u8 = 0x2;
+    const CLOSE: u8 = 0x8;
+    const PING: u8 = 0x9;
+    const PONG: u8 = 0xA;
+
+    /// Encodes a frame masked as a client does
+    fn masked(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        assert!(payload.len() < 126);
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![(fin as u8) << 7 | opcode, 0x80 | payload.len() as u8];
+        frame.extend(mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// Encodes an unmasked frame, as sent by the server
+    fn unmasked(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x80 | opcode, payload.len() as u8];
+        frame.extend(payload);
+        frame
+    }
+
+    /// Returns a client and the stream serving it once the opening handshake
+    /// is complete
+    async fn stream(
+        maximum_packet_size: Option<u32>,
+    ) -> (DuplexStream, WebSocketStream<DuplexStream>) {
+        let (client, server) = tokio::io::duplex(1024);
+        let max_size = max_message_size(maximum_packet_size);
+        let config = WebSocketConfig::default()
+            .max_frame_size(Some(max_size))
+            .max_message_size(Some(max_size));
+        let inner =
+            tokio_tungstenite::WebSocketStream::from_raw_socket(server, Role::Server, Some(config))
+                .await;
+        (client, WebSocketStream::new(inner))
+    }
+
+    /// Expects the given frames to be sent to the client
+    async fn expect(client: &mut DuplexStream, expected: Vec<u8>) {
+        let mut received = vec![0; expected.len()];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn subprotocols() {
+        let request = |protocols: &str| {
+            Request::builder()
+                .header(SEC_WEBSOCKET_PROTOCOL, protocols)
+                .body(())
+                .unwrap()
+        };
+
+        let response = select_subprotocol(&request("mqttv3.1, mqtt"), Response::default());
+        assert_eq!(
+            response.unwrap().headers()[SEC_WEBSOCKET_PROTOCOL],
+            SUBPROTOCOL
+        );
+        let response = select_subprotocol(&request("mqttv3.1"), Response::default());
+        assert_eq!(response.unwrap_err().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn stream_data() {
+        let (mut client, mut stream) = stream(None).await;
+
+        // A packet can be split over several frames and a frame can hold
+        // several packets
+        let mut frames = masked(false, BINARY, &[1, 2]);
+        frames.extend(masked(true, PING, b"ping"));
+        frames.extend(masked(true, CONTINUATION, &[3]));
+        frames.extend(masked(true, BINARY, &[4, 5]));
+        client.write_all(&frames).await.unwrap();
+
+        let mut data = [0; 5];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(data, [1, 2, 3, 4, 5]);
+
+        stream.write_all(&[6, 7]).await.unwrap();
+        stream.flush().await.unwrap();
+        let mut expected = unmasked(PONG, b"ping");
+        expected.extend(unmasked(BINARY, &[6, 7]));
+        expect(&mut client, expected).await;
+
+        // The closing handshake ends the stream
+        client
+            .write_all(&masked(true, CLOSE, &[0x03, 0xE8]))
+            .await
+            .unwrap();
+        assert_eq!(stream.read(&mut data).await.unwrap(), 0);
+        stream.shutdown().await.unwrap();
+        expect(&mut client, unmasked(CLOSE, &[0x03, 0xE8])).await;
+    }
+
+    #[tokio::test]
+    async fn text_frames_are_refused() {
+        let (mut client, mut stream) = stream(None).await;
+        client.write_all(&masked(true, TEXT, b"{}")).await.unwrap();
+        let mut data = [0; 16];
+        let error = stream.read(&mut data).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Unsupported data
+        stream.shutdown().await.unwrap();
+        let mut expected = unmasked(CLOSE, &[0x03, 0xEB]);
+        expected.extend(b"MQTT packets must be sent in binary frames");
+        expected[1] = expected.len() as u8 - 2;
+        expect(&mut client, expected).await;
+    }
+
+    #[tokio::test]
+    async fn large_messages_are_refused() {
+        // A frame larger than a packet
+        let mut frame = vec![0x80 | BINARY, 0x80 | 126];
+        frame.extend(1000u16.to_be_bytes());
+        frame.extend([0x37, 0xfa, 0x21, 0x3d]);
+        // A message whose frames are larger than a packet together
+        let mut fragmented = masked(false, BINARY, &[0; 10]);
+        fragmented.extend(masked(true, CONTINUATION, &[0; 10]));
+
+        for frames in [frame, fragmented] {
+            let (mut client, mut stream) = stream(Some(8)).await;
+            client.write_all(&frames).await.unwrap();
+            let mut data = [0; 16];
+            let error = stream.read(&mut data).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+            // Message too big
+            stream.shutdown().await.unwrap();
+            let mut header = [0; 4];
+            client.read_exact(&mut header).await.unwrap();
+            assert_eq!(header[0], 0x80 | CLOSE);
+            assert_eq!(header[2..], [0x03, 0xF1]);
+        }
+    }
+}
@@ -1,6 +1,7 @@
-use crate::{Cache, Peer, Subs};
-use log::info;
+use crate::{auth::Principal, Ack, InFlight, Peer, Subs};
+use log::{info, warn};
 use nanoid::nanoid;
+use sage_mqtt::{Publish, QoS, Topic, Will};
 use std::sync::{Arc, RwLock, Weak};
 
 /// Represents a client and holds all of its data, may it be active or not.
@@ -12,11 +13,15 @@ pub struct Session {
     client_id: String,
     peer: RwLock<Weak<Peer>>,
     subs: RwLock<Subs>,
+    inflight: RwLock<InFlight>,
+    will: RwLock<Option<Will>>,
+    expiry_interval: RwLock<u32>,
+    principal: RwLock<Option<Principal>>,
 }
 
 impl Session {
     /// Creates a new session, giving a peer and an id
-    pub fn new(client_id: &str, peer: Arc<Peer>, cache: Arc<Cache>) -> Self {
+    pub fn new(client_id: &str, peer: Arc<Peer>) -> Self {
         let id = format!("session_{}", nanoid!(10));
         info!("New session: Unique ID:{:?}, Client ID:{:?}", id, client_id);
 
@@ -24,7 +29,11 @@ impl Session {
             id,
             client_id: client_id.into(),
             peer: RwLock::new(Arc::downgrade(&peer)),
-            subs: RwLock::new(Subs::new(cache)),
+            subs: Default::default(),
+            inflight: Default::default(),
+            will: Default::default(),
+            expiry_interval: Default::default(),
+            principal: Default::default(),
         }
     }
 
@@ -51,8 +60,185 @@ impl Session {
         self.peer.read().unwrap().upgrade()
     }
 
+    /// Returns true if the session is bound to the given peer, even if that
+    /// peer is not alive anymore
+    pub fn is_bound(&self, peer: &Weak<Peer>) -> bool {
+        Weak::ptr_eq(&self.peer.read().unwrap(), peer)
+    }
+
+    /// Returns the time in seconds the session is kept once the connection
+    /// is closed. `0` means the session ends with the connection and
+    /// `0xFFFFFFFF` that it never expires.
+    pub fn expiry_interval(&self) -> u32 {
+        *self.expiry_interval.read().unwrap()
+    }
+
+    /// Sets the session expiry interval
+    pub fn set_expiry_interval(&self, expiry_interval: u32) {
+        *(self.expiry_interval.write().unwrap()) = expiry_interval;
+    }
+
+    /// Returns the identity the client was authenticated as when it last
+    /// connected, if the broker has an authenticator
+    pub fn principal(&self) -> Option<Principal> {
+        self.principal.read().unwrap().clone()
+    }
+
+    /// Sets the identity the client is authenticated as
+    pub fn set_principal(&self, principal: Option<Principal>) {
+        *(self.principal.write().unwrap()) = principal;
+    }
+
+    /// Sets the will message of the session, replacing any existing one.
+    pub fn set_will(&self, will: Option<Will>) {
+        *(self.will.write().unwrap()) = will;
+    }
+
+    /// Removes the will message from the session and returns it
+    pub fn take_will(&self) -> Option<Will> {
+        self.will.write().unwrap().take()
+    }
+
+    /// Returns the delay in seconds before the will message is published
+    /// once the connection is closed, or None if the session has no will
+    pub fn will_delay(&self) -> Option<u32> {
+        self.will.read().unwrap().as_ref().map(|w| w.delay_interval)
+    }
+
     /// Gets the subscriptions this session has
     pub fn subs(&self) -> &RwLock<Subs> {
         &self.subs
     }
+
+    /// Sends an application message to the client.
+    /// QoS 0 messages are only sent if the session has a peer.
+    /// Messages of higher QoS are kept until acknowledged, even if the session
+    /// has no peer at the moment. They are queued while the client has
+    /// Receive Maximum messages to acknowledge, and given a packet identifier
+    /// once sent.
+    pub fn publish(&self, publish: Publish) {
+        self.publish_shared(publish, None)
+    }
+
+    /// Same as `publish` for a message sent on behalf of the given shared
+    /// subscription, if any. Until they are acknowledged, QoS 1 and QoS 2
+    /// messages can be handed over to another session of the group with
+    /// `take_shared`.
+    pub fn publish_shared(&self, publish: Publish, share: Option<Topic>) {
+        let peer = self.peer();
+        if publish.qos == QoS::AtMostOnce {
+            if let Some(peer) = peer {
+                peer.send(
+                    Publish {
+                        duplicate: false,
+                        packet_identifier: None,
+                        ..publish
+                    }
+                    .into(),
+                );
+            }
+        } else {
+            let mut inflight = self.inflight.write().unwrap();
+            if let (Some(publish), Some(peer)) =
+                (inflight.push(publish, share, peer.is_some()), peer)
+            {
+                peer.send(publish.into());
+            }
+        }
+    }
+
+    /// Removes the messages of the given shared subscription which can be sent
+    /// to another session of the group instead.
+    pub fn take_shared(&self, share: &Topic) -> Vec<Publish> {
+        self.inflight.write().unwrap().take_shared(share)
+    }
+
+    /// Returns the number of QoS 1 and QoS 2 messages sent or queued and not
+    /// acknowledged yet.
+    pub fn outgoing(&self) -> usize {
+        self.inflight.read().unwrap().outgoing()
+    }
+
+    /// Sets the number of QoS 1 and QoS 2 messages the client is willing to
+    /// process concurrently.
+    pub fn set_receive_maximum(&self, receive_maximum: u16) {
+        self.inflight
+            .write()
+            .unwrap()
+            .set_receive_maximum(receive_maximum);
+    }
+
+    /// Sets the number of QoS 1 and QoS 2 messages which can be queued while
+    /// the client is offline or has Receive Maximum messages to acknowledge.
+    /// The oldest ones are dropped beyond it.
+    pub fn set_max_queued(&self, max_queued: Option<usize>) {
+        self.inflight.write().unwrap().set_max_queued(max_queued);
+    }
+
+    /// Acknowledges the outbound message with the given packet identifier
+    /// with the given packet. An acknowledgement which does not match the
+    /// delivery of the message is ignored.
+    /// Queued messages are sent as long as the Receive Maximum of the client
+    /// allows it.
+    pub(crate) fn acknowledge(&self, packet_identifier: u16, ack: Ack) {
+        let mut inflight = self.inflight.write().unwrap();
+        if !inflight.acknowledge(packet_identifier, ack) {
+            warn!(
+                "[{}] Unexpected {:?} for packet identifier {}, ignored",
+                self.client_id, ack, packet_identifier
+            );
+        }
+        self.send_pending(&mut inflight);
+    }
+
+    /// Discards the outbound message with the given packet identifier, which
+    /// cannot be delivered.
+    pub fn discard(&self, packet_identifier: u16) {
+        let mut inflight = self.inflight.write().unwrap();
+        inflight.discard(packet_identifier);
+        self.send_pending(&mut inflight);
+    }
+
+    /// Sends the queued messages the Receive Maximum of the client allows
+    fn send_pending(&self, inflight: &mut InFlight) {
+        if let Some(peer) = self.peer() {
+            for publish in inflight.send_pending() {
+                peer.send(publish.into());
+            }
+        }
+    }
+
+    /// Marks the outbound QoS 2 message with the given packet identifier as
+    /// released. Returns false if the packet identifier is unknown.
+    pub fn release(&self, packet_identifier: u16) -> bool {
+        self.inflight.write().unwrap().release(packet_identifier)
+    }
+
+    /// Records the packet identifier of an inbound QoS 2 message.
+    /// Returns false if the message was already received and not released
+    /// yet, in which case it must not be delivered again.
+    pub fn receive(&self, packet_identifier: u16) -> bool {
+        self.inflight.write().unwrap().receive(packet_identifier)
+    }
+
+    /// Returns the number of inbound QoS 2 messages not released yet
+    pub fn receiving(&self) -> usize {
+        self.inflight.read().unwrap().receiving()
+    }
+
+    /// Ends the reception of the inbound QoS 2 message with the given packet
+    /// identifier. Returns false if the packet identifier is unknown.
+    pub fn complete(&self, packet_identifier: u16) -> bool {
+        self.inflight.write().unwrap().complete(packet_identifier)
+    }
+
+    /// Sends again all unacknowledged messages and pending PUBREL packets to
+    /// the peer. This is used when a session is resumed [MQTT-4.4.0-1].
+    pub fn resume(&self) {
+        if let Some(peer) = self.peer() {
+            for packet in self.inflight.write().unwrap().resend() {
+                peer.send(packet);
+            }
+        }
+    }
 }
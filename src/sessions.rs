@@ -1,10 +1,16 @@
-use crate::Session;
-use std::sync::Arc;
+use crate::{subs::Subscription, topic, Session, SharingStrategy, SubsTree};
+use sage_mqtt::{SubscriptionOptions, Topic};
+use std::{collections::HashMap, sync::Arc};
 
 /// Holds sessions manipulated from the Command Loop
+/// Along with the sessions themselves, the collection maintains a broker-wide
+/// subscription tree, which also holds the groups of shared subscriptions,
+/// used to resolve the recipients of a publish message.
 #[derive(Default, Debug)]
 pub struct Sessions {
-    db: Vec<Arc<Session>>,
+    db: HashMap<String, Arc<Session>>,
+    tree: SubsTree,
+    sharing_strategy: SharingStrategy,
 }
 
 impl Sessions {
@@ -20,36 +26,153 @@ impl Sessions {
 
     /// Searches for the Session at given index and returns it.
     /// If `take`  is set, the session will be extracted from the database
+    /// Its subscriptions are removed from the subscription tree but kept
+    /// in the session itself.
     pub fn take(&mut self, client_id: &str) -> Option<Arc<Session>> {
-        self.db
-            .iter()
-            .position(|c| c.client_id() == client_id)
-            .map(|index| self.db.swap_remove(index))
+        let session = self.db.remove(client_id)?;
+        for filter in session.subs().read().unwrap().filters() {
+            self.unlink(filter, session.id());
+        }
+        Some(session)
+    }
+
+    /// Registers the session as a subscriber of the given filter, in the
+    /// subscription tree along with the options and identifier of the
+    /// subscription, or in the group of a shared subscription.
+    fn link(&mut self, filter: &Topic, session: Arc<Session>, subscription: Subscription) {
+        if topic::share(filter).is_some() {
+            self.tree.insert_shared(filter, session);
+        } else {
+            self.tree.insert(filter, session, subscription);
+        }
+    }
+
+    /// Unregisters the session from the given filter
+    fn unlink(&mut self, filter: &Topic, session_id: &str) {
+        if topic::share(filter).is_some() {
+            self.tree.remove_shared(filter, session_id);
+        } else {
+            self.tree.remove(filter, session_id);
+        }
+    }
+
+    /// Removes the given session from the database, along with its
+    /// subscriptions. Another session with the same client id is left
+    /// untouched.
+    /// Returns true if the session was in the database.
+    pub fn remove(&mut self, session: &Session) -> bool {
+        if self
+            .db
+            .get(session.client_id())
+            .is_some_and(|s| s.id() == session.id())
+        {
+            self.unstick(session.client_id());
+            self.take(session.client_id()).is_some()
+        } else {
+            false
+        }
+    }
+
+    /// Forgets the sessions of shared subscriptions the messages of the given
+    /// client stick to. This is used once the session of the client ends.
+    pub fn unstick(&self, client_id: &str) {
+        self.tree.unstick(client_id);
     }
 
     /// Returns the client given its id. If not client exist, returns None
     pub fn get(&self, client_id: &str) -> Option<Arc<Session>> {
-        self.db
-            .iter()
-            .position(|c| c.client_id() == client_id)
-            .map(|index| self.db[index].clone())
+        self.db.get(client_id).cloned()
     }
 
     /// Add the given session into the database
+    /// Any subscription already held by the session is added to the
+    /// subscription tree.
     pub fn add(&mut self, session: Arc<Session>) {
-        self.db.push(session);
+        for (filter, subscription) in session.subs().read().unwrap().iter() {
+            self.link(filter, session.clone(), *subscription);
+        }
+        self.db.insert(session.client_id().into(), session);
+    }
+
+    /// Adds a subscription to the given session.
+    /// Returns true if it replaces an existing one
+    pub fn subscribe(
+        &mut self,
+        session: &Arc<Session>,
+        filter: Topic,
+        options: SubscriptionOptions,
+        identifier: Option<u32>,
+    ) -> bool {
+        self.link(&filter, session.clone(), (options, identifier));
+        session
+            .subs()
+            .write()
+            .unwrap()
+            .add(filter, options, identifier)
+    }
+
+    /// Removes the subscription with the given filter from the given session.
+    /// Returns false if the session had no such subscription.
+    pub fn unsubscribe(&mut self, session: &Session, filter: &Topic) -> bool {
+        self.unlink(filter, session.id());
+        session.subs().write().unwrap().remove(filter)
+    }
+
+    /// Returns all the sessions having at least one subscription matching the
+    /// given topic name, along with the options and identifiers of their
+    /// matching subscriptions.
+    pub fn subscribers(&self, name: &Topic) -> Vec<(Arc<Session>, Vec<Subscription>)> {
+        self.tree.matches(name)
+    }
+
+    /// Sets the way messages of shared subscriptions are distributed among
+    /// the sessions of their group
+    pub fn set_sharing_strategy(&mut self, strategy: SharingStrategy) {
+        self.sharing_strategy = strategy;
+    }
+
+    /// Returns one session of each shared subscription matching the given
+    /// topic name, along with the shared subscription it is chosen for.
+    /// `publisher` is the client id of the session publishing the message, if
+    /// known.
+    pub fn shared_subscribers(
+        &self,
+        name: &Topic,
+        publisher: Option<&str>,
+    ) -> Vec<(Topic, Arc<Session>)> {
+        self.tree
+            .matching_groups(name)
+            .into_iter()
+            .filter_map(|(share, group)| {
+                group
+                    .pick(self.sharing_strategy, publisher)
+                    .map(|session| (share.clone(), session))
+            })
+            .collect()
+    }
+
+    /// Returns a connected session of the given shared subscription, other
+    /// than the given one.
+    pub fn other_shared_subscriber(
+        &self,
+        share: &Topic,
+        session: &Session,
+    ) -> Option<Arc<Session>> {
+        self.tree
+            .group(share)?
+            .pick_other(self.sharing_strategy, session.id())
     }
 
     /// Returns an iterator over sessions
-    pub fn iter(&self) -> SessionsIterator {
+    pub fn iter(&self) -> SessionsIterator<'_> {
         SessionsIterator {
-            inner_it: self.db.iter(),
+            inner_it: self.db.values(),
         }
     }
 }
 
 pub struct SessionsIterator<'a> {
-    inner_it: std::slice::Iter<'a, Arc<Session>>,
+    inner_it: std::collections::hash_map::Values<'a, String, Arc<Session>>,
 }
 
 impl<'a> Iterator for SessionsIterator<'a> {
@@ -0,0 +1,267 @@
+use crate::Session;
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// The way messages matching a shared subscription are distributed among the
+/// sessions of its group. Each message is sent to one session only.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SharingStrategy {
+    /// Sessions take turns (default)
+    #[default]
+    RoundRobin,
+    /// A session is chosen at random
+    Random,
+    /// All messages of a same publisher are sent to the same session for as
+    /// long as it stays connected
+    Sticky,
+    /// The session with the fewest QoS 1 and QoS 2 messages left to
+    /// acknowledge is chosen
+    LeastInFlight,
+}
+
+/// The sessions subscribed to a shared subscription, in subscription order.
+/// The group is held by the subscription tree while its turns are kept
+/// behind a lock of its own, so that messages are distributed without
+/// locking the whole tree [MQTT 4.8.2].
+#[derive(Default, Debug)]
+pub struct Group {
+    state: Mutex<State>,
+}
+
+#[derive(Default, Debug)]
+struct State {
+    members: Vec<Arc<Session>>,
+    next: usize,
+    sticky: HashMap<String, String>,
+}
+
+impl State {
+    /// Returns the index of the next turn
+    fn turn(&mut self) -> usize {
+        let turn = self.next;
+        self.next = self.next.wrapping_add(1);
+        turn
+    }
+
+    /// Chooses a session among the given candidates according to the
+    /// strategy. `publisher` is the client id of the session which published
+    /// the message, if known.
+    fn choose(
+        &mut self,
+        strategy: SharingStrategy,
+        publisher: Option<&str>,
+        candidates: Vec<Arc<Session>>,
+    ) -> Option<Arc<Session>> {
+        let len = candidates.len();
+        if len == 0 {
+            return None;
+        }
+
+        let session = match strategy {
+            SharingStrategy::RoundRobin => candidates[self.turn() % len].clone(),
+            SharingStrategy::Random => candidates[rand::thread_rng().gen_range(0..len)].clone(),
+            SharingStrategy::Sticky => {
+                let stuck = publisher
+                    .and_then(|publisher| self.sticky.get(publisher))
+                    .and_then(|id| candidates.iter().find(|s| s.id() == id))
+                    .cloned();
+                match stuck {
+                    Some(session) => session,
+                    None => {
+                        let session = candidates[self.turn() % len].clone();
+                        if let Some(publisher) = publisher {
+                            self.sticky.insert(publisher.into(), session.id().into());
+                        }
+                        session
+                    }
+                }
+            }
+            // Ties are broken in turns
+            SharingStrategy::LeastInFlight => {
+                let turn = self.turn();
+                (0..len)
+                    .map(|index| &candidates[(turn + index) % len])
+                    .min_by_key(|session| session.outgoing())
+                    .cloned()
+                    .unwrap()
+            }
+        };
+        Some(session)
+    }
+}
+
+impl Group {
+    /// Returns true if no session is left in the group
+    pub fn is_empty(&mut self) -> bool {
+        self.state.get_mut().unwrap().members.is_empty()
+    }
+
+    /// Adds the session to the group
+    pub fn insert(&mut self, session: Arc<Session>) {
+        let state = self.state.get_mut().unwrap();
+        state.members.retain(|s| s.id() != session.id());
+        state.members.push(session);
+    }
+
+    /// Removes the session from the group.
+    /// Returns true if the session was in the group.
+    pub fn remove(&mut self, session_id: &str) -> bool {
+        let state = self.state.get_mut().unwrap();
+        let len = state.members.len();
+        state.members.retain(|s| s.id() != session_id);
+        state.sticky.retain(|_, id| id != session_id);
+        state.members.len() != len
+    }
+
+    /// Chooses one session of the group. Connected sessions are preferred,
+    /// others only receive messages if no session of the group is connected.
+    pub fn pick(&self, strategy: SharingStrategy, publisher: Option<&str>) -> Option<Arc<Session>> {
+        let mut state = self.state.lock().unwrap();
+        let online: Vec<Arc<Session>> = state
+            .members
+            .iter()
+            .filter(|s| s.peer().is_some())
+            .cloned()
+            .collect();
+        let candidates = if online.is_empty() {
+            state.members.clone()
+        } else {
+            online
+        };
+        state.choose(strategy, publisher, candidates)
+    }
+
+    /// Chooses a connected session of the group, other than the given one.
+    pub fn pick_other(&self, strategy: SharingStrategy, session_id: &str) -> Option<Arc<Session>> {
+        let mut state = self.state.lock().unwrap();
+        let candidates = state
+            .members
+            .iter()
+            .filter(|s| s.id() != session_id && s.peer().is_some())
+            .cloned()
+            .collect();
+        state.choose(strategy, None, candidates)
+    }
+
+    /// Forgets the session the messages of the given publisher stick to
+    pub fn unstick(&self, publisher: &str) {
+        self.state.lock().unwrap().sticky.remove(publisher);
+    }
+}
+
+#[cfg(test)]
+mod unit {
+
+    use super::*;
+    use crate::{Peer, RemoteAddr};
+    use sage_mqtt::{Publish, QoS};
+    use tokio::sync::mpsc;
+
+    fn member(group: &mut Group, client_id: &str) -> (Arc<Session>, Arc<Peer>) {
+        let (sender, _) = mpsc::unbounded_channel();
+        let (command_sender, _) = mpsc::unbounded_channel();
+        let peer = Arc::new(Peer::new(
+            RemoteAddr::Socket("127.0.0.1:1883".parse().unwrap()),
+            Default::default(),
+            sender,
+            command_sender,
+        ));
+        let session = Arc::new(Session::new(client_id, peer.clone()));
+        group.insert(session.clone());
+        (session, peer)
+    }
+
+    fn picked(group: &Group, strategy: SharingStrategy, publisher: &str) -> String {
+        group
+            .pick(strategy, Some(publisher))
+            .unwrap()
+            .client_id()
+            .into()
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let mut group = Group::default();
+        let _jaden = member(&mut group, "jaden");
+        let _jarod = member(&mut group, "jarod");
+
+        let picks: Vec<String> = (0..4)
+            .map(|_| picked(&group, SharingStrategy::RoundRobin, "jason"))
+            .collect();
+        assert_eq!(picks, vec!["jaden", "jarod", "jaden", "jarod"]);
+    }
+
+    #[test]
+    fn sticky_keeps_publishers_on_one_session() {
+        let strategy = SharingStrategy::Sticky;
+        let mut group = Group::default();
+        let _jaden = member(&mut group, "jaden");
+        let _jarod = member(&mut group, "jarod");
+
+        let first = picked(&group, strategy, "jason");
+        let second = picked(&group, strategy, "james");
+        assert_ne!(first, second);
+        for _ in 0..4 {
+            assert_eq!(picked(&group, strategy, "jason"), first);
+            assert_eq!(picked(&group, strategy, "james"), second);
+        }
+
+        // A publisher whose session ended is given the next turn
+        group.unstick("jason");
+        assert_eq!(picked(&group, strategy, "jason"), first);
+        assert!(group.state.get_mut().unwrap().sticky.contains_key("jason"));
+        group.unstick("jason");
+        group.unstick("james");
+        assert!(group.state.get_mut().unwrap().sticky.is_empty());
+    }
+
+    #[test]
+    fn least_in_flight_prefers_idle_sessions() {
+        let strategy = SharingStrategy::LeastInFlight;
+        let mut group = Group::default();
+        let (jaden, _jaden) = member(&mut group, "jaden");
+        let _jarod = member(&mut group, "jarod");
+
+        jaden.publish(Publish {
+            qos: QoS::AtLeastOnce,
+            ..Default::default()
+        });
+        for _ in 0..4 {
+            assert_eq!(picked(&group, strategy, "jason"), "jarod");
+        }
+    }
+
+    #[test]
+    fn connected_sessions_are_preferred() {
+        let strategy = SharingStrategy::Random;
+        let mut group = Group::default();
+        let (jaden, peer) = member(&mut group, "jaden");
+        let (jarod, _) = member(&mut group, "jarod");
+
+        for _ in 0..4 {
+            assert_eq!(picked(&group, strategy, "jason"), "jaden");
+        }
+        assert!(group.pick_other(strategy, jaden.id()).is_none());
+        assert_eq!(
+            group.pick_other(strategy, jarod.id()).unwrap().id(),
+            jaden.id()
+        );
+
+        // Offline sessions are chosen when nobody is connected
+        drop(peer);
+        assert!(!picked(&group, strategy, "jason").is_empty());
+    }
+
+    #[test]
+    fn remove_forgets_members() {
+        let mut group = Group::default();
+        let (jaden, _peer) = member(&mut group, "jaden");
+
+        assert!(group.remove(jaden.id()));
+        assert!(!group.remove(jaden.id()));
+        assert!(group.is_empty());
+    }
+}
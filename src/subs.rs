@@ -1,28 +1,27 @@
-use crate::Cache;
+use crate::topic;
 use sage_mqtt::{SubscriptionOptions, Topic};
-use std::{collections::HashMap, sync::Arc};
+use std::collections::HashMap;
+
+/// The options and the identifier of a subscription
+pub type Subscription = (SubscriptionOptions, Option<u32>);
 
 /// The list of all subcriptions registered by the broker
-#[derive(Debug, Clone)]
+#[derive(Default, Debug, Clone)]
 pub struct Subs {
-    db: HashMap<Topic, (SubscriptionOptions, Option<u32>)>,
-    cache: Arc<Cache>,
+    db: HashMap<Topic, Subscription>,
 }
 
 impl Subs {
-    /// Builds a new Subscription DB with the given cache
-    pub fn new(cache: Arc<Cache>) -> Self {
-        Subs {
-            db: Default::default(),
-            cache,
-        }
-    }
-
     /// The number of subscriptions
     pub fn len(&self) -> usize {
         self.db.len()
     }
 
+    /// Returns true if there is no subscription
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Add a subscription for the given filter to the given session
     /// Returns true if it replaces an existing one
     pub fn add(
@@ -31,18 +30,43 @@ impl Subs {
         options: SubscriptionOptions,
         identifier: Option<u32>,
     ) -> bool {
-        self.cache.clear();
-        log::warn!("Subscription Identifier: {:?}", identifier);
         self.db.insert(topic, (options, identifier)).is_some()
     }
 
+    /// Removes the subscription with the given filter.
+    /// Filters are compared character by character, wildcards included.
+    /// Returns false if no such subscription exists.
+    pub fn remove(&mut self, topic: &Topic) -> bool {
+        self.db.remove(topic).is_some()
+    }
+
+    /// Returns an iterator over the filters of all subscriptions
+    pub fn filters(&self) -> impl Iterator<Item = &Topic> {
+        self.db.keys()
+    }
+
+    /// Returns an iterator over all subscriptions, along with their filter
+    pub fn iter(&self) -> impl Iterator<Item = (&Topic, &Subscription)> {
+        self.db.iter()
+    }
+
     /// Check wether the given session is subscribed to the given filter
     pub fn has_filter(&self, topic: &Topic) -> bool {
         self.db.contains_key(topic)
     }
 
-    /// Check wether the given topic name matches any filter within this subs
+    /// Returns the options and identifier of the subscription with the given
+    /// filter, if any.
+    pub fn get(&self, topic: &Topic) -> Option<Subscription> {
+        self.db.get(topic).copied()
+    }
+
+    /// Check wether the given topic name matches any non-shared filter within
+    /// this subs. Filters may contain wildcards. Shared subscriptions are
+    /// delivered on their own.
     pub fn matches(&self, name: &Topic) -> bool {
-        self.has_filter(name)
+        self.db
+            .keys()
+            .any(|filter| topic::share(filter).is_none() && topic::matches(filter, name))
     }
 }
@@ -0,0 +1,335 @@
+use crate::{subs::Subscription, topic, Group, Session};
+use sage_mqtt::Topic;
+use std::{collections::HashMap, sync::Arc};
+
+const SINGLE_LEVEL_WILDCARD: &str = "+";
+const MULTI_LEVEL_WILDCARD: &str = "#";
+
+/// A tree of topic filter levels used to find which sessions are subscribed to
+/// a given topic name in a time proportional to the topic depth rather than
+/// the number of sessions.
+/// Each node represents a filter level and holds the sessions whose filter
+/// ends at that level, indexed by their unique session id with the options
+/// and identifier of their subscription, along with the
+/// groups of the shared subscriptions whose filter (following
+/// `$share/{ShareName}/`) ends at that level, indexed by their full filter.
+#[derive(Default, Debug)]
+pub struct SubsTree {
+    root: Node,
+}
+
+#[derive(Default, Debug)]
+struct Node {
+    children: HashMap<String, Node>,
+    subscribers: HashMap<String, (Arc<Session>, Subscription)>,
+    groups: HashMap<Topic, Group>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.subscribers.is_empty() && self.groups.is_empty()
+    }
+
+    /// Applies `remove` to the node at the end of `levels`, pruning any node
+    /// left empty on the way back.
+    fn remove<F: FnOnce(&mut Node) -> bool>(&mut self, levels: &[String], remove: F) -> bool {
+        if let Some((level, levels)) = levels.split_first() {
+            if let Some(child) = self.children.get_mut(level) {
+                let removed = child.remove(levels, remove);
+                if child.is_empty() {
+                    self.children.remove(level);
+                }
+                removed
+            } else {
+                false
+            }
+        } else {
+            remove(self)
+        }
+    }
+
+    /// Returns the node at the end of `levels`, if any
+    fn get(&self, levels: &[String]) -> Option<&Node> {
+        match levels.split_first() {
+            Some((level, levels)) => self.children.get(level)?.get(levels),
+            None => Some(self),
+        }
+    }
+
+    /// Visits this node if it matches the remaining `levels` of a topic name,
+    /// along with any child that matches them.
+    fn visit<'a, F: FnMut(&'a Node)>(&'a self, levels: &[String], root: bool, visit: &mut F) {
+        // Filters starting with a wildcard never match topic names starting
+        // with a '$' character [MQTT-4.7.2-1]
+        let wildcards = !(root && levels.first().is_some_and(|l| l.starts_with('$')));
+
+        // `#` matches the parent level and any number of child levels
+        if wildcards {
+            if let Some(child) = self.children.get(MULTI_LEVEL_WILDCARD) {
+                visit(child);
+            }
+        }
+
+        if let Some((level, levels)) = levels.split_first() {
+            if let Some(child) = self.children.get(level) {
+                child.visit(levels, false, visit);
+            }
+            if wildcards {
+                if let Some(child) = self.children.get(SINGLE_LEVEL_WILDCARD) {
+                    child.visit(levels, false, visit);
+                }
+            }
+        } else {
+            visit(self);
+        }
+    }
+
+    /// Visits all the groups of this node and of its children
+    fn visit_groups<F: FnMut(&Group)>(&self, visit: &mut F) {
+        self.groups.values().for_each(&mut *visit);
+        for child in self.children.values() {
+            child.visit_groups(visit);
+        }
+    }
+}
+
+impl SubsTree {
+    /// Registers the session as a subscriber of the given filter, replacing
+    /// the options and identifier of any existing subscription
+    pub fn insert(&mut self, filter: &Topic, session: Arc<Session>, subscription: Subscription) {
+        self.node(filter)
+            .subscribers
+            .insert(session.id().into(), (session, subscription));
+    }
+
+    /// Unregisters the session from the given filter.
+    /// Returns true if the session was registered.
+    pub fn remove(&mut self, filter: &Topic, session_id: &str) -> bool {
+        self.root.remove(&topic::levels(filter), |node| {
+            node.subscribers.remove(session_id).is_some()
+        })
+    }
+
+    /// Adds the session to the group of the given shared subscription
+    /// (`$share/{ShareName}/{filter}`)
+    pub fn insert_shared(&mut self, share: &Topic, session: Arc<Session>) {
+        let filter = topic::share(share).unwrap_or_default();
+        self.node(&filter)
+            .groups
+            .entry(share.clone())
+            .or_default()
+            .insert(session);
+    }
+
+    /// Removes the session from the group of the given shared subscription,
+    /// dropping the group if it is left empty.
+    /// Returns true if the session was in the group.
+    pub fn remove_shared(&mut self, share: &Topic, session_id: &str) -> bool {
+        let filter = topic::share(share).unwrap_or_default();
+        self.root.remove(&topic::levels(&filter), |node| {
+            if let Some(group) = node.groups.get_mut(share) {
+                let removed = group.remove(session_id);
+                if group.is_empty() {
+                    node.groups.remove(share);
+                }
+                removed
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Returns the node of the given filter, creating it if needed
+    fn node(&mut self, filter: &Topic) -> &mut Node {
+        topic::levels(filter)
+            .into_iter()
+            .fold(&mut self.root, |node, level| {
+                node.children.entry(level).or_default()
+            })
+    }
+
+    /// Returns all the sessions subscribed to at least one filter matching the
+    /// given topic name, along with the options and identifiers of their
+    /// matching subscriptions. Each session is returned once, even if several
+    /// of its filters match.
+    pub fn matches(&self, name: &Topic) -> Vec<(Arc<Session>, Vec<Subscription>)> {
+        let mut result: HashMap<&str, (Arc<Session>, Vec<Subscription>)> = HashMap::new();
+        self.root.visit(&topic::levels(name), true, &mut |node| {
+            for (session, subscription) in node.subscribers.values() {
+                result
+                    .entry(session.id())
+                    .or_insert_with(|| (session.clone(), Vec::new()))
+                    .1
+                    .push(*subscription);
+            }
+        });
+        result.into_values().collect()
+    }
+
+    /// Returns the groups of all the shared subscriptions matching the given
+    /// topic name, along with their full filter.
+    pub fn matching_groups(&self, name: &Topic) -> Vec<(&Topic, &Group)> {
+        let mut result = Vec::new();
+        self.root.visit(&topic::levels(name), true, &mut |node| {
+            result.extend(node.groups.iter())
+        });
+        result
+    }
+
+    /// Returns the group of the given shared subscription, if any
+    pub fn group(&self, share: &Topic) -> Option<&Group> {
+        let filter = topic::share(share)?;
+        self.root.get(&topic::levels(&filter))?.groups.get(share)
+    }
+
+    /// Forgets the sessions the messages of the given publisher stick to, in
+    /// all groups
+    pub fn unstick(&self, publisher: &str) {
+        self.root
+            .visit_groups(&mut |group| group.unstick(publisher));
+    }
+}
+
+#[cfg(test)]
+mod unit {
+
+    use super::*;
+    use crate::{Peer, RemoteAddr};
+    use sage_mqtt::{QoS, SubscriptionOptions};
+    use tokio::sync::mpsc;
+
+    fn session(client_id: &str) -> Arc<Session> {
+        let (sender, _) = mpsc::unbounded_channel();
+        let (command_sender, _) = mpsc::unbounded_channel();
+        let peer = Arc::new(Peer::new(
+            RemoteAddr::Socket("127.0.0.1:1883".parse().unwrap()),
+            Default::default(),
+            sender,
+            command_sender,
+        ));
+        Arc::new(Session::new(client_id, peer))
+    }
+
+    fn matching_clients(tree: &SubsTree, name: &str) -> Vec<String> {
+        let mut clients: Vec<String> = tree
+            .matches(&Topic::from(name))
+            .iter()
+            .map(|(s, _)| s.client_id().into())
+            .collect();
+        clients.sort();
+        clients
+    }
+
+    #[test]
+    fn matches_exact_and_wildcards() {
+        let mut tree = SubsTree::default();
+        tree.insert(
+            &Topic::from("sport/tennis"),
+            session("exact"),
+            Default::default(),
+        );
+        tree.insert(&Topic::from("sport/+"), session("plus"), Default::default());
+        tree.insert(
+            &Topic::from("sport/#"),
+            session("pound"),
+            Default::default(),
+        );
+        tree.insert(&Topic::from("#"), session("all"), Default::default());
+
+        assert_eq!(
+            matching_clients(&tree, "sport/tennis"),
+            vec!["all", "exact", "plus", "pound"]
+        );
+        assert_eq!(matching_clients(&tree, "sport"), vec!["all", "pound"]);
+        assert_eq!(
+            matching_clients(&tree, "sport/tennis/player1"),
+            vec!["all", "pound"]
+        );
+        assert_eq!(matching_clients(&tree, "music"), vec!["all"]);
+    }
+
+    #[test]
+    fn dollar_topics_ignore_root_wildcards() {
+        let mut tree = SubsTree::default();
+        tree.insert(&Topic::from("#"), session("pound"), Default::default());
+        tree.insert(
+            &Topic::from("+/uptime"),
+            session("plus"),
+            Default::default(),
+        );
+        tree.insert(&Topic::from("$SYS/#"), session("sys"), Default::default());
+
+        assert_eq!(matching_clients(&tree, "$SYS/uptime"), vec!["sys"]);
+    }
+
+    #[test]
+    fn overlapping_filters_yield_one_session() {
+        let mut tree = SubsTree::default();
+        let session = session("jaden");
+        let options = |qos| SubscriptionOptions {
+            qos,
+            ..Default::default()
+        };
+        tree.insert(
+            &Topic::from("sport/#"),
+            session.clone(),
+            (options(QoS::AtMostOnce), Some(1)),
+        );
+        tree.insert(
+            &Topic::from("sport/+"),
+            session.clone(),
+            (options(QoS::ExactlyOnce), None),
+        );
+        tree.insert(
+            &Topic::from("sport/tennis"),
+            session,
+            (options(QoS::AtLeastOnce), Some(3)),
+        );
+
+        assert_eq!(matching_clients(&tree, "sport/tennis"), vec!["jaden"]);
+
+        // The options of each matching subscription are given along
+        let matches = tree.matches(&Topic::from("sport/tennis"));
+        let mut identifiers: Vec<_> = matches[0].1.iter().map(|(_, i)| *i).collect();
+        identifiers.sort();
+        assert_eq!(identifiers, vec![None, Some(1), Some(3)]);
+        let matches = tree.matches(&Topic::from("sport"));
+        assert_eq!(matches[0].1, vec![(options(QoS::AtMostOnce), Some(1))]);
+    }
+
+    #[test]
+    fn remove_prunes_nodes() {
+        let mut tree = SubsTree::default();
+        let session = session("jaden");
+        let filter = Topic::from("sport/tennis/+");
+        tree.insert(&filter, session.clone(), Default::default());
+
+        assert!(tree.remove(&filter, session.id()));
+        assert!(!tree.remove(&filter, session.id()));
+        assert!(tree.root.is_empty());
+        assert!(matching_clients(&tree, "sport/tennis/player1").is_empty());
+    }
+
+    #[test]
+    fn groups_are_indexed_by_their_filter() {
+        let mut tree = SubsTree::default();
+        let session = session("jaden");
+        let workers = Topic::from("$share/workers/sport/+");
+        let players = Topic::from("$share/players/sport/#");
+        tree.insert_shared(&workers, session.clone());
+        tree.insert_shared(&players, session.clone());
+
+        let groups = tree.matching_groups(&Topic::from("sport/tennis"));
+        assert_eq!(groups.len(), 2);
+        assert!(groups.iter().any(|(share, _)| **share == workers));
+        assert!(groups.iter().any(|(share, _)| **share == players));
+        assert_eq!(tree.matching_groups(&Topic::from("sport")).len(), 1);
+        assert!(tree.group(&workers).is_some());
+
+        assert!(tree.remove_shared(&workers, session.id()));
+        assert!(tree.group(&workers).is_none());
+        assert!(tree.remove_shared(&players, session.id()));
+        assert!(!tree.remove_shared(&players, session.id()));
+        assert!(tree.root.is_empty());
+    }
+}
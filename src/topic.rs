@@ -0,0 +1,310 @@
+use sage_mqtt::Topic;
+
+const LEVEL_SEPARATOR: char = '/';
+const SINGLE_LEVEL_WILDCARD: &str = "+";
+const MULTI_LEVEL_WILDCARD: &str = "#";
+
+/// Beginning of a shared subscription filter
+const SHARE_PREFIX: &str = "$share/";
+
+/// Splits the given topic into its levels.
+/// A topic always has at least one level, which may be empty.
+pub fn levels(topic: &Topic) -> Vec<String> {
+    topic
+        .to_string()
+        .split(LEVEL_SEPARATOR)
+        .map(String::from)
+        .collect()
+}
+
+/// Splits a raw shared subscription filter (`$share/{ShareName}/{filter}`)
+/// into its share name and its topic filter, which is empty if missing.
+/// Returns None if the filter is not shared.
+///
+/// This is the only place shared filters are parsed, because of the way
+/// `sage_mqtt` 0.5, which the broker is pinned to, handles them: a decoded
+/// `Topic` keeps the share level but never exposes it (`Topic::share` always
+/// returns None) and displays it as "Shareuh", losing the share name. The
+/// share name is thus only known from the raw filter, while SUBSCRIBE packets
+/// are decoded, and decoded filters are split from their display by `share`.
+pub fn split_share(filter: &str) -> Option<(&str, &str)> {
+    let share = filter.strip_prefix(SHARE_PREFIX)?;
+    Some(share.split_once(LEVEL_SEPARATOR).unwrap_or((share, "")))
+}
+
+/// Checks whether the share name of the given raw topic filter is valid, if
+/// the filter is shared. The share name must be at least one character long
+/// [MQTT-4.8.2-1] and must not contain wildcards [MQTT-4.8.2-2].
+pub fn is_valid_share_name(filter: &str) -> bool {
+    split_share(filter).is_none_or(|(name, _)| !name.is_empty() && !name.contains(['+', '#']))
+}
+
+/// Returns the topic filter of a decoded shared subscription filter, that is
+/// what follows `$share/{ShareName}/`, or None if the filter is not shared.
+/// The display of a shared filter, whose share level reads "Shareuh", reads
+/// back as a plain filter: only shared filters differ from their display.
+pub fn share(filter: &Topic) -> Option<Topic> {
+    let display = filter.to_string();
+    if Topic::from(display.as_str()) == *filter {
+        return None;
+    }
+    let raw = format!("{}{}", SHARE_PREFIX, display);
+    split_share(&raw).map(|(_, filter)| Topic::from(filter))
+}
+
+/// Checks whether the given topic filter is correctly formed regarding its
+/// wildcard characters:
+/// - The multi-level wildcard `#` must occupy an entire level and be the last
+///   one of the filter [MQTT-4.7.1-1]
+/// - The single-level wildcard `+` must occupy an entire level
+///   [MQTT-4.7.1-2]
+///
+/// The share name of a shared subscription must be followed by a non empty
+/// topic filter [MQTT-4.8.2-2]. The share name itself is checked by
+/// `is_valid_share_name`.
+pub fn is_valid_filter(filter: &Topic) -> bool {
+    if let Some(filter) = share(filter) {
+        return !filter.to_string().is_empty() && is_valid_filter(&filter);
+    }
+
+    let levels = levels(filter);
+    let last = levels.len() - 1;
+    levels
+        .iter()
+        .enumerate()
+        .all(|(index, level)| match level.as_str() {
+            MULTI_LEVEL_WILDCARD => index == last,
+            SINGLE_LEVEL_WILDCARD => true,
+            level => !level.contains(['+', '#']),
+        })
+}
+
+/// Checks whether the topic name matches the given topic filter.
+/// Filters starting with a wildcard never match names starting with a `$`
+/// character [MQTT-4.7.2-1].
+pub fn matches(filter: &Topic, name: &Topic) -> bool {
+    matches_levels(&levels(filter), &levels(name))
+}
+
+/// Same as `matches` but working with already split levels.
+pub fn matches_levels<F: AsRef<str>, N: AsRef<str>>(filter: &[F], name: &[N]) -> bool {
+    if let (Some(first_filter), Some(first_name)) = (filter.first(), name.first()) {
+        let first_filter = first_filter.as_ref();
+        if first_name.as_ref().starts_with('$')
+            && (first_filter == SINGLE_LEVEL_WILDCARD || first_filter == MULTI_LEVEL_WILDCARD)
+        {
+            return false;
+        }
+    }
+
+    let mut name = name.iter();
+    for level in filter {
+        match (level.as_ref(), name.next()) {
+            // `#` matches its parent level and any number of child levels
+            (MULTI_LEVEL_WILDCARD, _) => return true,
+            (SINGLE_LEVEL_WILDCARD, Some(_)) => {}
+            (level, Some(name_level)) if level == name_level.as_ref() => {}
+            _ => return false,
+        }
+    }
+    name.next().is_none()
+}
+
+/// Returns true if a filter starts with a wildcard while the other starts
+/// with a `$` level, in which case they have no topic name in common
+/// [MQTT-4.7.2-1].
+fn dollar_mismatch<A: AsRef<str>, B: AsRef<str>>(a: &[A], b: &[B]) -> bool {
+    match (a.first(), b.first()) {
+        (Some(a), Some(b)) => {
+            let (a, b) = (a.as_ref(), b.as_ref());
+            let wildcard = |l: &str| l == SINGLE_LEVEL_WILDCARD || l == MULTI_LEVEL_WILDCARD;
+            (wildcard(a) && b.starts_with('$')) || (wildcard(b) && a.starts_with('$'))
+        }
+        _ => false,
+    }
+}
+
+/// Checks whether all the topic names matching the filter `other` also match
+/// the filter `filter`. Both filters are given as split levels.
+pub fn covers_levels<F: AsRef<str>, O: AsRef<str>>(filter: &[F], other: &[O]) -> bool {
+    if dollar_mismatch(filter, other) {
+        return false;
+    }
+
+    let mut other = other.iter().map(AsRef::as_ref);
+    for level in filter {
+        match (level.as_ref(), other.next()) {
+            (MULTI_LEVEL_WILDCARD, _) => return true,
+            (SINGLE_LEVEL_WILDCARD, Some(other_level)) if other_level != MULTI_LEVEL_WILDCARD => {}
+            (level, Some(other_level)) if level == other_level => {}
+            _ => return false,
+        }
+    }
+    other.next().is_none()
+}
+
+/// Checks whether at least one topic name matches both filters, given as
+/// split levels.
+pub fn overlaps_levels<A: AsRef<str>, B: AsRef<str>>(a: &[A], b: &[B]) -> bool {
+    if dollar_mismatch(a, b) {
+        return false;
+    }
+
+    let mut a = a.iter().map(AsRef::as_ref);
+    let mut b = b.iter().map(AsRef::as_ref);
+    loop {
+        match (a.next(), b.next()) {
+            (Some(MULTI_LEVEL_WILDCARD), _) | (_, Some(MULTI_LEVEL_WILDCARD)) => return true,
+            (None, None) => return true,
+            (Some(a), Some(b))
+                if a == SINGLE_LEVEL_WILDCARD || b == SINGLE_LEVEL_WILDCARD || a == b => {}
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit {
+
+    use super::*;
+
+    macro_rules! matches_data {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (filter, name, expected) = $value;
+                    assert_eq!(
+                        matches(&Topic::from(filter), &Topic::from(name)),
+                        expected
+                    );
+                }
+            )*
+        }
+    }
+
+    matches_data! {
+        exact:                  ("sport/tennis", "sport/tennis", true),
+        exact_mismatch:         ("sport/tennis", "sport/golf", false),
+        exact_longer_name:      ("sport/tennis", "sport/tennis/player1", false),
+        exact_shorter_name:     ("sport/tennis", "sport", false),
+        pound_alone:            ("#", "sport/tennis/player1", true),
+        pound_parent:           ("sport/#", "sport", true),
+        pound_children:         ("sport/#", "sport/tennis/player1", true),
+        pound_empty_level:      ("sport/#", "sport/", true),
+        pound_mismatch:         ("sport/#", "music/rock", false),
+        plus_alone:             ("+", "sport", true),
+        plus_alone_multiple:    ("+", "sport/tennis", false),
+        plus_alone_empty:       ("+", "/sport", false),
+        plus_middle:            ("sport/+/player1", "sport/tennis/player1", true),
+        plus_middle_mismatch:   ("sport/+/player1", "sport/tennis/player2", false),
+        plus_empty_level:       ("sport/+", "sport/", true),
+        plus_no_level:          ("sport/+", "sport", false),
+        plus_leading_empty:     ("+/+", "/finance", true),
+        plus_then_pound:        ("site/+/telemetry/#", "site/a/telemetry/temp/1", true),
+        plus_then_pound_parent: ("site/+/telemetry/#", "site/a/telemetry", true),
+        plus_then_pound_miss:   ("site/+/telemetry/#", "site/a/status", false),
+        dollar_pound:           ("#", "$SYS/uptime", false),
+        dollar_plus:            ("+/uptime", "$SYS/uptime", false),
+        dollar_explicit:        ("$SYS/#", "$SYS/uptime", true),
+        dollar_not_first:       ("sport/+", "sport/$score", true),
+    }
+
+    macro_rules! filters_data {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (filter, other, covers, overlaps) = $value;
+                    let (filter, other): (Vec<&str>, Vec<&str>) =
+                        (filter.split('/').collect(), other.split('/').collect());
+                    assert_eq!(covers_levels(&filter, &other), covers);
+                    assert_eq!(overlaps_levels(&filter, &other), overlaps);
+                    assert_eq!(overlaps_levels(&other, &filter), overlaps);
+                }
+            )*
+        }
+    }
+
+    filters_data! {
+        filters_same:             ("sport/tennis", "sport/tennis", true, true),
+        filters_different:        ("sport/tennis", "sport/golf", false, false),
+        filters_pound_name:       ("sport/#", "sport/tennis", true, true),
+        filters_pound_parent:     ("sport/#", "sport", true, true),
+        filters_pound_pound:      ("sport/#", "sport/tennis/#", true, true),
+        filters_pound_wider:      ("sport/tennis/#", "sport/#", false, true),
+        filters_plus_name:        ("sport/+", "sport/tennis", true, true),
+        filters_plus_plus:        ("sport/+", "sport/+", true, true),
+        filters_plus_pound:       ("sport/+", "sport/#", false, true),
+        filters_plus_wider:       ("sport/tennis", "sport/+", false, true),
+        filters_plus_shorter:     ("sport/+/player", "sport/+", false, false),
+        filters_plus_crossed:     ("sport/+/player", "sport/tennis/+", false, true),
+        filters_dollar_pound:     ("#", "$SYS/#", false, false),
+        filters_dollar_explicit:  ("$SYS/#", "$SYS/uptime", true, true),
+    }
+
+    macro_rules! valid_data {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (filter, expected) = $value;
+                    assert_eq!(
+                        is_valid_share_name(filter) && is_valid_filter(&Topic::from(filter)),
+                        expected
+                    );
+                }
+            )*
+        }
+    }
+
+    valid_data! {
+        valid_name:           ("sport/tennis", true),
+        valid_pound:          ("#", true),
+        valid_pound_last:     ("sport/tennis/#", true),
+        valid_plus:           ("+", true),
+        valid_plus_levels:    ("+/tennis/+", true),
+        invalid_pound_middle: ("sport/#/player", false),
+        invalid_pound_glued:  ("sport/tennis#", false),
+        invalid_plus_glued:   ("sport+", false),
+        invalid_pound_plus:   ("sport/#+", false),
+        valid_share:          ("$share/group/sport/#", true),
+        invalid_share_empty:  ("$share//sport/#", false),
+        invalid_share_alone:  ("$share/group", false),
+        invalid_share_slash:  ("$share/group/", false),
+        invalid_share_plus:   ("$share/gr+oup/sport", false),
+        invalid_share_filter: ("$share/group/sport#", false),
+    }
+
+    #[test]
+    fn share_returns_the_shared_filter() {
+        assert_eq!(
+            share(&Topic::from("$share/group/sport/+")),
+            Some(Topic::from("sport/+"))
+        );
+        assert_eq!(share(&Topic::from("$share/group")), Some(Topic::from("")));
+        assert_eq!(share(&Topic::from("sport/tennis")), None);
+        assert_eq!(share(&Topic::from("Shareuh/tennis")), None);
+    }
+
+    #[test]
+    fn split_share_reads_raw_filters() {
+        assert_eq!(
+            split_share("$share/group/sport/+"),
+            Some(("group", "sport/+"))
+        );
+        assert_eq!(split_share("$share/group"), Some(("group", "")));
+        assert_eq!(split_share("$share//sport"), Some(("", "sport")));
+        assert_eq!(split_share("Shareuh/sport"), None);
+        assert_eq!(split_share("sport/$share/group"), None);
+    }
+
+    #[test]
+    fn share_name_is_read_from_raw_filter() {
+        assert!(is_valid_share_name("$share/a\"b/sport"));
+        assert!(is_valid_share_name("Shareuh/sport"));
+        assert!(is_valid_share_name("sport/+"));
+        assert!(!is_valid_share_name("$share//sport"));
+        assert!(!is_valid_share_name("$share/a#b/sport"));
+    }
+}
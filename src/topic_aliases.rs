@@ -0,0 +1,218 @@
+use sage_mqtt::{Publish, ReasonCode, Topic};
+use std::collections::HashMap;
+
+/// Holds the topic aliases of a network connection in both directions:
+/// - Inbound aliases are set by the client, up to the Topic Alias Maximum of
+///   the server
+/// - Outbound aliases are assigned by the server, up to the Topic Alias
+///   Maximum of the client [MQTT-3.3.2-11]. Once all of them are in use, the
+///   least recently used one is given to the new topic name.
+///
+/// Aliases are never carried forward from a network connection to another
+/// [MQTT-3.3.2-7].
+///
+/// An outbound alias is only recorded once the message carrying it is sent,
+/// so that a message discarded on the way does not leave the client unaware
+/// of an alias the following messages rely on.
+#[derive(Default, Debug)]
+pub struct TopicAliases {
+    inbound: HashMap<u16, Topic>,
+    outbound: HashMap<Topic, (u16, u64)>,
+    outbound_maximum: u16,
+    last_use: u64,
+}
+
+fn is_empty(topic: &Topic) -> bool {
+    topic.to_string().is_empty()
+}
+
+impl TopicAliases {
+    /// Sets the number of aliases the client accepts, as sent in its CONNECT
+    /// packet.
+    pub fn set_outbound_maximum(&mut self, maximum: u16) {
+        self.outbound_maximum = maximum;
+    }
+
+    /// Replaces the alias of an inbound message by the topic name it stands
+    /// for, or maps it to the topic name if present. `maximum` is the number
+    /// of aliases accepted by the server.
+    /// Returns `TopicAliasInvalid` if the alias is 0 or greater than `maximum`
+    /// [MQTT-3.3.2-8] [MQTT-3.3.2-12] and `ProtocolError` if the topic name is
+    /// empty and the alias is unknown or absent.
+    pub fn resolve(&mut self, publish: &mut Publish, maximum: u16) -> Result<(), ReasonCode> {
+        match publish.topic_alias.take() {
+            Some(alias) if alias == 0 || alias > maximum => Err(ReasonCode::TopicAliasInvalid),
+            Some(alias) if is_empty(&publish.topic_name) => {
+                if let Some(topic_name) = self.inbound.get(&alias) {
+                    publish.topic_name = topic_name.clone();
+                    Ok(())
+                } else {
+                    Err(ReasonCode::ProtocolError)
+                }
+            }
+            Some(alias) => {
+                self.inbound.insert(alias, publish.topic_name.clone());
+                Ok(())
+            }
+            None if is_empty(&publish.topic_name) => Err(ReasonCode::ProtocolError),
+            None => Ok(()),
+        }
+    }
+
+    /// Assigns an alias to the topic name of an outbound message, if the
+    /// client accepts aliases. The topic name is left out if the alias was
+    /// already sent along with it.
+    /// The returned assignment must be recorded once the message is sent.
+    pub fn assign(&self, publish: &mut Publish) -> Option<Assignment> {
+        if self.outbound_maximum == 0 {
+            return None;
+        }
+
+        let topic_name = publish.topic_name.clone();
+        if let Some((alias, _)) = self.outbound.get(&topic_name) {
+            publish.topic_alias = Some(*alias);
+            publish.topic_name = Topic::from("");
+            return Some(Assignment {
+                topic_name,
+                alias: *alias,
+            });
+        }
+
+        let alias = if self.outbound.len() < self.outbound_maximum as usize {
+            self.outbound.len() as u16 + 1
+        } else {
+            self.outbound
+                .values()
+                .min_by_key(|(_, last_use)| *last_use)
+                .map(|(alias, _)| *alias)
+                .unwrap()
+        };
+        publish.topic_alias = Some(alias);
+        Some(Assignment { topic_name, alias })
+    }
+
+    /// Records the alias of an outbound message which was sent, taking it
+    /// from the topic name it was previously given to, if any.
+    pub fn record(&mut self, assignment: Assignment) {
+        self.last_use += 1;
+        let Assignment { topic_name, alias } = assignment;
+        self.outbound
+            .retain(|name, (other, _)| *other != alias || *name == topic_name);
+        self.outbound.insert(topic_name, (alias, self.last_use));
+    }
+}
+
+/// The alias given to the topic name of an outbound message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assignment {
+    topic_name: Topic,
+    alias: u16,
+}
+
+#[cfg(test)]
+mod unit {
+
+    use super::*;
+
+    fn publish(name: &str, topic_alias: Option<u16>) -> Publish {
+        Publish {
+            topic_name: Topic::from(name),
+            topic_alias,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn resolve_maps_and_replaces_aliases() {
+        let mut aliases = TopicAliases::default();
+        let mut first = publish("sport/tennis", Some(1));
+        assert_eq!(aliases.resolve(&mut first, 10), Ok(()));
+        assert_eq!(first.topic_alias, None);
+
+        let mut second = publish("", Some(1));
+        assert_eq!(aliases.resolve(&mut second, 10), Ok(()));
+        assert_eq!(second.topic_name, Topic::from("sport/tennis"));
+    }
+
+    #[test]
+    fn resolve_rejects_invalid_aliases() {
+        let mut aliases = TopicAliases::default();
+        for (mut publish, reason_code) in [
+            (publish("sport", Some(0)), ReasonCode::TopicAliasInvalid),
+            (publish("sport", Some(11)), ReasonCode::TopicAliasInvalid),
+            (publish("", Some(1)), ReasonCode::ProtocolError),
+            (publish("", None), ReasonCode::ProtocolError),
+        ] {
+            assert_eq!(aliases.resolve(&mut publish, 10), Err(reason_code));
+        }
+    }
+
+    /// Assigns an alias to the message and records it as sent
+    fn send(aliases: &mut TopicAliases, publish: &mut Publish) {
+        if let Some(assignment) = aliases.assign(publish) {
+            aliases.record(assignment);
+        }
+    }
+
+    #[test]
+    fn assign_requires_client_maximum() {
+        let mut aliases = TopicAliases::default();
+        let mut publish = publish("sport/tennis", None);
+        send(&mut aliases, &mut publish);
+        assert_eq!(publish.topic_alias, None);
+        assert_eq!(publish.topic_name, Topic::from("sport/tennis"));
+    }
+
+    #[test]
+    fn assign_omits_known_topic_names() {
+        let mut aliases = TopicAliases::default();
+        aliases.set_outbound_maximum(10);
+
+        let mut first = publish("sport/tennis", None);
+        send(&mut aliases, &mut first);
+        assert_eq!(first.topic_alias, Some(1));
+        assert_eq!(first.topic_name, Topic::from("sport/tennis"));
+
+        let mut second = publish("sport/tennis", None);
+        send(&mut aliases, &mut second);
+        assert_eq!(second.topic_alias, Some(1));
+        assert_eq!(second.topic_name, Topic::from(""));
+    }
+
+    #[test]
+    fn assign_evicts_least_recently_used() {
+        let mut aliases = TopicAliases::default();
+        aliases.set_outbound_maximum(2);
+
+        for name in ["sport/tennis", "sport/golf", "sport/tennis", "news"] {
+            send(&mut aliases, &mut publish(name, None));
+        }
+
+        // "sport/golf" lost its alias to "news"
+        let mut publish = publish("sport/golf", None);
+        send(&mut aliases, &mut publish);
+        assert_eq!(publish.topic_alias, Some(1));
+        assert_eq!(publish.topic_name, Topic::from("sport/golf"));
+    }
+
+    #[test]
+    fn unrecorded_aliases_are_assigned_again() {
+        let mut aliases = TopicAliases::default();
+        aliases.set_outbound_maximum(1);
+        send(&mut aliases, &mut publish("sport/tennis", None));
+
+        // The message is discarded: its alias is not recorded
+        let mut discarded = publish("sport/golf", None);
+        aliases.assign(&mut discarded).unwrap();
+        assert_eq!(discarded.topic_alias, Some(1));
+
+        // The topic names keep going along with their alias
+        let mut golf = publish("sport/golf", None);
+        send(&mut aliases, &mut golf);
+        assert_eq!(golf.topic_name, Topic::from("sport/golf"));
+        let mut tennis = publish("sport/tennis", None);
+        send(&mut aliases, &mut tennis);
+        assert_eq!(tennis.topic_name, Topic::from("sport/tennis"));
+        assert_eq!(tennis.topic_alias, Some(1));
+    }
+}
@@ -0,0 +1,221 @@
+//! Access control requirements: the broker restricts the topics clients can
+//! publish on and subscribe to with its access control lists.
+use sage_broker::{
+    auth::{Acl, PasswordFile},
+    BrokerSettings,
+};
+use sage_mqtt::{
+    Connect, Packet, Publish, QoS, ReasonCode, SubAck, Subscribe, SubscriptionOptions, Topic, Will,
+};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::net::TcpStream;
+pub mod utils;
+
+use utils::client::{DisPacket, Response};
+pub use utils::*;
+
+const ACL: &str = "
+    pattern read news/#
+    pattern readwrite tenants/%u/#
+    pattern write devices/%c/status
+
+    user editor
+    topic readwrite news/#
+";
+
+/// The settings of a broker with the ACL above, authenticating the `editor`,
+/// `jaden` and `jarod` users, whose password is their name.
+fn settings() -> BrokerSettings {
+    let mut passwords = PasswordFile::default();
+    for user_name in ["editor", "jaden", "jarod"] {
+        passwords.insert(user_name, user_name);
+    }
+    BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        authenticator: Some(Arc::new(passwords)),
+        acl: Some(Arc::new(ACL.parse::<Acl>().unwrap())),
+        ..BrokerSettings::valid_default()
+    }
+}
+
+/// Connects a client authenticated as the given user
+async fn connect(local_addr: &SocketAddr, client_id: &str, user_name: &str) -> TcpStream {
+    let connect = Connect {
+        client_id: Some(client_id.into()),
+        user_name: Some(user_name.into()),
+        password: Some(user_name.as_bytes().to_vec()),
+        ..Default::default()
+    };
+    client::connect(local_addr, connect).await.0
+}
+
+/// Subscribes to the given filters, returning the reason codes of the SUBACK
+async fn subscribe(stream: &mut TcpStream, filters: &[&str]) -> Vec<ReasonCode> {
+    let subscribe = Subscribe {
+        subscriptions: filters
+            .iter()
+            .map(|filter| {
+                (
+                    Topic::from(*filter),
+                    SubscriptionOptions {
+                        qos: QoS::AtLeastOnce,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect(),
+        ..Default::default()
+    };
+    if let Response::Packet(Packet::SubAck(SubAck { reason_codes, .. })) =
+        client::send_waitback(stream, subscribe.into()).await
+    {
+        reason_codes
+    } else {
+        panic!("Expected SUBACK packet");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Each subscription the client is not authorized to make is refused with
+/// `NotAuthorized` in the SUBACK, the others being granted.
+#[tokio::test]
+async fn subscribe_not_authorized() {
+    let (_, server, local_addr, shutdown) = server::spawn(settings()).await;
+    let mut stream = connect(&local_addr, "Jaden", "jaden").await;
+
+    let reason_codes = subscribe(
+        &mut stream,
+        &["news/#", "#", "tenants/jaden/#", "tenants/+/kitchen"],
+    )
+    .await;
+    assert_eq!(
+        reason_codes,
+        vec![
+            ReasonCode::GrantedQoS1,
+            ReasonCode::NotAuthorized,
+            ReasonCode::GrantedQoS1,
+            ReasonCode::NotAuthorized,
+        ]
+    );
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// A message the client is not authorized to publish is acknowledged with
+/// `NotAuthorized` and is not delivered to the subscribers.
+#[tokio::test]
+async fn publish_not_authorized() {
+    let (_, server, local_addr, shutdown) = server::spawn(settings()).await;
+    let mut subscriber = connect(&local_addr, "Reader", "jarod").await;
+    subscribe(&mut subscriber, &["news/#"]).await;
+    let mut jaden = connect(&local_addr, "Jaden", "jaden").await;
+    let mut editor = connect(&local_addr, "Editor", "editor").await;
+
+    for (stream, message, reason_code) in [
+        (&mut jaden, "fake", ReasonCode::NotAuthorized),
+        (&mut editor, "news", ReasonCode::Success),
+    ] {
+        let publish = Publish {
+            qos: QoS::AtLeastOnce,
+            packet_identifier: Some(1),
+            topic_name: Topic::from("news/sport"),
+            message: message.into(),
+            ..Default::default()
+        };
+        if let Response::Packet(Packet::PubAck(puback)) =
+            client::send_waitback(stream, publish.into()).await
+        {
+            assert_eq!(puback.reason_code, reason_code);
+        } else {
+            panic!("Expected PUBACK packet");
+        }
+    }
+
+    // Only the message of the editor is received
+    let publish = client::receive_publish(&mut subscriber).await;
+    assert_eq!(publish.message, b"news");
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// `%u` and `%c` patterns give each user and client their own topics, which
+/// other users cannot access.
+#[tokio::test]
+async fn patterns() {
+    let (_, server, local_addr, shutdown) = server::spawn(settings()).await;
+    let mut jaden = connect(&local_addr, "Jaden", "jaden").await;
+    let mut jarod = connect(&local_addr, "Jarod", "jarod").await;
+
+    assert_eq!(
+        subscribe(&mut jaden, &["tenants/jaden/#", "tenants/jarod/#"]).await,
+        vec![ReasonCode::GrantedQoS1, ReasonCode::NotAuthorized]
+    );
+
+    for (topic_name, message) in [
+        ("tenants/jaden/kitchen", "intruder"),
+        ("devices/Jaden/status", "impersonated"),
+        ("devices/Jarod/status", "online"),
+        ("tenants/jarod/kitchen", "hot"),
+    ] {
+        let publish = Publish {
+            topic_name: Topic::from(topic_name),
+            message: message.into(),
+            ..Default::default()
+        };
+        client::send(&mut jarod, publish.into()).await;
+    }
+    let mut jaden_writer = connect(&local_addr, "Jaden2", "jaden").await;
+    let publish = Publish {
+        topic_name: Topic::from("tenants/jaden/kitchen"),
+        message: "cold".into(),
+        ..Default::default()
+    };
+    client::send(&mut jaden_writer, publish.into()).await;
+
+    // The messages of Jarod to the tenant of Jaden are dropped
+    let publish = client::receive_publish(&mut jaden).await;
+    assert_eq!(publish.message, b"cold");
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// A client whose will message is on a topic it is not authorized to publish
+/// on is refused with `NotAuthorized`.
+#[tokio::test]
+async fn will_not_authorized() {
+    let (_, server, local_addr, shutdown) = server::spawn(settings()).await;
+
+    for (client_id, reason_code) in [
+        ("Jarod", ReasonCode::NotAuthorized),
+        ("Jaden", ReasonCode::Success),
+    ] {
+        let mut stream = client::spawn(&local_addr).await;
+        let connect = Connect {
+            client_id: Some(client_id.into()),
+            user_name: Some("jaden".into()),
+            password: Some(b"jaden".to_vec()),
+            will: Some(Will::with_message(
+                Topic::from("devices/Jaden/status"),
+                "offline",
+            )),
+            ..Default::default()
+        };
+        if let Response::Packet(Packet::ConnAck(connack)) =
+            client::send_waitback(&mut stream, connect.into()).await
+        {
+            assert_eq!(connack.reason_code, reason_code);
+        } else {
+            panic!("Expected CONNACK packet");
+        }
+        if reason_code != ReasonCode::Success {
+            if let Some(what) = client::wait_close(stream, DisPacket::Forbid).await {
+                panic!("{}", what);
+            }
+        }
+    }
+
+    server::stop(shutdown, server).await;
+}
@@ -0,0 +1,567 @@
+//! Authentication requirements: the broker authenticates clients as they
+//! connect [MQTT-3.1.4-2], possibly with enhanced authentication, which
+//! consists in all [MQTT 4.12.x-x] conformances.
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use sage_broker::{
+    auth::{Authenticator, Credentials, PasswordFile, Principal, ScramSha256},
+    BrokerSettings,
+};
+use sage_mqtt::{Auth, Authentication, Connect, Packet, Publish, ReasonCode};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tokio::{net::TcpStream, sync::Notify};
+pub mod utils;
+
+use utils::client::{DisPacket, Response};
+pub use utils::*;
+
+/// Sends a CONNECT packet with the given credentials and returns the stream if
+/// the client is accepted, or the reason code of the CONNACK. The connection
+/// is expected to be closed unless the client is accepted.
+async fn connect(
+    local_addr: &SocketAddr,
+    client_id: &str,
+    user_name: Option<&str>,
+    password: Option<&str>,
+) -> Result<TcpStream, ReasonCode> {
+    let mut stream = client::spawn(local_addr).await;
+    let connect = Connect {
+        client_id: Some(client_id.into()),
+        user_name: user_name.map(Into::into),
+        password: password.map(|p| p.as_bytes().to_vec()),
+        ..Default::default()
+    };
+    if let Response::Packet(Packet::ConnAck(connack)) =
+        client::send_waitback(&mut stream, connect.into()).await
+    {
+        if connack.reason_code == ReasonCode::Success {
+            Ok(stream)
+        } else {
+            if let Some(what) = client::wait_close(stream, DisPacket::Forbid).await {
+                panic!("{}", what);
+            }
+            Err(connack.reason_code)
+        }
+    } else {
+        panic!("Expected CONNACK after CONNECT");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// The password file authenticator accepts known users with the right
+/// password only, refusing others with `BadUserNameOrPassword` and clients
+/// without a user name with `NotAuthorized`.
+#[tokio::test]
+async fn password_file() {
+    let mut passwords = PasswordFile::default();
+    passwords.insert("jaden", "secret");
+    let passwords: PasswordFile = passwords.to_string().parse().unwrap();
+
+    let (sessions, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        authenticator: Some(Arc::new(passwords)),
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    for (user_name, password, reason_code) in [
+        (
+            Some("jaden"),
+            Some("public"),
+            ReasonCode::BadUserNameOrPassword,
+        ),
+        (Some("jaden"), None, ReasonCode::BadUserNameOrPassword),
+        (
+            Some("jarod"),
+            Some("secret"),
+            ReasonCode::BadUserNameOrPassword,
+        ),
+        (None, None, ReasonCode::NotAuthorized),
+    ] {
+        let result = connect(&local_addr, "Jaden", user_name, password).await;
+        assert_eq!(result.err(), Some(reason_code));
+    }
+    let _stream = connect(&local_addr, "Jaden", Some("jaden"), Some("secret"))
+        .await
+        .unwrap();
+
+    // Only the authenticated client has a session
+    {
+        let sessions = sessions.read().unwrap();
+        assert_eq!(sessions.len(), 1);
+        let session = sessions.get("Jaden").unwrap();
+        assert_eq!(session.principal(), Some(Principal::new("jaden")));
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+/// Accepts the clients whose identifier starts with their user name
+#[derive(Debug)]
+struct Prefixed;
+
+impl Authenticator for Prefixed {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a Credentials,
+    ) -> BoxFuture<'a, Result<Principal, ReasonCode>> {
+        Box::pin(async move {
+            match &credentials.user_name {
+                Some(user_name) if credentials.client_id.starts_with(user_name.as_str()) => {
+                    Ok(Principal::new(user_name))
+                }
+                _ => Err(ReasonCode::NotAuthorized),
+            }
+        })
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// An application can provide its own authenticator, which decides of the
+/// principal and of the reason code of refused connections.
+#[tokio::test]
+async fn custom_authenticator() {
+    let (sessions, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        authenticator: Some(Arc::new(Prefixed)),
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let result = connect(&local_addr, "jadenKettle", Some("jarod"), Some("kettle")).await;
+    assert_eq!(result.err(), Some(ReasonCode::NotAuthorized));
+    let _stream = connect(&local_addr, "jadenKettle", Some("jaden"), Some("kettle"))
+        .await
+        .unwrap();
+    assert_eq!(
+        sessions
+            .read()
+            .unwrap()
+            .get("jadenKettle")
+            .unwrap()
+            .principal(),
+        Some(Principal::new("jaden"))
+    );
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Without authenticator, any client can connect, with or without a user
+/// name.
+#[tokio::test]
+async fn no_authenticator() {
+    let (sessions, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let _stream = connect(&local_addr, "Jaden", Some("jaden"), Some("secret"))
+        .await
+        .unwrap();
+    assert_eq!(
+        sessions.read().unwrap().get("Jaden").unwrap().principal(),
+        None
+    );
+
+    server::stop(shutdown, server).await;
+}
+
+/// Accepts any client, keeping those with the user name `slow` waiting until
+/// it is notified
+#[derive(Debug, Default)]
+struct Slow {
+    notify: Notify,
+}
+
+impl Authenticator for Slow {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a Credentials,
+    ) -> BoxFuture<'a, Result<Principal, ReasonCode>> {
+        Box::pin(async move {
+            if credentials.user_name.as_deref() == Some("slow") {
+                self.notify.notified().await;
+            }
+            Ok(Principal::new(&credentials.client_id))
+        })
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// A slow authenticator does not keep the broker from serving other clients.
+/// The packets a client sends right after its CONNECT packet are processed
+/// once it is authenticated.
+#[tokio::test]
+async fn slow_authenticator() {
+    let slow = Arc::new(Slow::default());
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        authenticator: Some(slow.clone()),
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut waiting = client::spawn(&local_addr).await;
+    let packet = Connect {
+        client_id: Some("Jaden".into()),
+        user_name: Some("slow".into()),
+        ..Default::default()
+    };
+    client::send(&mut waiting, packet.into()).await;
+    client::send(&mut waiting, Packet::PingReq).await;
+
+    let _stream = connect(&local_addr, "Jarod", None, None).await.unwrap();
+    slow.notify.notify_one();
+
+    assert!(matches!(
+        client::receive(&mut waiting).await,
+        Response::Packet(Packet::ConnAck(connack)) if connack.reason_code == ReasonCode::Success
+    ));
+    assert!(matches!(
+        client::receive(&mut waiting).await,
+        Response::Packet(Packet::PingResp)
+    ));
+
+    server::stop(shutdown, server).await;
+}
+
+const SCRAM: &str = "SCRAM-SHA-256";
+const CLIENT_NONCE: &str = "rOprNGfwEbeRWgbNEkqO";
+
+/// Broker settings accepting the SCRAM-SHA-256 authentication of the user
+/// "jaden" with the password "secret"
+fn scram_settings() -> BrokerSettings {
+    let mut passwords = PasswordFile::default();
+    passwords.insert("jaden", "secret");
+    BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        authentication_mechanisms: vec![Arc::new(ScramSha256::new(Arc::new(passwords)))],
+        ..BrokerSettings::valid_default()
+    }
+}
+
+/// The SCRAM client-first-message of the given user
+fn client_first(user_name: &str) -> Vec<u8> {
+    format!("n,,n={},r={}", user_name, CLIENT_NONCE).into_bytes()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Computes the SCRAM client-final-message answering the given
+/// server-first-message, along with the expected server-final-message
+fn client_final(user_name: &str, password: &str, server_first: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let server_first = String::from_utf8(server_first.to_vec()).unwrap();
+    let fields: HashMap<&str, &str> = server_first
+        .split(',')
+        .map(|field| field.split_once('=').unwrap())
+        .collect();
+    assert!(fields["r"].starts_with(CLIENT_NONCE));
+    let salt = BASE64.decode(fields["s"]).unwrap();
+    let salted_password = pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(
+        password.as_bytes(),
+        &salt,
+        fields["i"].parse().unwrap(),
+    );
+
+    let without_proof = format!("c=biws,r={}", fields["r"]);
+    let auth_message = format!(
+        "n={},r={},{},{}",
+        user_name, CLIENT_NONCE, server_first, without_proof
+    );
+    let client_key = hmac_sha256(&salted_password, b"Client Key");
+    let stored_key = Sha256::digest(&client_key);
+    let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+    let proof: Vec<u8> = client_key
+        .iter()
+        .zip(client_signature)
+        .map(|(k, s)| k ^ s)
+        .collect();
+    let server_key = hmac_sha256(&salted_password, b"Server Key");
+    let server_signature = hmac_sha256(&server_key, auth_message.as_bytes());
+
+    (
+        format!("{},p={}", without_proof, BASE64.encode(proof)).into_bytes(),
+        format!("v={}", BASE64.encode(server_signature)).into_bytes(),
+    )
+}
+
+fn auth(reason_code: ReasonCode, method: &str, data: Vec<u8>) -> Packet {
+    Auth {
+        reason_code,
+        authentication: Authentication {
+            method: method.into(),
+            data,
+        },
+        ..Default::default()
+    }
+    .into()
+}
+
+/// Sends the given packet and expects an AUTH packet continuing the SCRAM
+/// exchange, returning its data
+async fn continue_scram(stream: &mut TcpStream, packet: Packet) -> Vec<u8> {
+    if let Response::Packet(Packet::Auth(packet)) = client::send_waitback(stream, packet).await {
+        assert_eq!(packet.reason_code, ReasonCode::ContinueAuthentication);
+        assert_eq!(packet.authentication.method, SCRAM);
+        packet.authentication.data
+    } else {
+        panic!("Expected AUTH packet");
+    }
+}
+
+/// Starts a SCRAM authentication with a CONNECT packet and returns the
+/// server-first-message
+async fn scram_connect(stream: &mut TcpStream, user_name: &str) -> Vec<u8> {
+    let connect = Connect {
+        client_id: Some("Jaden".into()),
+        authentication: Some(Authentication {
+            method: SCRAM.into(),
+            data: client_first(user_name),
+        }),
+        ..Default::default()
+    };
+    continue_scram(stream, connect.into()).await
+}
+
+/// Connects with a successful SCRAM authentication of "jaden"
+async fn scram_client(local_addr: &SocketAddr) -> TcpStream {
+    let mut stream = client::spawn(local_addr).await;
+    let server_first = scram_connect(&mut stream, "jaden").await;
+    let (client_final, _) = client_final("jaden", "secret", &server_first);
+    let packet = auth(ReasonCode::ContinueAuthentication, SCRAM, client_final);
+    if let Response::Packet(Packet::ConnAck(connack)) =
+        client::send_waitback(&mut stream, packet).await
+    {
+        assert_eq!(connack.reason_code, ReasonCode::Success);
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+    stream
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.12.0-1: If the Server does not support the Authentication Method
+/// supplied by the Client, it MAY send a CONNACK with a Reason Code of 0x8C
+/// (Bad authentication method) or 0x87 (Not Authorized) as described in
+/// section 4.13 and MUST close the Network Connection.
+#[tokio::test]
+async fn mqtt_4_12_0_1() {
+    let (_, server, local_addr, shutdown) = server::spawn(scram_settings()).await;
+
+    let mut stream = client::spawn(&local_addr).await;
+    let connect = Connect {
+        authentication: Some(Authentication {
+            method: "GS2-KRB5".into(),
+            data: Default::default(),
+        }),
+        ..Default::default()
+    };
+    if let Response::Packet(Packet::ConnAck(connack)) =
+        client::send_waitback(&mut stream, connect.into()).await
+    {
+        assert_eq!(connack.reason_code, ReasonCode::BadAuthenticationMethod);
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+    if let Some(what) = client::wait_close(stream, DisPacket::Forbid).await {
+        panic!("{}", what);
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.12.0-2: If the Server requires additional information to complete
+/// the authorization, it can send an AUTH packet to the Client. This packet
+/// MUST contain a Reason Code of 0x18 (Continue authentication).
+/// MQTT-4.12.0-5: If the initial CONNECT packet included an Authentication
+/// Method property then all AUTH packets, and any successful CONNACK packet
+/// MUST include an Authentication Method Property with the same value as in
+/// the CONNECT packet.
+#[tokio::test]
+async fn mqtt_4_12_0_2() {
+    let (sessions, server, local_addr, shutdown) = server::spawn(scram_settings()).await;
+
+    let mut stream = client::spawn(&local_addr).await;
+    let server_first = scram_connect(&mut stream, "jaden").await;
+    let (client_final, server_final) = client_final("jaden", "secret", &server_first);
+    let packet = auth(ReasonCode::ContinueAuthentication, SCRAM, client_final);
+    if let Response::Packet(Packet::ConnAck(connack)) =
+        client::send_waitback(&mut stream, packet).await
+    {
+        assert_eq!(connack.reason_code, ReasonCode::Success);
+        // The server proves it knows the password too
+        assert_eq!(
+            connack.authentication,
+            Some(Authentication {
+                method: SCRAM.into(),
+                data: server_final
+            })
+        );
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+    assert_eq!(
+        sessions.read().unwrap().get("Jaden").unwrap().principal(),
+        Some(Principal::new("jaden"))
+    );
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.12.0-4: The Server can reject the authentication at any point in
+/// this process. It MAY send a CONNACK with a Reason Code of 0x80 or above as
+/// described in section 4.13, and MUST close the Network Connection.
+#[tokio::test]
+async fn mqtt_4_12_0_4() {
+    let (sessions, server, local_addr, shutdown) = server::spawn(scram_settings()).await;
+
+    for (user_name, password) in [("jaden", "public"), ("jarod", "secret")] {
+        let mut stream = client::spawn(&local_addr).await;
+        let server_first = scram_connect(&mut stream, user_name).await;
+        let (client_final, _) = client_final(user_name, password, &server_first);
+        let packet = auth(ReasonCode::ContinueAuthentication, SCRAM, client_final);
+        if let Response::Packet(Packet::ConnAck(connack)) =
+            client::send_waitback(&mut stream, packet).await
+        {
+            assert_eq!(connack.reason_code, ReasonCode::NotAuthorized);
+        } else {
+            panic!("Expected CONNACK packet");
+        }
+        if let Some(what) = client::wait_close(stream, DisPacket::Forbid).await {
+            panic!("{}", what);
+        }
+    }
+    assert!(sessions.read().unwrap().is_empty());
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.12.0-6: If the Client does not include an Authentication Method in
+/// the CONNECT, the Server MUST NOT send an AUTH packet, and it MUST NOT send
+/// an Authentication Method in the CONNACK packet.
+/// MQTT-4.12.0-7: If the Client does not include an Authentication Method in
+/// the CONNECT, the Client MUST NOT send an AUTH packet to the Server.
+#[tokio::test]
+async fn mqtt_4_12_0_6() {
+    let (_, server, local_addr, shutdown) = server::spawn(scram_settings()).await;
+
+    let mut stream = client::spawn(&local_addr).await;
+    if let Response::Packet(Packet::ConnAck(connack)) =
+        client::send_waitback(&mut stream, Connect::default().into()).await
+    {
+        assert_eq!(connack.reason_code, ReasonCode::Success);
+        assert_eq!(connack.authentication, None);
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+
+    // An AUTH packet is a protocol error
+    let packet = auth(ReasonCode::ReAuthenticate, SCRAM, client_first("jaden"));
+    client::send(&mut stream, packet).await;
+    let policy = DisPacket::Force(Some(ReasonCode::ProtocolError));
+    if let Some(what) = client::wait_close(stream, policy).await {
+        panic!("{}", what);
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.1.2-30: If a Client sets an Authentication Method in the CONNECT,
+/// the Client MUST NOT send any packets other than AUTH or DISCONNECT packets
+/// until it has received a CONNACK packet.
+#[tokio::test]
+async fn mqtt_3_1_2_30() {
+    let (sessions, server, local_addr, shutdown) = server::spawn(scram_settings()).await;
+
+    let mut stream = client::spawn(&local_addr).await;
+    scram_connect(&mut stream, "jaden").await;
+    let publish = Publish {
+        topic_name: "sport/tennis".into(),
+        ..Default::default()
+    };
+    if let Response::Packet(Packet::ConnAck(connack)) =
+        client::send_waitback(&mut stream, publish.into()).await
+    {
+        assert_eq!(connack.reason_code, ReasonCode::ProtocolError);
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+    if let Some(what) = client::wait_close(stream, DisPacket::Forbid).await {
+        panic!("{}", what);
+    }
+    assert!(sessions.read().unwrap().is_empty());
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.12.1-1: If the Client supplied an Authentication Method in the
+/// CONNECT packet it can initiate a re-authentication at any time after
+/// receiving a CONNACK. It does this by sending an AUTH packet with a Reason
+/// Code of 0x19 (Re-authentication). The Client MUST set the Authentication
+/// Method to the same value as the Authentication Method originally used to
+/// authenticate the Network Connection.
+#[tokio::test]
+async fn mqtt_4_12_1_1() {
+    let (_, server, local_addr, shutdown) = server::spawn(scram_settings()).await;
+
+    let mut stream = scram_client(&local_addr).await;
+    for _ in 0..2 {
+        let packet = auth(ReasonCode::ReAuthenticate, SCRAM, client_first("jaden"));
+        let server_first = continue_scram(&mut stream, packet).await;
+        let (client_final, server_final) = client_final("jaden", "secret", &server_first);
+        let packet = auth(ReasonCode::ContinueAuthentication, SCRAM, client_final);
+        assert!(matches!(
+            client::send_waitback(&mut stream, packet).await,
+            Response::Packet(Packet::Auth(Auth {
+                reason_code: ReasonCode::Success,
+                authentication: Authentication { method, data },
+                ..
+            })) if method == SCRAM && data == server_final
+        ));
+    }
+
+    // The method must be the one of the connection
+    let packet = auth(ReasonCode::ReAuthenticate, "GS2-KRB5", Default::default());
+    client::send(&mut stream, packet).await;
+    let policy = DisPacket::Force(Some(ReasonCode::ProtocolError));
+    if let Some(what) = client::wait_close(stream, policy).await {
+        panic!("{}", what);
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.12.1-2: If the re-authentication fails, the Client or Server SHOULD
+/// send DISCONNECT with an appropriate Reason Code and MUST close the Network
+/// Connection.
+#[tokio::test]
+async fn mqtt_4_12_1_2() {
+    let (_, server, local_addr, shutdown) = server::spawn(scram_settings()).await;
+
+    let mut stream = scram_client(&local_addr).await;
+    let packet = auth(ReasonCode::ReAuthenticate, SCRAM, client_first("jaden"));
+    let server_first = continue_scram(&mut stream, packet).await;
+    let (client_final, _) = client_final("jaden", "public", &server_first);
+    let packet = auth(ReasonCode::ContinueAuthentication, SCRAM, client_final);
+    client::send(&mut stream, packet).await;
+    let policy = DisPacket::Force(Some(ReasonCode::NotAuthorized));
+    if let Some(what) = client::wait_close(stream, policy).await {
+        panic!("{}", what);
+    }
+
+    server::stop(shutdown, server).await;
+}
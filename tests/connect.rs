@@ -1,11 +1,17 @@
 //! CONNECT Actions requirements consists in all [MQTT 3.1.4-x] conformances.
 //! It also describes some elements from [MQTT 3.1.2-x].
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 use tokio::{net::TcpStream, task};
 
-use sage_broker::BrokerSettings;
-use sage_mqtt::{Connect, Packet, ReasonCode};
-use std::time::Instant;
+use sage_broker::{
+    auth::{PasswordFile, ScramSha256},
+    BrokerSettings,
+};
+use sage_mqtt::{
+    Auth, Authentication, Connect, Disconnect, Packet, Publish, QoS, ReasonCode, Subscribe,
+    SubscriptionOptions, Topic, Will,
+};
+use std::time::{Duration, Instant};
 pub mod utils;
 use utils::client::{DisPacket, Response};
 pub use utils::*;
@@ -35,7 +41,7 @@ async fn mqtt_3_1_2_4() {
     };
 
     // Let's do the same, forcing clean start to 1
-    mqtt_3_1_4_4_connect(&client_id, &local_addr, Some(true)).await;
+    let _stream = mqtt_3_1_4_4_connect(&client_id, &local_addr, Some(true)).await;
 
     // Wait for the first client to be closed by the server, which MUST
     // happen.
@@ -46,13 +52,15 @@ async fn mqtt_3_1_2_4() {
         panic!("{}", what);
     }
 
-    let sessions = sessions.read().unwrap();
-    assert_eq!(sessions.len(), 1); // We have 1 client exactly
-    let session = sessions.get(&client_id).unwrap();
+    {
+        let sessions = sessions.read().unwrap();
+        assert_eq!(sessions.len(), 1); // We have 1 client exactly
+        let session = sessions.get(&client_id).unwrap();
 
-    // Test: Client ID must be same but session id must be different
-    assert_eq!(session.client_id(), client_id);
-    assert_ne!(session_id, session.id());
+        // Test: Client ID must be same but session id must be different
+        assert_eq!(session.client_id(), client_id);
+        assert_ne!(session_id, session.id());
+    }
 
     server::stop(shutdown, server).await;
 }
@@ -81,7 +89,7 @@ async fn mqtt_3_1_2_5() {
     };
 
     // Let's do the same, forcing clean start to 0
-    mqtt_3_1_4_4_connect(&client_id, &local_addr, Some(false)).await;
+    let _stream = mqtt_3_1_4_4_connect(&client_id, &local_addr, Some(false)).await;
 
     // The first client must have been disconnected by the server
     let policy = DisPacket::Ignore(Some(ReasonCode::SessionTakenOver));
@@ -89,13 +97,15 @@ async fn mqtt_3_1_2_5() {
         panic!("{}", what);
     }
 
-    let sessions = sessions.read().unwrap();
-    assert_eq!(sessions.len(), 1); // Because previous session was taken over
-    let session = sessions.get(&client_id).unwrap();
+    {
+        let sessions = sessions.read().unwrap();
+        assert_eq!(sessions.len(), 1); // Because previous session was taken over
+        let session = sessions.get(&client_id).unwrap();
 
-    // Test: Client ID and session ID must be same
-    assert_eq!(session.client_id(), client_id);
-    assert_eq!(session_id, session.id());
+        // Test: Client ID and session ID must be same
+        assert_eq!(session.client_id(), client_id);
+        assert_eq!(session_id, session.id());
+    }
 
     server::stop(shutdown, server).await;
 }
@@ -113,7 +123,7 @@ async fn mqtt_3_1_2_6() {
     let second_client_id = String::from("Jarod");
 
     // First, we connect a client with a fixed id and wait for ACK
-    mqtt_3_1_4_4_connect(&first_client_id, &local_addr, None).await;
+    let _first = mqtt_3_1_4_4_connect(&first_client_id, &local_addr, None).await;
 
     let session_id = {
         // Search db for the current connexion
@@ -125,16 +135,259 @@ async fn mqtt_3_1_2_6() {
     };
 
     // Let's do the same, forcing clean start to 0
-    mqtt_3_1_4_4_connect(&second_client_id, &local_addr, Some(false)).await;
+    let _second = mqtt_3_1_4_4_connect(&second_client_id, &local_addr, Some(false)).await;
+
+    {
+        let sessions = sessions.read().unwrap();
+        assert_eq!(sessions.len(), 2); // We have 2 client exactly
+        let session = sessions.get(&second_client_id).unwrap();
+
+        // Test: Client ID must be same but session id must be different
+        assert_eq!(session.client_id(), second_client_id);
+        assert_ne!(session.client_id(), first_client_id);
+        assert_ne!(session.id(), session_id);
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+/// Connects a client with the given will message and session expiry interval
+async fn will_client(
+    local_addr: &SocketAddr,
+    client_id: &str,
+    will: Will,
+    session_expiry_interval: Option<u32>,
+) -> TcpStream {
+    let connect = Connect {
+        client_id: Some(client_id.into()),
+        will: Some(will),
+        session_expiry_interval,
+        ..Default::default()
+    };
+    client::connect(local_addr, connect).await.0
+}
+
+/// Builds a will message for the given topic and message, without delay
+fn will(topic: &str, message: &str) -> Will {
+    Will {
+        delay_interval: 0,
+        ..Will::with_message(Topic::from(topic), message)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.1.2-8: The Will Message MUST be published after the Network
+/// Connection is subsequently closed and either the Will Delay Interval has
+/// elapsed or the Session ends, unless the Will Message has been deleted by
+/// the Server on receipt of a DISCONNECT packet with Reason Code 0x00 (Normal
+/// disconnection) or a new Network Connection for the ClientID is opened
+/// before the Will Delay Interval has elapsed.
+/// MQTT-3.1.2-7: If the Will Flag is set to 1 this indicates that, a Will
+/// Message MUST be stored on the Server and associated with the Session.
+#[tokio::test]
+async fn mqtt_3_1_2_8() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut subscriber =
+        client::subscriber(&local_addr, "Jarod", "devices/+/status", QoS::AtMostOnce).await;
+
+    // The session ends with the connection: no delay
+    let stream = will_client(
+        &local_addr,
+        "Jaden",
+        will("devices/kettle/status", "offline"),
+        None,
+    )
+    .await;
+    drop(stream);
+    let publish = client::receive_publish(&mut subscriber).await;
+    assert_eq!(publish.topic_name, Topic::from("devices/kettle/status"));
+    assert_eq!(publish.message, b"offline");
+
+    // The will is published after its delay
+    let will = Will {
+        delay_interval: 2,
+        ..will("devices/toaster/status", "offline")
+    };
+    let stream = will_client(&local_addr, "Jason", will, Some(60)).await;
+    drop(stream);
+    let start = Instant::now();
+    let publish = client::receive_publish(&mut subscriber).await;
+    assert!(start.elapsed().as_secs() >= 1);
+    assert_eq!(publish.topic_name, Topic::from("devices/toaster/status"));
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.1.2-10: The Will Message MUST be removed from the stored Session
+/// State in the Server once it has been published or the Server has received
+/// a DISCONNECT packet with a Reason Code of 0x00 (Normal disconnection) from
+/// the Client.
+#[tokio::test]
+async fn mqtt_3_1_2_10() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut subscriber =
+        client::subscriber(&local_addr, "Jarod", "devices/+/status", QoS::AtMostOnce).await;
+
+    for (client_id, reason_code) in [
+        ("Jaden", ReasonCode::Success),
+        ("Jason", ReasonCode::DisconnectWithWillMessage),
+    ] {
+        let topic = format!("devices/{}/status", client_id);
+        let mut stream = will_client(&local_addr, client_id, will(&topic, "offline"), None).await;
+        let disconnect = Disconnect {
+            reason_code,
+            ..Default::default()
+        };
+        client::send(&mut stream, disconnect.into()).await;
+        if let Some(what) = client::wait_close(stream, DisPacket::Forbid).await {
+            panic!("{}", what);
+        }
+    }
+
+    // Only the will of the second client is published
+    let publish = client::receive_publish(&mut subscriber).await;
+    assert_eq!(publish.topic_name, Topic::from("devices/Jason/status"));
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.1.2-15: If the Will Flag is set to 1 and Will Retain is set to 1,
+/// the Server MUST publish the Will Message as a retained message.
+#[tokio::test]
+async fn mqtt_3_1_2_15() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let will = Will {
+        retain: true,
+        ..will("devices/kettle/status", "offline")
+    };
+    drop(will_client(&local_addr, "Jaden", will, None).await);
+
+    // Once published, the will is received as retained message by a later
+    // subscriber
+    let mut live =
+        client::subscriber(&local_addr, "Jarod", "devices/+/status", QoS::AtMostOnce).await;
+    client::receive_publish(&mut live).await;
+    let mut subscriber =
+        client::subscriber(&local_addr, "Jason", "devices/+/status", QoS::AtMostOnce).await;
+    let publish = client::receive_publish(&mut subscriber).await;
+    assert!(publish.retain);
+    assert_eq!(publish.message, b"offline");
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.1.3-9: If a new Network Connection to this Session is made before
+/// the Will Delay Interval has passed, the Server MUST NOT send the Will
+/// Message.
+#[tokio::test]
+async fn mqtt_3_1_3_9() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut subscriber =
+        client::subscriber(&local_addr, "Jarod", "devices/+/status", QoS::AtMostOnce).await;
 
-    let sessions = sessions.read().unwrap();
-    assert_eq!(sessions.len(), 2); // We have 2 client exactly
-    let session = sessions.get(&second_client_id).unwrap();
+    let will = Will {
+        delay_interval: 2,
+        ..will("devices/kettle/status", "offline")
+    };
+    drop(will_client(&local_addr, "Jaden", will, Some(60)).await);
 
-    // Test: Client ID must be same but session id must be different
-    assert_eq!(session.client_id(), second_client_id);
-    assert_ne!(session.client_id(), first_client_id);
-    assert_ne!(session.id(), session_id);
+    // Reconnect before the delay elapsed and wait for it to be over
+    let connect = Connect {
+        client_id: Some("Jaden".into()),
+        session_expiry_interval: Some(60),
+        ..Default::default()
+    };
+    let (mut stream, _) = client::connect(&local_addr, connect).await;
+    tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+
+    let publish = Publish {
+        topic_name: Topic::from("devices/kettle/status"),
+        message: b"online".to_vec(),
+        ..Default::default()
+    };
+    client::send(&mut stream, publish.into()).await;
+    assert_eq!(
+        client::receive_publish(&mut subscriber).await.message,
+        b"online"
+    );
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.2.2-12: If a Server receives a CONNECT packet containing a Will QoS
+/// that exceeds its capabilities, it MUST reject the connection. It SHOULD
+/// use a CONNACK packet with Reason Code 0x9B (QoS not supported).
+/// MQTT-3.2.2-13: If a Server receives a CONNECT packet containing a Will
+/// Message with the Will Retain 1, and it does not support retained messages,
+/// the Server MUST reject the connection request. It SHOULD send CONNACK with
+/// Reason Code 0x9A (Retain not supported) and then it MUST close the Network
+/// Connection.
+#[tokio::test]
+async fn mqtt_3_2_2_12() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        maximum_qos: QoS::AtLeastOnce,
+        retain_enabled: false,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    for (will, reason_code) in [
+        (
+            Will {
+                qos: QoS::ExactlyOnce,
+                ..will("devices/kettle/status", "offline")
+            },
+            ReasonCode::QoSNotSupported,
+        ),
+        (
+            Will {
+                retain: true,
+                ..will("devices/kettle/status", "offline")
+            },
+            ReasonCode::RetainNotSupported,
+        ),
+    ] {
+        let mut stream = client::spawn(&local_addr).await;
+        let connect = Connect {
+            will: Some(will),
+            ..Default::default()
+        };
+        if let Response::Packet(Packet::ConnAck(connack)) =
+            client::send_waitback(&mut stream, connect.into()).await
+        {
+            assert_eq!(connack.reason_code, reason_code);
+        } else {
+            panic!("Expected CONNACK after CONNECT");
+        }
+        if let Some(what) = client::wait_close(stream, DisPacket::Forbid).await {
+            panic!("{}", what);
+        }
+    }
 
     server::stop(shutdown, server).await;
 }
@@ -240,12 +493,23 @@ async fn mqtt_3_1_4_2() {
             ReasonCode::BadAuthenticationMethod,
         ),
         (
-            settings.clone(),
+            BrokerSettings {
+                authenticator: Some(Arc::new(PasswordFile::default())),
+                ..settings.clone()
+            },
             Connect {
                 user_name: Some("Thanos".into()),
                 ..Default::default()
             },
-            ReasonCode::BadAuthenticationMethod,
+            ReasonCode::BadUserNameOrPassword,
+        ),
+        (
+            BrokerSettings {
+                authenticator: Some(Arc::new(PasswordFile::default())),
+                ..settings.clone()
+            },
+            Default::default(),
+            ReasonCode::NotAuthorized,
         ),
     ];
 
@@ -337,7 +601,7 @@ async fn mqtt_3_1_4_4_connect(
     };
 
     // First, we connect a client with a fixed id and wait for ACK
-    let mut stream = client::spawn(&local_addr).await;
+    let mut stream = client::spawn(local_addr).await;
     if let Response::Packet(Packet::ConnAck(packet)) =
         client::send_waitback(&mut stream, connect.into()).await
     {
@@ -380,11 +644,15 @@ async fn mqtt_3_1_4_5() {
 ///////////////////////////////////////////////////////////////////////////////
 /// MQTT-3.1.4-6: If the Server rejects the CONNECT, it MUST NOT process any
 /// data sent by the Client after the CONNECT packet except AUTH packets.
-/// NOTE: Currently AUTH packet is not accepted either
+/// AUTH packets are only processed while the CONNECT packet is being
+/// authenticated.
 #[tokio::test]
 async fn mqtt_3_1_4_6() {
+    let mut passwords = PasswordFile::default();
+    passwords.insert("Jaden", "secret");
     let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
         keep_alive: TIMEOUT_DELAY,
+        authentication_mechanisms: vec![Arc::new(ScramSha256::new(Arc::new(passwords)))],
         ..BrokerSettings::valid_default()
     })
     .await;
@@ -410,7 +678,8 @@ async fn mqtt_3_1_4_6() {
     for second_packet in packets {
         let mut stream = client::spawn(&local_addr).await;
 
-        // Auth not supported, so we can reject a packet by providing one
+        // The method is not supported, so we can reject a packet by providing
+        // one
         let rejected_connect = Connect {
             authentication: Some(Default::default()),
             ..Default::default()
@@ -435,5 +704,320 @@ async fn mqtt_3_1_4_6() {
         ));
     }
 
+    // An AUTH packet continues the authentication of the CONNECT packet
+    let mut stream = client::spawn(&local_addr).await;
+    let authentication = Authentication {
+        method: "SCRAM-SHA-256".into(),
+        data: b"n,,n=Jaden,r=rOprNGfwEbeRWgbNEkqO".to_vec(),
+    };
+    let connect = Connect {
+        authentication: Some(authentication.clone()),
+        ..Default::default()
+    };
+    assert!(matches!(
+        client::send_waitback(&mut stream, connect.into()).await,
+        Response::Packet(Packet::Auth(_))
+    ));
+    let auth = Auth {
+        reason_code: ReasonCode::ContinueAuthentication,
+        authentication: Authentication {
+            data: b"c=biws,r=rOprNGfwEbeRWgbNEkqO,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+                .to_vec(),
+            ..authentication
+        },
+        ..Default::default()
+    };
+    if let Response::Packet(Packet::ConnAck(connack)) =
+        client::send_waitback(&mut stream, auth.into()).await
+    {
+        assert_eq!(connack.reason_code, ReasonCode::NotAuthorized);
+    } else {
+        panic!("Expected CONNACK after AUTH");
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Subscriptions are part of the Session State: they survive a takeover with
+/// Clean Start set to 0 and are discarded with Clean Start set to 1
+/// [MQTT-3.1.2-4] [MQTT-3.1.2-5].
+#[tokio::test]
+async fn session_subscriptions_follow_clean_start() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let client_id = String::from("Jaden");
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    let publish = |name: &str| {
+        Packet::from(Publish {
+            topic_name: Topic::from(name),
+            ..Default::default()
+        })
+    };
+
+    let mut stream = mqtt_3_1_4_4_connect(&client_id, &local_addr, None).await;
+    let subscribe = Subscribe {
+        subscriptions: vec![(Topic::from("sport/#"), Default::default())],
+        ..Default::default()
+    };
+    assert!(matches!(
+        client::send_waitback(&mut stream, subscribe.into()).await,
+        Response::Packet(Packet::SubAck(_))
+    ));
+
+    // The session is resumed: the subscription still applies
+    let mut stream = mqtt_3_1_4_4_connect(&client_id, &local_addr, Some(false)).await;
+    client::send(&mut publisher, publish("sport/tennis")).await;
+    if let Response::Packet(Packet::Publish(packet)) = client::receive(&mut stream).await {
+        assert_eq!(packet.topic_name, Topic::from("sport/tennis"));
+    } else {
+        panic!("Expected PUBLISH packet on resumed session");
+    }
+
+    // The session is discarded: only the new subscription applies
+    let mut stream = mqtt_3_1_4_4_connect(&client_id, &local_addr, Some(true)).await;
+    let subscribe = Subscribe {
+        subscriptions: vec![(Topic::from("news"), Default::default())],
+        ..Default::default()
+    };
+    assert!(matches!(
+        client::send_waitback(&mut stream, subscribe.into()).await,
+        Response::Packet(Packet::SubAck(_))
+    ));
+    client::send(&mut publisher, publish("sport/tennis")).await;
+    client::send(&mut publisher, publish("news")).await;
+    if let Response::Packet(Packet::Publish(packet)) = client::receive(&mut stream).await {
+        assert_eq!(packet.topic_name, Topic::from("news"));
+    } else {
+        panic!("Expected PUBLISH packet on new session");
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+/// Connects a client with the given session expiry interval
+async fn expiring_client(
+    local_addr: &SocketAddr,
+    client_id: &str,
+    session_expiry_interval: Option<u32>,
+) -> TcpStream {
+    let connect = Connect {
+        client_id: Some(client_id.into()),
+        session_expiry_interval,
+        ..Default::default()
+    };
+    client::connect(local_addr, connect).await.0
+}
+
+/// Waits for the server to handle the end of a network connection, which
+/// takes up to one second once the server closed it.
+async fn wait_session_state() {
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.1.2-23: The Client and Server MUST store the Session State after the
+/// Network Connection is closed if the Session Expiry Interval is greater than
+/// 0.
+#[tokio::test]
+async fn mqtt_3_1_2_23() {
+    let (sessions, server, local_addr, shutdown) =
+        server::spawn(BrokerSettings::valid_default()).await;
+
+    drop(expiring_client(&local_addr, "Jaden", None).await);
+    drop(expiring_client(&local_addr, "Jarod", Some(60)).await);
+    wait_session_state().await;
+
+    {
+        let sessions = sessions.read().unwrap();
+        assert!(sessions.get("Jaden").is_none());
+        assert!(sessions.get("Jarod").is_some());
+    }
+
+    // The stored session is resumed
+    let mut stream = client::spawn(&local_addr).await;
+    let connect = Connect {
+        client_id: Some("Jarod".into()),
+        clean_start: false,
+        ..Default::default()
+    };
+    if let Response::Packet(Packet::ConnAck(connack)) =
+        client::send_waitback(&mut stream, connect.into()).await
+    {
+        assert!(connack.session_present);
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.1.0-2: The Server MUST discard the Session State when the Network
+/// Connection is closed and the Session Expiry Interval has passed.
+#[tokio::test]
+async fn mqtt_4_1_0_2() {
+    let (sessions, server, local_addr, shutdown) =
+        server::spawn(BrokerSettings::valid_default()).await;
+
+    drop(client::subscriber(&local_addr, "Jaden", "sport/#", QoS::AtLeastOnce).await);
+    let mut stream = expiring_client(&local_addr, "Jarod", Some(3)).await;
+    client::subscribe(&mut stream, "sport/#", Default::default()).await;
+    drop(stream);
+    wait_session_state().await;
+    assert!(sessions.read().unwrap().get("Jarod").is_some());
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    {
+        let sessions = sessions.read().unwrap();
+        assert!(sessions.get("Jarod").is_none());
+        assert!(sessions
+            .subscribers(&Topic::from("sport/tennis"))
+            .is_empty());
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// The Session Expiry Interval of a DISCONNECT packet replaces the one of the
+/// CONNECT packet, except from zero which is a Protocol Error [MQTT 3.14.2.2.2]
+#[tokio::test]
+async fn disconnect_session_expiry_interval() {
+    let (sessions, server, local_addr, shutdown) =
+        server::spawn(BrokerSettings::valid_default()).await;
+
+    // DISCONNECT with a Session Expiry Interval of 0, which `sage_mqtt`
+    // leaves out when encoding
+    let mut stream = expiring_client(&local_addr, "Jaden", Some(60)).await;
+    let buffer = vec![0xE0, 0x07, 0x00, 0x05, 0x11, 0x00, 0x00, 0x00, 0x00];
+    assert!(matches!(
+        client::send_waitback_data(&mut stream, buffer).await,
+        Response::Close
+    ));
+
+    let mut stream = expiring_client(&local_addr, "Jarod", None).await;
+    let disconnect = Disconnect {
+        session_expiry_interval: Some(60),
+        ..Default::default()
+    };
+    client::send(&mut stream, disconnect.into()).await;
+    let policy = DisPacket::Force(Some(ReasonCode::ProtocolError));
+    if let Some(what) = client::wait_close(stream, policy).await {
+        panic!("{}", what);
+    }
+
+    wait_session_state().await;
+    assert_eq!(sessions.read().unwrap().len(), 0);
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.2.2-15: The Client MUST NOT send packets exceeding Maximum Packet
+/// Size to the Server.
+/// The Server disconnects such a client with Reason Code 0x95 (Packet too
+/// large).
+#[tokio::test]
+async fn mqtt_3_2_2_15() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        maximum_packet_size: Some(64),
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut stream = client::spawn(&local_addr).await;
+    if let Response::Packet(Packet::ConnAck(connack)) =
+        client::send_waitback(&mut stream, Connect::default().into()).await
+    {
+        assert_eq!(connack.maximum_packet_size, Some(64));
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+
+    let publish = Publish {
+        topic_name: Topic::from("sport/tennis"),
+        message: vec![0; 64],
+        ..Default::default()
+    };
+    if let Response::Packet(Packet::Disconnect(disconnect)) =
+        client::send_waitback(&mut stream, publish.into()).await
+    {
+        assert_eq!(disconnect.reason_code, ReasonCode::PacketTooLarge);
+    } else {
+        panic!("Expected DISCONNECT after packet too large");
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.1.2-24: The Server MUST NOT send packets exceeding Maximum Packet
+/// Size to the Client.
+/// MQTT-3.1.2-25: Where a Packet is too large to send, the Server MUST discard
+/// it without sending it and then behave as if it had completed sending that
+/// Application Message.
+#[tokio::test]
+async fn mqtt_3_1_2_24() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let subscriber = |clean_start| Connect {
+        client_id: Some("Jaden".into()),
+        clean_start,
+        session_expiry_interval: Some(60),
+        maximum_packet_size: Some(64),
+        ..Default::default()
+    };
+    let (mut stream, _) = client::connect(&local_addr, subscriber(true)).await;
+    let options = SubscriptionOptions {
+        qos: QoS::AtLeastOnce,
+        ..Default::default()
+    };
+    client::subscribe(&mut stream, "sport/#", options).await;
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    let publish = |message: &str| {
+        Packet::from(Publish {
+            topic_name: Topic::from("sport/tennis"),
+            message: message.as_bytes().to_vec(),
+            ..Default::default()
+        })
+    };
+    let large = Publish {
+        qos: QoS::AtLeastOnce,
+        packet_identifier: Some(1),
+        topic_name: Topic::from("sport/tennis"),
+        message: vec![0; 64],
+        ..Default::default()
+    };
+    client::send_waitback(&mut publisher, large.into()).await;
+    client::send(&mut publisher, publish("small")).await;
+    assert_eq!(client::receive_publish(&mut stream).await.message, b"small");
+
+    // The large message is not sent again once the session is resumed
+    drop(stream);
+    let mut stream = client::spawn(&local_addr).await;
+    if let Response::Packet(Packet::ConnAck(connack)) =
+        client::send_waitback(&mut stream, subscriber(false).into()).await
+    {
+        assert!(connack.session_present);
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+    client::send(&mut publisher, publish("resumed")).await;
+    assert_eq!(
+        client::receive_publish(&mut stream).await.message,
+        b"resumed"
+    );
+
     server::stop(shutdown, server).await;
 }
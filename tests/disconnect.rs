@@ -0,0 +1,113 @@
+//! DISCONNECT Actions requirements
+use sage_broker::BrokerSettings;
+use sage_mqtt::{Connect, Disconnect, Packet, QoS, ReasonCode, Topic, Will};
+pub mod utils;
+
+use utils::client::{DisPacket, Response};
+pub use utils::*;
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.14.1-1: The Client or Server MUST validate that reserved bits are set to 0. If they are
+/// not zero it sends a DISCONNECT packet with a Reason code of 0x81 (Malformed Packet).
+#[tokio::test]
+async fn mqtt_3_14_1_1() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut stream, _) = client::connect(&local_addr, Default::default()).await;
+
+    let mut buffer = Vec::new();
+    Packet::Disconnect(Default::default())
+        .encode(&mut buffer)
+        .await
+        .unwrap();
+    buffer[0] |= 0b0000_0001; // Set a reserved bit
+
+    if let Response::Packet(Packet::Disconnect(packet)) =
+        client::send_waitback_data(&mut stream, buffer).await
+    {
+        assert_eq!(packet.reason_code, ReasonCode::MalformedPacket);
+    } else {
+        panic!("Expected DISCONNECT after malformed DISCONNECT");
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// On receipt of a DISCONNECT packet, the server closes the network connection
+/// without sending anything back, whatever the reason code [MQTT 3.14.4].
+#[tokio::test]
+async fn disconnect_closes_connection() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    for reason_code in [
+        ReasonCode::Success,
+        ReasonCode::DisconnectWithWillMessage,
+        ReasonCode::UnspecifiedError,
+    ] {
+        let (mut stream, _) = client::connect(&local_addr, Default::default()).await;
+        let disconnect = Disconnect {
+            reason_code,
+            ..Default::default()
+        };
+        client::send(&mut stream, disconnect.into()).await;
+        if let Some(what) = client::wait_close(stream, DisPacket::Forbid).await {
+            panic!("{}", what);
+        }
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// If the Session Expiry Interval in the CONNECT packet was zero, it is a
+/// Protocol Error to set a non-zero Session Expiry Interval in the DISCONNECT
+/// packet sent by the Client [MQTT 3.14.2.2.2]. The server closes the
+/// connection with a Protocol Error: the session ends and its will message is
+/// published.
+#[tokio::test]
+async fn non_zero_expiry_on_ending_session() {
+    let (sessions, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut subscriber =
+        client::subscriber(&local_addr, "Jarod", "devices/+/status", QoS::AtMostOnce).await;
+
+    let connect = Connect {
+        client_id: Some("Jaden".into()),
+        will: Some(Will {
+            delay_interval: 0,
+            ..Will::with_message(Topic::from("devices/kettle/status"), "offline")
+        }),
+        session_expiry_interval: Some(0),
+        ..Default::default()
+    };
+    let (mut stream, _) = client::connect(&local_addr, connect).await;
+
+    let disconnect = Disconnect {
+        session_expiry_interval: Some(60),
+        ..Default::default()
+    };
+    client::send(&mut stream, disconnect.into()).await;
+    let policy = DisPacket::Force(Some(ReasonCode::ProtocolError));
+    if let Some(what) = client::wait_close(stream, policy).await {
+        panic!("{}", what);
+    }
+
+    let publish = client::receive_publish(&mut subscriber).await;
+    assert_eq!(publish.topic_name, Topic::from("devices/kettle/status"));
+    assert!(sessions.read().unwrap().get("Jaden").is_none());
+
+    server::stop(shutdown, server).await;
+}
@@ -0,0 +1,179 @@
+//! Several listeners can share the same command loop, each serving its
+//! clients with its own settings.
+use sage_broker::{
+    auth::PasswordFile, service, BrokerSettings, CommandReceiver, Sessions, Trigger,
+};
+use sage_mqtt::{
+    Connect, Packet, Publish, QoS, ReasonCode, SubAck, Subscribe, SubscriptionOptions, Topic,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::{self, JoinHandle},
+    time,
+};
+pub mod utils;
+
+use utils::client::Response;
+pub use utils::*;
+
+/// Spawns a server with a permissive internal listener and a restricted
+/// public one, returning their addresses. Only the `jaden` user, whose
+/// password is `secret`, can connect to the public listener, with a QoS of 1
+/// at most, and only one client at a time.
+async fn spawn() -> (JoinHandle<CommandReceiver>, SocketAddr, SocketAddr, Trigger) {
+    let internal = BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    };
+    let mut passwords = PasswordFile::default();
+    passwords.insert("jaden", "secret");
+    let public = BrokerSettings {
+        maximum_qos: QoS::AtLeastOnce,
+        max_connections: Some(1),
+        authenticator: Some(Arc::new(passwords)),
+        ..internal.clone()
+    };
+
+    let internal_listener = TcpListener::bind("localhost:0").await.unwrap();
+    let public_listener = TcpListener::bind("localhost:0").await.unwrap();
+    let internal_addr = internal_listener.local_addr().unwrap();
+    let public_addr = public_listener.local_addr().unwrap();
+    let shutdown = Trigger::default();
+
+    let server = task::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            let internal = Arc::new(internal);
+            let (command_sender, command_receiver) = mpsc::unbounded_channel();
+            let command_loop = task::spawn(service::command_loop(
+                internal.clone(),
+                Arc::new(RwLock::new(Sessions::default())),
+                command_receiver,
+                shutdown.clone(),
+            ));
+            let public = task::spawn(service::listen_tcp(
+                public_listener,
+                command_sender.clone(),
+                Arc::new(public),
+                shutdown.clone(),
+            ));
+            service::listen_tcp(internal_listener, command_sender, internal, shutdown).await;
+            public.await.unwrap();
+            command_loop.await.unwrap()
+        }
+    });
+    (server, internal_addr, public_addr, shutdown)
+}
+
+/// Sends a CONNECT packet and returns the stream along with the reason code
+/// of the CONNACK
+async fn connect(
+    local_addr: &SocketAddr,
+    client_id: &str,
+    password: Option<&str>,
+) -> (TcpStream, ReasonCode) {
+    let mut stream = client::spawn(local_addr).await;
+    let connect = Connect {
+        client_id: Some(client_id.into()),
+        user_name: password.map(|_| "jaden".into()),
+        password: password.map(|password| password.as_bytes().to_vec()),
+        ..Default::default()
+    };
+    if let Response::Packet(Packet::ConnAck(connack)) =
+        client::send_waitback(&mut stream, connect.into()).await
+    {
+        (stream, connack.reason_code)
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+}
+
+/// Subscribes with QoS 2 and returns the reason code of the SUBACK
+async fn subscribe(stream: &mut TcpStream, filter: &str) -> ReasonCode {
+    let subscribe = Subscribe {
+        subscriptions: vec![(
+            Topic::from(filter),
+            SubscriptionOptions {
+                qos: QoS::ExactlyOnce,
+                ..Default::default()
+            },
+        )],
+        ..Default::default()
+    };
+    if let Response::Packet(Packet::SubAck(SubAck { reason_codes, .. })) =
+        client::send_waitback(stream, subscribe.into()).await
+    {
+        reason_codes[0]
+    } else {
+        panic!("Expected SUBACK packet");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Each listener authenticates its clients and grants subscriptions with its
+/// own settings, while the messages flow between the clients of all the
+/// listeners.
+#[tokio::test]
+async fn settings_per_listener() {
+    let (server, internal_addr, public_addr, shutdown) = spawn().await;
+
+    let (_, reason_code) = connect(&public_addr, "Anonymous", None).await;
+    assert_eq!(reason_code, ReasonCode::NotAuthorized);
+
+    let (mut public, reason_code) = connect(&public_addr, "Jaden", Some("secret")).await;
+    assert_eq!(reason_code, ReasonCode::Success);
+    assert_eq!(
+        subscribe(&mut public, "sport").await,
+        ReasonCode::GrantedQoS1
+    );
+
+    let (mut internal, reason_code) = connect(&internal_addr, "Sensor", None).await;
+    assert_eq!(reason_code, ReasonCode::Success);
+    assert_eq!(
+        subscribe(&mut internal, "news").await,
+        ReasonCode::GrantedQoS2
+    );
+
+    let publish = Publish {
+        topic_name: Topic::from("sport"),
+        message: "goal".into(),
+        ..Default::default()
+    };
+    client::send(&mut internal, publish.into()).await;
+    let publish = client::receive_publish(&mut public).await;
+    assert_eq!(publish.message, b"goal");
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// A listener closes the connections exceeding its maximum number of
+/// clients, others listeners being unaffected.
+#[tokio::test]
+async fn max_connections() {
+    let (server, internal_addr, public_addr, shutdown) = spawn().await;
+
+    let (_first, reason_code) = connect(&public_addr, "Jaden", Some("secret")).await;
+    assert_eq!(reason_code, ReasonCode::Success);
+
+    let mut second = client::spawn(&public_addr).await;
+    let mut buffer = [0u8; 16];
+    let read = time::timeout(
+        Duration::from_secs(TIMEOUT_DELAY as u64),
+        second.read(&mut buffer),
+    )
+    .await;
+    assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+
+    let (_internal, reason_code) = connect(&internal_addr, "Sensor", None).await;
+    assert_eq!(reason_code, ReasonCode::Success);
+
+    server::stop(shutdown, server).await;
+}
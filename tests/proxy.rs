@@ -0,0 +1,249 @@
+//! Listeners behind a load balancer can read the PROXY protocol header it
+//! sends ahead of each connection, serving the client with its own address
+//! and the TLS connection the balancer terminated.
+use futures::future::BoxFuture;
+use sage_broker::{
+    auth::{Authenticator, CertificateIdentity, Credentials, Principal},
+    BrokerSettings,
+};
+use sage_mqtt::{ConnAck, Connect, Packet, ReasonCode};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+pub mod utils;
+
+use utils::client::Response;
+pub use utils::*;
+
+/// The address of the client behind the load balancer
+const CLIENT_ADDR: &str = "203.0.113.7:4000";
+
+/// Authenticates the clients connecting from `CLIENT_ADDR` only
+#[derive(Debug)]
+struct ClientAddr;
+
+impl Authenticator for ClientAddr {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a Credentials,
+    ) -> BoxFuture<'a, Result<Principal, ReasonCode>> {
+        Box::pin(async move {
+            if credentials.addr.to_string() == CLIENT_ADDR {
+                Ok(Principal::new("client"))
+            } else {
+                Err(ReasonCode::NotAuthorized)
+            }
+        })
+    }
+}
+
+fn settings() -> BrokerSettings {
+    BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        authenticator: Some(Arc::new(ClientAddr)),
+        proxy_protocol: true,
+        trusted_proxies: vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()],
+        ..BrokerSettings::valid_default()
+    }
+}
+
+/// Builds a version 2 header relaying a TCP over IPv4 connection from
+/// `CLIENT_ADDR`, followed by the given TLVs
+fn v2_header(command: u8, tlvs: &[u8]) -> Vec<u8> {
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    header.extend_from_slice(&[0x20 | command, 0x11]);
+    header.extend_from_slice(&(12 + tlvs.len() as u16).to_be_bytes());
+    header.extend_from_slice(&[203, 0, 113, 7, 127, 0, 0, 1]);
+    header.extend_from_slice(&4000u16.to_be_bytes());
+    header.extend_from_slice(&1883u16.to_be_bytes());
+    header.extend_from_slice(tlvs);
+    header
+}
+
+fn tlv(kind: u8, value: &[u8]) -> Vec<u8> {
+    let mut tlv = vec![kind];
+    tlv.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    tlv.extend_from_slice(value);
+    tlv
+}
+
+/// Asserts the connection is closed without processing any packet
+async fn assert_closed(stream: &mut TcpStream) {
+    // The unread CONNECT packet may reset the connection instead of closing it
+    let mut buffer = [0u8; 16];
+    let delay = Duration::from_secs(TIMEOUT_DELAY as u64);
+    let read = time::timeout(delay, stream.read(&mut buffer))
+        .await
+        .expect("Connection not closed");
+    assert!(matches!(read, Ok(0) | Err(_)), "Unexpected response");
+}
+
+/// Connects a client which sends the given header before its CONNECT packet,
+/// returning its stream and the CONNACK packet
+async fn connect(local_addr: &SocketAddr, header: &[u8]) -> (TcpStream, ConnAck) {
+    let mut stream = client::spawn(local_addr).await;
+    stream.write_all(header).await.unwrap();
+    let connect = Connect {
+        client_id: Some("BehindTheBalancer".into()),
+        ..Default::default()
+    };
+    if let Response::Packet(Packet::ConnAck(connack)) =
+        client::send_waitback(&mut stream, connect.into()).await
+    {
+        (stream, connack)
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// The address of a version 1 header replaces the one of the balancer.
+#[tokio::test]
+async fn proxy_v1() {
+    let (_, server, local_addr, shutdown) = server::spawn(settings()).await;
+
+    let (_stream, connack) = connect(
+        &local_addr,
+        b"PROXY TCP4 203.0.113.7 127.0.0.1 4000 1883\r\n",
+    )
+    .await;
+    assert_eq!(connack.reason_code, ReasonCode::Success);
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// The verified certificate of the TLS connection the balancer terminated
+/// identifies the client, according to the certificate identity, if the
+/// certificates of proxies are trusted.
+#[tokio::test]
+async fn proxy_v2_certificate() {
+    let settings = BrokerSettings {
+        certificate_identity: CertificateIdentity::ClientId,
+        trust_proxy_certificates: true,
+        ..settings()
+    };
+    let (_, server, local_addr, shutdown) = server::spawn(settings).await;
+
+    // Client connected over TLS with a verified certificate
+    let mut ssl = vec![0x01 | 0x02, 0, 0, 0, 0];
+    ssl.extend(tlv(0x21, b"TLSv1.3"));
+    ssl.extend(tlv(0x22, b"kettle"));
+    let (_stream, connack) = connect(&local_addr, &v2_header(0x01, &tlv(0x20, &ssl))).await;
+    assert_eq!(connack.reason_code, ReasonCode::Success);
+    assert_eq!(connack.assigned_client_id.as_deref(), Some("kettle"));
+
+    // Client connected without TLS
+    let (_stream, connack) = connect(&local_addr, &v2_header(0x01, &[])).await;
+    assert_eq!(connack.reason_code, ReasonCode::NotAuthorized);
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Unless the certificates of proxies are trusted, the common name a balancer
+/// tells does not identify the client.
+#[tokio::test]
+async fn proxy_v2_untrusted_certificate() {
+    let settings = BrokerSettings {
+        certificate_identity: CertificateIdentity::ClientId,
+        ..settings()
+    };
+    let (_, server, local_addr, shutdown) = server::spawn(settings).await;
+
+    let mut ssl = vec![0x01 | 0x02, 0, 0, 0, 0];
+    ssl.extend(tlv(0x22, b"kettle"));
+    let (_stream, connack) = connect(&local_addr, &v2_header(0x01, &tlv(0x20, &ssl))).await;
+    assert_eq!(connack.reason_code, ReasonCode::NotAuthorized);
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// The LOCAL connections of the balancer itself keep its address.
+#[tokio::test]
+async fn proxy_v2_local() {
+    let (_, server, local_addr, shutdown) = server::spawn(settings()).await;
+
+    let (_stream, connack) = connect(&local_addr, &v2_header(0x00, &[])).await;
+    assert_eq!(connack.reason_code, ReasonCode::NotAuthorized);
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// A connection which does not start with a valid header is closed before
+/// any packet is processed.
+#[tokio::test]
+async fn missing_header() {
+    let (_, server, local_addr, shutdown) = server::spawn(settings()).await;
+
+    let mut stream = client::spawn(&local_addr).await;
+    let connect = Connect {
+        client_id: Some("BehindTheBalancer".into()),
+        ..Default::default()
+    };
+    client::send(&mut stream, connect.into()).await;
+    assert_closed(&mut stream).await;
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// A connection which does not come from a trusted proxy is closed, even with
+/// a valid header.
+#[tokio::test]
+async fn untrusted_proxy() {
+    let settings = BrokerSettings {
+        trusted_proxies: vec![IpAddr::from([192, 0, 2, 1])],
+        ..settings()
+    };
+    let (_, server, local_addr, shutdown) = server::spawn(settings).await;
+
+    let mut stream = client::spawn(&local_addr).await;
+    stream
+        .write_all(b"PROXY TCP4 203.0.113.7 127.0.0.1 4000 1883\r\n")
+        .await
+        .unwrap();
+    let connect = Connect {
+        client_id: Some("BehindTheBalancer".into()),
+        ..Default::default()
+    };
+    client::send(&mut stream, connect.into()).await;
+    assert_closed(&mut stream).await;
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Without the PROXY protocol, the address of the balancer is kept.
+#[tokio::test]
+async fn proxy_protocol_disabled() {
+    let settings = BrokerSettings {
+        proxy_protocol: false,
+        ..settings()
+    };
+    let (_, server, local_addr, shutdown) = server::spawn(settings).await;
+
+    let mut stream = client::spawn(&local_addr).await;
+    let connect = Connect {
+        client_id: Some("BehindTheBalancer".into()),
+        ..Default::default()
+    };
+    if let Response::Packet(Packet::ConnAck(connack)) =
+        client::send_waitback(&mut stream, connect.into()).await
+    {
+        assert_eq!(connack.reason_code, ReasonCode::NotAuthorized);
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+
+    server::stop(shutdown, server).await;
+}
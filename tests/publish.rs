@@ -0,0 +1,1077 @@
+//! PUBLISH Actions requirements consists in all [MQTT 3.3.4-x] conformances.
+//! It also describes some elements from the QoS delivery protocols [MQTT 4.3.x-x]
+use sage_broker::BrokerSettings;
+use sage_mqtt::{
+    Connect, Packet, PubAck, PubComp, PubRec, PubRel, Publish, QoS, ReasonCode, RetainHandling,
+    Subscribe, SubscriptionOptions, Topic,
+};
+use tokio::net::TcpStream;
+pub mod utils;
+
+use utils::client::Response;
+pub use utils::*;
+
+/// Publishes a retained message with QoS 1 and waits for its PUBACK, making
+/// sure the message is stored before going further.
+async fn retain(stream: &mut TcpStream, name: &str, message: &str) {
+    let publish = Publish {
+        qos: QoS::AtLeastOnce,
+        retain: true,
+        packet_identifier: Some(1),
+        topic_name: Topic::from(name),
+        message: message.as_bytes().to_vec(),
+        ..Default::default()
+    };
+    assert!(matches!(
+        client::send_waitback(stream, publish.into()).await,
+        Response::Packet(Packet::PubAck(_))
+    ));
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.3.1-5: If the RETAIN flag is set to 1 in a PUBLISH packet sent by a
+/// Client to a Server, the Server MUST replace any existing retained message
+/// for this topic and store the Application Message.
+/// MQTT-3.3.1-8: If the RETAIN flag is 0 in a PUBLISH packet sent by a Client
+/// to a Server, the Server MUST NOT store the message as a retained message
+/// and MUST NOT remove or replace any existing retained message.
+#[tokio::test]
+async fn mqtt_3_3_1_5() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    retain(&mut publisher, "devices/kettle", "off").await;
+    retain(&mut publisher, "devices/kettle", "on").await;
+    let publish = Publish {
+        qos: QoS::AtLeastOnce,
+        packet_identifier: Some(1),
+        topic_name: Topic::from("devices/kettle"),
+        message: b"boiling".to_vec(),
+        ..Default::default()
+    };
+    client::send_waitback(&mut publisher, publish.into()).await;
+
+    let mut stream = client::subscriber(&local_addr, "Jaden", "devices/+", QoS::AtMostOnce).await;
+    let publish = client::receive_publish(&mut stream).await;
+    assert!(publish.retain);
+    assert_eq!(publish.message, b"on");
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.3.1-6: If the Payload contains zero bytes it is processed normally
+/// by the Server but any retained message with the same topic name MUST be
+/// removed and any future subscribers for the topic will not receive a
+/// retained message.
+/// MQTT-3.3.1-7: A retained message with a Payload containing zero bytes MUST
+/// NOT be stored as a retained message on the Server.
+#[tokio::test]
+async fn mqtt_3_3_1_6() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    retain(&mut publisher, "devices/kettle", "on").await;
+    retain(&mut publisher, "devices/kettle", "").await;
+    retain(&mut publisher, "devices/toaster", "off").await;
+
+    // Only the toaster has a retained message
+    let mut stream = client::subscriber(&local_addr, "Jaden", "devices/+", QoS::AtMostOnce).await;
+    let publish = client::receive_publish(&mut stream).await;
+    assert_eq!(publish.topic_name, Topic::from("devices/toaster"));
+
+    // Further messages are not retained ones
+    retain(&mut publisher, "devices/kettle", "on").await;
+    let publish = client::receive_publish(&mut stream).await;
+    assert_eq!(publish.topic_name, Topic::from("devices/kettle"));
+    assert!(!publish.retain);
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.3.1-10: If Retain Handling is set to 1 then if the subscription did
+/// not exist, the Server MUST send all retained message matching the Topic
+/// Filter of the subscription to the Client, and if the subscription did
+/// exist the Server MUST NOT send the retained messages.
+/// MQTT-3.3.1-11: If Retain Handling is set to 2, the Server MUST NOT send
+/// the retained messages.
+#[tokio::test]
+async fn mqtt_3_3_1_10() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    retain(&mut publisher, "devices/kettle", "on").await;
+
+    let (mut first, _) = client::connect(&local_addr, Default::default()).await;
+    let (mut never, _) = client::connect(&local_addr, Default::default()).await;
+    for expected in [Some("on"), None] {
+        client::subscribe(
+            &mut first,
+            "devices/kettle",
+            SubscriptionOptions {
+                retain_handling: RetainHandling::OnFirstSubscribe,
+                ..Default::default()
+            },
+        )
+        .await;
+        if let Some(expected) = expected {
+            let publish = client::receive_publish(&mut first).await;
+            assert_eq!(publish.message, expected.as_bytes());
+        }
+
+        client::subscribe(
+            &mut never,
+            "devices/kettle",
+            SubscriptionOptions {
+                retain_handling: RetainHandling::DontSend,
+                ..Default::default()
+            },
+        )
+        .await;
+    }
+
+    // The retained message was received once, and only by the first
+    // subscriber: the next message is the new one
+    retain(&mut publisher, "devices/kettle", "off").await;
+    assert_eq!(client::receive_publish(&mut first).await.message, b"off");
+    assert_eq!(client::receive_publish(&mut never).await.message, b"off");
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.3.1-12: If the value of Retain As Published subscription option is
+/// set to 0, the Server MUST set the RETAIN flag to 0 when forwarding an
+/// Application Message regardless of how the RETAIN flag was set in the
+/// received PUBLISH packet.
+/// MQTT-3.3.1-13: If the value of Retain As Published subscription option is
+/// set to 1, the Server MUST set the RETAIN flag equal to the RETAIN flag in
+/// the received PUBLISH packet.
+#[tokio::test]
+async fn mqtt_3_3_1_12() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut streams = Vec::new();
+    for retain_as_published in [false, true] {
+        let (mut stream, _) = client::connect(&local_addr, Default::default()).await;
+        client::subscribe(
+            &mut stream,
+            "devices/+",
+            SubscriptionOptions {
+                retain_as_published,
+                ..Default::default()
+            },
+        )
+        .await;
+        streams.push((stream, retain_as_published));
+    }
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    retain(&mut publisher, "devices/kettle", "on").await;
+
+    for (stream, retain_as_published) in &mut streams {
+        assert_eq!(
+            client::receive_publish(stream).await.retain,
+            *retain_as_published
+        );
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.3.2-2: In the QoS 1 delivery protocol, the sender MUST send a
+/// PUBLISH packet containing this Packet Identifier with QoS 1 and DUP flag
+/// set to 0.
+/// MQTT-4.3.2-1: In the QoS 1 delivery protocol, the sender MUST assign an
+/// unused Packet Identifier each time it has a new Application Message to
+/// publish.
+#[tokio::test]
+async fn mqtt_4_3_2_2() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut stream =
+        client::subscriber(&local_addr, "Jaden", "sport/tennis", QoS::AtLeastOnce).await;
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+
+    let mut packet_identifiers = Vec::new();
+    for packet_identifier in [12, 12] {
+        let publish = Publish {
+            qos: QoS::AtLeastOnce,
+            packet_identifier: Some(packet_identifier),
+            topic_name: Topic::from("sport/tennis"),
+            ..Default::default()
+        };
+        client::send(&mut publisher, publish.into()).await;
+
+        let publish = client::receive_publish(&mut stream).await;
+        assert_eq!(publish.qos, QoS::AtLeastOnce);
+        assert!(!publish.duplicate);
+        assert!(publish.packet_identifier.is_some());
+        packet_identifiers.push(publish.packet_identifier);
+    }
+
+    // None of the messages were acknowledged: identifiers must differ
+    assert_ne!(packet_identifiers[0], packet_identifiers[1]);
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.3.2-4: In the QoS 1 delivery protocol, the receiver MUST respond
+/// with a PUBACK packet containing the Packet Identifier from the incoming
+/// PUBLISH packet, having accepted ownership of the Application Message.
+#[tokio::test]
+async fn mqtt_4_3_2_4() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let _stream = client::subscriber(&local_addr, "Jaden", "sport/tennis", QoS::AtLeastOnce).await;
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+
+    for (name, reason_code) in [
+        ("sport/tennis", ReasonCode::Success),
+        ("sport/golf", ReasonCode::NoMatchingSubscribers),
+    ] {
+        let packet_identifier = rand::random::<u16>().max(1);
+        let publish = Publish {
+            qos: QoS::AtLeastOnce,
+            packet_identifier: Some(packet_identifier),
+            topic_name: Topic::from(name),
+            ..Default::default()
+        };
+
+        if let Response::Packet(Packet::PubAck(puback)) =
+            client::send_waitback(&mut publisher, publish.into()).await
+        {
+            assert_eq!(puback.packet_identifier, packet_identifier);
+            assert_eq!(puback.reason_code, reason_code);
+        } else {
+            panic!("Expected PUBACK after QoS 1 PUBLISH");
+        }
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.3.3-2: In the QoS 2 delivery protocol, the sender MUST send a
+/// PUBLISH packet containing this Packet Identifier with QoS 2 and DUP flag
+/// set to 0.
+/// MQTT-4.3.3-4: In the QoS 2 delivery protocol, the sender MUST send a
+/// PUBREL packet when it receives a PUBREC packet from the receiver with a
+/// Reason Code value less than 0x80. This PUBREL packet MUST contain the same
+/// Packet Identifier as the original PUBLISH packet.
+#[tokio::test]
+async fn mqtt_4_3_3_2() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut stream =
+        client::subscriber(&local_addr, "Jaden", "sport/tennis", QoS::ExactlyOnce).await;
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+
+    let publish = Publish {
+        qos: QoS::ExactlyOnce,
+        packet_identifier: Some(12),
+        topic_name: Topic::from("sport/tennis"),
+        ..Default::default()
+    };
+    client::send(&mut publisher, publish.into()).await;
+
+    let publish = client::receive_publish(&mut stream).await;
+    assert_eq!(publish.qos, QoS::ExactlyOnce);
+    assert!(!publish.duplicate);
+    let packet_identifier = publish.packet_identifier.unwrap();
+
+    let pubrec = PubRec {
+        packet_identifier,
+        ..Default::default()
+    };
+    if let Response::Packet(Packet::PubRel(pubrel)) =
+        client::send_waitback(&mut stream, pubrec.into()).await
+    {
+        assert_eq!(pubrel.packet_identifier, packet_identifier);
+        assert_eq!(pubrel.reason_code, ReasonCode::Success);
+    } else {
+        panic!("Expected PUBREL after PUBREC");
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.3.3-10: In the QoS 2 delivery protocol, the receiver until it has
+/// received the corresponding PUBREL packet, the receiver MUST acknowledge any
+/// subsequent PUBLISH packet with the same Packet Identifier by sending a
+/// PUBREC. It MUST NOT cause duplicate messages to be delivered to any onward
+/// recipients in this case.
+/// MQTT-4.3.3-11: In the QoS 2 delivery protocol, the receiver MUST respond to
+/// a PUBREL packet by sending a PUBCOMP packet containing the same Packet
+/// Identifier as the PUBREL.
+/// MQTT-4.3.3-12: In the QoS 2 delivery protocol, the receiver After it has
+/// sent a PUBCOMP, the receiver MUST treat any subsequent PUBLISH packet that
+/// contains that Packet Identifier as being a new Application Message.
+#[tokio::test]
+async fn mqtt_4_3_3_10() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut stream = client::subscriber(&local_addr, "Jaden", "billing", QoS::AtMostOnce).await;
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+
+    // The first message is sent twice before being released
+    for (duplicate, message, release) in [
+        (false, "first", false),
+        (true, "first", true),
+        (false, "second", true),
+    ] {
+        let publish = Publish {
+            duplicate,
+            qos: QoS::ExactlyOnce,
+            packet_identifier: Some(7),
+            topic_name: Topic::from("billing"),
+            message: message.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        if let Response::Packet(Packet::PubRec(pubrec)) =
+            client::send_waitback(&mut publisher, publish.into()).await
+        {
+            assert_eq!(pubrec.packet_identifier, 7);
+        } else {
+            panic!("Expected PUBREC after QoS 2 PUBLISH");
+        }
+
+        if release {
+            let pubrel = PubRel {
+                packet_identifier: 7,
+                ..Default::default()
+            };
+            if let Response::Packet(Packet::PubComp(pubcomp)) =
+                client::send_waitback(&mut publisher, pubrel.into()).await
+            {
+                assert_eq!(pubcomp.packet_identifier, 7);
+                assert_eq!(pubcomp.reason_code, ReasonCode::Success);
+            } else {
+                panic!("Expected PUBCOMP after PUBREL");
+            }
+        }
+    }
+
+    // The duplicate was not delivered, the message after PUBCOMP was
+    assert_eq!(client::receive_publish(&mut stream).await.message, b"first");
+    assert_eq!(
+        client::receive_publish(&mut stream).await.message,
+        b"second"
+    );
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.3.3-6: In the QoS 2 delivery protocol, the sender MUST NOT re-send
+/// the PUBLISH once it has sent the corresponding PUBREL packet.
+/// MQTT-4.3.3-5: In the QoS 2 delivery protocol, the sender MUST treat the
+/// PUBREL packet as “unacknowledged” until it has received the corresponding
+/// PUBCOMP packet from the receiver.
+#[tokio::test]
+async fn mqtt_4_3_3_6() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut stream = client::subscriber(&local_addr, "Jaden", "billing", QoS::ExactlyOnce).await;
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+
+    for message in ["first", "second"] {
+        let publish = Publish {
+            qos: QoS::ExactlyOnce,
+            packet_identifier: Some(1),
+            topic_name: Topic::from("billing"),
+            message: message.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        client::send_waitback(&mut publisher, publish.into()).await;
+        client::send(
+            &mut publisher,
+            PubRel {
+                packet_identifier: 1,
+                ..Default::default()
+            }
+            .into(),
+        )
+        .await;
+    }
+
+    // Only the first message is received, nothing is completed
+    let first = client::receive_publish(&mut stream).await;
+    let second = client::receive_publish(&mut stream).await;
+    let pubrec = PubRec {
+        packet_identifier: first.packet_identifier.unwrap(),
+        ..Default::default()
+    };
+    assert!(matches!(
+        client::send_waitback(&mut stream, pubrec.into()).await,
+        Response::Packet(Packet::PubRel(_))
+    ));
+
+    // Resume the session from a new connection
+    let mut stream = client::spawn(&local_addr).await;
+    let connect = Connect {
+        client_id: Some("Jaden".into()),
+        ..Default::default()
+    };
+    client::send(&mut stream, connect.into()).await;
+    assert!(matches!(
+        client::receive(&mut stream).await,
+        Response::Packet(Packet::ConnAck(_))
+    ));
+
+    // The first message is released, only its PUBREL is sent again
+    if let Response::Packet(Packet::PubRel(pubrel)) = client::receive(&mut stream).await {
+        assert_eq!(Some(pubrel.packet_identifier), first.packet_identifier);
+    } else {
+        panic!("Expected PUBREL on session resume");
+    }
+    let publish = client::receive_publish(&mut stream).await;
+    assert!(publish.duplicate);
+    assert_eq!(publish.packet_identifier, second.packet_identifier);
+    assert_eq!(publish.message, b"second");
+
+    // Completing the first message frees its packet identifier
+    client::send(
+        &mut stream,
+        PubComp {
+            packet_identifier: first.packet_identifier.unwrap(),
+            ..Default::default()
+        }
+        .into(),
+    )
+    .await;
+    let pubrec = PubRec {
+        packet_identifier: first.packet_identifier.unwrap(),
+        ..Default::default()
+    };
+    if let Response::Packet(Packet::PubRel(pubrel)) =
+        client::send_waitback(&mut stream, pubrec.into()).await
+    {
+        assert_eq!(pubrel.reason_code, ReasonCode::PacketIdentifierNotFound);
+    } else {
+        panic!("Expected PUBREL after PUBREC");
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.4.0-1: When a Client reconnects with Clean Start set to 0 and a
+/// session is present, both the Client and Server MUST resend any
+/// unacknowledged PUBLISH packets (where QoS > 0) and PUBREL packets using
+/// their original Packet Identifiers.
+/// MQTT-3.3.1-1: The DUP flag MUST be set to 1 by the Client or Server when
+/// it attempts to re-deliver a PUBLISH packet.
+#[tokio::test]
+async fn mqtt_4_4_0_1() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut stream = client::subscriber(&local_addr, "Jaden", "sport/#", QoS::AtLeastOnce).await;
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+
+    for name in ["sport/tennis", "sport/golf", "sport/rugby"] {
+        let publish = Publish {
+            qos: QoS::AtLeastOnce,
+            packet_identifier: Some(1),
+            topic_name: Topic::from(name),
+            ..Default::default()
+        };
+        client::send(&mut publisher, publish.into()).await;
+    }
+
+    // Only the second message is acknowledged
+    let mut sent = Vec::new();
+    for _ in 0..3 {
+        sent.push(client::receive_publish(&mut stream).await);
+    }
+    client::send(
+        &mut stream,
+        PubAck {
+            packet_identifier: sent[1].packet_identifier.unwrap(),
+            ..Default::default()
+        }
+        .into(),
+    )
+    .await;
+
+    // Resume the session from a new connection
+    let mut stream = client::spawn(&local_addr).await;
+    let connect = Connect {
+        client_id: Some("Jaden".into()),
+        ..Default::default()
+    };
+    client::send(&mut stream, connect.into()).await;
+    if let Response::Packet(Packet::ConnAck(connack)) = client::receive(&mut stream).await {
+        assert!(connack.session_present);
+    } else {
+        panic!("Expected CONNACK after CONNECT");
+    }
+
+    for expected in [&sent[0], &sent[2]] {
+        let publish = client::receive_publish(&mut stream).await;
+        assert!(publish.duplicate);
+        assert_eq!(publish.packet_identifier, expected.packet_identifier);
+        assert_eq!(publish.topic_name, expected.topic_name);
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// If the Server receives a PUBLISH packet with a QoS greater than the
+/// Maximum QoS it specified in the CONNACK, it uses DISCONNECT with Reason
+/// Code 0x9B (QoS not supported) [MQTT-3.2.2-11].
+#[tokio::test]
+async fn publish_qos_not_supported() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        maximum_qos: QoS::AtLeastOnce,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut stream, _) = client::connect(&local_addr, Default::default()).await;
+    let publish = Publish {
+        qos: QoS::ExactlyOnce,
+        packet_identifier: Some(1),
+        topic_name: Topic::from("sport/tennis"),
+        ..Default::default()
+    };
+
+    if let Response::Packet(Packet::Disconnect(disconnect)) =
+        client::send_waitback(&mut stream, publish.into()).await
+    {
+        assert_eq!(disconnect.reason_code, ReasonCode::QoSNotSupported);
+    } else {
+        panic!("Expected DISCONNECT after unsupported QoS");
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// If the Server receives a PUBLISH packet with the RETAIN flag set while it
+/// does not support retained messages, it uses DISCONNECT with Reason Code
+/// 0x9A (Retain not supported) [MQTT-3.2.2-14].
+#[tokio::test]
+async fn publish_retain_not_supported() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        retain_enabled: false,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut stream, _) = client::connect(&local_addr, Default::default()).await;
+    let publish = Publish {
+        retain: true,
+        topic_name: Topic::from("devices/kettle"),
+        message: b"on".to_vec(),
+        ..Default::default()
+    };
+
+    if let Response::Packet(Packet::Disconnect(disconnect)) =
+        client::send_waitback(&mut stream, publish.into()).await
+    {
+        assert_eq!(disconnect.reason_code, ReasonCode::RetainNotSupported);
+    } else {
+        panic!("Expected DISCONNECT after unsupported retain");
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+/// Publishes a QoS 0 message with the given topic name and alias
+async fn publish_alias(stream: &mut TcpStream, name: &str, topic_alias: u16) {
+    let publish = Publish {
+        topic_name: Topic::from(name),
+        topic_alias: Some(topic_alias),
+        ..Default::default()
+    };
+    client::send(stream, publish.into()).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.3.2-7: A receiver MUST NOT carry forward any Topic Alias mappings
+/// from one Network Connection to another.
+/// MQTT-3.3.2-12: A Server MUST accept all Topic Alias values greater than 0
+/// and less than or equal to the Topic Alias Maximum value that it returned in
+/// the CONNACK packet.
+#[tokio::test]
+async fn mqtt_3_3_2_7() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        topic_alias_maximum: 10,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut stream = client::subscriber(&local_addr, "Jaden", "sport/#", QoS::AtMostOnce).await;
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    publish_alias(&mut publisher, "sport/tennis", 10).await;
+    publish_alias(&mut publisher, "", 10).await;
+
+    // The alias is resolved and not forwarded to subscribers
+    for _ in 0..2 {
+        let publish = client::receive_publish(&mut stream).await;
+        assert_eq!(publish.topic_name, Topic::from("sport/tennis"));
+        assert_eq!(publish.topic_alias, None);
+    }
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    publish_alias(&mut publisher, "", 10).await;
+    if let Response::Packet(Packet::Disconnect(disconnect)) = client::receive(&mut publisher).await
+    {
+        assert_eq!(disconnect.reason_code, ReasonCode::ProtocolError);
+    } else {
+        panic!("Expected DISCONNECT after unknown topic alias");
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.3.2-8: A sender MUST NOT send a PUBLISH packet containing a Topic
+/// Alias which has the value 0.
+/// MQTT-3.3.2-9: A Client MUST NOT send a PUBLISH packet with a Topic Alias
+/// greater than the Topic Alias Maximum value returned by the Server in the
+/// CONNACK packet.
+#[tokio::test]
+async fn mqtt_3_3_2_8() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        topic_alias_maximum: 10,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    for topic_alias in [0, 11] {
+        let (mut stream, _) = client::connect(&local_addr, Default::default()).await;
+        publish_alias(&mut stream, "sport/tennis", topic_alias).await;
+        if let Response::Packet(Packet::Disconnect(disconnect)) = client::receive(&mut stream).await
+        {
+            assert_eq!(disconnect.reason_code, ReasonCode::TopicAliasInvalid);
+        } else {
+            panic!("Expected DISCONNECT after invalid topic alias");
+        }
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.3.2-11: A Server MUST NOT send a PUBLISH packet with a Topic Alias
+/// greater than the Topic Alias Maximum value sent by the Client in the
+/// CONNECT packet.
+#[tokio::test]
+async fn mqtt_3_3_2_11() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let connect = Connect {
+        client_id: Some("Jaden".into()),
+        topic_alias_maximum: 1,
+        ..Default::default()
+    };
+    let (mut stream, _) = client::connect(&local_addr, connect).await;
+    client::subscribe(&mut stream, "sport/#", Default::default()).await;
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    for name in ["sport/tennis", "sport/tennis", "sport/golf", "sport/tennis"] {
+        let publish = Publish {
+            topic_name: Topic::from(name),
+            ..Default::default()
+        };
+        client::send(&mut publisher, publish.into()).await;
+    }
+
+    // The topic name is left out when its alias is known by the client
+    for name in ["sport/tennis", "", "sport/golf", "sport/tennis"] {
+        let publish = client::receive_publish(&mut stream).await;
+        assert_eq!(publish.topic_name, Topic::from(name));
+        assert_eq!(publish.topic_alias, Some(1));
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// A message discarded for exceeding the Maximum Packet Size of the client
+/// does not give its topic name an alias the client would not know.
+#[tokio::test]
+async fn discarded_message_alias() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let connect = Connect {
+        client_id: Some("Jaden".into()),
+        topic_alias_maximum: 1,
+        maximum_packet_size: Some(64),
+        ..Default::default()
+    };
+    let (mut stream, _) = client::connect(&local_addr, connect).await;
+    client::subscribe(&mut stream, "sport/#", Default::default()).await;
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    for (name, size) in [("sport/tennis", 8), ("sport/golf", 64), ("sport/golf", 8)] {
+        let publish = Publish {
+            topic_name: Topic::from(name),
+            message: vec![0; size],
+            ..Default::default()
+        };
+        client::send(&mut publisher, publish.into()).await;
+    }
+
+    for name in ["sport/tennis", "sport/golf"] {
+        let publish = client::receive_publish(&mut stream).await;
+        assert_eq!(publish.topic_name, Topic::from(name));
+        assert_eq!(publish.topic_alias, Some(1));
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.3.4-9: The Server MUST NOT send more than Receive Maximum QoS 1 and
+/// QoS 2 PUBLISH packets for which it has not received PUBACK, PUBCOMP, or
+/// PUBREC with a Reason Code of 128 or greater from the Client.
+/// MQTT-3.3.4-10: The Server MUST NOT delay the sending of any packets other
+/// than PUBLISH packets due to having sent Receive Maximum PUBLISH packets
+/// without receiving acknowledgements for them.
+#[tokio::test]
+async fn mqtt_3_3_4_9() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let connect = Connect {
+        client_id: Some("Jaden".into()),
+        receive_maximum: 2,
+        ..Default::default()
+    };
+    let (mut stream, _) = client::connect(&local_addr, connect).await;
+    let options = SubscriptionOptions {
+        qos: QoS::AtLeastOnce,
+        ..Default::default()
+    };
+    client::subscribe(&mut stream, "sport/#", options).await;
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    for packet_identifier in 1..=3 {
+        let publish = Publish {
+            qos: QoS::AtLeastOnce,
+            packet_identifier: Some(packet_identifier),
+            topic_name: Topic::from("sport/tennis"),
+            message: vec![packet_identifier as u8],
+            ..Default::default()
+        };
+        client::send_waitback(&mut publisher, publish.into()).await;
+    }
+
+    let first = client::receive_publish(&mut stream).await;
+    assert_eq!(first.message, vec![1]);
+    assert_eq!(client::receive_publish(&mut stream).await.message, vec![2]);
+
+    // The third message is withheld, not the PINGRESP
+    assert!(matches!(
+        client::send_waitback(&mut stream, Packet::PingReq).await,
+        Response::Packet(Packet::PingResp)
+    ));
+
+    let puback = PubAck {
+        packet_identifier: first.packet_identifier.unwrap(),
+        ..Default::default()
+    };
+    client::send(&mut stream, puback.into()).await;
+    assert_eq!(client::receive_publish(&mut stream).await.message, vec![3]);
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// The messages queued for a session are limited by `max_queued_messages`,
+/// the oldest ones being dropped first.
+#[tokio::test]
+async fn max_queued_messages() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        max_queued_messages: Some(2),
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let subscriber = |clean_start| Connect {
+        client_id: Some("Jaden".into()),
+        clean_start,
+        session_expiry_interval: Some(60),
+        ..Default::default()
+    };
+    let (mut stream, _) = client::connect(&local_addr, subscriber(true)).await;
+    let options = SubscriptionOptions {
+        qos: QoS::AtLeastOnce,
+        ..Default::default()
+    };
+    client::subscribe(&mut stream, "sport/#", options).await;
+    drop(stream);
+
+    // The queue of the offline session is filled beyond its limit
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    for packet_identifier in 1..=4 {
+        let publish = Publish {
+            qos: QoS::AtLeastOnce,
+            packet_identifier: Some(packet_identifier),
+            topic_name: Topic::from("sport/tennis"),
+            message: vec![packet_identifier as u8],
+            ..Default::default()
+        };
+        client::send_waitback(&mut publisher, publish.into()).await;
+    }
+
+    let mut stream = client::spawn(&local_addr).await;
+    client::send_waitback(&mut stream, subscriber(false).into()).await;
+    assert_eq!(client::receive_publish(&mut stream).await.message, vec![3]);
+    assert_eq!(client::receive_publish(&mut stream).await.message, vec![4]);
+    assert!(matches!(
+        client::send_waitback(&mut stream, Packet::PingReq).await,
+        Response::Packet(Packet::PingResp)
+    ));
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.3.4-7: The Client MUST NOT send more than Receive Maximum QoS 1 and
+/// QoS 2 PUBLISH packets for which it has not received PUBACK, PUBCOMP, or
+/// PUBREC with a Reason Code of 128 or greater from the Server.
+/// The Server disconnects such a client with Reason Code 0x93 (Receive Maximum
+/// exceeded).
+#[tokio::test]
+async fn mqtt_3_3_4_7() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        receive_maximum: 1,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut stream, _) = client::connect(&local_addr, Default::default()).await;
+    let publish = |packet_identifier| {
+        Packet::from(Publish {
+            qos: QoS::ExactlyOnce,
+            packet_identifier: Some(packet_identifier),
+            topic_name: Topic::from("sport/tennis"),
+            ..Default::default()
+        })
+    };
+
+    assert!(matches!(
+        client::send_waitback(&mut stream, publish(1)).await,
+        Response::Packet(Packet::PubRec(_))
+    ));
+    if let Response::Packet(Packet::Disconnect(disconnect)) =
+        client::send_waitback(&mut stream, publish(2)).await
+    {
+        assert_eq!(disconnect.reason_code, ReasonCode::ReceiveMaximumExceeded);
+    } else {
+        panic!("Expected DISCONNECT after receive maximum exceeded");
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.3.2-5: If the Message Expiry Interval has passed and the Server has
+/// not managed to start onward delivery to a matching subscriber, then it MUST
+/// delete the copy of the message for that subscriber.
+/// MQTT-3.3.2-6: The PUBLISH packet sent to a Client by the Server MUST contain
+/// a Message Expiry Interval set to the received value minus the time that the
+/// message has been waiting in the Server.
+#[tokio::test]
+async fn mqtt_3_3_2_5() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let subscriber = |clean_start| Connect {
+        client_id: Some("Jaden".into()),
+        clean_start,
+        session_expiry_interval: Some(60),
+        ..Default::default()
+    };
+    let (mut stream, _) = client::connect(&local_addr, subscriber(true)).await;
+    let options = SubscriptionOptions {
+        qos: QoS::AtLeastOnce,
+        ..Default::default()
+    };
+    client::subscribe(&mut stream, "sport/#", options).await;
+    drop(stream);
+
+    // Both messages are queued in the session and retained
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    for (packet_identifier, name, message_expiry_interval) in
+        [(1, "sport/tennis", 1), (2, "sport/golf", 60)]
+    {
+        let publish = Publish {
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            packet_identifier: Some(packet_identifier),
+            topic_name: Topic::from(name),
+            message_expiry_interval: Some(message_expiry_interval),
+            message: b"results".to_vec(),
+            ..Default::default()
+        };
+        client::send_waitback(&mut publisher, publish.into()).await;
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+
+    // Only the message which did not expire is sent, from the session queue
+    // then as a retained message
+    let mut stream = client::spawn(&local_addr).await;
+    client::send_waitback(&mut stream, subscriber(false).into()).await;
+    let mut retained = client::subscriber(&local_addr, "Jarod", "sport/#", QoS::AtMostOnce).await;
+    for stream in [&mut stream, &mut retained] {
+        let publish = client::receive_publish(stream).await;
+        assert_eq!(publish.topic_name, Topic::from("sport/golf"));
+        assert!(matches!(publish.message_expiry_interval, Some(58..=59)));
+        assert!(matches!(
+            client::send_waitback(stream, Packet::PingReq).await,
+            Response::Packet(Packet::PingResp)
+        ));
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+/// Subscribes to the given filter with the given subscription identifier,
+/// expecting a SUBACK.
+async fn subscribe_identified(stream: &mut TcpStream, filter: &str, identifier: u32) {
+    let subscribe = Subscribe {
+        subscription_identifier: Some(identifier),
+        subscriptions: vec![(Topic::from(filter), Default::default())],
+        ..Default::default()
+    };
+    assert!(matches!(
+        client::send_waitback(stream, subscribe.into()).await,
+        Response::Packet(Packet::SubAck(_))
+    ));
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.3.4-3: If the Client specified a Subscription Identifier for any of
+/// the overlapping subscriptions the Server MUST send those Subscription
+/// Identifiers in the message which is published as the result of the
+/// subscriptions.
+/// MQTT-3.3.4-4: If the Server sends a single copy of the message it MUST
+/// include in the PUBLISH packet the Subscription Identifiers for all matching
+/// subscriptions which have a Subscription Identifiers, their order is not
+/// significant.
+#[tokio::test]
+async fn mqtt_3_3_4_3() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut stream, _) = client::connect(&local_addr, Default::default()).await;
+    subscribe_identified(&mut stream, "sport/#", 1).await;
+    subscribe_identified(&mut stream, "sport/+", 2).await;
+    client::subscribe(&mut stream, "sport/tennis", Default::default()).await;
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    retain(&mut publisher, "sport/tennis", "results").await;
+
+    let mut publish = client::receive_publish(&mut stream).await;
+    publish.subscription_identifiers.sort_unstable();
+    assert_eq!(publish.subscription_identifiers, vec![1, 2]);
+
+    // Retained messages carry the identifier of the new subscription
+    subscribe_identified(&mut stream, "sport/tennis", 3).await;
+    let publish = client::receive_publish(&mut stream).await;
+    assert_eq!(publish.subscription_identifiers, vec![3]);
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.3.4-6: A PUBLISH packet sent from a Client to a Server MUST NOT
+/// contain a Subscription Identifier.
+#[tokio::test]
+async fn mqtt_3_3_4_6() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut stream, _) = client::connect(&local_addr, Default::default()).await;
+    let publish = Publish {
+        topic_name: Topic::from("sport/tennis"),
+        subscription_identifiers: vec![1],
+        ..Default::default()
+    };
+
+    if let Response::Packet(Packet::Disconnect(disconnect)) =
+        client::send_waitback(&mut stream, publish.into()).await
+    {
+        assert_eq!(disconnect.reason_code, ReasonCode::ProtocolError);
+    } else {
+        panic!("Expected DISCONNECT after subscription identifier");
+    }
+
+    server::stop(shutdown, server).await;
+}
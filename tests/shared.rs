@@ -0,0 +1,364 @@
+//! Shared Subscriptions requirements consists in all [MQTT 4.8.x-x]
+//! conformances.
+use sage_broker::{BrokerSettings, SharingStrategy};
+use sage_mqtt::{Connect, Packet, PubAck, PubRec, Publish, QoS, ReasonCode, Topic};
+use std::{net::SocketAddr, time::Duration};
+use tokio::net::TcpStream;
+pub mod utils;
+
+use utils::client::Response;
+pub use utils::*;
+
+const SHARE: &str = "$share/workers/sport/#";
+
+/// Encodes a SUBSCRIBE packet to the given filters with the given QoS.
+/// `sage_mqtt` cannot be used here as it writes the share name of shared
+/// subscriptions as "Shareuh".
+fn subscribe_packet(filters: &[&str], qos: QoS) -> Vec<u8> {
+    // Packet identifier and empty properties
+    let mut content = vec![0, 1, 0];
+    for filter in filters {
+        content.extend((filter.len() as u16).to_be_bytes());
+        content.extend(filter.as_bytes());
+        content.push(qos as u8);
+    }
+
+    let mut buffer = vec![0x82];
+    let mut remaining_length = content.len();
+    loop {
+        let byte = (remaining_length % 128) as u8;
+        remaining_length /= 128;
+        if remaining_length > 0 {
+            buffer.push(byte | 0x80);
+        } else {
+            buffer.push(byte);
+            break;
+        }
+    }
+    buffer.extend(content);
+    buffer
+}
+
+/// Subscribes to the given filters with the given QoS, returning the SUBACK
+/// reason codes.
+async fn subscribe(stream: &mut TcpStream, filters: &[&str], qos: QoS) -> Vec<ReasonCode> {
+    if let Response::Packet(Packet::SubAck(suback)) =
+        client::send_waitback_data(stream, subscribe_packet(filters, qos)).await
+    {
+        suback.reason_codes
+    } else {
+        panic!("Expected SUBACK after SUBSCRIBE");
+    }
+}
+
+/// Connects a new client and subscribes it to the shared subscription with
+/// the given QoS.
+async fn worker(local_addr: &SocketAddr, client_id: &str, qos: QoS) -> TcpStream {
+    let connect = Connect {
+        client_id: Some(client_id.into()),
+        ..Default::default()
+    };
+    let (mut stream, _) = client::connect(local_addr, connect).await;
+    subscribe(&mut stream, &[SHARE], qos).await;
+    stream
+}
+
+/// Publishes a message on the given topic name, whose content is the client id
+/// of the publisher.
+async fn publish(stream: &mut TcpStream, publisher: &str, name: &str) {
+    let publish = Publish {
+        topic_name: Topic::from(name),
+        message: publisher.as_bytes().to_vec(),
+        ..Default::default()
+    };
+    client::send(stream, publish.into()).await;
+}
+
+/// Makes sure no PUBLISH packet is waiting in the stream
+async fn assert_nothing_received(stream: &mut TcpStream) {
+    assert!(matches!(
+        client::send_waitback(stream, Packet::PingReq).await,
+        Response::Packet(Packet::PingResp)
+    ));
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.8.2-1: A Shared Subscription's Topic Filter MUST start with $share/
+/// and MUST contain a ShareName that is at least one character long.
+/// MQTT-4.8.2-2: The ShareName MUST NOT contain the characters "/", "+" or
+/// "#", but MUST be followed by a "/" character. This "/" character MUST be
+/// followed by a Topic Filter.
+#[tokio::test]
+async fn mqtt_4_8_2_1() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut stream = client::spawn(&local_addr).await;
+    if let Response::Packet(Packet::ConnAck(connack)) =
+        client::send_waitback(&mut stream, Connect::default().into()).await
+    {
+        assert!(connack.shared_subscription_available);
+    } else {
+        panic!("Expected CONNACK after CONNECT");
+    }
+
+    let filters = [
+        SHARE,
+        "$share//sport/#",
+        "$share/workers",
+        "$share/workers/",
+        "$share/work+ers/sport/#",
+        "$share/work#ers/sport/#",
+    ];
+    let reason_codes = subscribe(&mut stream, &filters, QoS::AtMostOnce).await;
+    assert_eq!(reason_codes[0], ReasonCode::Success);
+    assert!(reason_codes[1..]
+        .iter()
+        .all(|&rc| rc == ReasonCode::TopicFilterInvalid));
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// Each message matching a shared subscription is sent to one session of its
+/// group only, in turns by default. Non-shared subscriptions are not affected.
+#[tokio::test]
+async fn shared_subscription_round_robin() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut jaden = worker(&local_addr, "Jaden", QoS::AtMostOnce).await;
+    let mut jarod = worker(&local_addr, "Jarod", QoS::AtMostOnce).await;
+    let mut jason = client::subscriber(&local_addr, "Jason", "sport/#", QoS::AtMostOnce).await;
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    for name in ["sport/tennis", "sport/golf", "sport/squash", "sport/rugby"] {
+        publish(&mut publisher, "James", name).await;
+    }
+
+    for (stream, names) in [
+        (&mut jaden, vec!["sport/tennis", "sport/squash"]),
+        (&mut jarod, vec!["sport/golf", "sport/rugby"]),
+        (
+            &mut jason,
+            vec!["sport/tennis", "sport/golf", "sport/squash", "sport/rugby"],
+        ),
+    ] {
+        for name in names {
+            let publish = client::receive_publish(stream).await;
+            assert_eq!(publish.topic_name, Topic::from(name));
+        }
+        assert_nothing_received(stream).await;
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// With the sticky strategy, all messages of a publisher are sent to the same
+/// session of the group.
+#[tokio::test]
+async fn shared_subscription_sticky() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        shared_subscription_strategy: SharingStrategy::Sticky,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut jaden = worker(&local_addr, "Jaden", QoS::AtMostOnce).await;
+    let mut jarod = worker(&local_addr, "Jarod", QoS::AtMostOnce).await;
+
+    let connect = |client_id: &str| Connect {
+        client_id: Some(client_id.into()),
+        ..Default::default()
+    };
+    let (mut james, _) = client::connect(&local_addr, connect("James")).await;
+    let (mut jimmy, _) = client::connect(&local_addr, connect("Jimmy")).await;
+    // Round robin would send the second message of each publisher to the
+    // other session
+    for _ in 0..2 {
+        for (publisher, name, stream) in [
+            (&mut james, "James", &mut jaden),
+            (&mut jimmy, "Jimmy", &mut jarod),
+        ] {
+            for _ in 0..2 {
+                publish(publisher, name, "sport/tennis").await;
+                assert_eq!(
+                    client::receive_publish(stream).await.message,
+                    name.as_bytes().to_vec()
+                );
+            }
+        }
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// Retained messages are not sent to shared subscriptions.
+#[tokio::test]
+async fn shared_subscription_no_retained() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    let publish = Publish {
+        qos: QoS::AtLeastOnce,
+        retain: true,
+        packet_identifier: Some(1),
+        topic_name: Topic::from("sport/tennis"),
+        message: b"results".to_vec(),
+        ..Default::default()
+    };
+    client::send_waitback(&mut publisher, publish.into()).await;
+
+    let mut jaden = worker(&local_addr, "Jaden", QoS::AtLeastOnce).await;
+    assert_nothing_received(&mut jaden).await;
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.8.2-3: The Server MUST respect the granted QoS for the Clients
+/// subscription.
+#[tokio::test]
+async fn mqtt_4_8_2_3() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut jaden = worker(&local_addr, "Jaden", QoS::AtMostOnce).await;
+    let mut jarod = worker(&local_addr, "Jarod", QoS::AtLeastOnce).await;
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    for packet_identifier in [1, 2] {
+        let publish = Publish {
+            qos: QoS::AtLeastOnce,
+            packet_identifier: Some(packet_identifier),
+            topic_name: Topic::from("sport/tennis"),
+            ..Default::default()
+        };
+        client::send_waitback(&mut publisher, publish.into()).await;
+    }
+
+    assert_eq!(
+        client::receive_publish(&mut jaden).await.qos,
+        QoS::AtMostOnce
+    );
+    assert_eq!(
+        client::receive_publish(&mut jarod).await.qos,
+        QoS::AtLeastOnce
+    );
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// A QoS 1 message not acknowledged by a session which loses its connection is
+/// sent to another connected session of the group.
+#[tokio::test]
+async fn shared_subscription_redistribution() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut jaden = worker(&local_addr, "Jaden", QoS::AtLeastOnce).await;
+    let mut jarod = worker(&local_addr, "Jarod", QoS::AtLeastOnce).await;
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    for (packet_identifier, name) in [(1, "sport/tennis"), (2, "sport/golf")] {
+        let publish = Publish {
+            qos: QoS::AtLeastOnce,
+            packet_identifier: Some(packet_identifier),
+            topic_name: Topic::from(name),
+            ..Default::default()
+        };
+        client::send_waitback(&mut publisher, publish.into()).await;
+    }
+
+    // Jaden leaves without acknowledging its message
+    let publish = client::receive_publish(&mut jaden).await;
+    assert_eq!(publish.topic_name, Topic::from("sport/tennis"));
+    drop(jaden);
+
+    for name in ["sport/golf", "sport/tennis"] {
+        let publish = client::receive_publish(&mut jarod).await;
+        assert_eq!(publish.topic_name, Topic::from(name));
+        assert!(!publish.duplicate);
+        let puback = PubAck {
+            packet_identifier: publish.packet_identifier.unwrap(),
+            ..Default::default()
+        };
+        client::send(&mut jarod, puback.into()).await;
+    }
+    assert_nothing_received(&mut jarod).await;
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.8.2-4: The Server MUST complete the delivery of the message to that
+/// Client when it reconnects.
+#[tokio::test]
+async fn mqtt_4_8_2_4() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let connect = |clean_start| Connect {
+        client_id: Some("Jaden".into()),
+        clean_start,
+        session_expiry_interval: Some(60),
+        ..Default::default()
+    };
+    let (mut jaden, _) = client::connect(&local_addr, connect(true)).await;
+    subscribe(&mut jaden, &[SHARE], QoS::ExactlyOnce).await;
+    let mut jarod = worker(&local_addr, "Jarod", QoS::ExactlyOnce).await;
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    let publish = Publish {
+        qos: QoS::ExactlyOnce,
+        packet_identifier: Some(1),
+        topic_name: Topic::from("sport/tennis"),
+        ..Default::default()
+    };
+    client::send_waitback(&mut publisher, publish.into()).await;
+
+    // Jaden leaves before acknowledging its QoS 2 message
+    let publish = client::receive_publish(&mut jaden).await;
+    drop(jaden);
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_nothing_received(&mut jarod).await;
+
+    let mut jaden = client::spawn(&local_addr).await;
+    client::send_waitback(&mut jaden, connect(false).into()).await;
+    let resent = client::receive_publish(&mut jaden).await;
+    assert!(resent.duplicate);
+    assert_eq!(resent.packet_identifier, publish.packet_identifier);
+    let pubrec = PubRec {
+        packet_identifier: publish.packet_identifier.unwrap(),
+        ..Default::default()
+    };
+    assert!(matches!(
+        client::send_waitback(&mut jaden, pubrec.into()).await,
+        Response::Packet(Packet::PubRel(_))
+    ));
+
+    server::stop(shutdown, server).await;
+}
@@ -0,0 +1,189 @@
+//! The broker can serve clients over any async stream, and not only the TCP
+//! connections accepted by `listen_tcp`, such as TLS streams whose client
+//! certificates identify the clients.
+use sage_broker::{
+    auth::{CertificateIdentity, ClientCertificate, PasswordFile, Principal, TlsInfo},
+    service, BrokerSettings, CommandReceiver, Sessions, Trigger,
+};
+use sage_mqtt::{ConnAck, Connect, Packet, ReasonCode};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+use tokio::{
+    io::{AsyncWriteExt, DuplexStream},
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
+pub mod utils;
+
+pub use utils::*;
+
+/// A broker serving a single client over an in-memory stream
+struct Broker {
+    sessions: Arc<RwLock<Sessions>>,
+    client: DuplexStream,
+    shutdown: Trigger,
+    tasks: Vec<JoinHandle<()>>,
+    command_loop: JoinHandle<CommandReceiver>,
+}
+
+impl Broker {
+    /// Serves a client which presented the given certificate
+    fn spawn(settings: BrokerSettings, certificate: Option<ClientCertificate>) -> Self {
+        let tls = certificate.map(|certificate| TlsInfo {
+            certificate: Some(certificate),
+            ..Default::default()
+        });
+        let settings = Arc::new(BrokerSettings {
+            keep_alive: TIMEOUT_DELAY,
+            ..settings
+        });
+        let sessions = Arc::new(RwLock::new(Sessions::default()));
+        let shutdown = Trigger::default();
+        let (command_sender, command_receiver) = mpsc::unbounded_channel();
+        let command_loop = task::spawn(service::command_loop(
+            settings.clone(),
+            sessions.clone(),
+            command_receiver,
+            shutdown.clone(),
+        ));
+
+        let (client, server) = tokio::io::duplex(1024);
+        let (listen_task, send_task) = service::spawn_peer(
+            server,
+            "127.0.0.1:1883".parse::<SocketAddr>().unwrap().into(),
+            tls,
+            command_sender,
+            settings,
+            shutdown.clone(),
+        );
+
+        Broker {
+            sessions,
+            client,
+            shutdown,
+            tasks: vec![listen_task, send_task],
+            command_loop,
+        }
+    }
+
+    /// Sends the CONNECT packet and returns the CONNACK
+    async fn connect(&mut self, connect: Connect) -> ConnAck {
+        self.client
+            .write_all(&client::encode(connect.into()).await)
+            .await
+            .unwrap();
+        if let Packet::ConnAck(connack) = Packet::decode(&mut self.client).await.unwrap() {
+            connack
+        } else {
+            panic!("Expected CONNACK packet");
+        }
+    }
+
+    /// Returns the principal of the session of the given client, which must
+    /// exist
+    fn principal(&self, client_id: &str) -> Option<Principal> {
+        self.sessions
+            .read()
+            .unwrap()
+            .get(client_id)
+            .expect("Expected a session")
+            .principal()
+    }
+
+    async fn stop(self) {
+        self.shutdown.fire();
+        for task in self.tasks {
+            task.await.unwrap();
+        }
+        self.command_loop.await.unwrap();
+    }
+}
+
+fn connect(client_id: &str) -> Connect {
+    Connect {
+        client_id: Some(client_id.into()),
+        ..Default::default()
+    }
+}
+
+fn certificate(common_name: Option<&str>) -> Option<ClientCertificate> {
+    Some(ClientCertificate {
+        subject: match common_name {
+            Some(common_name) => format!("CN={},O=Factory", common_name),
+            None => "O=Factory".into(),
+        },
+        common_name: common_name.map(Into::into),
+        subject_alt_names: Vec::new(),
+    })
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// A client connects over an in-memory stream handed to `spawn_peer`.
+#[tokio::test]
+async fn spawn_peer() {
+    let mut broker = Broker::spawn(BrokerSettings::valid_default(), None);
+    let connack = broker.connect(connect("Jaden")).await;
+    assert_eq!(connack.reason_code, ReasonCode::Success);
+    assert_eq!(broker.principal("Jaden"), None);
+    broker.stop().await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// With `CertificateIdentity::UserName`, the client is authenticated as the
+/// common name of its certificate, without checking any password.
+#[tokio::test]
+async fn certificate_user_name() {
+    let settings = BrokerSettings {
+        certificate_identity: CertificateIdentity::UserName,
+        authenticator: Some(Arc::new(PasswordFile::default())),
+        ..BrokerSettings::valid_default()
+    };
+    let mut broker = Broker::spawn(settings, certificate(Some("kettle")));
+    let connack = broker
+        .connect(Connect {
+            user_name: Some("jaden".into()),
+            ..connect("Jaden")
+        })
+        .await;
+    assert_eq!(connack.reason_code, ReasonCode::Success);
+    assert_eq!(broker.principal("Jaden"), Some(Principal::new("kettle")));
+    broker.stop().await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// With `CertificateIdentity::ClientId`, the client identifier is the common
+/// name of the certificate.
+#[tokio::test]
+async fn certificate_client_id() {
+    let settings = BrokerSettings {
+        certificate_identity: CertificateIdentity::ClientId,
+        ..BrokerSettings::valid_default()
+    };
+    let mut broker = Broker::spawn(settings, certificate(Some("kettle")));
+    let connack = broker.connect(connect("Jaden")).await;
+    assert_eq!(connack.reason_code, ReasonCode::Success);
+    assert_eq!(connack.assigned_client_id, Some("kettle".into()));
+    assert_eq!(broker.principal("kettle"), None);
+    assert!(broker.sessions.read().unwrap().get("Jaden").is_none());
+    broker.stop().await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Clients without a certificate, or whose certificate has no common name,
+/// are refused with `NotAuthorized` when certificates identify the clients.
+#[tokio::test]
+async fn certificate_rejected() {
+    for certificate in [None, certificate(None)] {
+        let settings = BrokerSettings {
+            certificate_identity: CertificateIdentity::UserName,
+            ..BrokerSettings::valid_default()
+        };
+        let mut broker = Broker::spawn(settings, certificate);
+        let connack = broker.connect(connect("Jaden")).await;
+        assert_eq!(connack.reason_code, ReasonCode::NotAuthorized);
+        assert!(broker.sessions.read().unwrap().is_empty());
+        broker.stop().await;
+    }
+}
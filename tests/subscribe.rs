@@ -1,6 +1,6 @@
 //! SUBSCRIBE Actions requirements
 use sage_broker::BrokerSettings;
-use sage_mqtt::{Packet, ReasonCode, Subscribe, Topic};
+use sage_mqtt::{Connect, Packet, Publish, QoS, ReasonCode, Subscribe, SubscriptionOptions, Topic};
 pub mod utils;
 
 use utils::client::Response;
@@ -130,12 +130,74 @@ async fn mqtt_3_8_3_2() {
 /// 1, Application Messages MUST NOT be forwarded to a connection with a ClientID equal to the
 /// ClientID of the publishing connection.
 #[tokio::test]
-async fn mqtt_3_8_3_3() {}
+async fn mqtt_3_8_3_3() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let connect = Connect {
+        client_id: Some("Jaden".into()),
+        ..Default::default()
+    };
+    let (mut jaden, _) = client::connect(&local_addr, connect).await;
+    let no_local = SubscriptionOptions {
+        no_local: true,
+        ..Default::default()
+    };
+    client::subscribe(&mut jaden, "sport/tennis", no_local).await;
+    let mut jarod = client::subscriber(&local_addr, "Jarod", "sport/tennis", QoS::AtMostOnce).await;
+
+    let publish = Publish {
+        topic_name: Topic::from("sport/tennis"),
+        ..Default::default()
+    };
+    client::send(&mut jaden, publish.clone().into()).await;
+    client::receive_publish(&mut jarod).await;
+    assert!(matches!(
+        client::send_waitback(&mut jaden, Packet::PingReq).await,
+        Response::Packet(Packet::PingResp)
+    ));
+
+    // Another matching subscription without No Local still receives the
+    // messages of its own client
+    client::subscribe(&mut jaden, "sport/#", Default::default()).await;
+    client::send(&mut jaden, publish.into()).await;
+    let publish = client::receive_publish(&mut jaden).await;
+    assert_eq!(publish.topic_name, Topic::from("sport/tennis"));
+
+    server::stop(shutdown, server).await;
+}
 
 ////////////////////////////////////////////////////////////////////////////////
 /// MQTT-3.8.3-4: It is a Protocol Error to set the No Local bit to 1 on a Shared Subscription.
 #[tokio::test]
-async fn mqtt_3_8_3_4() {}
+async fn mqtt_3_8_3_4() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut stream, _) = client::connect(&local_addr, Default::default()).await;
+
+    // The filter is written by hand as sage_mqtt loses the share name
+    let filter = b"$share/workers/sport/#";
+    let mut buffer = vec![0x82, 6 + filter.len() as u8, 0, 1, 0, 0, filter.len() as u8];
+    buffer.extend(filter);
+    buffer.push(0b0000_0100);
+
+    if let Response::Packet(Packet::Disconnect(disconnect)) =
+        client::send_waitback_data(&mut stream, buffer).await
+    {
+        assert_eq!(disconnect.reason_code, ReasonCode::ProtocolError);
+    } else {
+        panic!("Expected DISCONNECT after No Local on a shared subscription");
+    }
+
+    server::stop(shutdown, server).await;
+}
 
 ////////////////////////////////////////////////////////////////////////////////
 /// MQTT-3.8.3-5: The Server MUST treat a SUBSCRIBE packet as malformed if any of Reserved bits in
@@ -290,7 +352,39 @@ async fn mqtt_3_8_4_3() {
 /// Topic Filter MUST be re-sent, but Application Messages MUST NOT be lost due to replacing the
 /// Subscription.
 #[tokio::test]
-async fn mqtt_3_8_4_4() {}
+async fn mqtt_3_8_4_4() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    let publish = Publish {
+        qos: QoS::AtLeastOnce,
+        retain: true,
+        packet_identifier: Some(1),
+        topic_name: Topic::from("devices/kettle"),
+        message: b"on".to_vec(),
+        ..Default::default()
+    };
+    assert!(matches!(
+        client::send_waitback(&mut publisher, publish.into()).await,
+        Response::Packet(Packet::PubAck(_))
+    ));
+
+    // The retained message is sent again each time the subscription is
+    // replaced
+    let (mut stream, _) = client::connect(&local_addr, Default::default()).await;
+    for _ in 0..2 {
+        client::subscribe(&mut stream, "devices/+", Default::default()).await;
+        let publish = client::receive_publish(&mut stream).await;
+        assert!(publish.retain);
+        assert_eq!(publish.message, b"on");
+    }
+
+    server::stop(shutdown, server).await;
+}
 
 ////////////////////////////////////////////////////////////////////////////////
 /// MQTT-3.8.4-5: If a Server receives a SUBSCRIBE packet that contains multiple Topic Filters it
@@ -299,7 +393,7 @@ async fn mqtt_3_8_4_4() {}
 #[tokio::test]
 async fn mqtt_3_8_4_5() {
     // Send a sub with three topics
-    let topics = vec!["topic1", "topic2", "topic3"];
+    let topics = ["topic1", "topic2", "topic3"];
 
     let (sessions, server, local_addr, shutdown) = server::spawn(BrokerSettings {
         keep_alive: TIMEOUT_DELAY,
@@ -360,7 +454,7 @@ async fn mqtt_3_8_4_5() {
 #[tokio::test]
 async fn mqtt_3_8_4_6() {
     // Send a sub with three topics
-    let topics = vec!["topic1", "topic2", "topic3"];
+    let topics = ["topic1", "topic2", "topic3"];
 
     let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
         keep_alive: TIMEOUT_DELAY,
@@ -393,8 +487,45 @@ async fn mqtt_3_8_4_6() {
 async fn mqtt_3_8_4_7() {}
 
 ////////////////////////////////////////////////////////////////////////////////
-/// MQTT-3.8.4-8: The QoS of Payload Messages sent in response to a Subscription MUST be the
-/// minimum of the QoS of the originally published message and the Maximum QoS granted by the
-/// Server.
+/// MQTT-3.8.4-8: The QoS of Payload Messages sent in response to a
+/// Subscription MUST be the minimum of the QoS of the originally published
+/// message and the Maximum QoS granted by the Server.
 #[tokio::test]
-async fn mqtt_3_8_4_8() {}
+async fn mqtt_3_8_4_8() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut qos0 = client::subscriber(&local_addr, "Jaden", "sport/tennis", QoS::AtMostOnce).await;
+    let mut qos1 = client::subscriber(&local_addr, "Jarod", "sport/tennis", QoS::AtLeastOnce).await;
+    let mut qos2 = client::subscriber(&local_addr, "Jason", "sport/tennis", QoS::ExactlyOnce).await;
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+
+    for (packet_identifier, qos) in [
+        (None, QoS::AtMostOnce),
+        (Some(42), QoS::AtLeastOnce),
+        (Some(43), QoS::ExactlyOnce),
+    ] {
+        let publish = Publish {
+            qos,
+            packet_identifier,
+            topic_name: Topic::from("sport/tennis"),
+            ..Default::default()
+        };
+        client::send(&mut publisher, publish.into()).await;
+
+        assert_eq!(
+            client::receive_publish(&mut qos0).await.qos,
+            QoS::AtMostOnce
+        );
+        assert_eq!(
+            client::receive_publish(&mut qos1).await.qos as u8,
+            (qos as u8).min(QoS::AtLeastOnce as u8)
+        );
+        assert_eq!(client::receive_publish(&mut qos2).await.qos, qos);
+    }
+
+    server::stop(shutdown, server).await;
+}
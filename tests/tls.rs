@@ -0,0 +1,426 @@
+//! MQTT over TLS listeners serve their clients once the TLS handshake is
+//! complete, with the certificate chain, private key and cipher suites of
+//! their settings. The certificates of the clients are verified against
+//! their client CA bundle.
+#![cfg(feature = "tls")]
+use futures::future::BoxFuture;
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+};
+use sage_broker::{
+    auth::{Authenticator, CertificateIdentity, Credentials, Principal, TlsInfo},
+    service, BrokerSettings, CommandReceiver, Sessions, Trigger,
+};
+use sage_mqtt::{ConnAck, Connect, Packet, ReasonCode};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::{self, JoinHandle},
+    time,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+        ClientConfig, RootCertStore, ALL_VERSIONS,
+    },
+    TlsConnector,
+};
+pub mod utils;
+
+pub use utils::*;
+
+/// Records the TLS connection of the clients it authenticates
+#[derive(Debug, Default)]
+struct Recorder(Mutex<Vec<TlsInfo>>);
+
+impl Authenticator for Recorder {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a Credentials,
+    ) -> BoxFuture<'a, Result<Principal, ReasonCode>> {
+        let tls = credentials.tls.clone().unwrap_or_default();
+        self.0.lock().unwrap().push(tls);
+        Box::pin(async { Ok(Principal::new("client")) })
+    }
+}
+
+/// A file removed once dropped
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn write(name: &str, contents: String) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("sage_broker_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        TempFile(path)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A self-signed certificate of `localhost`, written to PEM files
+struct Certificate {
+    der: CertificateDer<'static>,
+    chain: TempFile,
+    key: TempFile,
+}
+
+impl Certificate {
+    fn generate(name: &str) -> Self {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        Certificate {
+            der: certified.cert.der().clone(),
+            chain: TempFile::write(&format!("{}.crt", name), certified.cert.pem()),
+            key: TempFile::write(
+                &format!("{}.key", name),
+                certified.signing_key.serialize_pem(),
+            ),
+        }
+    }
+
+    fn settings(&self) -> BrokerSettings {
+        BrokerSettings {
+            keep_alive: TIMEOUT_DELAY,
+            tls_certificate_chain: Some(self.chain.0.clone()),
+            tls_private_key: Some(self.key.0.clone()),
+            ..BrokerSettings::valid_default()
+        }
+    }
+}
+
+/// The certificate and private key a client presents
+type Identity = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+/// A certificate authority issuing the certificates of the clients, written
+/// to a PEM file
+struct Authority {
+    issuer: Issuer<'static, KeyPair>,
+    bundle: TempFile,
+}
+
+impl Authority {
+    fn generate(name: &str) -> Self {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Sage Authority");
+        let key = KeyPair::generate().unwrap();
+        let certificate = params.self_signed(&key).unwrap();
+        Authority {
+            issuer: Issuer::new(params, key),
+            bundle: TempFile::write(&format!("{}.ca", name), certificate.pem()),
+        }
+    }
+
+    /// Issues the certificate of a client of the given common name and
+    /// alternative names
+    fn issue(&self, common_name: &str, alt_names: &[&str]) -> Identity {
+        let alt_names: Vec<String> = alt_names.iter().map(|name| name.to_string()).collect();
+        let mut params = CertificateParams::new(alt_names).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let key = KeyPair::generate().unwrap();
+        let certificate = params.signed_by(&key, &self.issuer).unwrap();
+        (
+            vec![certificate.der().clone()],
+            PrivatePkcs8KeyDer::from(key.serialize_der()).into(),
+        )
+    }
+
+    fn settings(&self, certificate: &Certificate, required: bool) -> BrokerSettings {
+        BrokerSettings {
+            tls_client_ca: Some(self.bundle.0.clone()),
+            tls_require_client_certificate: required,
+            ..certificate.settings()
+        }
+    }
+}
+
+/// Spawns a server listening to TLS connections only
+async fn spawn(settings: BrokerSettings) -> (JoinHandle<CommandReceiver>, SocketAddr, Trigger) {
+    let listener = TcpListener::bind("localhost:0").await.unwrap();
+    let local_addr = listener.local_addr().unwrap();
+    let settings = Arc::new(settings);
+    let shutdown = Trigger::default();
+
+    let server = task::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            let (command_sender, command_receiver) = mpsc::unbounded_channel();
+            let command_loop = task::spawn(service::command_loop(
+                settings.clone(),
+                Arc::new(RwLock::new(Sessions::default())),
+                command_receiver,
+                shutdown.clone(),
+            ));
+            service::listen_tls(listener, command_sender, settings, shutdown).await;
+            command_loop.await.unwrap()
+        }
+    });
+    (server, local_addr, shutdown)
+}
+
+/// Establishes a TLS connection trusting the given certificate only,
+/// presenting the given identity if any
+async fn handshake(
+    local_addr: &SocketAddr,
+    certificate: &Certificate,
+    identity: Option<Identity>,
+) -> std::io::Result<TlsStream<TcpStream>> {
+    let mut roots = RootCertStore::empty();
+    roots.add(certificate.der.clone()).unwrap();
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(ALL_VERSIONS)
+        .unwrap()
+        .with_root_certificates(roots);
+    let config = match identity {
+        Some((chain, key)) => builder.with_client_auth_cert(chain, key).unwrap(),
+        None => builder.with_no_client_auth(),
+    };
+    let stream = client::spawn(local_addr).await;
+    TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+}
+
+/// Sends a CONNECT packet and returns the CONNACK, or `None` if the
+/// connection is closed
+async fn send_connect(stream: &mut TlsStream<TcpStream>) -> Option<ConnAck> {
+    let connect = Connect {
+        client_id: Some("Secured".into()),
+        ..Default::default()
+    };
+    let buffer = client::encode(connect.into()).await;
+    // With TLS 1.3, the server verifies the certificate of the client once
+    // the client completed the handshake
+    if stream.write_all(&buffer).await.is_err() {
+        return None;
+    }
+
+    let delay = Duration::from_secs(TIMEOUT_DELAY as u64);
+    match time::timeout(delay, Packet::decode(stream)).await {
+        Ok(Ok(Packet::ConnAck(connack))) => Some(connack),
+        Ok(Err(_)) => None,
+        _ => panic!("Expected CONNACK packet"),
+    }
+}
+
+/// Sends a CONNECT packet and expects a successful CONNACK
+async fn connect(stream: &mut TlsStream<TcpStream>) -> ConnAck {
+    let connack = send_connect(stream).await.expect("Connection closed");
+    assert_eq!(connack.reason_code, ReasonCode::Success);
+    connack
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Clients are served over TLS and authenticated along with the TLS
+/// connection they established.
+#[tokio::test]
+async fn tls_connect() {
+    let certificate = Certificate::generate("connect");
+    let recorder = Arc::new(Recorder::default());
+    let settings = BrokerSettings {
+        authenticator: Some(recorder.clone()),
+        ..certificate.settings()
+    };
+    let (server, local_addr, shutdown) = spawn(settings).await;
+
+    let mut stream = handshake(&local_addr, &certificate, None).await.unwrap();
+    connect(&mut stream).await;
+
+    let tls = recorder.0.lock().unwrap()[0].clone();
+    assert_eq!(tls.version.as_deref(), Some("TLSv1.3"));
+    assert!(tls.cipher.is_some());
+    assert_eq!(tls.certificate, None);
+
+    shutdown.fire();
+    drop(stream);
+    server.await.unwrap();
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// The listener only negotiates the cipher suites of its settings.
+#[tokio::test]
+async fn tls_cipher_suites() {
+    let certificate = Certificate::generate("cipher_suites");
+    let recorder = Arc::new(Recorder::default());
+    let settings = BrokerSettings {
+        authenticator: Some(recorder.clone()),
+        tls_cipher_suites: vec!["TLS13_CHACHA20_POLY1305_SHA256".into()],
+        ..certificate.settings()
+    };
+    let (server, local_addr, shutdown) = spawn(settings).await;
+
+    let mut stream = handshake(&local_addr, &certificate, None).await.unwrap();
+    connect(&mut stream).await;
+
+    let tls = recorder.0.lock().unwrap()[0].clone();
+    assert_eq!(
+        tls.cipher.as_deref(),
+        Some("TLS13_CHACHA20_POLY1305_SHA256")
+    );
+
+    shutdown.fire();
+    drop(stream);
+    server.await.unwrap();
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// A client which does not start a TLS handshake is not served.
+#[tokio::test]
+async fn plain_client() {
+    let certificate = Certificate::generate("plain_client");
+    let (server, local_addr, shutdown) = spawn(certificate.settings()).await;
+
+    let mut stream = client::spawn(&local_addr).await;
+    let connect = Connect {
+        client_id: Some("Plain".into()),
+        ..Default::default()
+    };
+    client::send(&mut stream, connect.into()).await;
+
+    // The server answers with a TLS alert at most, then closes the connection
+    let mut buffer = Vec::new();
+    let delay = Duration::from_secs(TIMEOUT_DELAY as u64);
+    let read = time::timeout(delay, stream.read_to_end(&mut buffer))
+        .await
+        .expect("Connection not closed");
+    assert!(read.is_err() || Packet::decode(&mut buffer.as_slice()).await.is_err());
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// The certificate of a client is verified against the client CA bundle, its
+/// common name and alternative names being read from it.
+#[tokio::test]
+async fn client_certificate() {
+    let certificate = Certificate::generate("client_certificate");
+    let authority = Authority::generate("client_certificate");
+    let recorder = Arc::new(Recorder::default());
+    let settings = BrokerSettings {
+        authenticator: Some(recorder.clone()),
+        certificate_identity: CertificateIdentity::ClientId,
+        ..authority.settings(&certificate, true)
+    };
+    let (server, local_addr, shutdown) = spawn(settings).await;
+
+    let identity = authority.issue("kettle", &["kettle.local"]);
+    let mut stream = handshake(&local_addr, &certificate, Some(identity))
+        .await
+        .unwrap();
+    let connack = connect(&mut stream).await;
+    assert_eq!(connack.assigned_client_id.as_deref(), Some("kettle"));
+
+    let tls = recorder.0.lock().unwrap()[0].clone();
+    let client = tls.certificate.unwrap();
+    assert_eq!(client.subject, "CN=kettle");
+    assert_eq!(client.common_name.as_deref(), Some("kettle"));
+    assert_eq!(client.subject_alt_names, vec![String::from("kettle.local")]);
+    assert!(tls.signature_algorithm.is_some());
+    assert!(tls.key_algorithm.is_some());
+
+    shutdown.fire();
+    drop(stream);
+    server.await.unwrap();
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// A certificate issued by another authority is refused during the
+/// handshake, so its common name cannot identify the client.
+#[tokio::test]
+async fn untrusted_client_certificate() {
+    let certificate = Certificate::generate("untrusted_client_certificate");
+    let authority = Authority::generate("untrusted_client_certificate");
+    let settings = BrokerSettings {
+        certificate_identity: CertificateIdentity::UserName,
+        ..authority.settings(&certificate, false)
+    };
+    let (server, local_addr, shutdown) = spawn(settings).await;
+
+    let impostor = Authority::generate("untrusted_client_certificate_impostor");
+    let identity = impostor.issue("admin", &[]);
+    if let Ok(mut stream) = handshake(&local_addr, &certificate, Some(identity)).await {
+        assert!(send_connect(&mut stream).await.is_none());
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Clients without a certificate are refused during the handshake if it is
+/// required, and are served without one otherwise.
+#[tokio::test]
+async fn required_client_certificate() {
+    let certificate = Certificate::generate("required_client_certificate");
+    let authority = Authority::generate("required_client_certificate");
+
+    let (server, local_addr, shutdown) = spawn(authority.settings(&certificate, true)).await;
+    if let Ok(mut stream) = handshake(&local_addr, &certificate, None).await {
+        assert!(send_connect(&mut stream).await.is_none());
+    }
+    server::stop(shutdown, server).await;
+
+    let settings = BrokerSettings {
+        certificate_identity: CertificateIdentity::UserName,
+        ..authority.settings(&certificate, false)
+    };
+    let (server, local_addr, shutdown) = spawn(settings).await;
+    let mut stream = handshake(&local_addr, &certificate, None).await.unwrap();
+    let connack = send_connect(&mut stream).await.unwrap();
+    assert_eq!(connack.reason_code, ReasonCode::NotAuthorized);
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// A listener without a valid certificate chain and private key, cipher
+/// suites or client CA bundle does not listen.
+#[tokio::test]
+async fn invalid_tls_settings() {
+    let certificate = Certificate::generate("invalid_tls_settings");
+    for settings in [
+        BrokerSettings {
+            tls_certificate_chain: None,
+            ..certificate.settings()
+        },
+        BrokerSettings {
+            tls_private_key: Some(certificate.chain.0.clone()),
+            ..certificate.settings()
+        },
+        BrokerSettings {
+            tls_cipher_suites: vec!["TLS_NULL_WITH_NULL_NULL".into()],
+            ..certificate.settings()
+        },
+        BrokerSettings {
+            tls_require_client_certificate: true,
+            ..certificate.settings()
+        },
+        BrokerSettings {
+            tls_client_ca: Some(certificate.key.0.clone()),
+            ..certificate.settings()
+        },
+    ] {
+        let (server, _, _shutdown) = spawn(settings).await;
+        let delay = Duration::from_secs(TIMEOUT_DELAY as u64);
+        time::timeout(delay, server)
+            .await
+            .expect("Listener still listening")
+            .unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+//! Topic Names and Topic Filters requirements consists in all [MQTT 4.7.x-x]
+//! conformances.
+use sage_broker::BrokerSettings;
+use sage_mqtt::{Packet, Publish, ReasonCode, Subscribe, Topic};
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+pub mod utils;
+
+use utils::client::Response;
+pub use utils::*;
+
+/// Connects a new client and subscribes it to the given filters, returning
+/// the SUBACK reason codes along with the stream.
+async fn subscribe(local_addr: &SocketAddr, filters: &[&str]) -> (TcpStream, Vec<ReasonCode>) {
+    let (mut stream, _) = client::connect(local_addr, Default::default()).await;
+    let subscribe = Subscribe {
+        subscriptions: filters
+            .iter()
+            .map(|&f| (Topic::from(f), Default::default()))
+            .collect(),
+        ..Default::default()
+    };
+
+    if let Response::Packet(Packet::SubAck(suback)) =
+        client::send_waitback(&mut stream, subscribe.into()).await
+    {
+        (stream, suback.reason_codes)
+    } else {
+        panic!("Expected SUBACK after SUBSCRIBE");
+    }
+}
+
+/// Publishes each of the given topic names, in order, from the given stream.
+async fn publish(stream: &mut TcpStream, names: &[&str]) {
+    for &name in names {
+        let publish = Publish {
+            topic_name: Topic::from(name),
+            message: name.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        client::send(stream, publish.into()).await;
+    }
+}
+
+/// Reads the next PUBLISH packet from the given stream and returns its topic
+async fn received_topic(stream: &mut TcpStream) -> String {
+    if let Response::Packet(Packet::Publish(publish)) = client::receive(stream).await {
+        publish.topic_name.to_string()
+    } else {
+        panic!("Expected PUBLISH packet");
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.7.1-1: The multi-level wildcard character MUST be specified either
+/// on its own or following a topic level separator. In either case it MUST be
+/// the last character specified in the Topic Filter.
+#[tokio::test]
+async fn mqtt_4_7_1_1() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (_, reason_codes) = subscribe(
+        &local_addr,
+        &["#", "sport/#", "sport/#/player1", "sport/tennis#"],
+    )
+    .await;
+    assert_eq!(
+        reason_codes,
+        vec![
+            ReasonCode::Success,
+            ReasonCode::Success,
+            ReasonCode::TopicFilterInvalid,
+            ReasonCode::TopicFilterInvalid,
+        ]
+    );
+
+    // The multi-level wildcard matches the parent and any number of child
+    // levels
+    let (mut subscriber, _) = subscribe(&local_addr, &["sport/tennis/player1/#"]).await;
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    publish(
+        &mut publisher,
+        &[
+            "sport/tennis/player2",
+            "sport/tennis/player1",
+            "sport/tennis/player1/ranking",
+            "sport/tennis/player1/score/wimbledon",
+        ],
+    )
+    .await;
+
+    assert_eq!(
+        received_topic(&mut subscriber).await,
+        "sport/tennis/player1"
+    );
+    assert_eq!(
+        received_topic(&mut subscriber).await,
+        "sport/tennis/player1/ranking"
+    );
+    assert_eq!(
+        received_topic(&mut subscriber).await,
+        "sport/tennis/player1/score/wimbledon"
+    );
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.7.1-2: The single-level wildcard can be used at any level in the
+/// Topic Filter, including first and last levels. Where it is used, it MUST
+/// occupy an entire level of the filter.
+#[tokio::test]
+async fn mqtt_4_7_1_2() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (_, reason_codes) = subscribe(&local_addr, &["+", "+/tennis/#", "sport+"]).await;
+    assert_eq!(
+        reason_codes,
+        vec![
+            ReasonCode::Success,
+            ReasonCode::Success,
+            ReasonCode::TopicFilterInvalid,
+        ]
+    );
+
+    let (mut subscriber, _) = subscribe(&local_addr, &["sport/+/player1"]).await;
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    publish(
+        &mut publisher,
+        &[
+            "sport/player1",
+            "sport/tennis/player2",
+            "sport/tennis/player1",
+            "sport/golf/player1",
+        ],
+    )
+    .await;
+
+    assert_eq!(
+        received_topic(&mut subscriber).await,
+        "sport/tennis/player1"
+    );
+    assert_eq!(received_topic(&mut subscriber).await, "sport/golf/player1");
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-4.7.2-1: The Server MUST NOT match Topic Filters starting with a
+/// wildcard character (# or +) with Topic Names beginning with a $ character.
+#[tokio::test]
+async fn mqtt_4_7_2_1() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut subscriber, _) = subscribe(&local_addr, &["#", "+/monitor/Clients"]).await;
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+    publish(
+        &mut publisher,
+        &["$SYS/monitor/Clients", "sport/monitor/Clients"],
+    )
+    .await;
+
+    // Only the second message is expected
+    assert_eq!(
+        received_topic(&mut subscriber).await,
+        "sport/monitor/Clients"
+    );
+
+    server::stop(shutdown, server).await;
+}
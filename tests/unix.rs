@@ -0,0 +1,136 @@
+//! Clients running on the same host can connect over a Unix domain socket,
+//! and be authenticated by the credentials of their process.
+#![cfg(unix)]
+use futures::future::BoxFuture;
+use sage_broker::{
+    auth::{Authenticator, Credentials, Principal},
+    service, BrokerSettings, CommandReceiver, RemoteAddr, Sessions, Trigger,
+};
+use sage_mqtt::{Connect, Packet, ReasonCode};
+use std::{
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{UnixListener, UnixStream},
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
+pub mod utils;
+
+pub use utils::*;
+
+/// Authenticates the clients whose process runs as the given user
+#[derive(Debug)]
+struct SameUser {
+    uid: u32,
+}
+
+impl Authenticator for SameUser {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a Credentials,
+    ) -> BoxFuture<'a, Result<Principal, ReasonCode>> {
+        Box::pin(async move {
+            match &credentials.addr {
+                RemoteAddr::Unix(addr) if addr.uid == Some(self.uid) => {
+                    Ok(Principal::new(&format!("uid:{}", self.uid)))
+                }
+                _ => Err(ReasonCode::NotAuthorized),
+            }
+        })
+    }
+}
+
+/// Spawns a server listening to a new Unix domain socket, whose clients are
+/// authenticated if their process runs as the given user, or as the owner of
+/// the socket if None.
+async fn spawn(
+    name: &str,
+    uid: Option<u32>,
+) -> (
+    Arc<RwLock<Sessions>>,
+    JoinHandle<CommandReceiver>,
+    PathBuf,
+    Trigger,
+) {
+    let path = std::env::temp_dir().join(format!("sage_broker-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let uid = uid.unwrap_or_else(|| std::fs::metadata(&path).unwrap().uid());
+
+    let settings = Arc::new(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        authenticator: Some(Arc::new(SameUser { uid })),
+        ..BrokerSettings::valid_default()
+    });
+    let sessions = Arc::new(RwLock::new(Sessions::default()));
+    let shutdown = Trigger::default();
+
+    let server = task::spawn({
+        let (sessions, shutdown) = (sessions.clone(), shutdown.clone());
+        async move {
+            let (command_sender, command_receiver) = mpsc::unbounded_channel();
+            let command_loop = task::spawn(service::command_loop(
+                settings.clone(),
+                sessions,
+                command_receiver,
+                shutdown.clone(),
+            ));
+            service::listen_unix(listener, command_sender, settings, shutdown).await;
+            command_loop.await.unwrap()
+        }
+    });
+    (sessions, server, path, shutdown)
+}
+
+/// Connects to the socket and returns the stream along with the reason code
+/// of the CONNACK
+async fn connect(path: &PathBuf, client_id: &str) -> (UnixStream, ReasonCode) {
+    let mut stream = UnixStream::connect(path).await.unwrap();
+    let connect = Connect {
+        client_id: Some(client_id.into()),
+        ..Default::default()
+    };
+    stream
+        .write_all(&client::encode(connect.into()).await)
+        .await
+        .unwrap();
+    if let Packet::ConnAck(connack) = Packet::decode(&mut stream).await.unwrap() {
+        (stream, connack.reason_code)
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// The authenticator is given the credentials of the process of the client.
+#[tokio::test]
+async fn peer_credentials() {
+    let (sessions, server, path, shutdown) = spawn("peer_credentials", None).await;
+
+    let (_stream, reason_code) = connect(&path, "Sidecar").await;
+    assert_eq!(reason_code, ReasonCode::Success);
+    let uid = std::fs::metadata(&path).unwrap().uid();
+    let principal = sessions.read().unwrap().get("Sidecar").unwrap().principal();
+    assert_eq!(principal, Some(Principal::new(&format!("uid:{}", uid))));
+
+    server::stop(shutdown, server).await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Clients whose process runs as another user are refused.
+#[tokio::test]
+async fn other_user() {
+    let (sessions, server, path, shutdown) = spawn("other_user", Some(u32::MAX - 1)).await;
+
+    let (_stream, reason_code) = connect(&path, "Sidecar").await;
+    assert_eq!(reason_code, ReasonCode::NotAuthorized);
+    assert!(sessions.read().unwrap().is_empty());
+
+    server::stop(shutdown, server).await;
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,175 @@
+//! UNSUBSCRIBE Actions requirements
+use sage_broker::BrokerSettings;
+use sage_mqtt::{Packet, Publish, QoS, ReasonCode, Topic, UnSubscribe};
+use tokio::net::TcpStream;
+pub mod utils;
+
+use utils::client::Response;
+pub use utils::*;
+
+/// Sends an UNSUBSCRIBE packet for the given filters and returns the reason
+/// codes of the UNSUBACK response
+async fn unsubscribe(stream: &mut TcpStream, filters: &[&str]) -> Vec<ReasonCode> {
+    let unsubscribe = UnSubscribe {
+        packet_identifier: 42,
+        subscriptions: filters.iter().map(|&f| f.into()).collect(),
+        ..Default::default()
+    };
+    if let Response::Packet(Packet::UnSubAck(unsuback)) =
+        client::send_waitback(stream, unsubscribe.into()).await
+    {
+        assert_eq!(unsuback.packet_identifier, 42);
+        unsuback.reason_codes
+    } else {
+        panic!("Expected UNSUBACK packet");
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.10.1-1: Bits 3,2,1 and 0 of the Fixed Header of the UNSUBSCRIBE packet are reserved and
+/// MUST be set to 0,0,1 and 0 respectively. The Server MUST treat any other value as malformed and
+/// close the Network Connection
+macro_rules! mqtt_3_10_1_1 {
+    ($($name:ident: $value:expr,)*) => {
+        $(
+
+                #[tokio::test]
+                async fn $name() {
+                    let (fixed_header, expect_success) = $value;
+                    mqtt_3_10_1_1(fixed_header, expect_success).await
+                }
+
+
+        )*
+    }
+}
+
+mqtt_3_10_1_1! {
+    mqtt_3_10_1_1_0010: (0b1010_0010, true),
+    mqtt_3_10_1_1_0000: (0b1010_0000, false),
+    mqtt_3_10_1_1_0011: (0b1010_0011, false),
+    mqtt_3_10_1_1_1010: (0b1010_1010, false),
+}
+
+async fn mqtt_3_10_1_1(fixed_header: u8, expect_success: bool) {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut stream, _) = client::connect(&local_addr, Default::default()).await;
+
+    let mut buffer = Vec::new();
+    Packet::UnSubscribe(UnSubscribe {
+        subscriptions: vec!["hello/world".into()],
+        ..Default::default()
+    })
+    .encode(&mut buffer)
+    .await
+    .unwrap();
+    buffer[0] = fixed_header; // Force Fixed Header value
+
+    let response = client::send_waitback_data(&mut stream, buffer).await;
+    if expect_success {
+        assert!(matches!(response, Response::Packet(Packet::UnSubAck(_))));
+    } else if let Response::Packet(Packet::Disconnect(packet)) = response {
+        assert_eq!(packet.reason_code, ReasonCode::MalformedPacket);
+    } else {
+        panic!("Expected DISCONNECT after malformed UNSUBSCRIBE");
+    }
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.10.4-1: The Topic Filters (whether they contain wildcards or not) supplied in an
+/// UNSUBSCRIBE packet MUST be compared character-by-character with the current set of Topic
+/// Filters held by the Server for the Client. If any filter matches exactly then its owning
+/// Subscription MUST be deleted.
+/// MQTT-3.10.4-2: When a Server receives UNSUBSCRIBE It MUST stop adding any new messages which
+/// match the Topic Filters, for delivery to the Client.
+#[tokio::test]
+async fn mqtt_3_10_4_1() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut stream = client::subscriber(&local_addr, "Jaden", "sport/#", QoS::AtMostOnce).await;
+    client::subscribe(&mut stream, "news", Default::default()).await;
+    let (mut publisher, _) = client::connect(&local_addr, Default::default()).await;
+
+    // A filter matching the subscription is not enough
+    assert_eq!(
+        unsubscribe(&mut stream, &["sport/+", "sport/#"]).await,
+        vec![ReasonCode::NoSubscriptionExisted, ReasonCode::Success]
+    );
+
+    for name in ["sport/tennis", "news"] {
+        let publish = Publish {
+            topic_name: Topic::from(name),
+            ..Default::default()
+        };
+        client::send(&mut publisher, publish.into()).await;
+    }
+    assert_eq!(
+        client::receive_publish(&mut stream).await.topic_name,
+        Topic::from("news")
+    );
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.10.4-4: The Server MUST respond to an UNSUBSCRIBE request by sending an UNSUBACK packet.
+/// MQTT-3.10.4-5: The UNSUBACK packet MUST have the same Packet Identifier as the UNSUBSCRIBE
+/// packet. Even where no Topic Subscriptions are deleted, the Server MUST respond with an UNSUBACK.
+#[tokio::test]
+async fn mqtt_3_10_4_5() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let (mut stream, _) = client::connect(&local_addr, Default::default()).await;
+    assert_eq!(
+        unsubscribe(&mut stream, &["sport/tennis"]).await,
+        vec![ReasonCode::NoSubscriptionExisted]
+    );
+
+    server::stop(shutdown, server).await;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+/// MQTT-3.10.4-6: If a Server receives an UNSUBSCRIBE packet that contains multiple Topic
+/// Filters, it MUST process that packet as if it had received a sequence of multiple UNSUBSCRIBE
+/// packets, except that it sends just one UNSUBACK response.
+/// MQTT-3.11.3-1: The order of Reason Codes in the UNSUBACK packet MUST match the order of Topic
+/// Filters in the UNSUBSCRIBE packet.
+#[tokio::test]
+async fn mqtt_3_11_3_1() {
+    let (_, server, local_addr, shutdown) = server::spawn(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..BrokerSettings::valid_default()
+    })
+    .await;
+
+    let mut stream = client::subscriber(&local_addr, "Jaden", "sport/#", QoS::AtMostOnce).await;
+    client::subscribe(&mut stream, "news", Default::default()).await;
+
+    // The same filter twice is only removed once
+    assert_eq!(
+        unsubscribe(&mut stream, &["news", "music", "sport/#", "news"]).await,
+        vec![
+            ReasonCode::Success,
+            ReasonCode::NoSubscriptionExisted,
+            ReasonCode::Success,
+            ReasonCode::NoSubscriptionExisted
+        ]
+    );
+
+    server::stop(shutdown, server).await;
+}
@@ -1,5 +1,5 @@
 use crate::utils::TIMEOUT_DELAY;
-use sage_mqtt::{Connect, Packet, ReasonCode};
+use sage_mqtt::{Connect, Packet, Publish, QoS, ReasonCode, Subscribe, SubscriptionOptions, Topic};
 use std::{io::Cursor, net::SocketAddr, time::Duration};
 use tokio::{io::AsyncReadExt, io::AsyncWriteExt, net::TcpStream, time};
 
@@ -23,7 +23,7 @@ pub async fn spawn(local_addr: &SocketAddr) -> TcpStream {
 /// Create a valid TcpStream client and send a connect request, returning the
 /// stream in case of success
 pub async fn connect(local_addr: &SocketAddr, connect: Connect) -> (TcpStream, Option<String>) {
-    let mut stream = spawn(&local_addr).await;
+    let mut stream = spawn(local_addr).await;
 
     if let Response::Packet(Packet::ConnAck(connack)) =
         send_waitback(&mut stream, Packet::Connect(connect)).await
@@ -35,6 +35,55 @@ pub async fn connect(local_addr: &SocketAddr, connect: Connect) -> (TcpStream, O
     }
 }
 
+///////////////////////////////////////////////////////////////////////////////
+/// Connects a new client with the given id and subscribes it to the given
+/// filter with the given QoS.
+pub async fn subscriber(
+    local_addr: &SocketAddr,
+    client_id: &str,
+    filter: &str,
+    qos: QoS,
+) -> TcpStream {
+    let packet = Connect {
+        client_id: Some(client_id.into()),
+        ..Default::default()
+    };
+    let (mut stream, _) = connect(local_addr, packet).await;
+    subscribe(
+        &mut stream,
+        filter,
+        SubscriptionOptions {
+            qos,
+            ..Default::default()
+        },
+    )
+    .await;
+    stream
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Subscribes to the given filter with the given options, expecting a SUBACK
+pub async fn subscribe(stream: &mut TcpStream, filter: &str, options: SubscriptionOptions) {
+    let subscribe = Subscribe {
+        subscriptions: vec![(Topic::from(filter), options)],
+        ..Default::default()
+    };
+    assert!(matches!(
+        send_waitback(stream, subscribe.into()).await,
+        Response::Packet(Packet::SubAck(_))
+    ));
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Reads the next packet from the stream, expecting a PUBLISH
+pub async fn receive_publish(stream: &mut TcpStream) -> Publish {
+    if let Response::Packet(Packet::Publish(publish)) = receive(stream).await {
+        publish
+    } else {
+        panic!("Expected PUBLISH packet");
+    }
+}
+
 /// The kind of response send by send_waitback_data and send_waitback
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
@@ -62,22 +111,84 @@ pub async fn send_waitback_data(stream: &mut TcpStream, buffer: Vec<u8>) -> Resp
         match response {
             Err(e) => panic!("IO Error: {:?}", e),
             Ok(0) => Response::Close,
-            Ok(_) => Response::Packet(Packet::decode(&mut Cursor::new(buf)).await.unwrap()),
+            Ok(_) => {
+                let header = buf[0];
+                let packet = Packet::decode(&mut Cursor::new(buf)).await.unwrap();
+                Response::Packet(restore_header(packet, header))
+            }
+        }
+    } else {
+        Response::None
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Waits for the next packet sent by the server, without sending anything.
+pub async fn receive(stream: &mut TcpStream) -> Response {
+    let delay_with_tolerance = Duration::from_secs((TIMEOUT_DELAY as f32 * 1.5) as u64);
+
+    let mut header = [0u8];
+    let decode = async {
+        stream.peek(&mut header).await?;
+        Packet::decode(stream).await
+    };
+    if let Ok(response) = time::timeout(delay_with_tolerance, decode).await {
+        match response {
+            Err(_) => Response::Close,
+            Ok(packet) => Response::Packet(restore_header(packet, header[0])),
         }
     } else {
         Response::None
     }
 }
 
+///////////////////////////////////////////////////////////////////////////////
+/// Sends the given packet without waiting for any response.
+pub async fn send(stream: &mut TcpStream, packet: Packet) {
+    let buffer = encode(packet).await;
+    stream.write_all(&buffer).await.unwrap();
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 /// Sends the given packet and wait for the next response from the server.
 /// Note that nothing ensures the received packet from the server is a response
 /// to the sent packet.
 pub async fn send_waitback(stream: &mut TcpStream, packet: Packet) -> Response {
-    // Send the packet as buffer
+    // Only the next packet is read, leaving any following one in the stream
+    send(stream, packet).await;
+    receive(stream).await
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Encodes the given packet.
+/// `sage_mqtt` 0.5 misplaces the QoS bits of PUBLISH packets, which are
+/// rewritten here as defined in [MQTT 3.3.1].
+pub async fn encode(packet: Packet) -> Vec<u8> {
+    let header = if let Packet::Publish(publish) = &packet {
+        Some(
+            0b0011_0000
+                | (publish.duplicate as u8) << 3
+                | (publish.qos as u8) << 1
+                | publish.retain as u8,
+        )
+    } else {
+        None
+    };
     let mut buffer = Vec::new();
     packet.encode(&mut buffer).await.unwrap();
-    send_waitback_data(stream, buffer).await
+    if let Some(header) = header {
+        buffer[0] = header;
+    }
+    buffer
+}
+
+/// Restores the DUP flag of a decoded PUBLISH packet from its first byte,
+/// which `sage_mqtt` 0.5 reads from the wrong bits.
+fn restore_header(mut packet: Packet, header: u8) -> Packet {
+    if let Packet::Publish(publish) = &mut packet {
+        publish.duplicate = header & 0b0000_1000 > 0;
+    }
+    packet
 }
 
 ///////////////////////////////////////////////////////////////////////////////
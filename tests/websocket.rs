@@ -0,0 +1,256 @@
+//! MQTT over WebSocket requirements consists in all [MQTT 6.0.0-x]
+//! conformances.
+#![cfg(feature = "websocket")]
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use sage_broker::{service, BrokerSettings, CommandReceiver, Sessions, Trigger};
+use sage_mqtt::{Connect, Packet, ReasonCode};
+use sha1::{Digest, Sha1};
+use std::{
+    io::Cursor,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::{self, JoinHandle},
+    time,
+};
+pub mod utils;
+
+pub use utils::*;
+
+const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+/// The status codes of the closing frames
+const UNSUPPORTED_DATA: u16 = 1003;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Spawns a server listening to WebSocket connections only
+async fn spawn(settings: BrokerSettings) -> (JoinHandle<CommandReceiver>, SocketAddr, Trigger) {
+    let listener = TcpListener::bind("localhost:0").await.unwrap();
+    let local_addr = listener.local_addr().unwrap();
+    let settings = Arc::new(BrokerSettings {
+        keep_alive: TIMEOUT_DELAY,
+        ..settings
+    });
+    let shutdown = Trigger::default();
+
+    let server = task::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            let (command_sender, command_receiver) = mpsc::unbounded_channel();
+            let command_loop = task::spawn(service::command_loop(
+                settings.clone(),
+                Arc::new(RwLock::new(Sessions::default())),
+                command_receiver,
+                shutdown.clone(),
+            ));
+            service::listen_websocket(listener, command_sender, settings, shutdown).await;
+            command_loop.await.unwrap()
+        }
+    });
+    (server, local_addr, shutdown)
+}
+
+/// Sends the opening handshake offering the given subprotocols and returns
+/// the stream along with the status line and headers of the response, whose
+/// names are in lowercase
+async fn handshake(
+    local_addr: &SocketAddr,
+    protocols: &str,
+) -> (BufReader<TcpStream>, String, Vec<String>) {
+    let mut stream = BufReader::new(client::spawn(local_addr).await);
+    let request = format!(
+        "GET /mqtt HTTP/1.1\r\n\
+         Host: localhost\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Protocol: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n",
+        KEY, protocols
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut status = String::new();
+    stream.read_line(&mut status).await.unwrap();
+    let mut headers = Vec::new();
+    loop {
+        let mut header = String::new();
+        stream.read_line(&mut header).await.unwrap();
+        if header.trim().is_empty() {
+            break;
+        }
+        // Header names are case-insensitive
+        let (name, value) = header.split_once(':').unwrap();
+        headers.push(format!("{}: {}", name.to_ascii_lowercase(), value.trim()));
+    }
+    (stream, status.trim().to_string(), headers)
+}
+
+/// Encodes a masked frame, as sent by clients
+fn frame(opcode: u8, fin: bool, payload: &[u8]) -> Vec<u8> {
+    assert!(payload.len() < 126);
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![(fin as u8) << 7 | opcode, 0x80 | payload.len() as u8];
+    frame.extend(mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+/// Reads the next frame sent by the server, returning its opcode and payload
+async fn receive_frame(stream: &mut BufReader<TcpStream>) -> (u8, Vec<u8>) {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await.unwrap();
+    assert_eq!(header[1] & 0x80, 0, "Server frames must not be masked");
+    let mut payload = vec![0u8; (header[1] & 0x7F) as usize];
+    stream.read_exact(&mut payload).await.unwrap();
+    (header[0] & 0x0F, payload)
+}
+
+/// Expects the server to close the connection with the given status code
+async fn expect_close(mut stream: BufReader<TcpStream>, status: u16) {
+    let (opcode, payload) = receive_frame(&mut stream).await;
+    assert_eq!(opcode, CLOSE);
+    assert_eq!(payload[..2], status.to_be_bytes());
+    let mut rest = Vec::new();
+    let closed = time::timeout(
+        Duration::from_secs(TIMEOUT_DELAY as u64),
+        stream.read_to_end(&mut rest),
+    )
+    .await;
+    assert!(matches!(closed, Ok(Ok(0))));
+}
+
+async fn connect_packet(client_id: &str) -> Vec<u8> {
+    let connect = Connect {
+        client_id: Some(client_id.into()),
+        ..Default::default()
+    };
+    client::encode(connect.into()).await
+}
+
+/// Expects the next frame to be a binary one holding a successful CONNACK
+async fn receive_connack(stream: &mut BufReader<TcpStream>) {
+    let (opcode, payload) = receive_frame(stream).await;
+    assert_eq!(opcode, BINARY);
+    if let Packet::ConnAck(connack) = Packet::decode(&mut Cursor::new(payload)).await.unwrap() {
+        assert_eq!(connack.reason_code, ReasonCode::Success);
+    } else {
+        panic!("Expected CONNACK packet");
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-6.0.0-1: MQTT Control Packets MUST be sent in WebSocket binary data
+/// frames. If any other type of data frame is received the recipient MUST
+/// close the Network Connection.
+#[tokio::test]
+async fn mqtt_6_0_0_1() {
+    let (server, local_addr, shutdown) = spawn(BrokerSettings::valid_default()).await;
+
+    let (mut stream, _, _) = handshake(&local_addr, "mqtt").await;
+    let connect = connect_packet("Jaden").await;
+    stream
+        .write_all(&frame(BINARY, true, &connect))
+        .await
+        .unwrap();
+    receive_connack(&mut stream).await;
+
+    // A text frame closes the connection with the unsupported data status,
+    // after a DISCONNECT packet in a binary frame
+    stream.write_all(&frame(0x1, true, b"{}")).await.unwrap();
+    let (opcode, payload) = receive_frame(&mut stream).await;
+    assert_eq!(opcode, BINARY);
+    let packet = Packet::decode(&mut Cursor::new(payload)).await.unwrap();
+    assert!(matches!(packet, Packet::Disconnect(_)));
+    expect_close(stream, UNSUPPORTED_DATA).await;
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-6.0.0-2: A single WebSocket data frame can contain multiple or
+/// partial MQTT Control Packets. The receiver MUST NOT assume that MQTT
+/// Control Packets are aligned on WebSocket frame boundaries.
+#[tokio::test]
+async fn mqtt_6_0_0_2() {
+    let (server, local_addr, shutdown) = spawn(BrokerSettings::valid_default()).await;
+
+    let (mut stream, _, _) = handshake(&local_addr, "mqtt").await;
+    let connect = connect_packet("Jaden").await;
+    let (start, end) = connect.split_at(5);
+    // The CONNECT packet is split over a fragmented message and a second one
+    let mut frames = frame(BINARY, false, &start[..2]);
+    frames.extend(frame(0x0, true, &start[2..]));
+    frames.extend(frame(BINARY, true, end));
+    stream.write_all(&frames).await.unwrap();
+    receive_connack(&mut stream).await;
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-6.0.0-3: The Client MUST include “mqtt” in the list of WebSocket Sub
+/// Protocols it offers.
+#[tokio::test]
+async fn mqtt_6_0_0_3() {
+    let (server, local_addr, shutdown) = spawn(BrokerSettings::valid_default()).await;
+
+    let (_, status, _) = handshake(&local_addr, "mqttv3.1").await;
+    assert_eq!(status, "HTTP/1.1 400 Bad Request");
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// MQTT-6.0.0-4: The WebSocket Subprotocol name selected and returned by the
+/// Server MUST be “mqtt”.
+#[tokio::test]
+async fn mqtt_6_0_0_4() {
+    let (server, local_addr, shutdown) = spawn(BrokerSettings::valid_default()).await;
+
+    let (_, status, headers) = handshake(&local_addr, "mqttv3.1, mqtt").await;
+    assert_eq!(status, "HTTP/1.1 101 Switching Protocols");
+    assert!(headers.contains(&"sec-websocket-protocol: mqtt".to_string()));
+    let accept = BASE64.encode(Sha1::digest(format!(
+        "{}258EAFA5-E914-47DA-95CA-C5AB0DC85B11",
+        KEY
+    )));
+    assert!(headers.contains(&format!("sec-websocket-accept: {}", accept)));
+
+    server::stop(shutdown, server).await;
+}
+
+///////////////////////////////////////////////////////////////////////////////
+/// Frames and messages larger than the maximum packet size are refused
+/// before their payload is received, closing the connection with the
+/// message too big status.
+#[tokio::test]
+async fn message_too_big() {
+    let settings = BrokerSettings {
+        maximum_packet_size: Some(64),
+        ..BrokerSettings::valid_default()
+    };
+    let (server, local_addr, shutdown) = spawn(settings).await;
+
+    // A frame announcing a 1 MB payload
+    let mut large_frame = vec![0x80 | BINARY, 0x80 | 127];
+    large_frame.extend(1_000_000u64.to_be_bytes());
+    large_frame.extend([0x12, 0x34, 0x56, 0x78]);
+    // A fragmented message, whose frames are smaller than a packet
+    let mut fragmented = frame(BINARY, false, &[0; 60]);
+    fragmented.extend(frame(0x0, true, &[0; 60]));
+
+    for frames in [large_frame, fragmented] {
+        let (mut stream, _, _) = handshake(&local_addr, "mqtt").await;
+        stream.write_all(&frames).await.unwrap();
+        expect_close(stream, MESSAGE_TOO_BIG).await;
+    }
+
+    server::stop(shutdown, server).await;
+}